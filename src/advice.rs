@@ -0,0 +1,54 @@
+//! Hints to the page cache of the OS about reads of data files
+//!
+//! Hints only change read-ahead and caching of the OS, so failures to give them are ignored.
+//! On systems without `posix_fadvise` no hints are given
+
+use std::fs::File;
+
+/// Pattern of reads of values, that hints are chosen by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// Lookups of single keys, whose values are scattered over data files,
+    /// so read-ahead of the OS only evicts useful pages
+    Point,
+    /// Scans, that read values in order of keys, which are mostly adjacent in data files
+    Scan,
+    /// Compaction, that reads all values of data files once in order of their offsets
+    Compaction,
+}
+
+/// Hints, that given file is read with given pattern
+pub(crate) fn advise(file: &File, access: Access) {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        let advice = match access {
+            Access::Point => libc::POSIX_FADV_RANDOM,
+            Access::Scan | Access::Compaction => libc::POSIX_FADV_SEQUENTIAL,
+        };
+        fadvise(file, 0, 0, advice);
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let _ = (file, access);
+}
+
+/// Hints, that given range of given file is read soon, so the OS may start reading it
+pub(crate) fn will_need(file: &File, offset: u64, len: u64) {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fadvise(file, offset, len, libc::POSIX_FADV_WILLNEED);
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let _ = (file, offset, len);
+}
+
+/// Gives given advice about given range of given file, ignoring failures
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn fadvise(file: &File, offset: u64, len: u64, advice: libc::c_int) {
+    use std::os::fd::AsRawFd;
+
+    let (Ok(offset), Ok(len)) = (libc::off_t::try_from(offset), libc::off_t::try_from(len)) else {
+        return;
+    };
+    // SAFETY: descriptor is owned by given file, which is open for the duration of the call
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), offset, len, advice);
+    }
+}
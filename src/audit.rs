@@ -0,0 +1,240 @@
+//! Append-only log of changes of a tree
+//!
+//! Every insert and removal of a tree with an audit log is recorded with its time, key,
+//! size of the value and origin tag, see [`crate::bplus_tree::BPlus::with_audit_log`].
+//! Records are appended to numbered files in a directory of the log, which are rotated
+//! once they grow over the size limit
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::error::{BPlusError, Result};
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 1 << 20;
+
+/// Kind of a recorded change
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditOp {
+    /// Value was inserted by the key
+    Insert,
+    /// Key was removed
+    Remove,
+}
+
+/// Recorded change of a tree
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord<K> {
+    /// Time of the change in microseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Kind of the change
+    pub op: AuditOp,
+    /// Changed key
+    pub key: K,
+    /// Size of the inserted value, 0 for removals
+    pub size: u64,
+    /// Tag of the origin of the change, see [`crate::bplus_tree::WriteOptions::origin`]
+    pub origin: Option<String>,
+}
+
+/// Log, that records changes of a tree to files in its directory
+pub struct AuditLog<K> {
+    /// Directory with files of the log
+    path: PathBuf,
+    /// Size, after which records are appended to a new file
+    max_file_size: u64,
+    /// Max number of files, older ones are removed on rotation; None if unlimited
+    max_files: Option<usize>,
+    /// Current file of the log
+    file: Mutex<AuditFile>,
+    /// Error of the last failed record, that is not reported yet
+    error: Mutex<Option<BPlusError>>,
+    _keys: PhantomData<fn(K)>,
+}
+
+/// File of the log, that records are appended to
+struct AuditFile {
+    number: usize,
+    size: u64,
+    file: File,
+}
+
+impl AuditFile {
+    /// Opens file of the log with given number for appending, creating it if needed
+    fn open(path: &Path, number: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.join(number.to_string()))?;
+        let size = file.metadata()?.len();
+        Ok(Self { number, size, file })
+    }
+}
+
+impl<K: Serialize + DeserializeOwned> AuditLog<K> {
+    /// Opens log in directory by given path, appending records after the existing ones
+    pub fn open(path: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        let last = file_numbers(&path)?.last().copied().unwrap_or(0);
+        let file = AuditFile::open(&path, last)?;
+        Ok(Self {
+            path,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_files: None,
+            file: Mutex::new(file),
+            error: Mutex::default(),
+            _keys: PhantomData,
+        })
+    }
+
+    /// Sets size of a file, after which records are appended to a new one
+    ///
+    /// 1 MiB by default
+    pub fn with_max_file_size(mut self, size: u64) -> Self {
+        self.max_file_size = size;
+        self
+    }
+
+    /// Makes log keep only given number of the latest files, removing older ones on rotation
+    ///
+    /// All files are kept by default
+    pub fn with_max_files(mut self, files: usize) -> Self {
+        self.max_files = Some(files.max(1));
+        self
+    }
+
+    /// Appends given change to the log
+    pub fn append(&self, op: AuditOp, key: &K, size: u64, origin: Option<&str>) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64);
+        let record = AuditRecord {
+            timestamp,
+            op,
+            key,
+            size,
+            origin: origin.map(str::to_string),
+        };
+        let data = bincode::serialize(&record)?;
+        let mut frame = Vec::with_capacity(data.len() + 8);
+        frame.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        frame.extend_from_slice(&data);
+
+        let mut file = self.file.lock().unwrap();
+        if file.size > 0 && file.size + frame.len() as u64 > self.max_file_size {
+            *file = AuditFile::open(&self.path, file.number + 1)?;
+            self.remove_old(file.number)?;
+        }
+        // Whole record is written at once, so a failed write leaves at most one torn record
+        file.file.write_all(&frame)?;
+        file.size += frame.len() as u64;
+        Ok(())
+    }
+
+    /// Returns error of the last failed record of a change of the tree, if there is one
+    ///
+    /// Returned error is considered reported and will not be returned again
+    pub fn last_error(&self) -> Option<BPlusError> {
+        self.error.lock().unwrap().take()
+    }
+
+    /// Returns all records of the log in order of their appends
+    ///
+    /// Torn record at the end of a file, which append was interrupted, is skipped
+    pub fn records(&self) -> Result<Vec<AuditRecord<K>>> {
+        self.query(|_| true)
+    }
+
+    /// Returns records of the log, that match given filter, in order of their appends
+    pub fn query<F>(&self, mut filter: F) -> Result<Vec<AuditRecord<K>>>
+    where
+        F: FnMut(&AuditRecord<K>) -> bool,
+    {
+        // Appends wait, so the last file is not read while it is written
+        let _file = self.file.lock().unwrap();
+        let mut records = Vec::new();
+        for number in file_numbers(&self.path)? {
+            let file = File::open(self.path.join(number.to_string()))?;
+            let mut reader = BufReader::new(file);
+            while let Some(data) = read_frame(&mut reader)? {
+                let record = bincode::deserialize(&data)?;
+                if filter(&record) {
+                    records.push(record);
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Removes files of the log, that are older than the kept ones, given number of the current one
+    fn remove_old(&self, current: usize) -> io::Result<()> {
+        let Some(max_files) = self.max_files else {
+            return Ok(());
+        };
+        for number in file_numbers(&self.path)? {
+            if number + max_files <= current {
+                std::fs::remove_file(self.path.join(number.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receiver of changes of a tree, which keys may not be serializable themselves
+pub(crate) trait AuditSink<K>: Send + Sync {
+    /// Records given change, keeping the error to report it later
+    fn record(&self, op: AuditOp, key: &K, size: u64, origin: Option<&str>);
+}
+
+impl<K: Serialize + DeserializeOwned> AuditSink<K> for AuditLog<K> {
+    fn record(&self, op: AuditOp, key: &K, size: u64, origin: Option<&str>) {
+        if let Err(e) = self.append(op, key, size, origin) {
+            *self.error.lock().unwrap() = Some(e);
+        }
+    }
+}
+
+/// Returns numbers of files of the log in directory by given path in ascending order
+fn file_numbers(path: &Path) -> io::Result<Vec<usize>> {
+    let mut numbers = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        if let Some(number) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            numbers.push(number);
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Reads next record from a file, returns None at its end or at a torn record
+pub(crate) fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 8];
+    if !read_full(reader, &mut len)? {
+        return Ok(None);
+    }
+    let mut data = vec![0; u64::from_le_bytes(len) as usize];
+    if !read_full(reader, &mut data)? {
+        return Ok(None);
+    }
+    Ok(Some(data))
+}
+
+/// Fills given buffer from reader, returns false if reader ends before
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
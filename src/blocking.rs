@@ -0,0 +1,171 @@
+use std::{
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    slice, vec,
+};
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{
+    bplus_tree::{BPlus, BPlusKey, BPlusKeySerializable, InsertOutcome},
+    error::Result,
+    runtime::Blocking,
+};
+
+/// Synchronous facade over BPlus, that drives the tree with its own runtime
+///
+/// Runtime can either be owned (by default, a current-thread tokio runtime),
+/// or borrowed, e.g. `&Runtime`.
+pub struct BlockingBPlus<K, R = Runtime> {
+    /// Underlying B+ tree
+    tree: BPlus<K>,
+    /// Runtime, that drives all operations
+    runtime: R,
+}
+
+/// Entries of the tree in ascending order of keys, collected by a scan of the whole tree
+pub struct Snapshot<K> {
+    entries: Vec<(K, Vec<u8>)>,
+}
+
+impl<K> Snapshot<K> {
+    /// Returns number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether there are no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K> IntoIterator for Snapshot<K> {
+    type Item = (K, Vec<u8>);
+    type IntoIter = vec::IntoIter<(K, Vec<u8>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<'a, K> IntoIterator for &'a Snapshot<K> {
+    type Item = &'a (K, Vec<u8>);
+    type IntoIter = slice::Iter<'a, (K, Vec<u8>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+/// Creates runtime, that is used by BlockingBPlus by default
+fn default_runtime() -> Result<Runtime> {
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
+
+impl<K: BPlusKey> BlockingBPlus<K> {
+    /// Creates new instance of B+ tree with given t and path
+    ///
+    /// t represents minimal and maximal quantity of keys in node
+    ///
+    /// All data will be written in files in directory by given path
+    pub fn new(t: usize, path: PathBuf) -> Result<Self> {
+        Self::with_runtime(default_runtime()?, t, path)
+    }
+}
+
+impl<K: BPlusKey> BlockingBPlus<K> {
+    /// Creates new instance of B+ tree with given t and path, that holds given entries,
+    /// see [`BPlus::from_entries`]
+    pub fn from_entries<I>(t: usize, path: PathBuf, entries: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (K, Vec<u8>)>,
+    {
+        let runtime = default_runtime()?;
+        let tree = runtime.block_on(BPlus::from_entries(t, path, entries))?;
+        Ok(Self { tree, runtime })
+    }
+}
+
+impl<K: BPlusKeySerializable + 'static> BlockingBPlus<K> {
+    /// Loads tree from file by provided path
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::load_with_runtime(default_runtime()?, path)
+    }
+}
+
+impl<K: BPlusKey, R: Blocking> BlockingBPlus<K, R> {
+    /// Creates new instance of B+ tree with given t and path, driven by given runtime
+    pub fn with_runtime(runtime: R, t: usize, path: PathBuf) -> Result<Self> {
+        Ok(Self {
+            tree: BPlus::new(t, path)?,
+            runtime,
+        })
+    }
+
+    /// Inserts given value by given key in the B+ tree
+    pub fn insert(&self, key: K, value: Vec<u8>) -> Result<InsertOutcome> {
+        self.runtime.block_on(self.tree.insert(key, value))
+    }
+
+    /// Removes value by given key from the B+ tree, returns sequence number of the removal,
+    /// or None if the key was not present
+    pub fn remove(&self, key: &K) -> Option<u64> {
+        self.runtime.block_on(self.tree.remove(key))
+    }
+
+    /// Gets value from a B+ tree by given key
+    pub fn get(&self, key: &K) -> Result<Vec<u8>> {
+        self.runtime.block_on(self.tree.get(key))
+    }
+
+    /// Returns whether key is contained in the B+ tree or not, without reading its value
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.runtime.block_on(self.tree.contains_key(key))
+    }
+
+    /// Returns all entries with keys in given range, in ascending order of keys
+    pub fn scan<B: RangeBounds<K>>(&self, range: B) -> Result<Vec<(K, Vec<u8>)>> {
+        self.runtime.block_on(self.tree.scan(range))
+    }
+
+    /// Returns all entries of the tree, which may be iterated over
+    pub fn snapshot(&self) -> Result<Snapshot<K>> {
+        let entries = self.scan(..)?;
+        Ok(Snapshot { entries })
+    }
+
+    /// Returns reference to the underlying async tree
+    pub fn tree(&self) -> &BPlus<K> {
+        &self.tree
+    }
+
+    /// Returns the underlying async tree, dropping the runtime
+    pub fn into_inner(self) -> BPlus<K> {
+        self.tree
+    }
+}
+
+impl<K: BPlusKey, R: Blocking> Extend<(K, Vec<u8>)> for BlockingBPlus<K, R> {
+    /// Inserts all given entries, see [`BPlus::extend`]
+    ///
+    /// Panics if a value could not be written, use [`BlockingBPlus::insert`] to handle errors
+    fn extend<I: IntoIterator<Item = (K, Vec<u8>)>>(&mut self, entries: I) {
+        self.runtime
+            .block_on(self.tree.extend(entries))
+            .expect("failed to insert entry");
+    }
+}
+
+impl<K: BPlusKeySerializable + 'static, R: Blocking> BlockingBPlus<K, R> {
+    /// Saves this tree by the provided path
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.runtime.block_on(self.tree.save(path))
+    }
+
+    /// Loads tree from file by provided path, driven by given runtime
+    pub fn load_with_runtime(runtime: R, path: &Path) -> Result<Self> {
+        let tree = runtime.block_on(BPlus::load(path))?;
+        Ok(Self { tree, runtime })
+    }
+}
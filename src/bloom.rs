@@ -0,0 +1,129 @@
+//! Bloom filter over keys of a tree
+//!
+//! Filter answers, whether a key may be in the tree, without descending it, so lookups of
+//! absent keys mostly skip the tree. Keys are only added, so removed keys stay in the filter
+//! and are looked up in the tree, until the filter is built again
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bplus_tree::{BPlus, BPlusKey, BPlusKeySerializable},
+    merkle::{key_hash, mix, KeyHash},
+    metrics::Metrics,
+};
+
+/// Max number of hashes of a key
+const MAX_HASHES: u32 = 16;
+
+/// Bloom filter over keys
+pub(crate) struct Bloom<K> {
+    key_hash: KeyHash<K>,
+    /// Number of bits, that are set for every key
+    hashes: u32,
+    /// Bits of the filter
+    words: Vec<AtomicU64>,
+}
+
+/// Serializable version of a bloom filter, that is saved with the tree
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SerializableBloom {
+    hashes: u32,
+    words: Vec<u64>,
+}
+
+impl<K> Bloom<K> {
+    /// Creates empty filter, sized for given number of keys with given rate of false positives
+    pub(crate) fn new(keys: usize, false_positive_rate: f64, key_hash: KeyHash<K>) -> Self {
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(keys.max(1) as f64) * rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let words = bits.div_ceil(64).max(1);
+        let hashes = ((words * 64) as f64 / keys.max(1) as f64 * ln2).round() as u32;
+        Self {
+            key_hash,
+            hashes: hashes.clamp(1, MAX_HASHES),
+            words: (0..words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Restores filter from its saved version
+    pub(crate) fn from_serializable(bloom: SerializableBloom, key_hash: KeyHash<K>) -> Self {
+        Self {
+            key_hash,
+            hashes: bloom.hashes.clamp(1, MAX_HASHES),
+            words: bloom.words.into_iter().map(AtomicU64::new).collect(),
+        }
+    }
+
+    /// Returns saved version of the filter
+    pub(crate) fn serialize(&self) -> SerializableBloom {
+        SerializableBloom {
+            hashes: self.hashes,
+            words: self
+                .words
+                .iter()
+                .map(|word| word.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+
+    /// Adds given key to the filter
+    pub(crate) fn insert(&self, key: &K) {
+        for bit in self.bits(key) {
+            self.words[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns whether given key may have been added, false if it surely was not
+    pub(crate) fn may_contain(&self, key: &K) -> bool {
+        self.bits(key)
+            .all(|bit| self.words[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    /// Returns size of the filter in bytes
+    pub(crate) fn size(&self) -> usize {
+        self.words.len() * 8
+    }
+
+    /// Returns bits of given key, derived from two hashes of it
+    fn bits(&self, key: &K) -> impl Iterator<Item = usize> {
+        let first = (self.key_hash)(key);
+        let second = mix(first) | 1;
+        let bits = self.words.len() as u64 * 64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bits) as usize)
+    }
+}
+
+impl<K: BPlusKey> BPlus<K> {
+    /// Returns whether bloom filter of the tree has never seen given key,
+    /// so lookup of it may skip descent
+    pub(crate) fn surely_absent(&self, key: &K) -> bool {
+        let absent = self
+            .bloom
+            .as_ref()
+            .is_some_and(|bloom| !bloom.may_contain(key));
+        if absent {
+            Metrics::inc(&self.metrics.bloom_skips);
+        }
+        absent
+    }
+}
+
+impl<K: BPlusKeySerializable> BPlus<K> {
+    /// Makes tree keep a bloom filter over its keys, sized for given number of keys
+    /// with given rate of false positives, e.g. 0.01
+    ///
+    /// Lookups of keys, that the filter has never seen, fail without descent. Filter is saved
+    /// with the tree, and keys of existing entries are added at once. Removed keys are not
+    /// dropped from the filter, so it is built again by calling this method on a loaded tree.
+    /// Filter is not kept by default
+    pub fn with_bloom_filter(mut self, keys: usize, false_positive_rate: f64) -> Self {
+        let bloom = Bloom::new(keys, false_positive_rate, key_hash::<K>);
+        self.for_each_entry(|key, _| bloom.insert(key));
+        self.bloom = Some(bloom);
+        self
+    }
+}
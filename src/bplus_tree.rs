@@ -1,104 +1,189 @@
 use std::{
-    collections::{HashSet, VecDeque},
-    fmt::Debug,
-    fs::{create_dir_all, File},
-    io::{self, BufReader, BufWriter, ErrorKind},
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt::{self, Debug},
+    fs::{create_dir_all, File, OpenOptions},
+    future::{poll_fn, Future},
+    io::{self, Write},
     mem,
+    ops::{Bound, RangeBounds},
     os::unix::fs::FileExt,
     path::{Path, PathBuf},
-    rc::Rc,
+    pin::{pin, Pin},
     sync::{
-        atomic::{AtomicU64, AtomicUsize, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
     },
-    thread, time,
+    task::Poll,
+    time::{Duration, Instant},
 };
 
 use async_recursion::async_recursion;
+use bincode::Options;
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use chunkfs::{Data, DataContainer, Database};
-use tokio::{self, runtime::Runtime, sync::RwLock};
+use futures::{
+    future::try_join_all,
+    stream::{self, Stream},
+};
+use rand::Rng;
+use tokio::{
+    self,
+    sync::{oneshot, Mutex as AsyncMutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{
+    advice::{self, Access},
+    audit::{AuditLog, AuditOp, AuditSink},
+    bloom::{Bloom, SerializableBloom},
+    buffer_pool::{BufferPoolBuilder, LeafPages, NodeId},
+    chunk_table::{ChunkTable, Location, Locations},
+    error::{BPlusError, Result},
+    events::{Hooks, TreeEvent},
+    free_space::FreeSpace,
+    key_codec::{self, Encoded, KeyCodec},
+    key_locks::{KeyLocks, Range},
+    latch::{Evictable, Latch, OwnedLatchReadGuard, OwnedLatchWriteGuard},
+    merkle::{hash_bytes, key_hash, Merkle},
+    metrics::{Histogram, Metrics, MetricsSnapshot, Phases},
+    prefix::{shortest_separator, Separators},
+    record,
+    recorder::{Operation, OperationSink, Recorder},
+    runtime::{timer_sleep, Spawner},
+    search::search_by_key,
+    snapshot,
+    write_buffer::{overlay, WriteBuffer},
+};
+
+pub use crate::compaction::{CompactionReport, FileUsage, SpaceUsage};
+pub use crate::free_space::Extent;
+pub use crate::latch::Fairness;
+pub use crate::merkle::SyncReport;
+pub use crate::record::{DataFileHeader, StoreId};
+pub use crate::store::BPlusStorage;
 
 const DEFAULT_MAX_FILE_SIZE: u64 = 2 << 20;
+const DEFAULT_READ_AHEAD: usize = 16;
+/// Number of values, that bulk loads write to data files in one call
+pub(crate) const BULK_BATCH: usize = 1024;
+/// Name of the directory inside data directory, that holds blob files, see [`BPlus::with_blob_threshold`]
+pub(crate) const BLOBS_DIR: &str = "blobs";
+/// Number of data file in locations of values, that lie in blob files, whose ids are their offsets
+const BLOB_FILE: u32 = u32::MAX;
+/// Name of the file, that marks data directory of a cleanly closed tree
+const CLEAN_MARKER: &str = "clean";
+/// Prefix of the name of the page file, that leaves are evicted to, see [`BPlus::with_buffer_pool`]
+const LEAF_PAGES: &str = "leaves-";
+/// Number of changes, after which memory of the tree is checked against its limit
+const MEMORY_CHECK_INTERVAL: u64 = 1024;
 
-pub trait BPlusKey: Default + Ord + Clone + Sized + Sync + Send {}
-impl<T: Default + Ord + Clone + Sized + Sync + Send> BPlusKey for T {}
+pub trait BPlusKey: Default + Ord + Clone + Sized + Sync + Send + 'static {}
+impl<T: Default + Ord + Clone + Sized + Sync + Send + 'static> BPlusKey for T {}
 
-pub trait BPlusKeySerializable: BPlusKey + Serialize + for<'de> Deserialize<'de> {}
-impl<T: Default + Ord + Clone + Sized + Sync + Send + Serialize + for<'de> Deserialize<'de>>
-    BPlusKeySerializable for T
-{
-}
+/// Key, that the tree persists by its [`KeyCodec`]
+pub trait BPlusKeySerializable: BPlusKey + KeyCodec {}
+impl<T: BPlusKey + KeyCodec> BPlusKeySerializable for T {}
 
 extern crate chunkfs;
 
 /// Serializable version of BPlusTree
+///
+/// Keys are serialized by their [`KeyCodec`]
 #[derive(Serialize, Deserialize)]
-struct SerializableBPlus<K> {
+#[serde(bound = "K: KeyCodec")]
+pub(crate) struct SerializableBPlus<K> {
     t: usize,
-    path: PathBuf,
-    file_number: usize,
-    offset: u64,
+    pub(crate) path: PathBuf,
+    pub(crate) file_number: usize,
+    pub(crate) offset: u64,
     max_file_size: u64,
-    root: SerializableNode<K>,
+    /// Lowest keys of partitions, except the first one
+    #[serde(with = "key_codec::keys")]
+    bounds: Vec<K>,
+    /// Roots of partitions in order of their keys
+    roots: Vec<SerializableNode<K>>,
+    /// Locations of chunks by their ids, see [`ChunkTable`]
+    chunks: Locations,
+    /// Sequence number of the last change
+    sequence: u64,
+    /// Bloom filter over keys; None if it is not kept
+    bloom: Option<SerializableBloom>,
+    /// Id of the store, that data files belong to
+    store: StoreId,
+    /// Dead extents of data files, see [`FreeSpace`]
+    free_space: FreeSpace,
 }
 
 /// Easily serializable version of BPlusTree Node
 #[derive(Serialize, Deserialize)]
+#[serde(bound = "K: KeyCodec")]
 enum SerializableNode<K> {
     Internal(SerializableInternalNode<K>),
     Leaf(SerializableLeaf<K>),
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(bound = "K: KeyCodec")]
 struct SerializableInternalNode<K> {
+    #[serde(with = "key_codec::keys")]
     keys: Vec<K>,
     children: Vec<SerializableNode<K>>,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(bound = "K: KeyCodec")]
 struct SerializableLeaf<K> {
+    #[serde(with = "key_codec::entries")]
     entries: Vec<(K, ChunkHandler)>,
 }
 
-impl<K: Clone + Send + Sync> BPlus<K> {
+impl<K: Clone + Send + Sync + 'static> BPlus<K> {
     /// Returns new instance of SerializableBPlus with data from provided BPlus
-    async fn serialize(&self) -> SerializableBPlus<K> {
+    pub(crate) async fn serialize(&self) -> SerializableBPlus<K> {
+        // Blobs, that die while nodes are read, may still be referred to by the snapshot
+        let dead_blobs = self.free_space.lock().unwrap().blobs();
+        let mut roots = Vec::new();
+        for partition in &self.partitions {
+            roots.push(partition.root.peek().await.serialize().await);
+        }
+        let mut free_space = self.free_space.lock().unwrap().clone();
+        free_space.retain_blobs(&dead_blobs);
+        let data_file = self.data_file.lock().unwrap();
         SerializableBPlus {
             t: self.t,
             path: self.path.clone(),
-            file_number: self.file_number.load(Ordering::SeqCst),
-            offset: self.offset.load(Ordering::SeqCst),
+            file_number: data_file.number,
+            offset: data_file.offset,
             max_file_size: self.max_file_size,
-            root: self.root.read().await.serialize().await,
+            bounds: self.bounds.clone(),
+            roots,
+            chunks: self.chunks.locations(),
+            sequence: self.sequence.load(Ordering::SeqCst),
+            bloom: self.bloom.as_ref().map(Bloom::serialize),
+            store: data_file.store,
+            free_space,
         }
     }
 }
 
-impl<K: Clone + Send + Sync> Node<K> {
+impl<K: Clone + Send + Sync + 'static> Node<K> {
     #[async_recursion]
     /// Returns new instance of SerializableNode with data from provided Node
     async fn serialize(&self) -> SerializableNode<K> {
         match self {
             Node::Internal(internal) => {
-                let keys = internal.keys.iter().map(|k| (**k).clone()).collect();
+                let keys = internal.keys.to_vec();
 
                 let children_clone = internal.children.clone();
                 let mut children = Vec::new();
                 for child in children_clone {
-                    children.push(child.read().await.serialize().await);
+                    children.push(child.peek().await.serialize().await);
                 }
 
                 SerializableNode::Internal(SerializableInternalNode { keys, children })
             }
             Node::Leaf(leaf) => SerializableNode::Leaf(SerializableLeaf {
-                entries: leaf
-                    .entries
-                    .iter()
-                    .map(|(k, v)| ((**k).clone(), v.clone()))
-                    .collect(),
+                entries: leaf.entries(),
             }),
         }
     }
@@ -106,565 +191,4876 @@ impl<K: Clone + Send + Sync> Node<K> {
 
 impl<K: BPlusKeySerializable> SerializableBPlus<K> {
     /// Returns new instance of BPlus with data from provided BPlusSerializable
-    async fn deserialize(self) -> BPlus<K> {
-        let root = Arc::new(RwLock::new(Node::from(self.root)));
+    ///
+    /// Returns Err(_) if saved state is inconsistent or current data file could not be opened
+    pub(crate) async fn deserialize(mut self) -> Result<BPlus<K>> {
+        if self.t < 2 {
+            return Err(BPlusError::Corruption(format!("invalid t = {}", self.t)));
+        }
+        if self.roots.len() != self.bounds.len() + 1 {
+            return Err(BPlusError::Corruption(format!(
+                "{} partitions with {} bounds",
+                self.roots.len(),
+                self.bounds.len()
+            )));
+        }
+        let compact = self.chunks.is_compact();
+        let chunks = ChunkTable::from_locations(Locations::new(self.compact_chunks()?, compact));
+        let partitions = self
+            .roots
+            .into_iter()
+            .enumerate()
+            .map(|(i, root)| Partition::from_serializable(root, self.bounds.get(i).cloned()))
+            .collect();
+        let closed_cleanly = take_clean_marker(&self.path)?;
 
         let tree = BPlus {
-            root: root.clone(),
+            partitions,
+            bounds: self.bounds,
             t: self.t,
             path: self.path.clone(),
-            file_number: AtomicUsize::new(self.file_number),
-            offset: AtomicU64::new(self.offset),
-            current_file: BPlus::<K>::open_current_file(&self.path, self.file_number).unwrap(),
+            data_file: Arc::new(Mutex::new(DataFile::recover(
+                &self.path,
+                self.file_number,
+                self.offset,
+                self.store,
+            )?)),
+            chunks,
             max_file_size: self.max_file_size,
-            latch: RwLock::new(()),
+            sequence: self.sequence.into(),
+            splits: RwLock::new(()),
+            writes: RwLock::new(()),
+            locks: KeyLocks::default(),
+            fairness: Fairness::default(),
+            spawner: None,
+            flush_on_drop: true.into(),
+            slow_threshold: AtomicU64::new(0),
+            read_ahead: DEFAULT_READ_AHEAD.into(),
+            read_hints: true.into(),
+            memory_limit: AtomicUsize::new(0),
+            memory_checked: AtomicU64::new(0),
+            compaction: AsyncMutex::new(()),
+            free_space: Mutex::new(self.free_space),
+            buffer: None,
+            max_key_size: None,
+            max_value_size: None,
+            blob_threshold: None,
+            versions: None,
+            audit: None,
+            recorder: None,
+            merkle: None,
+            bloom: self
+                .bloom
+                .map(|bloom| Bloom::from_serializable(bloom, key_hash::<K>)),
+            pages: None,
+            closed_cleanly,
+            dirty: false.into(),
+            metrics: Metrics::default(),
+            hooks: Hooks::default(),
         };
 
         tree.rebuild_links().await;
-        tree
+        Ok(tree)
+    }
+}
+
+impl<K> SerializableBPlus<K> {
+    /// Moves saved tree to given directory, where its data files keep their names
+    pub(crate) fn relocate(&mut self, dir: &Path) {
+        self.path = dir.to_path_buf();
+    }
+
+    /// Renumbers chunks of entries in order of leaves, dropping locations of chunks,
+    /// that no entry refers to, e.g. of overwritten values
+    ///
+    /// Returns locations of chunks by their new ids
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if an entry refers to a chunk, that is not saved
+    fn compact_chunks(&mut self) -> Result<Vec<Location>> {
+        let mut locations = Vec::new();
+        let mut stack: Vec<_> = self.roots.iter_mut().rev().collect();
+        while let Some(node) = stack.pop() {
+            match node {
+                SerializableNode::Internal(internal) => {
+                    stack.extend(internal.children.iter_mut().rev())
+                }
+                SerializableNode::Leaf(leaf) => {
+                    for (_, handler) in &mut leaf.entries {
+                        let location = self.chunks.get(handler.chunk).ok_or_else(|| {
+                            BPlusError::Corruption(format!(
+                                "entry refers to unknown chunk {}",
+                                handler.chunk
+                            ))
+                        })?;
+                        handler.chunk = locations.len() as u64;
+                        locations.push(location);
+                    }
+                }
+            }
+        }
+        Ok(locations)
+    }
+
+    /// Returns ids of blob files, that entries refer to, see [`BPlus::with_blob_threshold`]
+    pub(crate) fn blobs(&self) -> Vec<u64> {
+        let mut blobs = Vec::new();
+        let mut stack: Vec<_> = self.roots.iter().collect();
+        while let Some(node) = stack.pop() {
+            match node {
+                SerializableNode::Internal(internal) => stack.extend(&internal.children),
+                SerializableNode::Leaf(leaf) => blobs.extend(
+                    leaf.entries
+                        .iter()
+                        .filter_map(|(_, handler)| self.chunks.get(handler.chunk))
+                        .filter(|location| location.file == BLOB_FILE)
+                        .map(|location| location.offset),
+                ),
+            }
+        }
+        blobs.sort_unstable();
+        blobs.dedup();
+        blobs
     }
 }
 
-impl<K> From<SerializableNode<K>> for Node<K> {
-    fn from(node: SerializableNode<K>) -> Self {
+impl<K: Ord> SerializableBPlus<K> {
+    /// Checks saved tree against its data files
+    ///
+    /// Returns report and entries, whose values lie within data files, in order of leaves,
+    /// with kinds and places of their values
+    fn fsck(self) -> (FsckReport, Vec<(K, ChunkKind, ChunkRef)>) {
+        let mut report = FsckReport::default();
+        let mut entries = Vec::new();
+        let mut stack: Vec<_> = self.roots.into_iter().rev().collect();
+        while let Some(node) = stack.pop() {
+            match node {
+                SerializableNode::Internal(internal) => {
+                    stack.extend(internal.children.into_iter().rev())
+                }
+                SerializableNode::Leaf(leaf) => entries.extend(leaf.entries),
+            }
+        }
+        report.entries = entries.len();
+        report.unordered_keys = entries
+            .windows(2)
+            .filter(|pair| pair[0].0 >= pair[1].0)
+            .count();
+
+        let file_size = |path: &Path| std::fs::metadata(path).ok().map(|m| m.len());
+        let mut file_sizes: HashMap<PathBuf, Option<u64>> = (0..=self.file_number)
+            .map(|number| self.path.join(number.to_string()))
+            .map(|path| (path.clone(), file_size(&path)))
+            .collect();
+        let mut records: HashMap<PathBuf, Vec<(u64, u64)>> = HashMap::new();
+        let mut intact = Vec::new();
+        for (key, handler) in entries {
+            let Some(location) = self.chunks.get(handler.chunk) else {
+                report.dangling += 1;
+                continue;
+            };
+            let chunk = ChunkRef::new(&self.path, location, handler.size);
+            let size = *file_sizes
+                .entry(chunk.path.clone())
+                .or_insert_with(|| file_size(&chunk.path));
+            let end = chunk.end();
+            if size.is_some_and(|size| end <= size) {
+                records
+                    .entry(chunk.path.clone())
+                    .or_default()
+                    .push((chunk.offset, end));
+                intact.push((key, handler.kind, chunk));
+            } else {
+                report.dangling += 1;
+            }
+        }
+
+        let mut referenced = 0;
+        for ranges in records.values_mut() {
+            ranges.sort_unstable();
+            let mut covered = 0;
+            for &(start, end) in ranges.iter() {
+                if start < covered {
+                    report.overlapping += 1;
+                }
+                referenced += end.saturating_sub(start.max(covered));
+                covered = covered.max(end);
+            }
+        }
+        let total: u64 = file_sizes
+            .values()
+            .flatten()
+            .map(|size| size.saturating_sub(record::FILE_HEADER_SIZE))
+            .sum();
+        report.orphaned_bytes = total.saturating_sub(referenced);
+
+        (report, intact)
+    }
+}
+
+impl<K: Ord + Clone + 'static> Node<K> {
+    /// Returns node with given high key from its serializable version
+    ///
+    /// Links between siblings are not restored, see [`BPlus::rebuild_links`]
+    fn from_serializable(node: SerializableNode<K>, high_key: Option<K>) -> Self {
         match node {
-            SerializableNode::Internal(internal) => Node::Internal(InternalNode {
-                keys: internal.keys.into_iter().map(Arc::new).collect(),
-                children: internal
+            SerializableNode::Internal(internal) => {
+                let keys = internal.keys;
+                let children = internal
                     .children
                     .into_iter()
-                    .map(|c| Arc::new(RwLock::new(Node::from(c))))
-                    .collect(),
-            }),
+                    .enumerate()
+                    .map(|(i, child)| {
+                        let child_high_key = keys.get(i).cloned().or(high_key.clone());
+                        Arc::new(Latch::new(Node::from_serializable(child, child_high_key)))
+                    })
+                    .collect();
+                Node::Internal(InternalNode {
+                    children,
+                    keys: Separators::new(keys),
+                    high_key,
+                    right: None,
+                })
+            }
             SerializableNode::Leaf(leaf) => Node::Leaf(Leaf {
-                entries: leaf
-                    .entries
-                    .into_iter()
-                    .map(|(k, v)| (Arc::new(k), v))
-                    .collect(),
+                entries: leaf.entries,
                 next: None,
+                high_key,
+                page: None,
             }),
         }
     }
 }
 
-/// Structure that handles chunks written in files.
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
-pub struct ChunkHandler {
-    /// Path to file with chunk.
-    path: PathBuf,
-    /// Offset in file with chunk.
-    offset: u64,
-    /// Size of chunk.
-    size: usize,
+/// Result of offline consistency check of a saved tree and its data files
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Number of entries in the saved tree
+    pub entries: usize,
+    /// Number of entries, whose keys are not greater than keys of previous entries
+    pub unordered_keys: usize,
+    /// Number of entries, whose values lie outside of existing data files
+    pub dangling: usize,
+    /// Number of entries, whose values overlap with values of other entries
+    pub overlapping: usize,
+    /// Number of bytes in data files, that are not referenced by any entry
+    ///
+    /// Overwritten and removed values leave such bytes, so they are not an error by themselves
+    pub orphaned_bytes: u64,
 }
 
-impl ChunkHandler {
-    /// Creates new ChunkHandler, that points to the chunk, that stored in file by path
-    fn new(path: PathBuf, offset: u64, size: usize) -> Self {
-        ChunkHandler { path, offset, size }
+impl FsckReport {
+    /// Returns whether no errors were found
+    pub fn is_consistent(&self) -> bool {
+        self.unordered_keys == 0 && self.dangling == 0 && self.overlapping == 0
     }
+}
 
-    /// Reads data pointed by ChunkHandler.
-    ///
-    /// Returns Err(_) if there is error in opening the file or reading the chunk.
-    fn read(&self) -> io::Result<Vec<u8>> {
-        let file = File::open(self.path.clone())?;
-        let mut buf = vec![0; self.size];
-        file.read_exact_at(&mut buf, self.offset)?;
-        Ok(buf)
-    }
+/// Outcome of an insert, see [`BPlus::insert`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// Key was absent, and a new entry was created
+    Created {
+        /// Sequence number of the insert
+        sequence: u64,
+    },
+    /// Key was present, and its value was replaced
+    Replaced {
+        /// Size of the replaced value in bytes
+        old_size: u64,
+        /// Sequence number of the insert
+        sequence: u64,
+    },
 }
 
-/// A type that represents a reference to another node.
-type Link<K> = Arc<RwLock<Node<K>>>;
+impl InsertOutcome {
+    /// Returns sequence number of the insert, see [`BPlus::last_sequence`]
+    pub fn sequence(&self) -> u64 {
+        match *self {
+            Self::Created { sequence } | Self::Replaced { sequence, .. } => sequence,
+        }
+    }
 
-/// Represents a node in a B+ tree.
-/// All data resides in leaf nodes, while internal nodes.
-/// manage navigation between children.
-#[derive(Clone)]
-enum Node<K> {
-    Internal(InternalNode<K>),
-    Leaf(Leaf<K>),
-}
+    /// Returns size of the replaced value, or None if nothing was replaced
+    pub fn old_size(&self) -> Option<u64> {
+        match *self {
+            Self::Created { .. } => None,
+            Self::Replaced { old_size, .. } => Some(old_size),
+        }
+    }
 
-/// Internal node in a B+ tree
-#[derive(Clone)]
-struct InternalNode<K> {
-    /// Children of that node.
-    children: Vec<Link<K>>,
-    /// Keys of that node.
-    keys: Vec<Arc<K>>,
+    /// Returns outcome of an insert with given sequence number, that replaced a value
+    /// of given size, if there was one
+    fn new(old_size: Option<u64>, sequence: u64) -> Self {
+        match old_size {
+            Some(old_size) => Self::Replaced { old_size, sequence },
+            None => Self::Created { sequence },
+        }
+    }
 }
 
-/// Leaf node in a B+ tree
-#[derive(Default, Clone)]
-struct Leaf<K> {
-    /// Data entries that stored in that leaf.
-    entries: Vec<(Arc<K>, ChunkHandler)>,
-    /// Link to the next leaf; None if there are none.
-    next: Option<Link<K>>,
+/// Key of an entry with the location of its value, read without reading the value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryMeta<K> {
+    /// Key of the entry
+    pub key: K,
+    /// Path to the data file, that holds the value
+    pub path: PathBuf,
+    /// Offset of the value in the data file
+    pub offset: u64,
+    /// Size of the value
+    pub size: usize,
+    /// Kind of the value
+    pub kind: ChunkKind,
 }
 
-/// B+ tree
-pub struct BPlus<K> {
-    /// Root of the B+ tree.
-    root: Link<K>,
-    /// Parameter, that represents minimal and maximal amount of node keys.
-    t: usize,
-    /// Path to the directory, in which all data will be writen.
-    path: PathBuf,
-    /// Number of current file.
-    file_number: AtomicUsize,
-    /// Current offset in current file.
-    offset: AtomicU64,
-    /// Current file.
-    current_file: Arc<RwLock<File>>,
-    /// Max file size.
-    max_file_size: u64,
-    // Latch for root
-    latch: RwLock<()>,
+/// Approximate number of entries in a range of keys and size of their values
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct RangeEstimate {
+    /// Number of entries with keys in the range
+    pub entries: u64,
+    /// Bytes taken by their values in data files
+    pub bytes: u64,
 }
 
-/// Wrapper for BPlusTree with sync functions with async runtime
-pub struct BPlusStorage<K> {
-    /// BPlusTree
-    tree: Arc<BPlus<K>>,
-    /// Async tokio runtime for operations
-    runtime: Runtime,
-    /// Currently inserting keys
-    keys_set: Arc<Mutex<HashSet<K>>>,
+/// Options of a single read, see [`BPlus::get_opt`] and [`BPlus::scan_opt`]
+///
+/// Default options read the same way as [`BPlus::get`] and [`BPlus::scan`] do
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct ReadOptions {
+    /// Time, after which read gives up with Err(BPlusError::LockTimeout). None waits forever
+    pub timeout: Option<Duration>,
+    /// Read-ahead window of a scan, see [`BPlus::set_read_ahead`]. None uses the window of the tree
+    pub read_ahead: Option<usize>,
 }
 
-impl<K: BPlusKey> BPlusStorage<K> {
-    /// Creates new instance of B+ tree with given runtime, t and path
-    ///
-    /// runtime is tokio runtime
-    ///
-    /// t represents minimal and maximum quantity of keys in the node
-    ///
-    /// All data will be written in directory by given path
-    pub fn new(runtime: Runtime, t: usize, path: PathBuf) -> io::Result<Self> {
-        let tree = BPlus::new(t, path).unwrap();
-        Ok(Self {
-            tree: Arc::new(tree),
-            runtime,
-            keys_set: Arc::new(Mutex::new(HashSet::new())),
-        })
+impl ReadOptions {
+    /// Gives up reading after given timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
     }
-}
 
-impl<K: std::hash::Hash + 'static + BPlusKey> Database<K, DataContainer<()>> for BPlusStorage<K> {
-    /// Inserts given value by given key in the B+ tree
-    fn insert(&mut self, key: K, value: DataContainer<()>) -> io::Result<()> {
-        let tree = self.tree.clone();
+    /// Scans with given read-ahead window instead of the window of the tree
+    pub fn with_read_ahead(mut self, window: usize) -> Self {
+        self.read_ahead = Some(window);
+        self
+    }
+}
 
-        let value = match value.extract() {
-            Data::Chunk(chunk) => chunk.clone(),
-            Data::TargetChunk(_chunk) => unimplemented!(),
-        };
+/// Options of a single insert, see [`BPlus::insert_opt`]
+///
+/// Default options insert the same way as [`BPlus::insert`] does
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Kind of the inserted value
+    pub kind: ChunkKind,
+    /// Whether data files are synced to disk before insert returns, see [`BPlus::sync`]
+    pub sync: bool,
+    /// Whether insert fails with Err(BPlusError::KeyExists), if the key is already in the tree
+    pub if_absent: bool,
+    /// Time, after which insert gives up with Err(BPlusError::LockTimeout). None waits forever
+    pub timeout: Option<Duration>,
+    /// Tag of the origin of the insert, that is recorded to the audit log, see [`BPlus::with_audit_log`]
+    pub origin: Option<&'static str>,
+}
 
-        let set_clone = self.keys_set.clone();
-        set_clone.lock().unwrap().insert(key.clone());
+impl WriteOptions {
+    /// Inserts value of given kind
+    pub fn with_kind(mut self, kind: ChunkKind) -> Self {
+        self.kind = kind;
+        self
+    }
 
-        self.runtime.spawn(async move {
-            tree.insert(key.clone(), value).await;
-            set_clone.lock().unwrap().remove(&key);
-        });
-        Ok(())
+    /// Syncs data files to disk before insert returns
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
     }
 
-    /// Gets value by given key from B+ tree
-    fn get(&self, key: &K) -> io::Result<DataContainer<()>> {
-        let tree = self.tree.clone();
-        let set_clone = self.keys_set.clone();
+    /// Fails insert, if the key is already in the tree
+    pub fn with_if_absent(mut self, if_absent: bool) -> Self {
+        self.if_absent = if_absent;
+        self
+    }
 
-        Ok(self
-            .runtime
-            .block_on(async move {
-                while set_clone.lock().unwrap().contains(key) {
-                    thread::sleep(time::Duration::from_millis(10));
-                }
-                tree.get(key).await.unwrap()
-            })
-            .into())
+    /// Gives up inserting after given timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
     }
 
-    /// Returns whether key is contained in the B+ tree or not
-    fn contains(&self, key: &K) -> bool {
-        self.get(key).is_ok()
+    /// Tags insert with given origin in the audit log
+    pub fn with_origin(mut self, origin: &'static str) -> Self {
+        self.origin = Some(origin);
+        self
     }
 }
 
-#[allow(dead_code)]
-impl<K: BPlusKey> BPlus<K> {
-    /// Creates new instance of B+ tree with given t and path
-    ///
-    /// t represents minimal and maximal quantity of keys in node
-    ///
-    /// All data will be written in files in directory by given path
-    pub fn new(t: usize, path: PathBuf) -> io::Result<Self> {
-        let path_to_file = path.join("0");
-        create_dir_all(&path)?;
-        let current_file = File::create(path_to_file)?;
+/// Bucket of an equi-depth histogram of keys
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistogramBucket<K> {
+    /// Lowest key of the bucket, None for the first one. Bucket ends where the next one starts
+    pub start: Option<K>,
+    /// Approximate number of entries in the bucket
+    pub entries: u64,
+}
 
-        Ok(Self {
-            root: Arc::new(RwLock::new(Node::Leaf(Leaf::default()))),
-            t,
-            path,
-            file_number: 0.into(),
-            offset: 0.into(),
-            current_file: Arc::new(RwLock::new(current_file)),
-            max_file_size: DEFAULT_MAX_FILE_SIZE,
-            latch: RwLock::new(()),
-        })
+/// Statistics of the shape of the tree
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct TreeStats {
+    /// Number of levels in the tree, including leaves
+    pub height: usize,
+    /// Number of internal nodes
+    pub internal_nodes: usize,
+    /// Number of leaves
+    pub leaf_nodes: usize,
+    /// Number of stored entries
+    pub entries: usize,
+    /// Average ratio of leaf entries to maximal leaf capacity
+    pub avg_leaf_fill: f64,
+    /// Median leaf fill factor
+    pub p50_leaf_fill: f64,
+    /// 90th percentile of leaf fill factor
+    pub p90_leaf_fill: f64,
+    /// 99th percentile of leaf fill factor
+    pub p99_leaf_fill: f64,
+    /// Bytes taken by keys stored in nodes, excluding heap memory owned by keys
+    pub key_bytes: usize,
+}
+
+/// Approximate heap memory taken by the in-memory tree, in bytes
+///
+/// Heap memory owned by keys themselves, e.g. contents of strings, is not counted
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Nodes with their locks and vectors of children and entries, except stored keys
+    pub nodes: usize,
+    /// Keys stored inline in nodes
+    pub keys: usize,
+    /// Locations of values in data files, that value handles refer to
+    pub handles: usize,
+    /// Entries held by the write buffer, see [`BPlus::with_write_buffer`]
+    pub buffer: usize,
+    /// Bits of the bloom filter over keys, see [`BPlus::with_bloom_filter`]
+    pub bloom: usize,
+    /// Handlers of previous values, see [`BPlus::with_versions`]
+    pub versions: usize,
+    /// Pages of evicted leaves, that are cached by the buffer pool,
+    /// see [`BPlus::with_buffer_pool`]
+    pub pages: usize,
+}
+
+impl MemoryUsage {
+    /// Returns total number of bytes
+    pub fn total(&self) -> usize {
+        self.nodes
+            + self.keys
+            + self.handles
+            + self.buffer
+            + self.bloom
+            + self.versions
+            + self.pages
     }
+}
 
-    /// Creates new chunk_handler and writes data to a file
-    async fn get_chunk_handler(&self, value: Vec<u8>) -> io::Result<ChunkHandler> {
-        let mut file_guard = self.current_file.write().await;
-        if self.offset.load(std::sync::atomic::Ordering::SeqCst) >= self.max_file_size {
-            self.file_number
-                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-            self.offset.store(0, std::sync::atomic::Ordering::SeqCst);
-            let file_number = self.file_number.load(Ordering::SeqCst).to_string();
-            let file_path = self.path.join(file_number);
+/// Kind of data, that is stored by a key.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkKind {
+    /// Raw chunk data.
+    #[default]
+    Chunk,
+    /// Serialized references to target chunks.
+    Target,
+}
 
-            *file_guard = File::create(file_path).unwrap();
-        }
+/// Structure that handles chunks written in files.
+///
+/// Chunk is referred to by its id, which is resolved to its location in data files through
+/// the table of chunks of the tree
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct ChunkHandler {
+    /// Id of the chunk in the table of chunks.
+    pub(crate) chunk: u64,
+    /// Size of chunk.
+    pub(crate) size: usize,
+    /// Kind of the stored data.
+    pub(crate) kind: ChunkKind,
+    /// Sequence number of the change, that inserted the chunk by its key.
+    version: u64,
+    /// Hash of the chunk.
+    pub(crate) digest: u64,
+}
 
-        let value_size = value.len();
-        file_guard.write_at(
-            &value,
-            self.offset.load(std::sync::atomic::Ordering::SeqCst),
-        )?;
-        let value_to_insert = ChunkHandler::new(
-            self.path.join(
-                self.file_number
-                    .load(std::sync::atomic::Ordering::SeqCst)
-                    .to_string(),
-            ),
-            self.offset.load(std::sync::atomic::Ordering::SeqCst),
-            value.len(),
-        );
-        self.offset
-            .fetch_add(value_size as u64, std::sync::atomic::Ordering::SeqCst);
-        Ok(value_to_insert)
+impl ChunkHandler {
+    /// Creates new ChunkHandler, that points to the chunk with given id
+    fn new(chunk: u64, size: usize, kind: ChunkKind, digest: u64) -> Self {
+        ChunkHandler {
+            chunk,
+            size,
+            kind,
+            version: 0,
+            digest,
+        }
     }
+}
 
-    /// Inserts given value by given key in the B+ tree
-    ///
-    /// Returns Err(_) if file could not be created
-    pub async fn insert(&self, key: K, value: Vec<u8>) {
-        let value = self.get_chunk_handler(value).await.unwrap();
-        let mut path = Vec::new(); // Path to leaf
-                                   // Insert that implies that target leaf is safe. Otherwise returns Err()
-        if self
-            .optimistic_insert(key.clone(), value.clone())
-            .await
-            .is_ok()
-        {
-            return;
-        }
-        let mut latch_guard = Some(self.latch.write());
-        let key = Arc::new(key);
-        let mut current = self.root.clone();
-        let mut split_result;
-        let mut guards = VecDeque::new();
+/// Chunk, resolved to its place in a data file
+#[derive(Clone, Debug)]
+pub(crate) struct ChunkRef {
+    /// Path to file with chunk.
+    path: PathBuf,
+    /// Offset of the record of chunk in file, see [`record`].
+    offset: u64,
+    /// Size of chunk.
+    size: usize,
+}
 
-        // Descent to the leaf
-        loop {
-            let mut current_node = current.write_owned().await;
-            if let Some(guard) = latch_guard.take() {
-                drop(guard);
-                latch_guard = None;
+impl ChunkRef {
+    /// Returns chunk with given location and size in data files in given directory
+    ///
+    /// Location in [`BLOB_FILE`] refers to the only record of the blob file with id in its offset
+    fn new(dir: &Path, location: Location, size: usize) -> Self {
+        if location.file == BLOB_FILE {
+            return Self {
+                path: blob_path(dir, location.offset),
+                offset: record::FILE_HEADER_SIZE,
+                size,
             };
-            match &mut *current_node {
-                Node::Leaf(leaf) => {
-                    match leaf.entries.binary_search_by(|(k, _)| k.cmp(&key)) {
-                        Ok(pos) => leaf.entries[pos] = (key.clone(), value),
-                        Err(pos) => leaf.entries.insert(pos, (key.clone(), value)),
-                    };
+        }
+        Self {
+            path: dir.join(location.file.to_string()),
+            offset: location.offset,
+            size,
+        }
+    }
 
-                    split_result = if leaf.entries.len() == 2 * self.t {
-                        Some(current_node.split(self.t))
-                    } else {
-                        while !guards.is_empty() {
-                            drop(guards.pop_front().unwrap());
-                        }
-                        None
-                    };
+    /// Returns offset of the end of the record of chunk
+    fn end(&self) -> u64 {
+        self.offset + record::HEADER_SIZE + self.size as u64
+    }
 
-                    // if path is empty, then current node is root
-                    if path.is_empty() {
-                        guards.push_back(current_node);
-                    } else {
-                        drop(current_node);
-                    }
+    /// Reads data pointed by ChunkRef, hinting the OS, that the file is read with given
+    /// pattern, if there is one.
+    ///
+    /// Returns Err(_) if there is error in opening the file or reading the chunk,
+    /// and Err(_) of kind InvalidData if its record is torn or corrupted.
+    fn read(&self, access: Option<Access>) -> io::Result<Vec<u8>> {
+        let file = open_for(&self.path, access)?;
+        let mut buf = vec![0; (self.end() - self.offset) as usize];
+        file.read_exact_at(&mut buf, self.offset)?;
+        record::decode(&buf, self.size)?;
+        buf.drain(..record::HEADER_SIZE as usize);
+        Ok(buf)
+    }
 
+    /// Reads data pointed by all given chunks, in order of chunks
+    ///
+    /// Chunks are read in order of files and offsets, and adjacent chunks of one file
+    /// are read in one call. Returns values with the number of chunks, that were read
+    /// together with the preceding one. If there is a pattern of reads, the OS is hinted
+    /// with it and with all ranges to read, before they are read
+    ///
+    /// Returns Err(_) if there is error in opening some file or reading some chunk.
+    pub(crate) fn read_many(
+        chunks: &[ChunkRef],
+        access: Option<Access>,
+    ) -> io::Result<(Vec<Vec<u8>>, usize)> {
+        let mut order: Vec<_> = (0..chunks.len()).collect();
+        order.sort_by(|&a, &b| {
+            let (a, b) = (&chunks[a], &chunks[b]);
+            (&a.path, a.offset).cmp(&(&b.path, b.offset))
+        });
+        let mut coalesced = 0;
+        // Runs of adjacent chunks of one file, as ranges of order with ends of the runs
+        let mut runs = Vec::new();
+        let mut i = 0;
+        while i < order.len() {
+            let first = &chunks[order[i]];
+            let mut end = first.end();
+            let mut j = i + 1;
+            // Chunks of repeated keys overlap the run
+            while let Some(next) = order.get(j).map(|&k| &chunks[k]) {
+                if next.path != first.path || next.offset > end {
                     break;
                 }
-                Node::Internal(internal) => {
-                    let pos = match internal.keys.binary_search(&key) {
-                        Ok(pos) => pos + 1,
-                        Err(pos) => pos,
-                    };
-
-                    // droping guards if nodes are not going to be changed
-                    if internal.keys.len() != 2 * self.t - 2 {
-                        while !guards.is_empty() {
-                            drop(guards.pop_front().unwrap());
-                        }
-                    }
-
-                    let next_node = internal.children[pos].clone();
-
-                    path.push(pos);
-
-                    current = next_node;
-                }
+                end = end.max(next.end());
+                j += 1;
             }
-
-            guards.push_back(current_node);
+            coalesced += j - i - 1;
+            runs.push((i, j, end));
+            i = j;
         }
 
-        // Going up to the root splitting nodes if needed
-        while let Some(pos) = path.pop() {
-            if let Some((new_node, median)) = split_result.take() {
-                let mut node = guards.pop_back().unwrap();
-                if let Node::Internal(internal) = &mut *node {
-                    internal.keys.insert(pos, median.clone());
-                    internal.children.insert(pos + 1, new_node);
-                    if internal.keys.len() == 2 * self.t - 1 {
-                        split_result = Some(node.split(self.t));
-                    } else {
-                        split_result = None;
-                    }
-                }
-                if path.is_empty() {
-                    guards.push_back(node);
-                } else {
-                    drop(node);
-                }
-            }
-        }
-
-        // splitting root if needed
-        if let Some((new_node, median)) = split_result.take() {
-            // if path is empty, then current node is root
-            if path.is_empty() {
-                if let Some(mut node) = guards.pop_back() {
-                    match &mut *node {
-                        Node::Internal(internal) => {
-                            let mut old_root_children = Vec::new();
-                            let mut old_root_keys = Vec::new();
-                            mem::swap(&mut old_root_keys, &mut internal.keys);
-                            mem::swap(&mut old_root_children, &mut internal.children);
-                            let old_root = Node::<K>::Internal(InternalNode {
-                                children: (old_root_children),
-                                keys: (old_root_keys),
-                            });
-                            internal.children.push(Arc::new(RwLock::new(old_root)));
-                            internal.children.push(new_node);
-                            internal.keys.push(median.clone());
-                        }
-                        Node::Leaf(leaf) => {
-                            let mut old_root_entries = Vec::new();
-                            let old_root_next = leaf.next.clone();
-                            mem::swap(&mut old_root_entries, &mut leaf.entries);
-                            let old_root = Node::<K>::Leaf(Leaf {
-                                entries: old_root_entries,
-                                next: old_root_next,
-                            });
-                            let new_root = Node::<K>::Internal(InternalNode {
-                                children: (vec![Arc::new(RwLock::new(old_root)), new_node]),
-                                keys: (vec![median.clone()]),
-                            });
-                            *node = new_root;
-                        }
-                    }
-                    drop(node);
+        let mut files: HashMap<&Path, File> = HashMap::new();
+        if access.is_some() {
+            // All runs are requested at once, so the OS reads them while the first ones are copied
+            for &(i, _, end) in &runs {
+                let first = &chunks[order[i]];
+                if !files.contains_key(first.path.as_path()) {
+                    files.insert(&first.path, open_for(&first.path, access)?);
                 }
+                advice::will_need(
+                    &files[first.path.as_path()],
+                    first.offset,
+                    end - first.offset,
+                );
             }
         }
-
-        for guard in guards {
-            drop(guard);
+        let mut values = vec![Vec::new(); chunks.len()];
+        for (i, j, end) in runs {
+            let first = &chunks[order[i]];
+            if !files.contains_key(first.path.as_path()) {
+                files.insert(&first.path, open_for(&first.path, access)?);
+            }
+            let file = &files[first.path.as_path()];
+            let mut buf = vec![0; (end - first.offset) as usize];
+            file.read_exact_at(&mut buf, first.offset)?;
+            for &k in &order[i..j] {
+                let start = (chunks[k].offset - first.offset) as usize;
+                let end = (chunks[k].end() - first.offset) as usize;
+                values[k] = record::decode(&buf[start..end], chunks[k].size)?.to_vec();
+            }
         }
+        Ok((values, coalesced))
     }
+}
+
+/// Data file, that values are appended to, with the offset of the next value
+///
+/// Space in it is reserved, and it is rotated, only by the writer, that holds its lock
+pub(crate) struct DataFile {
+    /// Number of the file, which is also its name
+    number: usize,
+    /// Offset, at which the next value is written
+    offset: u64,
+    /// Opened file
+    file: File,
+    /// Id of the store, that all data files in the directory belong to
+    store: StoreId,
+    /// Id of the next blob file; None until blob files are listed
+    next_blob: Option<u64>,
+}
 
-    #[allow(unused_variables)]
-    fn remove(&mut self, key: Rc<K>) -> io::Result<()> {
-        unimplemented!()
+impl DataFile {
+    /// Creates data file with given number in given directory, that belongs to given store,
+    /// and writes its header
+    pub(crate) fn create(dir: &Path, number: usize, store: StoreId) -> io::Result<Self> {
+        let file = File::create(dir.join(number.to_string()))?;
+        let header = DataFileHeader::new(store).encode();
+        file.write_all_at(&header, 0)?;
+        Ok(Self {
+            number,
+            offset: record::FILE_HEADER_SIZE,
+            file,
+            store,
+            next_blob: None,
+        })
     }
 
-    /// Gets value from a B+ tree by given key
-    pub async fn get(&self, key: &K) -> io::Result<Vec<u8>> {
-        let mut latch_guard = Some(self.latch.read());
-        let mut current = self.root.clone();
+    /// Returns id of the store, that the file belongs to
+    pub(crate) fn store(&self) -> StoreId {
+        self.store
+    }
 
-        let mut prev_guard = None;
-        loop {
-            let node = current.read_owned().await;
-            if let Some(guard) = latch_guard {
-                drop(guard);
-                latch_guard = None;
-            }
-            if prev_guard.is_some() {
-                drop(prev_guard);
-            }
-            match &*node {
-                Node::Leaf(leaf) => {
-                    return match leaf.entries.binary_search_by(|(k, _)| k.as_ref().cmp(key)) {
-                        Ok(pos) => {
-                            let data_read_result = leaf.entries[pos].1.read()?;
-                            drop(node);
-                            Ok(data_read_result)
-                        }
-                        Err(_) => {
-                            drop(node);
-                            Err(ErrorKind::NotFound.into())
-                        }
-                    };
-                }
-                Node::Internal(internal) => {
-                    let pos = match internal.keys.binary_search_by(|k| k.as_ref().cmp(key)) {
-                        Ok(pos) => pos + 1,
-                        Err(pos) => pos,
-                    };
+    /// Returns number of the file and offset, at which the next value is written
+    pub(crate) fn position(&self) -> (usize, u64) {
+        (self.number, self.offset)
+    }
 
-                    current = match internal.children.get(pos) {
-                        Some(child) => child.clone(),
-                        None => {
-                            drop(node);
-                            return Err(ErrorKind::NotFound.into());
-                        }
-                    };
-                }
-            }
-            prev_guard = Some(node);
-        }
+    /// Opens existing data file with given number in given directory, that belongs to given
+    /// store, for appending at given offset
+    pub(crate) fn open(dir: &Path, number: usize, offset: u64, store: StoreId) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.join(number.to_string()))?;
+        Ok(Self {
+            number,
+            offset,
+            file,
+            store,
+            next_blob: None,
+        })
     }
 
-    /// For optimistic latch crabbing
-    ///
-    /// Insert firstly implies that leaf is safe
+    /// Opens the last data file in given directory for appending at its end, given number
+    /// of the file and offset, at which a saved tree of given store appended values
     ///
-    /// If it is safe, than inserts(without write locks on other nodes) to the leaf and returns Ok
+    /// Values may have been appended after the tree was saved, and files may have been rotated,
+    /// so saved position is only the lower bound. Offset is not moved below it, even if the
+    /// file is shorter, so values, that the tree refers to, are not overwritten. Records past
+    /// it are checked, and the torn tail of a crashed append is cut off
     ///
-    /// Else, returns Err
-    ///
-    /// Also returns Err if root is leaf
-    async fn optimistic_insert(&self, key: K, value: ChunkHandler) -> Result<(), ()> {
-        let mut latch_guard = Some(self.latch.read());
-        let mut current = self.root.clone();
-        let key = Arc::new(key);
+    /// Returns Err(BPlusError::UnsupportedVersion { .. }) if some data file in the directory
+    /// was written in another version, Err(BPlusError::Corruption(_)) if it belongs to another
+    /// store or has no valid header
+    pub(crate) fn recover(dir: &Path, number: usize, offset: u64, store: StoreId) -> Result<Self> {
+        let numbers = data_file_numbers(dir)?;
+        let last = numbers.last().map_or(number, |&last| last.max(number));
+        for &other in &numbers {
+            let path = dir.join(other.to_string());
+            let file = File::open(&path)?;
+            // Creation of a file, that is not referred to yet, may be torn by a crash
+            if other == last && other != number && file.metadata()?.len() < record::FILE_HEADER_SIZE
+            {
+                return Ok(Self::create(dir, last, store)?);
+            }
+            DataFileHeader::read_from(&file, &path)?.check(&path, store)?;
+        }
+        let mut file = Self::open(dir, last, 0, store)?;
+        let end = file.file.metadata()?.len();
+        let start = if last == number {
+            offset
+        } else {
+            record::FILE_HEADER_SIZE
+        };
+        file.offset = start.max(record::intact_end(&file.file, end, start)?);
+        if file.offset < end {
+            file.file.set_len(file.offset)?;
+        }
+        Ok(file)
+    }
 
-        let mut prev_guard = None;
-        let mut last_child_index = None;
+    /// Makes given file current, keeping ids of blob files counted
+    fn rotate(&mut self, next: Self) {
+        let next_blob = self.next_blob;
+        *self = next;
+        self.next_blob = next_blob;
+    }
 
-        loop {
-            let node = current.read_owned().await;
+    /// Returns id of a new blob file in given directory, creating the directory of blob files
+    /// on the first call, see [`BPlus::with_blob_threshold`]
+    ///
+    /// Ids follow the greatest id of existing blob files, so they are not reused, while the file
+    /// is open
+    fn next_blob(&mut self, dir: &Path) -> io::Result<u64> {
+        let next = match self.next_blob {
+            Some(next) => next,
+            None => {
+                let blobs = dir.join(BLOBS_DIR);
+                create_dir_all(&blobs)?;
+                data_file_numbers(&blobs)?
+                    .last()
+                    .map_or(0, |&last| last as u64 + 1)
+            }
+        };
+        self.next_blob = Some(next + 1);
+        Ok(next)
+    }
 
-            if let Some(guard) = latch_guard.take() {
-                drop(guard);
-                if matches!(&*node, Node::Leaf(_)) {
-                    return Err(());
+    /// Appends given data, rotating to the next file first, if this one reached given size
+    ///
+    /// Returns number of the new file, if the file was rotated, with number of the file
+    /// and offset, at which data was written. Next file becomes current only after it is
+    /// created, and failed write is rolled back, so its space is reused by the next one
+    fn append(
+        &mut self,
+        dir: &Path,
+        data: &[u8],
+        max_size: u64,
+    ) -> (Option<usize>, io::Result<(usize, u64)>) {
+        let mut rotated = None;
+        // File with only a header is not rotated, even if the header reaches given size
+        if self.offset >= max_size && self.offset > record::FILE_HEADER_SIZE {
+            match Self::create(dir, self.number + 1, self.store) {
+                Ok(next) => {
+                    self.rotate(next);
+                    rotated = Some(self.number);
                 }
+                Err(err) => return (None, Err(err)),
             }
+        }
+        let offset = self.offset;
+        if let Err(err) = self.file.write_all_at(data, offset) {
+            let _ = self.file.set_len(offset);
+            return (rotated, Err(err));
+        }
+        self.offset += data.len() as u64;
+        (rotated, Ok((self.number, offset)))
+    }
+}
 
-            if matches!(&*node, Node::Leaf(_)) {
-                break;
-            }
+/// A type that represents a reference to another node.
+///
+/// Nodes are reclaimed by counting references to them rather than by epochs. Every reader
+/// holds the latch of the node it reads, and awaits latches of the next ones on the way,
+/// while guards of epochs cannot be held across awaits, as futures of the tree are `Send`.
+/// So a replaced node is freed, once the last task, that still reads it, drops its link
+type Link<K> = Arc<Latch<Node<K>>>;
 
-            prev_guard = Some(node);
+/// Function, that returns size of a key in bytes.
+type KeySize<K> = fn(&K) -> u64;
 
-            if let Node::Internal(internal) = prev_guard.as_deref().unwrap() {
-                let pos = match internal.keys.binary_search(&key) {
-                    Ok(pos) => pos + 1,
-                    Err(pos) => pos,
-                };
-                last_child_index = Some(pos);
-                current = internal.children[pos].clone();
-            } else {
-                unreachable!();
+/// Represents a node in a B+ tree.
+/// All data resides in leaf nodes, while internal nodes.
+/// manage navigation between children.
+///
+/// Every node holds upper bound of its keys and a link to its right sibling (B-link tree),
+/// so a key, that moved to a new sibling on split, is found by moving right.
+/// Thus operations hold one latch at a time and need not lock the path from the root
+enum Node<K> {
+    Internal(InternalNode<K>),
+    Leaf(Leaf<K>),
+}
+
+/// Internal node in a B+ tree
+struct InternalNode<K> {
+    /// Children of that node.
+    children: Vec<Link<K>>,
+    /// Keys of that node.
+    keys: Separators<K>,
+    /// Upper bound of keys in the subtree, exclusive; None for the last node of a level.
+    high_key: Option<K>,
+    /// Link to the right sibling; None for the last node of a level.
+    right: Option<Link<K>>,
+}
+
+/// Leaf node in a B+ tree
+#[derive(Default)]
+struct Leaf<K> {
+    /// Data entries that stored in that leaf; empty, while they are evicted.
+    entries: Vec<(K, ChunkHandler)>,
+    /// Link to the next leaf; None if there are none.
+    next: Option<Link<K>>,
+    /// Upper bound of keys in the leaf, exclusive; None for the last leaf.
+    high_key: Option<K>,
+    /// Copy of entries in the page file of the tree; None if they were never evicted.
+    page: Option<LeafPage<K>>,
+}
+
+/// Page, that entries of a leaf are evicted to, see [`BPlus::with_buffer_pool`]
+///
+/// Page is released, once the leaf is dropped
+struct LeafPage<K> {
+    pages: Arc<dyn LeafPages<K>>,
+    id: NodeId,
+    /// Whether the page holds current entries of the leaf
+    clean: bool,
+    /// Whether entries of the leaf are only in the page
+    evicted: bool,
+}
+
+impl<K> Drop for LeafPage<K> {
+    fn drop(&mut self) {
+        self.pages.release(self.id);
+    }
+}
+
+impl<K: Clone> Leaf<K> {
+    /// Returns copy of entries of the leaf, reading them from its page, if they are evicted
+    ///
+    /// Panics, if evicted entries could not be read
+    fn entries(&self) -> Vec<(K, ChunkHandler)> {
+        match &self.page {
+            Some(page) if page.evicted => page
+                .pages
+                .read(page.id)
+                .expect("evicted leaf is read from its page"),
+            _ => self.entries.clone(),
+        }
+    }
+
+    /// Writes entries of the leaf to given page file, unless its page holds them already,
+    /// and frees them from memory
+    ///
+    /// Returns Err(_) if entries could not be written, they stay in memory then
+    fn evict(&mut self, pages: &Arc<dyn LeafPages<K>>) -> Result<()> {
+        match &mut self.page {
+            Some(page) => {
+                if !page.clean {
+                    page.pages.write(Some(page.id), &self.entries)?;
+                    page.clean = true;
+                }
+                page.evicted = true;
+            }
+            None => {
+                let id = pages.write(None, &self.entries)?;
+                self.page = Some(LeafPage {
+                    pages: pages.clone(),
+                    id,
+                    clean: true,
+                    evicted: true,
+                });
+            }
+        }
+        self.entries = Vec::new();
+        Ok(())
+    }
+}
+
+impl<K> Evictable for Node<K> {
+    fn is_evicted(&self) -> bool {
+        matches!(self, Node::Leaf(Leaf { page: Some(page), .. }) if page.evicted)
+    }
+
+    /// Panics, if evicted entries could not be read
+    fn load(&mut self) {
+        if let Node::Leaf(Leaf {
+            entries,
+            page: Some(page),
+            ..
+        }) = self
+        {
+            *entries = page
+                .pages
+                .read(page.id)
+                .expect("evicted leaf is read from its page");
+            page.evicted = false;
+        }
+    }
+
+    fn touch(&mut self) {
+        if let Node::Leaf(Leaf {
+            page: Some(page), ..
+        }) = self
+        {
+            page.clean = false;
+        }
+    }
+}
+
+impl<K> Node<K> {
+    /// Returns upper bound of keys in the node
+    fn high_key(&self) -> Option<&K> {
+        match self {
+            Node::Internal(internal) => internal.high_key.as_ref(),
+            Node::Leaf(leaf) => leaf.high_key.as_ref(),
+        }
+    }
+
+    /// Returns link to the right sibling of the node
+    fn right(&self) -> Option<&Link<K>> {
+        match self {
+            Node::Internal(internal) => internal.right.as_ref(),
+            Node::Leaf(leaf) => leaf.next.as_ref(),
+        }
+    }
+
+    /// Passes entries of the node to given function, if it is a leaf, and returns the next
+    /// node of a walk over leaves: the first child of an internal node or the next leaf
+    ///
+    /// Returns None after the last leaf
+    fn walk_leaves(&self, visit: impl FnOnce(&[(K, ChunkHandler)])) -> Option<Link<K>> {
+        match self {
+            Node::Internal(internal) => Some(internal.children[0].clone()),
+            Node::Leaf(leaf) => {
+                visit(&leaf.entries);
+                leaf.next.clone()
+            }
+        }
+    }
+}
+
+impl<K: Ord> Node<K> {
+    /// Returns right sibling, if given key lies beyond the high key of the node
+    ///
+    /// That happens, if the node was split after link to it was read
+    fn move_right(&self, key: &K) -> Option<Link<K>> {
+        match self.high_key() {
+            Some(high_key) if key >= high_key => self.right().cloned(),
+            _ => None,
+        }
+    }
+}
+
+impl<K: Ord + Clone + 'static> InternalNode<K> {
+    /// Returns index of the child, that covers given key
+    fn child_index(&self, key: &K) -> usize {
+        self.keys.child_index(key)
+    }
+}
+
+/// B+ tree
+///
+/// Nodes are kept in memory, values are written to data files. Leaves may be evicted
+/// to a page file, see [`BPlus::with_buffer_pool`]
+pub struct BPlus<K> {
+    /// Subtrees, that hold consecutive ranges of keys.
+    pub(crate) partitions: Vec<Partition<K>>,
+    /// Lowest keys of partitions, except the first one.
+    pub(crate) bounds: Vec<K>,
+    /// Parameter, that represents minimal and maximal amount of node keys.
+    pub(crate) t: usize,
+    /// Path to the directory, in which all data will be writen.
+    pub(crate) path: PathBuf,
+    /// Current data file, locked by the job, that writes the value.
+    pub(crate) data_file: Arc<Mutex<DataFile>>,
+    /// Locations of values in data files by ids of their chunks.
+    pub(crate) chunks: ChunkTable,
+    /// Max file size.
+    max_file_size: u64,
+    /// Sequence number of the last change.
+    sequence: AtomicU64,
+    /// Held shared by inserts, that split nodes, and exclusively by save,
+    /// so saved tree has no splits, that are not yet linked to parents.
+    pub(crate) splits: RwLock<()>,
+    /// Held shared by changes of the tree and exclusively by rebuild, so it loses no change,
+    /// and by conditional inserts, so the key is not changed after the check.
+    pub(crate) writes: RwLock<()>,
+    /// Keys, that are locked by callers, see [`BPlus::lock_range`].
+    locks: KeyLocks<K>,
+    /// Order, in which latches of nodes admit waiting readers and writers.
+    fairness: Fairness,
+    /// Spawner for blocking file I/O; None if I/O runs in the calling task.
+    pub(crate) spawner: Option<Arc<dyn Spawner>>,
+    /// Whether data files are synced and directory is marked clean on drop.
+    flush_on_drop: AtomicBool,
+    /// Whether the tree was closed cleanly before it was loaded.
+    closed_cleanly: bool,
+    /// Whether the tree was changed since it was last saved or loaded.
+    pub(crate) dirty: AtomicBool,
+    /// Counters of operations.
+    pub(crate) metrics: Metrics,
+    /// Callbacks, that are called on events of the tree.
+    pub(crate) hooks: Hooks,
+    /// Duration in microseconds, above which operations are reported as slow; 0 if disabled.
+    slow_threshold: AtomicU64,
+    /// Number of values, that scans read ahead of the collected ones.
+    read_ahead: AtomicUsize,
+    /// Whether reads give hints to the page cache of the OS, see [`BPlus::set_read_hints`]
+    read_hints: AtomicBool,
+    /// Limit of memory of the tree in bytes, see [`BPlus::set_memory_limit`]. 0 if there is none
+    memory_limit: AtomicUsize,
+    /// Sequence number of the change, after which memory was last checked against the limit
+    memory_checked: AtomicU64,
+    /// Held by compaction, so only one of them runs at a time.
+    pub(crate) compaction: AsyncMutex<()>,
+    /// Dead extents of data files, that compaction picks files by
+    pub(crate) free_space: Mutex<FreeSpace>,
+    /// Buffer, that absorbs inserts and removals before they reach the tree; None if disabled.
+    buffer: Option<WriteBuffer<K>>,
+    /// Max size of a key with the function, that measures it; None if unlimited.
+    max_key_size: Option<(u64, KeySize<K>)>,
+    /// Max size of a value; None if unlimited.
+    max_value_size: Option<u64>,
+    /// Size of a value, above which it is written to its own blob file; None if disabled.
+    blob_threshold: Option<u64>,
+    /// Previous values of overwritten and removed keys; None if they are not kept.
+    pub(crate) versions: Option<Versions<K>>,
+    /// Log, that records every change of the tree; None if disabled.
+    audit: Option<Arc<dyn AuditSink<K>>>,
+    /// Recorder of operations; None if they are not recorded
+    recorder: Option<Arc<dyn OperationSink<K>>>,
+    /// Hashes of entries of the tree; None if they are not kept.
+    pub(crate) merkle: Option<Merkle<K>>,
+    /// Filter over keys, that lets lookups of absent keys skip descent; None if disabled.
+    pub(crate) bloom: Option<Bloom<K>>,
+    /// Page file, that leaves are evicted to; None if they are kept in memory.
+    pages: Option<Arc<dyn LeafPages<K>>>,
+}
+
+/// Previous values of keys, that are kept on overwrites and removals
+pub(crate) struct Versions<K> {
+    /// Number of previous values, that are kept by every key
+    limit: usize,
+    /// Handlers of previous values by keys, the latest first
+    pub(crate) previous: Mutex<BTreeMap<K, VecDeque<ChunkHandler>>>,
+}
+
+/// Opaque position of a leaf, returned by hinted operations of [`BPlus`]
+///
+/// Next operation on a nearby key starts at that leaf instead of the root, if it still
+/// covers the key. Stale hints are ignored
+pub struct Hint<K> {
+    /// Leaf, that held the key of the operation, which returned the hint
+    leaf: Weak<Latch<Node<K>>>,
+    /// Index of partition of the leaf
+    partition: usize,
+}
+
+impl<K> Hint<K> {
+    /// Returns hint to given leaf of given partition
+    fn new(leaf: &Link<K>, partition: usize) -> Self {
+        Self {
+            leaf: Arc::downgrade(leaf),
+            partition,
+        }
+    }
+
+    /// Returns hint to no leaf, that is always ignored
+    fn empty(partition: usize) -> Self {
+        Self {
+            leaf: Weak::new(),
+            partition,
+        }
+    }
+}
+
+impl<K> Clone for Hint<K> {
+    fn clone(&self) -> Self {
+        Self {
+            leaf: self.leaf.clone(),
+            partition: self.partition,
+        }
+    }
+}
+
+/// Lock of a range of keys, returned by [`BPlus::lock_range`] and [`BPlus::lock_key`]
+///
+/// Other writers of locked keys wait until the guard is dropped. Holder changes locked keys
+/// through the guard, changing them through the tree itself waits for the guard forever
+pub struct KeyLock<'a, K> {
+    tree: &'a BPlus<K>,
+    /// Identifier of the lock, that lets changes through the guard pass it
+    owner: u64,
+    /// Locked range
+    range: Range<K>,
+}
+
+impl<K: BPlusKey> KeyLock<'_, K> {
+    /// Inserts given value by given key, see [`BPlus::insert`]
+    ///
+    /// Key may be outside the locked range, then it waits for other locks like any insert
+    pub async fn insert(&self, key: K, value: Vec<u8>) -> Result<InsertOutcome> {
+        let (_, outcome) = self
+            .tree
+            .insert_hinted(key, value, ChunkKind::Chunk, None, None, Some(self.owner))
+            .await?;
+        Ok(outcome)
+    }
+
+    /// Removes value by given key, see [`BPlus::remove`]
+    pub async fn remove(&self, key: &K) -> Option<u64> {
+        self.tree.remove_owned(key, Some(self.owner)).await
+    }
+
+    /// Returns all entries of the locked range, that is not changed by others meanwhile
+    ///
+    /// Returns Err(_) if some of the values could not be read
+    pub async fn scan(&self) -> Result<Vec<(K, Vec<u8>)>> {
+        self.tree.scan(self.range.clone()).await
+    }
+
+    /// Removes all entries of the locked range and returns them, so a maintenance job
+    /// migrates the range out of the tree
+    ///
+    /// Returns Err(_) if some of the values could not be read, then nothing is removed
+    pub async fn drain(&self) -> Result<Vec<(K, Vec<u8>)>> {
+        let entries = self.scan().await?;
+        for (key, _) in &entries {
+            self.tree.remove_owned(key, Some(self.owner)).await;
+        }
+        Ok(entries)
+    }
+}
+
+impl<K> Drop for KeyLock<'_, K> {
+    fn drop(&mut self) {
+        self.tree.locks.unlock(self.owner);
+    }
+}
+
+/// Shared lock of a range of keys, returned by [`BPlus::freeze_range`]
+///
+/// Keys of the range are not changed until the guard is dropped, while overlapping ranges
+/// may be frozen by others at once
+pub struct FrozenRange<'a, K> {
+    tree: &'a BPlus<K>,
+    /// Identifier of the lock
+    owner: u64,
+    /// Frozen range
+    range: Range<K>,
+}
+
+impl<K: BPlusKey> FrozenRange<'_, K> {
+    /// Returns all entries of the frozen range
+    ///
+    /// Returns Err(_) if some of the values could not be read
+    pub async fn scan(&self) -> Result<Vec<(K, Vec<u8>)>> {
+        self.tree.scan(self.range.clone()).await
+    }
+}
+
+impl<K> Drop for FrozenRange<'_, K> {
+    fn drop(&mut self) {
+        self.tree.locks.unlock(self.owner);
+    }
+}
+
+/// Read-only handle of a shared tree, returned by [`BPlus::reader`]
+///
+/// Clones of the handle share nodes and data files of the tree, so many tasks may read
+/// at once without wrapping the tree in a lock. Only the tree itself may be changed,
+/// readers see its changes as soon as they are made
+pub struct BPlusReader<K> {
+    tree: Arc<BPlus<K>>,
+}
+
+impl<K> Clone for BPlusReader<K> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+impl<K: BPlusKey> BPlusReader<K> {
+    /// Gets value by given key, see [`BPlus::get`]
+    pub async fn get(&self, key: &K) -> Result<Vec<u8>> {
+        self.tree.get(key).await
+    }
+
+    /// Gets value by given key with its kind, see [`BPlus::get_with_kind`]
+    pub async fn get_with_kind(&self, key: &K) -> Result<(Vec<u8>, ChunkKind)> {
+        self.tree.get_with_kind(key).await
+    }
+
+    /// Gets values by all given keys, see [`BPlus::get_many`]
+    pub async fn get_many(&self, keys: &[K]) -> Result<Vec<Vec<u8>>> {
+        self.tree.get_many(keys).await
+    }
+
+    /// Returns whether key is contained in the tree, see [`BPlus::contains_key`]
+    pub async fn contains_key(&self, key: &K) -> bool {
+        self.tree.contains_key(key).await
+    }
+
+    /// Returns all entries with keys in given range, see [`BPlus::scan`]
+    pub async fn scan<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, Vec<u8>)>> {
+        self.tree.scan(range).await
+    }
+
+    /// Returns all entries with keys in given range, scanning pieces of it at once,
+    /// see [`BPlus::par_scan`]
+    pub async fn par_scan<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        parallelism: usize,
+    ) -> Result<Vec<(K, Vec<u8>)>> {
+        self.tree.par_scan(range, parallelism).await
+    }
+
+    /// Returns stream of all entries of the tree, see [`BPlus::stream`]
+    pub fn stream(&self) -> impl Stream<Item = Result<(K, Vec<u8>)>> + '_ {
+        self.tree.stream()
+    }
+
+    /// Returns stream of all entries of the tree as of the call, see [`BPlus::snapshot_stream`]
+    pub async fn snapshot_stream(&self) -> impl Stream<Item = Result<(K, Vec<u8>)>> + '_ {
+        self.tree.snapshot_stream().await
+    }
+}
+
+/// Boxed future, that borrows the tree
+type FutureBox<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Entries collected by a scan, values of the latest of which are still being read
+struct ReadAhead<'a, K> {
+    /// Entries with read values, in order of keys
+    entries: Vec<(K, Vec<u8>)>,
+    /// Keys of entries following the collected ones, with reads of their values
+    reads: VecDeque<(K, FutureBox<'a, Result<Vec<u8>>>)>,
+    /// Maximal number of reads at once
+    window: usize,
+}
+
+impl<'a, K> ReadAhead<'a, K> {
+    /// Creates entries, that are read at most given number at once
+    fn new(window: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            reads: VecDeque::new(),
+            window: window.max(1),
+        }
+    }
+
+    /// Adds entry with given key, whose value is being read, waiting for the oldest read,
+    /// if there are too many of them
+    async fn push(
+        &mut self,
+        key: K,
+        read: FutureBox<'a, Result<Vec<u8>>>,
+        phases: &mut Phases,
+    ) -> Result<()> {
+        if self.reads.len() >= self.window {
+            self.collect_oldest(phases).await?;
+        }
+        self.reads.push_back((key, read));
+        Ok(())
+    }
+
+    /// Waits for the oldest read and collects its entry
+    async fn collect_oldest(&mut self, phases: &mut Phases) -> Result<()> {
+        let Some((key, read)) = self.reads.pop_front() else {
+            return Ok(());
+        };
+        let start = Instant::now();
+        let value = read.await?;
+        phases.io += start.elapsed();
+        self.entries.push((key, value));
+        Ok(())
+    }
+
+    /// Waits for the remaining reads and returns all entries
+    async fn finish(mut self, phases: &mut Phases) -> Result<Vec<(K, Vec<u8>)>> {
+        while !self.reads.is_empty() {
+            self.collect_oldest(phases).await?;
+        }
+        Ok(self.entries)
+    }
+}
+
+/// Position of [`BPlus::stream`] among leaves of the tree
+struct StreamCursor<K> {
+    /// Index of the partition, whose leaves are read, once the current one has no next leaf
+    partition: usize,
+    /// Next leaf of the current partition
+    next: Option<Link<K>>,
+    /// Entries of the last read leaf, whose values were not yielded yet
+    entries: VecDeque<(K, ChunkHandler)>,
+    /// Greatest key of the read leaves
+    last: Option<K>,
+}
+
+/// Value of an entry, that is pinned by [`BPlus::snapshot_stream`]
+pub(crate) enum Pinned {
+    /// Value in a data file
+    Stored(ChunkHandler),
+    /// Value of given kind in the write buffer
+    Buffered(Vec<u8>, ChunkKind),
+}
+
+impl Pinned {
+    /// Returns kind of the pinned value
+    pub(crate) fn kind(&self) -> ChunkKind {
+        match self {
+            Pinned::Stored(handler) => handler.kind,
+            Pinned::Buffered(_, kind) => *kind,
+        }
+    }
+}
+
+/// Subtree of BPlusTree, that holds keys from its bound up to the bound of the next one
+///
+/// Partitions have their own roots, so operations on different partitions never wait
+/// for the same latch
+pub(crate) struct Partition<K> {
+    /// Root of the subtree, its high key is the bound of the next partition.
+    root: Link<K>,
+    /// Number of levels of the subtree, including leaves.
+    height: AtomicUsize,
+    /// Last known rightmost leaf with its greatest key, to which greater keys are appended.
+    rightmost: Mutex<Option<(Link<K>, K)>>,
+}
+
+impl<K> Partition<K> {
+    /// Creates partition with empty leaf, that holds keys below given high key
+    fn new(high_key: Option<K>) -> Self {
+        Self {
+            root: Arc::new(Latch::new(Node::Leaf(Leaf {
+                entries: Vec::new(),
+                next: None,
+                high_key,
+                page: None,
+            }))),
+            height: 1.into(),
+            rightmost: Mutex::new(None),
+        }
+    }
+}
+
+impl<K: Ord + Clone + 'static> Partition<K> {
+    /// Creates partition from saved subtree, that holds keys below given high key
+    fn from_serializable(root: SerializableNode<K>, high_key: Option<K>) -> Self {
+        let mut height = 1;
+        let mut node = &root;
+        while let SerializableNode::Internal(internal) = node {
+            let Some(child) = internal.children.first() else {
+                break;
+            };
+            node = child;
+            height += 1;
+        }
+        Self {
+            root: Arc::new(Latch::new(Node::from_serializable(root, high_key))),
+            height: height.into(),
+            rightmost: Mutex::new(None),
+        }
+    }
+}
+
+impl<K> BPlus<K> {
+    /// Returns whether data files are synced and directory is marked clean on drop
+    pub(crate) fn flushes_on_drop(&self) -> bool {
+        self.flush_on_drop.load(Ordering::Acquire)
+    }
+
+    /// Returns roots of all partitions in order of their keys
+    fn roots(&self) -> Vec<Link<K>> {
+        self.partitions
+            .iter()
+            .map(|partition| partition.root.clone())
+            .collect()
+    }
+}
+
+/// Links nodes of every level of subtree with given root to their right siblings
+///
+/// Nodes of a level are collected in order of their keys, even if some of them became empty
+/// after removals. Rightmost nodes are not linked, as their high keys end the subtree
+async fn link_siblings<K>(root: &Link<K>) {
+    let mut level = vec![root.clone()];
+    while !level.is_empty() {
+        let mut next_level = Vec::new();
+        for (i, link) in level.iter().enumerate() {
+            let right = level.get(i + 1).cloned();
+            match &mut *link.write().await {
+                Node::Internal(internal) => {
+                    internal.right = right;
+                    next_level.extend(internal.children.iter().cloned());
+                }
+                Node::Leaf(leaf) => leaf.next = right,
+            }
+        }
+        level = next_level;
+    }
+}
+
+/// Sets fairness policy of latches of all nodes of given subtree
+///
+/// Policy is set without locking the node, children are read, once the node is unlocked
+async fn set_fairness<K>(root: &Link<K>, fairness: Fairness) {
+    let mut level = vec![root.clone()];
+    while !level.is_empty() {
+        let mut next_level = Vec::new();
+        for link in &level {
+            link.set_fairness(fairness);
+            if let Node::Internal(internal) = &*link.read().await {
+                next_level.extend(internal.children.iter().cloned());
+            }
+        }
+        level = next_level;
+    }
+}
+
+/// Returns subtree, that holds given entries sorted by keys in evenly filled nodes
+///
+/// Nodes are filled up to their capacity, and nodes of a level differ in size by one at most,
+/// so none of them is less than half full
+fn build_subtree<K: Clone + 'static>(
+    entries: Vec<(K, ChunkHandler)>,
+    t: usize,
+) -> SerializableNode<K> {
+    // Nodes of the current level and separators between them
+    let mut nodes = Vec::new();
+    let mut separators = Vec::new();
+    let mut entries = entries.into_iter();
+    for size in even_sizes(entries.len(), 2 * t - 1) {
+        let leaf: Vec<_> = entries.by_ref().take(size).collect();
+        if let Some(SerializableNode::Leaf(previous)) = nodes.last() {
+            let last = &previous.entries[previous.entries.len() - 1].0;
+            separators.push(shortest_separator(last, &leaf[0].0));
+        }
+        nodes.push(SerializableNode::Leaf(SerializableLeaf { entries: leaf }));
+    }
+
+    while nodes.len() > 1 {
+        let mut children = nodes.into_iter();
+        let mut keys = separators.into_iter();
+        (nodes, separators) = (Vec::new(), Vec::new());
+        for (i, size) in even_sizes(children.len(), 2 * t - 1)
+            .into_iter()
+            .enumerate()
+        {
+            // Separator between groups of children goes up a level
+            if i > 0 {
+                separators.extend(keys.next());
+            }
+            nodes.push(SerializableNode::Internal(SerializableInternalNode {
+                children: children.by_ref().take(size).collect(),
+                keys: keys.by_ref().take(size - 1).collect(),
+            }));
+        }
+    }
+    nodes
+        .pop()
+        .unwrap_or(SerializableNode::Leaf(SerializableLeaf {
+            entries: Vec::new(),
+        }))
+}
+
+/// Returns sizes of the fewest groups of at most given capacity, that hold given number
+/// of items, differing by one at most
+fn even_sizes(items: usize, capacity: usize) -> Vec<usize> {
+    let groups = items.div_ceil(capacity);
+    (0..groups)
+        .map(|i| items / groups + usize::from(i < items % groups))
+        .collect()
+}
+
+/// Returns identity of node, that is stable while the node is alive
+fn node_id<K>(link: &Link<K>) -> usize {
+    Arc::as_ptr(link) as usize
+}
+
+#[allow(dead_code)]
+impl<K: BPlusKey> BPlus<K> {
+    /// Creates new instance of B+ tree with given t and path
+    ///
+    /// t represents minimal and maximal quantity of keys in node
+    ///
+    /// All data will be written in files in directory by given path
+    pub fn new(t: usize, path: PathBuf) -> Result<Self> {
+        Self::new_partitioned(t, path, Vec::new())
+    }
+
+    /// Creates new instance of B+ tree with given t and path, which key space is split
+    /// into partitions by given bounds
+    ///
+    /// Partition holds keys from its bound up to the bound of the next one, first partition
+    /// holds keys below the first bound. Every partition has its own root, so operations on
+    /// keys of different partitions do not contend, and scans merge partitions transparently.
+    /// For hashes, evenly spaced prefixes, e.g. `vec![64], vec![128], vec![192]`, give even partitions
+    ///
+    /// Returns Err(_) if bounds are not strictly ascending
+    pub fn new_partitioned(t: usize, path: PathBuf, bounds: Vec<K>) -> Result<Self> {
+        if bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bounds of partitions are not strictly ascending",
+            )
+            .into());
+        }
+        create_dir_all(&path)?;
+        take_clean_marker(&path)?;
+        let data_file = DataFile::create(&path, 0, StoreId::random())?;
+        Ok(Self::with_data_file(
+            t,
+            path,
+            bounds,
+            Arc::new(Mutex::new(data_file)),
+        ))
+    }
+
+    /// Creates new instance of B+ tree with given t and bounds of partitions, that appends
+    /// values to given data file in directory by given path, which may be shared with other trees
+    pub(crate) fn with_data_file(
+        t: usize,
+        path: PathBuf,
+        bounds: Vec<K>,
+        data_file: Arc<Mutex<DataFile>>,
+    ) -> Self {
+        let partitions = (0..=bounds.len())
+            .map(|i| Partition::new(bounds.get(i).cloned()))
+            .collect();
+        Self {
+            partitions,
+            bounds,
+            t,
+            path,
+            data_file,
+            chunks: ChunkTable::default(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            sequence: AtomicU64::new(0),
+            splits: RwLock::new(()),
+            writes: RwLock::new(()),
+            locks: KeyLocks::default(),
+            fairness: Fairness::default(),
+            spawner: None,
+            flush_on_drop: true.into(),
+            slow_threshold: AtomicU64::new(0),
+            read_ahead: DEFAULT_READ_AHEAD.into(),
+            read_hints: true.into(),
+            memory_limit: AtomicUsize::new(0),
+            memory_checked: AtomicU64::new(0),
+            compaction: AsyncMutex::new(()),
+            free_space: Mutex::default(),
+            buffer: None,
+            max_key_size: None,
+            max_value_size: None,
+            blob_threshold: None,
+            versions: None,
+            audit: None,
+            recorder: None,
+            merkle: None,
+            bloom: None,
+            pages: None,
+            closed_cleanly: true,
+            dirty: false.into(),
+            metrics: Metrics::default(),
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Creates new instance of B+ tree with given t, that appends values to data files
+    /// of given tree, so both trees may refer to the same values, see [`BPlus::insert_shared`]
+    ///
+    /// Data files are shared while any of the trees is alive, and rebuild of either tree
+    /// does not remove them. [`BPlus::load`] does not share data files again, so trees,
+    /// that are saved, should be kept in a [`Store`](crate::store::Store)
+    pub fn new_sharing<O>(t: usize, other: &BPlus<O>) -> Self {
+        Self::with_data_file(t, other.path.clone(), Vec::new(), other.data_file.clone())
+    }
+
+    /// Returns read-only handle of the tree, which may be cloned and sent to other tasks
+    ///
+    /// The tree stays the only handle, that changes it
+    pub fn reader(self: &Arc<Self>) -> BPlusReader<K> {
+        BPlusReader { tree: self.clone() }
+    }
+
+    /// Makes the tree append values to given data file, which may be shared with other trees
+    pub(crate) fn share_data_file(&mut self, data_file: Arc<Mutex<DataFile>>) {
+        self.data_file = data_file;
+    }
+
+    /// Returns data file, that the tree appends values to
+    pub(crate) fn data_file(&self) -> Arc<Mutex<DataFile>> {
+        self.data_file.clone()
+    }
+
+    /// Creates new instance of B+ tree with given t and path, that holds given entries
+    ///
+    /// Entries with strictly ascending keys are bulk loaded into evenly filled nodes and
+    /// numbered in their order, like inserts, other entries are inserted one by one,
+    /// so later values replace earlier ones
+    pub async fn from_entries<I>(t: usize, path: PathBuf, entries: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (K, Vec<u8>)>,
+    {
+        let mut tree = Self::new(t, path)?;
+        let entries: Vec<_> = entries.into_iter().collect();
+        if entries.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            tree.extend(entries).await?;
+            return Ok(tree);
+        }
+
+        let mut phases = Phases::default();
+        let mut loaded = Vec::with_capacity(entries.len());
+        let mut entries = entries.into_iter().peekable();
+        while entries.peek().is_some() {
+            let (keys, values): (Vec<_>, Vec<_>) = entries.by_ref().take(BULK_BATCH).unzip();
+            let sizes: Vec<_> = values
+                .iter()
+                .map(|value| (value.len(), ChunkKind::Chunk))
+                .collect();
+            let handlers = tree
+                .write_values(values.concat(), &sizes, &mut phases)
+                .await?;
+            loaded.extend(keys.into_iter().zip(handlers).map(|(key, mut handler)| {
+                handler.version = tree.next_sequence();
+                (key, handler)
+            }));
+        }
+        let partition = Partition::from_serializable(build_subtree(loaded, t), None);
+        link_siblings(&partition.root).await;
+        tree.partitions = vec![partition];
+        tree.dirty.store(true, Ordering::Release);
+        Ok(tree)
+    }
+
+    /// Sets whether data files are synced and directory is marked clean, when tree is dropped
+    ///
+    /// Enabled by default
+    pub fn set_flush_on_drop(&self, flush: bool) {
+        self.flush_on_drop.store(flush, Ordering::Release);
+    }
+
+    /// Registers callback, that is called on every event of the tree
+    ///
+    /// Callbacks are called synchronously by the task, that caused the event, so they should be fast
+    pub fn on_event<F>(&self, hook: F)
+    where
+        F: Fn(&TreeEvent) + Send + Sync + 'static,
+    {
+        self.hooks.register(Arc::new(hook));
+    }
+
+    /// Sets duration, above which get, insert and scan log a warning with time spent
+    /// in the tree, waiting for latches and doing disk I/O, and are counted in metrics
+    ///
+    /// Warnings are emitted only with `tracing` feature. Disabled by default
+    pub fn set_slow_threshold(&self, threshold: Option<Duration>) {
+        let micros = threshold.map_or(0, |threshold| threshold.as_micros().max(1) as u64);
+        self.slow_threshold.store(micros, Ordering::Relaxed);
+    }
+
+    /// Sets number of values, that scans read at once ahead of the collected ones,
+    /// so reads of the next leaves overlap with each other
+    ///
+    /// Reads overlap only on a spawner, see [`BPlus::with_spawner`]. 0 or 1 disables read-ahead.
+    /// 16 by default
+    pub fn set_read_ahead(&self, window: usize) {
+        self.read_ahead.store(window, Ordering::Relaxed);
+    }
+
+    /// Sets whether reads of values hint the page cache of the OS with their pattern
+    ///
+    /// Point lookups disable read-ahead of the OS for their files, scans and compaction
+    /// make it more aggressive, and reads of many values request all of them at once.
+    /// Enabled by default, hints are not given on systems without `posix_fadvise`
+    pub fn set_read_hints(&self, enabled: bool) {
+        self.read_hints.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Sets limit of memory of the tree, see [`BPlus::memory_usage`]
+    ///
+    /// Memory is checked every 1024 changes. Once it exceeds the limit,
+    /// [`TreeEvent::MemoryLimitExceeded`] is emitted and the tree shrinks to the limit,
+    /// see [`BPlus::shrink`]. Changes, that are made with locks of [`BPlus::lock_range`],
+    /// do not check it. Unlimited by default
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.memory_limit
+            .store(limit.map_or(0, |limit| limit.max(1)), Ordering::Relaxed);
+    }
+
+    /// Returns given pattern of reads, if reads give hints, see [`BPlus::set_read_hints`]
+    pub(crate) fn hints(&self, access: Access) -> Option<Access> {
+        self.read_hints.load(Ordering::Relaxed).then_some(access)
+    }
+
+    /// Records latency of finished operation and reports it, if it was slow
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn finish_operation(
+        &self,
+        operation: &'static str,
+        histogram: &Histogram,
+        start: Instant,
+        phases: Phases,
+    ) {
+        let elapsed = start.elapsed();
+        histogram.record(elapsed);
+        let threshold = self.slow_threshold.load(Ordering::Relaxed);
+        if threshold == 0 || elapsed.as_micros() < u128::from(threshold) {
+            return;
+        }
+        Metrics::inc(&self.metrics.slow_operations);
+        trace_warn!(
+            operation = operation,
+            total_us = elapsed.as_micros() as u64,
+            descent_us = elapsed
+                .saturating_sub(phases.io + phases.latch_wait)
+                .as_micros() as u64,
+            latch_wait_us = phases.latch_wait.as_micros() as u64,
+            io_us = phases.io.as_micros() as u64,
+            "slow operation"
+        );
+    }
+
+    /// Returns partition, that holds given key
+    fn partition(&self, key: &K) -> &Partition<K> {
+        &self.partitions[self.partition_index(key)]
+    }
+
+    /// Returns index of partition, that holds given key
+    fn partition_index(&self, key: &K) -> usize {
+        self.bounds.partition_point(|bound| bound <= key)
+    }
+
+    /// Returns partition, which root is given link
+    fn rooted_at(&self, link: &Link<K>) -> Option<&Partition<K>> {
+        self.partitions
+            .iter()
+            .find(|partition| Arc::ptr_eq(link, &partition.root))
+    }
+
+    /// Returns number of partitions of the key space
+    pub fn partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// Returns whether the tree was closed cleanly before it was loaded
+    ///
+    /// Always true for newly created trees
+    pub fn closed_cleanly(&self) -> bool {
+        self.closed_cleanly
+    }
+
+    /// Makes tree absorb inserts and removals in an in-memory buffer, which is readable at once,
+    /// and apply them to data files and the tree in sorted batches, once the buffer takes
+    /// given number of bytes
+    ///
+    /// Buffered changes are lost, if the tree is dropped before [`BPlus::flush_buffer`],
+    /// [`BPlus::sync`] or [`BPlus::save`]
+    pub fn with_write_buffer(mut self, limit: usize) -> Self {
+        self.buffer = Some(WriteBuffer::new(limit));
+        self
+    }
+
+    /// Makes inserts of values, larger than given number of bytes,
+    /// fail with Err(BPlusError::ValueTooLarge { .. })
+    ///
+    /// Unlimited by default
+    pub fn with_max_value_size(mut self, limit: u64) -> Self {
+        self.max_value_size = Some(limit);
+        self
+    }
+
+    /// Makes values, larger than given number of bytes, be written to their own blob files
+    /// instead of shared data files
+    ///
+    /// Blob files lie in the `blobs` directory inside the data directory and are named by
+    /// their ids. Each holds the header of a data file and the record of one value, see
+    /// `record` module. Compaction and rebuild do not copy blobs. Once the tree no longer
+    /// refers to a blob, its file is kept, until the next save or compaction removes it,
+    /// unless data files are shared with other trees. So the last saved tree can be loaded,
+    /// until the tree is saved again, and then reads, that located the value before,
+    /// may fail like after a compaction. Threshold is not saved with the tree
+    ///
+    /// Disabled by default
+    pub fn with_blob_threshold(mut self, threshold: u64) -> Self {
+        self.blob_threshold = Some(threshold);
+        self
+    }
+
+    /// Makes tree store offsets of values in data files in 32 bits instead of 64, which halves
+    /// memory and snapshot space, that locations of values take
+    ///
+    /// Offsets fit while data files are smaller than 4 GiB, once one does not, locations are
+    /// stored in 64 bits again. Loaded trees keep the saved representation. Not used by default
+    pub fn with_compact_handles(self) -> Self {
+        self.chunks.compact();
+        self
+    }
+
+    /// Makes tree keep given number of previous values of every key, which are
+    /// replaced by inserts or removed, see [`BPlus::get_version`]
+    ///
+    /// Previous values are kept in memory until [`BPlus::rebuild`], and are not saved.
+    /// Overwrites, that are absorbed by the write buffer before a flush, keep no versions.
+    /// 0 keeps none, which is the default
+    pub fn with_versions(mut self, limit: usize) -> Self {
+        self.versions = (limit > 0).then(|| Versions {
+            limit,
+            previous: Mutex::default(),
+        });
+        self
+    }
+
+    /// Sets order, in which latches of nodes admit waiting readers and writers
+    ///
+    /// Latches are FIFO by default, so a reader waits behind a waiting writer.
+    /// Loaded and rebuilt trees keep the policy of the tree, which they are set on.
+    /// Nodes, that are locked, get the policy, once they are unlocked
+    pub async fn with_fairness(mut self, fairness: Fairness) -> Self {
+        self.fairness = fairness;
+        for partition in &self.partitions {
+            set_fairness(&partition.root, fairness).await;
+        }
+        self
+    }
+
+    /// Returns sequence number of the last change of the tree, 0 if there were none
+    pub fn last_sequence(&self) -> u64 {
+        self.sequence.load(Ordering::SeqCst)
+    }
+
+    /// Assigns sequence number to a change of the tree
+    fn next_sequence(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Records given change of the tree to its audit log, if there is one
+    fn audit(&self, op: AuditOp, key: &K, size: u64, origin: Option<&str>) {
+        if let Some(audit) = &self.audit {
+            audit.record(op, key, size, origin);
+        }
+    }
+
+    /// Records given operation of the tree to its recorder, if there is one
+    pub(crate) fn record(&self, operation: Operation<&K>) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(operation);
+        }
+    }
+
+    /// Remembers handler of the previous value of given key, if versions are kept
+    ///
+    /// Space of values, that are not kept, is recorded as free
+    fn keep_version(&self, key: K, handler: ChunkHandler) {
+        let Some(versions) = &self.versions else {
+            self.release(&handler);
+            return;
+        };
+        let mut previous = versions.previous.lock().unwrap();
+        let handlers = previous.entry(key).or_default();
+        handlers.push_front(handler);
+        if handlers.len() > versions.limit {
+            for handler in handlers.drain(versions.limit..) {
+                self.release(&handler);
+            }
+        }
+    }
+
+    /// Records record or blob file of given value, that the tree no longer refers to,
+    /// as free space, see [`BPlus::with_blob_threshold`]
+    ///
+    /// Blob files, that are shared with other trees, are not recorded, so they are kept
+    fn release(&self, handler: &ChunkHandler) {
+        let mut free_space = self.free_space.lock().unwrap();
+        // Location is read under the lock, so compaction does not move the value meanwhile
+        let Some(location) = self.chunks.get(handler.chunk) else {
+            return;
+        };
+        if location.file != BLOB_FILE {
+            free_space.record(location, record::HEADER_SIZE + handler.size as u64);
+        } else if Arc::strong_count(&self.data_file) == 1 {
+            free_space.record_blob(location.offset);
+        }
+    }
+
+    /// Removes given dead blob files and forgets them, see [`BPlus::with_blob_threshold`]
+    ///
+    /// Blobs, that could not be removed, are kept recorded, so they are removed next time
+    pub(crate) async fn remove_dead_blobs(&self, ids: Vec<u64>) {
+        if ids.is_empty() {
+            return;
+        }
+        let dir = self.path.clone();
+        let removed = self.unblock(move || {
+            let mut removed = Vec::new();
+            for id in ids {
+                match std::fs::remove_file(blob_path(&dir, id)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        trace_warn!(blob = id, error = %e, "blob file was not removed");
+                    }
+                    _ => removed.push(id),
+                }
+            }
+            removed
+        });
+        match removed.await {
+            Ok(removed) => {
+                trace_event!(blobs = removed.len(), "dead blob files removed");
+                self.free_space.lock().unwrap().forget_blobs(&removed);
+            }
+            Err(_e) => {
+                trace_warn!(error = %_e, "dead blob files were not removed");
+            }
+        }
+    }
+
+    /// Returns Err(_) if given key or value of given size exceed limits of the tree
+    fn check_size(&self, key: &K, value_size: usize) -> Result<()> {
+        if let Some((limit, measure)) = self.max_key_size {
+            let size = measure(key);
+            if size > limit {
+                return Err(BPlusError::KeyTooLarge { size, limit });
+            }
+        }
+        match self.max_value_size {
+            Some(limit) if value_size as u64 > limit => Err(BPlusError::ValueTooLarge {
+                size: value_size as u64,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes tree offload all blocking file I/O to the given spawner
+    pub fn with_spawner(mut self, spawner: Arc<dyn Spawner>) -> Self {
+        self.spawner = Some(spawner);
+        self
+    }
+
+    /// Runs blocking closure on the spawner of the tree, see [`unblock`]
+    pub(crate) fn unblock<T, F>(&self, f: F) -> impl Future<Output = Result<T>> + Send + 'static
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        unblock(self.spawner.as_ref(), f)
+    }
+
+    /// Creates new chunk_handler and writes data to a file
+    async fn get_chunk_handler(
+        &self,
+        value: Vec<u8>,
+        kind: ChunkKind,
+        phases: &mut Phases,
+    ) -> Result<ChunkHandler> {
+        let size = value.len();
+        let mut handlers = self.write_values(value, &[(size, kind)], phases).await?;
+        Ok(handlers.remove(0))
+    }
+
+    /// Returns whether value of given size is written to its own blob file,
+    /// see [`BPlus::with_blob_threshold`]
+    fn is_blob(&self, size: usize) -> bool {
+        self.blob_threshold
+            .is_some_and(|threshold| size as u64 > threshold)
+    }
+
+    /// Writes values, concatenated in given data, to a file in one call
+    ///
+    /// Values are given by their sizes and kinds. Returns their handlers in the same order.
+    /// Values above the blob threshold are written to their own files, and the rest together
+    async fn write_values(
+        &self,
+        value: Vec<u8>,
+        values: &[(usize, ChunkKind)],
+        phases: &mut Phases,
+    ) -> Result<Vec<ChunkHandler>> {
+        if !values.iter().any(|&(size, _)| self.is_blob(size)) {
+            return self.write_records(value, values, phases).await;
+        }
+        let mut rest = &value[..];
+        let mut blobs = Vec::new();
+        let mut small = Vec::new();
+        let mut small_values = Vec::new();
+        for (i, &(size, kind)) in values.iter().enumerate() {
+            let (chunk, tail) = rest.split_at(size);
+            rest = tail;
+            if self.is_blob(size) {
+                blobs.push((i, self.write_blob(chunk.to_vec(), kind, phases).await?));
+            } else {
+                small.extend_from_slice(chunk);
+                small_values.push((size, kind));
+            }
+        }
+        let mut handlers = Vec::with_capacity(values.len());
+        if !small_values.is_empty() {
+            handlers = self.write_records(small, &small_values, phases).await?;
+        }
+        for (i, handler) in blobs {
+            handlers.insert(i, handler);
+        }
+        Ok(handlers)
+    }
+
+    /// Writes given value of given kind to a new blob file, see [`BPlus::with_blob_threshold`]
+    async fn write_blob(
+        &self,
+        value: Vec<u8>,
+        kind: ChunkKind,
+        phases: &mut Phases,
+    ) -> Result<ChunkHandler> {
+        let size = value.len();
+        let data_file = self.data_file.clone();
+        let dir = self.path.clone();
+        let start = Instant::now();
+        let (id, digest) = self
+            .unblock(move || -> io::Result<(u64, u64)> {
+                let (id, store) = {
+                    let mut data_file = data_file.lock().unwrap();
+                    (data_file.next_blob(&dir)?, data_file.store)
+                };
+                let path = blob_path(&dir, id);
+                // Blob of a tree, that was loaded from an old save, is not overwritten
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)?;
+                let written = file
+                    .write_all_at(&DataFileHeader::new(store).encode(), 0)
+                    .and_then(|_| {
+                        file.write_all_at(&record::header(&value), record::FILE_HEADER_SIZE)
+                    })
+                    .and_then(|_| {
+                        let offset = record::FILE_HEADER_SIZE + record::HEADER_SIZE;
+                        file.write_all_at(&value, offset)
+                    });
+                if let Err(err) = written {
+                    let _ = std::fs::remove_file(&path);
+                    return Err(err);
+                }
+                Ok((id, hash_bytes(&value)))
+            })
+            .await??;
+        let elapsed = start.elapsed();
+        self.metrics.io_latency.record(elapsed);
+        phases.io += elapsed;
+        Metrics::add(&self.metrics.bytes_written, size as u64);
+        trace_event!(blob = id, bytes = size, "blob written");
+        let chunk = self.chunks.adopt(Location {
+            file: BLOB_FILE,
+            offset: id,
+        });
+        Ok(ChunkHandler::new(chunk, size, kind, digest))
+    }
+
+    /// Appends values, concatenated in given data, to the current data file in one call
+    ///
+    /// Values are given by their sizes and kinds. Returns their handlers in the same order
+    async fn write_records(
+        &self,
+        value: Vec<u8>,
+        values: &[(usize, ChunkKind)],
+        phases: &mut Phases,
+    ) -> Result<Vec<ChunkHandler>> {
+        let sizes: Vec<_> = values.iter().map(|&(size, _)| size).collect();
+        let (file_number, offsets, digests) = self.append_values(value, &sizes, phases).await?;
+        let first = self.chunks.insert(file_number, &offsets);
+        let handlers = values
+            .iter()
+            .zip(digests)
+            .zip(first..)
+            .map(|((&(size, kind), digest), chunk)| ChunkHandler::new(chunk, size, kind, digest))
+            .collect();
+        Ok(handlers)
+    }
+
+    /// Appends records of values, concatenated in given data, to the current data file
+    /// in one call, see [`record`]
+    ///
+    /// Values are given by their sizes. Returns number of the file and offsets of records,
+    /// with hashes of values in the same order
+    pub(crate) async fn append_values(
+        &self,
+        value: Vec<u8>,
+        sizes: &[usize],
+        phases: &mut Phases,
+    ) -> Result<(usize, Vec<u64>, Vec<u64>)> {
+        let value_size = value.len();
+        let data_file = self.data_file.clone();
+        let (dir, max_file_size) = (self.path.clone(), self.max_file_size);
+        let chunk_sizes = sizes.to_vec();
+        let start = Instant::now();
+        // Job reserves the space and advances the offset itself, so if the caller is cancelled
+        // while the job runs, the next value is not written over this one
+        let (rotated, written, digests) = self
+            .unblock(move || {
+                let mut rest = &value[..];
+                let headers = chunk_sizes.len() * record::HEADER_SIZE as usize;
+                let mut records = Vec::with_capacity(value.len() + headers);
+                let digests: Vec<_> = chunk_sizes
+                    .iter()
+                    .map(|&size| {
+                        let (chunk, tail) = rest.split_at(size);
+                        rest = tail;
+                        record::encode(chunk, &mut records);
+                        hash_bytes(chunk)
+                    })
+                    .collect();
+                let (rotated, written) =
+                    data_file
+                        .lock()
+                        .unwrap()
+                        .append(&dir, &records, max_file_size);
+                (rotated, written, digests)
+            })
+            .await?;
+        if let Some(file_number) = rotated {
+            Metrics::inc(&self.metrics.file_rotations);
+            trace_event!(file = file_number, "data file rotated");
+            self.hooks.emit(TreeEvent::FileRotated { file_number });
+        }
+        let (file_number, offset) = written?;
+        let elapsed = start.elapsed();
+        self.metrics.io_latency.record(elapsed);
+        phases.io += elapsed;
+        Metrics::add(&self.metrics.bytes_written, value_size as u64);
+        trace_event!(offset = offset, bytes = value_size, "value written");
+        let offsets = sizes
+            .iter()
+            .scan(offset, |next, &size| {
+                let offset = *next;
+                *next += record::HEADER_SIZE + size as u64;
+                Some(offset)
+            })
+            .collect();
+        Ok((file_number, offsets, digests))
+    }
+
+    /// Returns whether value of given handler lies in a blob file, see [`BPlus::with_blob_threshold`]
+    pub(crate) fn is_in_blob(&self, handler: &ChunkHandler) -> bool {
+        self.chunks
+            .get(handler.chunk)
+            .is_some_and(|location| location.file == BLOB_FILE)
+    }
+
+    /// Returns place of the chunk of given handler in data files
+    pub(crate) fn locate(&self, handler: &ChunkHandler) -> ChunkRef {
+        let location = self
+            .chunks
+            .get(handler.chunk)
+            .expect("chunks of entries are in the table");
+        ChunkRef::new(&self.path, location, handler.size)
+    }
+
+    /// Syncs all data files and blob files to disk
+    pub async fn sync(&self) -> Result<()> {
+        self.flush_buffer().await?;
+        let path = self.path.clone();
+        let data_file = self.data_file.clone();
+        self.unblock(move || {
+            // Holding file lock, so no data is written during sync
+            let data_file = data_file.lock().unwrap();
+            sync_files(&path, data_file.number)
+        })
+        .await??;
+        Ok(())
+    }
+
+    /// Inserts given value by given key in the B+ tree
+    ///
+    /// Returns whether the entry was created or its value of some size was replaced, with
+    /// sequence number of the insert. Sequence numbers of changes of the tree increase
+    /// in order of the changes, see [`BPlus::last_sequence`]. With write buffer, the replaced
+    /// value is the buffered one, or the one in the tree, if the key is not buffered
+    ///
+    /// Returns Err(_) if value could not be written to a file, tree is left unchanged then.
+    /// With write buffer, returns Err(_) if the buffer could not be flushed, buffered values,
+    /// including the given one, stay readable and are flushed again later then
+    pub async fn insert(&self, key: K, value: Vec<u8>) -> Result<InsertOutcome> {
+        self.insert_as(key, value, ChunkKind::Chunk).await
+    }
+
+    /// Inserts given value of given kind by given key in the B+ tree, see [`BPlus::insert`]
+    ///
+    /// Returns Err(_) if value could not be written to a file, tree is left unchanged then
+    pub async fn insert_as(
+        &self,
+        key: K,
+        value: Vec<u8>,
+        kind: ChunkKind,
+    ) -> Result<InsertOutcome> {
+        self.insert_hinted(key, value, kind, None, None, None)
+            .await
+            .map(|(_, outcome)| outcome)
+    }
+
+    /// Inserts given value by given key in the B+ tree with given options,
+    /// returns sequence number of the insert
+    ///
+    /// Returns Err(BPlusError::KeyExists) if key must be absent, but is in the tree,
+    /// Err(BPlusError::LockTimeout) if timeout of options is elapsed, and Err(_) if value could not
+    /// be written to a file or synced. Value may be inserted, even though sync failed
+    pub async fn insert_opt(&self, key: K, value: Vec<u8>, options: &WriteOptions) -> Result<u64> {
+        let insert = async {
+            let sequence = if options.if_absent {
+                let absent = |version: Option<u64>| match version {
+                    Some(_) => Err(BPlusError::KeyExists),
+                    None => Ok(()),
+                };
+                self.insert_checked(key, value, options, absent).await?
+            } else {
+                self.insert_hinted(key, value, options.kind, None, options.origin, None)
+                    .await?
+                    .1
+                    .sequence()
+            };
+            if options.sync {
+                self.sync().await?;
+            }
+            Ok(sequence)
+        };
+        self.within(options.timeout, insert).await
+    }
+
+    /// Inserts given value by given key, if given check accepts the current version of the key,
+    /// see [`BPlus::version`]
+    ///
+    /// Other changes of the tree wait, so the key is not changed between the check and the insert
+    async fn insert_checked<F>(
+        &self,
+        key: K,
+        value: Vec<u8>,
+        options: &WriteOptions,
+        check: F,
+    ) -> Result<u64>
+    where
+        F: FnOnce(Option<u64>) -> Result<()>,
+    {
+        self.check_size(&key, value.len())?;
+        let size = value.len() as u64;
+        let recorded = self
+            .recorder
+            .as_ref()
+            .map(|recorder| recorder.value(&value));
+        let start = Instant::now();
+        let mut phases = Phases::default();
+        let result = async {
+            let writes = self.lock_writes_exclusive(&key).await;
+            check(self.version(&key).await)?;
+            Metrics::inc(&self.metrics.inserts);
+            let sequence = match &self.buffer {
+                None => {
+                    let value = self
+                        .get_chunk_handler(value, options.kind, &mut phases)
+                        .await?;
+                    self.insert_handler(key.clone(), value, None, &mut phases)
+                        .await
+                        .1
+                        .sequence()
+                }
+                Some(buffer) => {
+                    let value = Some((value, options.kind));
+                    let (sequence, _, full) =
+                        buffer.put(key.clone(), value, || self.next_sequence());
+                    drop(writes);
+                    if full {
+                        self.try_apply_buffer(buffer, &mut phases).await?;
+                    }
+                    sequence
+                }
+            };
+            self.audit(AuditOp::Insert, &key, size, options.origin);
+            if let Some(value) = recorded {
+                self.record(Operation::Insert { key: &key, value });
+            }
+            Ok(sequence)
+        }
+        .await;
+        if result.is_ok() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        self.finish_operation("insert", &self.metrics.insert_latency, start, phases);
+        self.enforce_memory_limit().await;
+        result
+    }
+
+    /// Inserts given value by given key, if version of the entry is still the expected one,
+    /// see [`BPlus::version`]. None expects the key to be absent
+    ///
+    /// Returns sequence number of the insert, which is the new version of the entry.
+    /// Returns Err(BPlusError::VersionConflict { .. }) if the entry was changed
+    pub async fn insert_if_version(
+        &self,
+        key: K,
+        expected: Option<u64>,
+        value: Vec<u8>,
+    ) -> Result<u64> {
+        let expected_version = |actual: Option<u64>| match actual == expected {
+            true => Ok(()),
+            false => Err(BPlusError::VersionConflict { expected, actual }),
+        };
+        self.insert_checked(key, value, &WriteOptions::default(), expected_version)
+            .await
+    }
+
+    /// Inserts given value by given key in the B+ tree, starting at the leaf of given hint
+    /// instead of the root, if it still covers the key
+    ///
+    /// Returns hint to the leaf, that holds the key, for the next operation on a nearby key
+    ///
+    /// Returns Err(_) if value could not be written to a file, tree is left unchanged then
+    pub async fn insert_with_hint(
+        &self,
+        key: K,
+        value: Vec<u8>,
+        hint: Option<&Hint<K>>,
+    ) -> Result<Hint<K>> {
+        self.insert_hinted(key, value, ChunkKind::Chunk, hint, None, None)
+            .await
+            .map(|(hint, _)| hint)
+    }
+
+    /// Inserts all given entries in their order, starting every insert at the leaf
+    /// of the previous one
+    ///
+    /// Returns Err(_) if a value could not be written, then following entries are not inserted
+    pub async fn extend<I>(&self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, Vec<u8>)>,
+    {
+        let mut hint = None;
+        for (key, value) in entries {
+            hint = Some(self.insert_with_hint(key, value, hint.as_ref()).await?);
+        }
+        Ok(())
+    }
+
+    /// Inserts by given key the value, that given tree holds by source key, without copying it,
+    /// so indexes over the same values do not duplicate them in data files
+    ///
+    /// Buffered changes of both trees are flushed first, so buffered values are written.
+    /// Returns sequence number of the insert
+    ///
+    /// Returns Err(BPlusError::NotFound) if source tree has no such key, and Err(_) if it
+    /// does not share data files with this tree, see [`BPlus::new_sharing`]
+    pub async fn insert_shared<O: BPlusKey>(
+        &self,
+        key: K,
+        source: &BPlus<O>,
+        source_key: &O,
+    ) -> Result<u64> {
+        if !Arc::ptr_eq(&self.data_file, &source.data_file) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "trees do not share data files",
+            )
+            .into());
+        }
+        source.flush_buffer().await?;
+        self.flush_buffer().await?;
+        let mut phases = Phases::default();
+        let (_, handler) = source.find_handler(source_key, None, &mut phases).await;
+        let mut handler = handler.ok_or(BPlusError::NotFound)?;
+        self.check_size(&key, handler.size)?;
+        let size = handler.size as u64;
+        // Chunk is added to the table of this tree, as ids of chunks are local to a tree
+        let location = source.chunks.get(handler.chunk);
+        handler.chunk = self
+            .chunks
+            .adopt(location.expect("chunks of entries are in the table"));
+        let _writes = self.lock_writes(&key, None).await;
+        Metrics::inc(&self.metrics.inserts);
+        let (_, outcome) = self
+            .insert_handler(key.clone(), handler, None, &mut phases)
+            .await;
+        self.dirty.store(true, Ordering::Release);
+        self.audit(AuditOp::Insert, &key, size, None);
+        Ok(outcome.sequence())
+    }
+
+    /// Inserts value of given kind by given key, starting at the leaf of given hint if it is valid
+    ///
+    /// Returns hint to the leaf, into which the key was inserted, and outcome of the insert
+    async fn insert_hinted(
+        &self,
+        key: K,
+        value: Vec<u8>,
+        kind: ChunkKind,
+        hint: Option<&Hint<K>>,
+        origin: Option<&str>,
+        owner: Option<u64>,
+    ) -> Result<(Hint<K>, InsertOutcome)> {
+        self.check_size(&key, value.len())?;
+        let audited = (self.audit.is_some() || self.recorder.is_some()).then(|| key.clone());
+        let recorded = self
+            .recorder
+            .as_ref()
+            .map(|recorder| recorder.value(&value));
+        let size = value.len() as u64;
+        let start = Instant::now();
+        let mut phases = Phases::default();
+        let partition = self.partition_index(&key);
+        let result = in_span!("insert", bytes = value.len(); async {
+            let writes = self.lock_writes(&key, owner).await;
+            let Some(buffer) = &self.buffer else {
+                let (leaf, outcome) = self.insert_entry(key, value, kind, hint, &mut phases).await?;
+                return Ok((Hint::new(&leaf, partition), outcome));
+            };
+            Metrics::inc(&self.metrics.inserts);
+            let (sequence, buffered, full) =
+                buffer.put(key.clone(), Some((value, kind)), || self.next_sequence());
+            // Buffer is frozen with the exclusive writes guard, so the tree does not get
+            // the buffered value, until the key is looked up
+            let old_size = match buffered {
+                Some(size) => size.map(|size| size as u64),
+                None => {
+                    let (_, stored) = self.find_handler(&key, None, &mut phases).await;
+                    stored.map(|handler| handler.size as u64)
+                }
+            };
+            drop(writes);
+            if full {
+                self.try_apply_buffer(buffer, &mut phases).await?;
+            }
+            Ok((Hint::empty(partition), InsertOutcome::new(old_size, sequence)))
+        });
+        if result.is_ok() {
+            // Set after the change, so save, that clears it, can not miss the change
+            self.dirty.store(true, Ordering::Release);
+            if let Some(key) = &audited {
+                self.audit(AuditOp::Insert, key, size, origin);
+                if let Some(value) = recorded {
+                    self.record(Operation::Insert { key, value });
+                }
+            }
+        }
+        self.finish_operation("insert", &self.metrics.insert_latency, start, phases);
+        if owner.is_none() {
+            self.enforce_memory_limit().await;
+        }
+        result
+    }
+
+    /// Writes value to a file and inserts it by given key in the B+ tree
+    ///
+    /// Called with the shared writes guard.
+    /// Returns link to the leaf, into which the key was inserted, and outcome of the insert
+    async fn insert_entry(
+        &self,
+        key: K,
+        value: Vec<u8>,
+        kind: ChunkKind,
+        hint: Option<&Hint<K>>,
+        phases: &mut Phases,
+    ) -> Result<(Link<K>, InsertOutcome)> {
+        let value = self.get_chunk_handler(value, kind, phases).await?;
+        Metrics::inc(&self.metrics.inserts);
+        Ok(self.insert_handler(key, value, hint, phases).await)
+    }
+
+    /// Inserts handler of a written value by given key in the B+ tree with the next
+    /// sequence number
+    ///
+    /// Returns link to the leaf, into which the key was inserted, and outcome of the insert
+    async fn insert_handler(
+        &self,
+        key: K,
+        value: ChunkHandler,
+        hint: Option<&Hint<K>>,
+        phases: &mut Phases,
+    ) -> (Link<K>, InsertOutcome) {
+        let (link, outcome) = self.insert_numbered(key, value, hint, None, phases).await;
+        let outcome = outcome.expect("changes numbered under the leaf latch are the latest");
+        (link, outcome)
+    }
+
+    /// Inserts handler of a written value by given key in the B+ tree
+    ///
+    /// Handler is inserted with given sequence number, or the next one, which is assigned while
+    /// the leaf is locked, so changes of a key are numbered in their order. Handler, that was
+    /// inserted by a later change, is not replaced, and it is released then
+    ///
+    /// Returns link to the leaf, into which the key was inserted, and outcome of the insert,
+    /// or None if the handler was superseded
+    async fn insert_numbered(
+        &self,
+        key: K,
+        value: ChunkHandler,
+        hint: Option<&Hint<K>>,
+        sequence: Option<u64>,
+        phases: &mut Phases,
+    ) -> (Link<K>, Option<InsertOutcome>) {
+        // Key is added before it is visible, so lookups never miss it in the filter
+        if let Some(bloom) = &self.bloom {
+            bloom.insert(&key);
+        }
+        let (key, mut value) = match self.try_append(key, value, sequence, phases).await {
+            Ok((link, outcome)) => return (link, Some(outcome)),
+            Err(entry) => entry,
+        };
+        let partition = self.partition(&key);
+        let mut hinted = match hint {
+            Some(hint) => self.hinted_leaf(&key, hint, phases).await,
+            None => None,
+        };
+        let mut splits_guard = None;
+
+        loop {
+            // Parents of hinted leaf are found from the root, if it splits
+            let (mut link, path) = match hinted.take() {
+                Some(leaf) => (leaf, Vec::new()),
+                None => self.descend(&key, 0, phases).await,
+            };
+            let mut node = self.write_covering(&mut link, &key, phases).await;
+            if self.is_stale_root(&link, 0) {
+                Metrics::inc(&self.metrics.latch_retries);
+                continue;
+            }
+            let Node::Leaf(leaf) = &mut *node else {
+                unreachable!("only leaves are on level 0")
+            };
+            let pos = search_by_key(&leaf.entries, &key, |(k, _)| k);
+            if pos.is_err() && leaf.entries.len() == 2 * self.t - 1 && splits_guard.is_none() {
+                // Leaf is going to split, save must not see it before the parent is updated
+                drop(node);
+                Metrics::inc(&self.metrics.optimistic_fallbacks);
+                trace_event!("leaf is full, retrying insert with split");
+                splits_guard = Some(self.splits.read().await);
+                continue;
+            }
+
+            let sequence = sequence.unwrap_or_else(|| self.next_sequence());
+            value.version = sequence;
+            let mut outcome = InsertOutcome::new(None, sequence);
+            match pos {
+                Ok(pos) if leaf.entries[pos].1.version > sequence => {
+                    // Value is superseded by a newer one already
+                    self.release(&value);
+                    return (link, None);
+                }
+                Ok(pos) => {
+                    Metrics::inc(&self.metrics.overwrites);
+                    self.update_merkle(&key, Some(&leaf.entries[pos].1), Some(&value));
+                    let previous = mem::replace(&mut leaf.entries[pos].1, value);
+                    outcome = InsertOutcome::new(Some(previous.size as u64), sequence);
+                    self.keep_version(key, previous);
+                }
+                Err(pos) => {
+                    self.update_merkle(&key, None, Some(&value));
+                    leaf.entries.insert(pos, (key, value));
+                }
+            }
+            // Leaf is the rightmost one of its partition, greater keys may be appended to it
+            let rightmost = leaf.next.is_none();
+            if leaf.entries.len() == 2 * self.t {
+                self.split(link.clone(), node, path, phases).await;
+                if rightmost {
+                    self.cache_rightmost(partition, link.clone()).await;
+                }
+            } else if rightmost {
+                let max = leaf.entries[leaf.entries.len() - 1].0.clone();
+                *partition.rightmost.lock().unwrap() = Some((link.clone(), max));
+            }
+            return (link, Some(outcome));
+        }
+    }
+
+    /// Applies buffered inserts and removals to data files and the tree
+    ///
+    /// Does nothing, if the tree has no write buffer
+    ///
+    /// Returns Err(_) if values could not be written to a file, they stay buffered then
+    pub async fn flush_buffer(&self) -> Result<()> {
+        let Some(buffer) = &self.buffer else {
+            return Ok(());
+        };
+        let _flush = buffer.lock_flush().await;
+        self.apply_buffer(buffer, &mut Phases::default()).await
+    }
+
+    /// Applies buffered changes to the tree, unless they are being applied by another change
+    async fn try_apply_buffer(&self, buffer: &WriteBuffer<K>, phases: &mut Phases) -> Result<()> {
+        match buffer.try_lock_flush() {
+            Some(_flush) => self.apply_buffer(buffer, phases).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Freezes buffered changes and applies them to the tree in one sorted batch,
+    /// writing their values to a file in one call
+    ///
+    /// Called with the flush guard of the buffer. Changes in flight finish before the buffer
+    /// is frozen, so inserts look up values, that they replace, before the tree gets them
+    async fn apply_buffer(&self, buffer: &WriteBuffer<K>, phases: &mut Phases) -> Result<()> {
+        let writes = self.writes.write().await;
+        let batch = buffer.freeze();
+        let _writes = writes.downgrade();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let values: Vec<_> = batch
+            .values()
+            .filter_map(|(value, _)| value.as_ref())
+            .collect();
+        let sizes: Vec<_> = values
+            .iter()
+            .map(|(data, kind)| (data.len(), *kind))
+            .collect();
+        let data = values.iter().flat_map(|(data, _)| data).copied().collect();
+        let mut handlers = self.write_values(data, &sizes, phases).await?.into_iter();
+
+        // Sorted keys are inserted from the leaf of the previous one
+        let mut hint = None;
+        for (key, (value, sequence)) in batch.iter() {
+            // Buffered changes were numbered, when they were buffered
+            if value.is_none() {
+                self.remove_from_tree(key, Some(*sequence)).await;
+                continue;
+            }
+            let handler = handlers.next().expect("every buffered value is written");
+            let (leaf, _) = self
+                .insert_numbered(key.clone(), handler, hint.as_ref(), Some(*sequence), phases)
+                .await;
+            hint = Some(Hint::new(&leaf, self.partition_index(key)));
+        }
+        buffer.release();
+        self.dirty.store(true, Ordering::Release);
+        trace_event!(entries = batch.len(), "write buffer flushed");
+        Ok(())
+    }
+
+    /// Remembers the new right sibling of the split rightmost leaf of partition,
+    /// so greater keys are appended to it
+    async fn cache_rightmost(&self, partition: &Partition<K>, split: Link<K>) {
+        let right = match &*split.read().await {
+            Node::Leaf(leaf) => leaf.next.clone(),
+            Node::Internal(_) => None,
+        };
+        let Some(right) = right else {
+            return;
+        };
+        let max = match &*right.read().await {
+            Node::Leaf(leaf) => leaf.entries.last().map(|(key, _)| key.clone()),
+            Node::Internal(_) => None,
+        };
+        if let Some(max) = max {
+            *partition.rightmost.lock().unwrap() = Some((right, max));
+        }
+    }
+
+    /// Appends entry to the rightmost leaf of its partition without descent, if its key
+    /// is greater than all keys of the partition and the leaf does not need to split
+    ///
+    /// Returns link to the leaf with outcome of the insert, that creates the entry, or entry
+    /// back, if it has to be inserted from the root
+    async fn try_append(
+        &self,
+        key: K,
+        mut value: ChunkHandler,
+        sequence: Option<u64>,
+        phases: &mut Phases,
+    ) -> std::result::Result<(Link<K>, InsertOutcome), (K, ChunkHandler)> {
+        let partition = self.partition(&key);
+        let link = match &*partition.rightmost.lock().unwrap() {
+            Some((link, max)) if key > *max => link.clone(),
+            _ => return Err((key, value)),
+        };
+        let mut node = phases.wait(link.clone().write_owned()).await;
+        // Root leaf may have been split in place, and other leaves may have got right siblings
+        let Node::Leaf(leaf) = &mut *node else {
+            return Err((key, value));
+        };
+        let appendable = leaf.next.is_none()
+            && leaf.entries.len() < 2 * self.t - 1
+            && leaf.entries.last().is_some_and(|(last, _)| *last < key);
+        if !appendable {
+            return Err((key, value));
+        }
+        if let Some((_, max)) = &mut *partition.rightmost.lock().unwrap() {
+            *max = key.clone();
+        }
+        let sequence = sequence.unwrap_or_else(|| self.next_sequence());
+        value.version = sequence;
+        self.update_merkle(&key, None, Some(&value));
+        leaf.entries.push((key, value));
+        Metrics::inc(&self.metrics.appends);
+        Ok((link, InsertOutcome::new(None, sequence)))
+    }
+
+    /// Returns leaf of given hint, if it still covers given key, so descent may start there
+    ///
+    /// Leaf covers the key, if the key is not below its first key, as keys of a leaf never go
+    /// below its lower bound. Keys above its high key are reached by moving right
+    async fn hinted_leaf(&self, key: &K, hint: &Hint<K>, phases: &mut Phases) -> Option<Link<K>> {
+        if hint.partition != self.partition_index(key) {
+            return None;
+        }
+        let leaf = hint.leaf.upgrade()?;
+        let covers = match &*phases.wait(leaf.clone().read_owned()).await {
+            Node::Leaf(leaf) => leaf.entries.first().is_some_and(|(first, _)| first <= key),
+            // Root leaf was split in place
+            Node::Internal(_) => false,
+        };
+        if !covers {
+            return None;
+        }
+        Metrics::inc(&self.metrics.hint_hits);
+        Some(leaf)
+    }
+
+    /// Descends from the root of the partition of given key to the node on given level,
+    /// that covers given key, holding one latch at a time; leaves are on level 0
+    ///
+    /// Returns link to that node, which is not locked, and links to internal nodes passed on the way
+    async fn descend(&self, key: &K, level: usize, phases: &mut Phases) -> (Link<K>, Vec<Link<K>>) {
+        let partition = self.partition(key);
+        let mut link = partition.root.clone();
+        let mut path = Vec::new();
+        let mut node = Some(phases.wait(link.clone().read_owned()).await);
+        // Only the root changes its level, and only under its write latch
+        let mut current = partition.height.load(Ordering::SeqCst) - 1;
+        while current > level {
+            let guard = match node.take() {
+                Some(guard) => guard,
+                None => self.read_covering(&mut link, key, phases).await,
+            };
+            let Node::Internal(internal) = &*guard else {
+                unreachable!("only leaves are on level 0")
+            };
+            path.push(link.clone());
+            link = internal.children[internal.child_index(key)].clone();
+            current -= 1;
+        }
+        (link, path)
+    }
+
+    /// Descends from the root of the partition of given key to the leaf, that covers it,
+    /// holding one latch at a time
+    ///
+    /// Returns link to the leaf with its read guard
+    async fn read_leaf(
+        &self,
+        key: &K,
+        phases: &mut Phases,
+    ) -> (Link<K>, OwnedLatchReadGuard<Node<K>>) {
+        let mut link = self.partition(key).root.clone();
+        loop {
+            let node = self.read_covering(&mut link, key, phases).await;
+            let Node::Internal(internal) = &*node else {
+                return (link, node);
+            };
+            link = internal.children[internal.child_index(key)].clone();
+        }
+    }
+
+    /// Locks node by given link for reading, moving right while key lies beyond its high key
+    async fn read_covering(
+        &self,
+        link: &mut Link<K>,
+        key: &K,
+        phases: &mut Phases,
+    ) -> OwnedLatchReadGuard<Node<K>> {
+        loop {
+            let node = phases.wait(link.clone().read_owned()).await;
+            match node.move_right(key) {
+                Some(right) => {
+                    Metrics::inc(&self.metrics.latch_retries);
+                    *link = right;
+                }
+                None => return node,
+            }
+        }
+    }
+
+    /// Locks node by given link for writing, moving right while key lies beyond its high key
+    async fn write_covering(
+        &self,
+        link: &mut Link<K>,
+        key: &K,
+        phases: &mut Phases,
+    ) -> OwnedLatchWriteGuard<Node<K>> {
+        loop {
+            let node = phases.wait(link.clone().write_owned()).await;
+            match node.move_right(key) {
+                Some(right) => {
+                    Metrics::inc(&self.metrics.latch_retries);
+                    *link = right;
+                }
+                None => return node,
+            }
+        }
+    }
+
+    /// Returns whether given link is a root, that was split since it was found on given level
+    ///
+    /// Must be called with the root locked
+    fn is_stale_root(&self, link: &Link<K>, level: usize) -> bool {
+        self.rooted_at(link)
+            .is_some_and(|partition| partition.height.load(Ordering::SeqCst) - 1 != level)
+    }
+
+    /// Splits overflowed leaf, then adds separator to its parent, splitting it if needed,
+    /// and so on up to the root
+    ///
+    /// Latch of a node is released before its parent is locked, readers reach the new node
+    /// by moving right until then
+    async fn split(
+        &self,
+        mut link: Link<K>,
+        mut node: OwnedLatchWriteGuard<Node<K>>,
+        mut path: Vec<Link<K>>,
+        phases: &mut Phases,
+    ) {
+        // Events are emitted after all nodes are unlocked
+        let mut events = Vec::new();
+        let mut level = 0;
+        loop {
+            Metrics::inc(&self.metrics.splits);
+            events.push(TreeEvent::NodeSplit { leaf: level == 0 });
+            if let Some(partition) = self.rooted_at(&link) {
+                let height = partition.split_root(&mut node, self.t, self.fairness);
+                events.push(TreeEvent::RootHeightChanged { height });
+                break;
+            }
+            let (right, separator) = node.split(self.t);
+            right.set_fairness(self.fairness);
+            drop(node);
+
+            level += 1;
+            node = loop {
+                // Parent is the node passed on the way down, unless the root was split since
+                let mut parent = match path.pop() {
+                    Some(parent) => parent,
+                    None => {
+                        let (parent, rest) = self.descend(&separator, level, phases).await;
+                        path = rest;
+                        parent
+                    }
+                };
+                let guard = self.write_covering(&mut parent, &separator, phases).await;
+                if self.is_stale_root(&parent, level) {
+                    Metrics::inc(&self.metrics.latch_retries);
+                    path.clear();
+                    continue;
+                }
+                link = parent;
+                break guard;
+            };
+            let Node::Internal(internal) = &mut *node else {
+                unreachable!("parents are internal nodes")
+            };
+            let pos = internal.child_index(&separator);
+            internal.keys.insert(pos, separator);
+            internal.children.insert(pos + 1, right);
+            if internal.keys.len() < 2 * self.t - 1 {
+                break;
+            }
+        }
+        drop(node);
+        for event in events {
+            self.hooks.emit(event);
+        }
+    }
+
+    /// Removes value by given key from the B+ tree
+    ///
+    /// Record of the value stays in data file and is reported by [`BPlus::free_space`],
+    /// until [`BPlus::compact`] reclaims it. Leaves are not merged
+    ///
+    /// Returns sequence number of the removal, or None if the key was not present
+    pub async fn remove(&self, key: &K) -> Option<u64> {
+        self.remove_owned(key, None).await
+    }
+
+    /// Removes value by given key, passing locks of given owner, see [`BPlus::lock_range`]
+    async fn remove_owned(&self, key: &K, owner: Option<u64>) -> Option<u64> {
+        // Check and removal are not split by changes of the key
+        let writes = self.lock_writes(key, owner).await;
+        let sequence = match &self.buffer {
+            None => self.remove_from_tree(key, None).await,
+            Some(buffer) => {
+                let present = match buffer.get(key) {
+                    Some((value, _)) => value.is_some(),
+                    None => self.contains_in_tree(key).await,
+                };
+                // Removal is applied to the tree by the next flush
+                present.then(|| buffer.put(key.clone(), None, || self.next_sequence()).0)
+            }
+        };
+        drop(writes);
+        if sequence.is_some() {
+            self.audit(AuditOp::Remove, key, 0, None);
+        }
+        self.record(Operation::Remove {
+            key,
+            found: sequence.is_some(),
+        });
+        if owner.is_none() {
+            self.enforce_memory_limit().await;
+        }
+        sequence
+    }
+
+    /// Locks given range of keys until the returned guard is dropped
+    ///
+    /// Other inserts and removals of locked keys wait for the guard, while reads go on. Waits
+    /// for changes in flight and for locks of overlapping ranges, which are released first.
+    /// Holder changes locked keys through the guard, see [`KeyLock`]
+    pub async fn lock_range<R: RangeBounds<K>>(&self, range: R) -> KeyLock<'_, K> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let owner = self.lock_keys(range.clone(), false).await;
+        KeyLock {
+            tree: self,
+            owner,
+            range,
+        }
+    }
+
+    /// Freezes given range of keys until the returned guard is dropped
+    ///
+    /// Like [`BPlus::lock_range`], but overlapping ranges may be frozen by others at once,
+    /// and nobody changes frozen keys
+    pub async fn freeze_range<R: RangeBounds<K>>(&self, range: R) -> FrozenRange<'_, K> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let owner = self.lock_keys(range.clone(), true).await;
+        FrozenRange {
+            tree: self,
+            owner,
+            range,
+        }
+    }
+
+    /// Locks given range, waiting for changes in flight and conflicting locks,
+    /// returns identifier of its owner
+    async fn lock_keys(&self, range: Range<K>, shared: bool) -> u64 {
+        loop {
+            let released = self.locks.released();
+            {
+                // Changes, that passed the check of locks, finish before the range is locked
+                let _writes = self.writes.write().await;
+                if let Some(owner) = self.locks.try_lock(range.clone(), shared) {
+                    return owner;
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// Locks given key until the returned guard is dropped, see [`BPlus::lock_range`]
+    pub async fn lock_key(&self, key: &K) -> KeyLock<'_, K> {
+        self.lock_range(key.clone()..=key.clone()).await
+    }
+
+    /// Takes the shared writes guard for a change of given key, waiting until the key is not
+    /// locked by anyone except given owner
+    async fn lock_writes(&self, key: &K, owner: Option<u64>) -> RwLockReadGuard<'_, ()> {
+        loop {
+            let released = self.locks.released();
+            let writes = self.writes.read().await;
+            if !self.locks.is_locked(key, owner) {
+                return writes;
+            }
+            drop(writes);
+            released.await;
+        }
+    }
+
+    /// Takes the exclusive writes guard for a change of given key, waiting until the key
+    /// is not locked
+    async fn lock_writes_exclusive(&self, key: &K) -> RwLockWriteGuard<'_, ()> {
+        loop {
+            let released = self.locks.released();
+            let writes = self.writes.write().await;
+            if !self.locks.is_locked(key, None) {
+                return writes;
+            }
+            drop(writes);
+            released.await;
+        }
+    }
+
+    /// Removes value by given key from the tree, bypassing the write buffer
+    ///
+    /// Called with the shared writes guard. Removal has given sequence number, or the next one,
+    /// and does not remove handler, that was inserted by a later change
+    ///
+    /// Returns sequence number of the removal, or None if the key was not present
+    async fn remove_from_tree(&self, key: &K, sequence: Option<u64>) -> Option<u64> {
+        let mut phases = Phases::default();
+        loop {
+            let (mut link, node) = self.read_leaf(key, &mut phases).await;
+            drop(node);
+
+            // Leaf may be split until it is locked again, then key is found by moving right
+            let mut node = self.write_covering(&mut link, key, &mut phases).await;
+            let Node::Leaf(leaf) = &mut *node else {
+                // Root leaf was split in the meantime
+                drop(node);
+                Metrics::inc(&self.metrics.latch_retries);
+                continue;
+            };
+
+            return match search_by_key(&leaf.entries, key, |(k, _)| k) {
+                Ok(pos)
+                    if sequence.is_some_and(|sequence| leaf.entries[pos].1.version > sequence) =>
+                {
+                    sequence
+                }
+                Ok(pos) => {
+                    let (key, previous) = leaf.entries.remove(pos);
+                    self.update_merkle(&key, Some(&previous), None);
+                    self.keep_version(key, previous);
+                    self.dirty.store(true, Ordering::Release);
+                    Some(sequence.unwrap_or_else(|| self.next_sequence()))
+                }
+                Err(_) => None,
+            };
+        }
+    }
+
+    /// Gets value from a B+ tree by given key
+    pub async fn get(&self, key: &K) -> Result<Vec<u8>> {
+        self.get_with_kind(key).await.map(|(data, _)| data)
+    }
+
+    /// Gets given version of value from a B+ tree by given key: 0 is the current value,
+    /// 1 is the one it replaced, and so on, see [`BPlus::with_versions`]
+    ///
+    /// Returns Err(BPlusError::NotFound) if key has no such version
+    pub async fn get_version(&self, key: &K, version: usize) -> Result<Vec<u8>> {
+        if version == 0 {
+            return self.get(key).await;
+        }
+        let handler = self.versions.as_ref().and_then(|versions| {
+            let previous = versions.previous.lock().unwrap();
+            previous.get(key)?.get(version - 1).cloned()
+        });
+        let handler = handler.ok_or(BPlusError::NotFound)?;
+        self.read_value(handler, &mut Phases::default()).await
+    }
+
+    /// Gets value from a B+ tree by given key together with kind of that value
+    pub async fn get_with_kind(&self, key: &K) -> Result<(Vec<u8>, ChunkKind)> {
+        self.get_hinted(key, None)
+            .await
+            .map(|(data, kind, _)| (data, kind))
+    }
+
+    /// Gets value from a B+ tree by given key, starting at the leaf of given hint
+    /// instead of the root, if it still covers the key
+    ///
+    /// Returns value with hint to the leaf, that holds the key, for the next operation
+    /// on a nearby key
+    pub async fn get_with_hint(
+        &self,
+        key: &K,
+        hint: Option<&Hint<K>>,
+    ) -> Result<(Vec<u8>, Hint<K>)> {
+        let (data, _, hint) = self.get_hinted(key, hint).await?;
+        Ok((data, hint))
+    }
+
+    /// Gets value with its kind by given key, starting at the leaf of given hint if it is valid
+    ///
+    /// Returns hint to the leaf, that holds the key, too
+    async fn get_hinted(
+        &self,
+        key: &K,
+        hint: Option<&Hint<K>>,
+    ) -> Result<(Vec<u8>, ChunkKind, Hint<K>)> {
+        let start = Instant::now();
+        let mut phases = Phases::default();
+        let partition = self.partition_index(key);
+        let result = in_span!("get"; async {
+            Metrics::inc(&self.metrics.gets);
+            if let Some(buffered) = self.buffered(key) {
+                let (data, kind) = buffered.ok_or(BPlusError::NotFound)?;
+                return Ok((data, kind, Hint::empty(partition)));
+            }
+            if self.surely_absent(key) {
+                return Err(BPlusError::NotFound);
+            }
+            let (leaf, handler) = self.find_handler(key, hint, &mut phases).await;
+            let handler = handler.ok_or(BPlusError::NotFound)?;
+            let kind = handler.kind;
+            let data = self.read_value(handler, &mut phases).await?;
+            trace_event!(bytes = data.len(), "value read");
+            Ok((data, kind, Hint::new(&leaf, partition)))
+        });
+        self.finish_operation("get", &self.metrics.get_latency, start, phases);
+        if let Some(recorder) = &self.recorder {
+            let value = result
+                .as_ref()
+                .ok()
+                .map(|(data, _, _)| recorder.value(data));
+            recorder.record(Operation::Get { key, value });
+        }
+        result
+    }
+
+    /// Returns buffered value by given key: Some(None) if it was removed,
+    /// None if it is not buffered
+    fn buffered(&self, key: &K) -> Option<Option<(Vec<u8>, ChunkKind)>> {
+        self.buffer
+            .as_ref()
+            .and_then(|buffer| buffer.get(key))
+            .map(|(value, _)| value)
+    }
+
+    /// Reads value by given handler from its data file
+    async fn read_value(&self, handler: ChunkHandler, phases: &mut Phases) -> Result<Vec<u8>> {
+        let start = Instant::now();
+        let data = self.start_read(handler, Access::Point).await?;
+        phases.io += start.elapsed();
+        Ok(data)
+    }
+
+    /// Starts reading value by given handler from its data file with given pattern, before
+    /// the returned future is polled, so several reads may run at once
+    pub(crate) fn start_read(
+        &self,
+        handler: ChunkHandler,
+        access: Access,
+    ) -> FutureBox<'_, Result<Vec<u8>>> {
+        let start = Instant::now();
+        let chunk = self.locate(&handler);
+        let access = self.hints(access);
+        let read = self.unblock(move || chunk.read(access));
+        Box::pin(async move {
+            let data = read.await?.inspect_err(|err| self.report_short_read(err))?;
+            self.metrics.io_latency.record(start.elapsed());
+            Metrics::add(&self.metrics.bytes_read, data.len() as u64);
+            Ok(data)
+        })
+    }
+
+    /// Reports corruption, if value could not be read, as it lies beyond end of data file
+    /// or its record is corrupted
+    fn report_short_read(&self, err: &io::Error) {
+        let message = match err.kind() {
+            io::ErrorKind::UnexpectedEof => format!("value lies beyond end of data file: {err}"),
+            io::ErrorKind::InvalidData => format!("record of value is corrupted: {err}"),
+            _ => return,
+        };
+        self.hooks.emit(TreeEvent::CorruptionDetected { message });
+    }
+
+    /// Gets values from a B+ tree by all given keys, in order of keys
+    ///
+    /// Values are read in order of their positions in data files, adjacent ones in one call
+    ///
+    /// Returns Err(BPlusError::NotFound) if some of the keys is not present
+    pub async fn get_many(&self, keys: &[K]) -> Result<Vec<Vec<u8>>> {
+        let values = self.get_many_with_kind(keys).await?;
+        Ok(values.into_iter().map(|(data, _)| data).collect())
+    }
+
+    /// Gets values with their kinds from a B+ tree by all given keys, in order of keys,
+    /// see [`BPlus::get_many`]
+    pub async fn get_many_with_kind(&self, keys: &[K]) -> Result<Vec<(Vec<u8>, ChunkKind)>> {
+        let start = Instant::now();
+        let mut phases = Phases::default();
+        let result = in_span!("get_many", keys = keys.len(); async {
+            Metrics::add(&self.metrics.gets, keys.len() as u64);
+            let mut results = Vec::with_capacity(keys.len());
+            let mut handlers = Vec::with_capacity(keys.len());
+            // Leaf of the previous key is a hint for the next one, as keys are often close
+            let mut hint = None;
+            for key in keys {
+                if let Some(buffered) = self.buffered(key) {
+                    results.push(Some(buffered.ok_or(BPlusError::NotFound)?));
+                    continue;
+                }
+                if self.surely_absent(key) {
+                    return Err(BPlusError::NotFound);
+                }
+                let (leaf, handler) = self.find_handler(key, hint.as_ref(), &mut phases).await;
+                handlers.push(handler.ok_or(BPlusError::NotFound)?);
+                results.push(None);
+                hint = Some(Hint::new(&leaf, self.partition_index(key)));
+            }
+            let kinds: Vec<_> = handlers.iter().map(|handler| handler.kind).collect();
+            let chunks: Vec<_> = handlers.iter().map(|handler| self.locate(handler)).collect();
+
+            let read_start = Instant::now();
+            let access = self.hints(Access::Point);
+            let (values, coalesced) = self
+                .unblock(move || ChunkRef::read_many(&chunks, access))
+                .await?
+                .inspect_err(|err| self.report_short_read(err))?;
+            let elapsed = read_start.elapsed();
+            self.metrics.io_latency.record(elapsed);
+            phases.io += elapsed;
+            let bytes: usize = values.iter().map(Vec::len).sum();
+            Metrics::add(&self.metrics.bytes_read, bytes as u64);
+            Metrics::add(&self.metrics.coalesced_reads, coalesced as u64);
+            trace_event!(bytes = bytes, coalesced = coalesced, "values read");
+            // Values, that are not buffered, are read in order of keys
+            let mut read = values.into_iter().zip(kinds);
+            Ok(results
+                .into_iter()
+                .map(|result| result.or_else(|| read.next()).expect("every handler is read"))
+                .collect())
+        });
+        self.finish_operation("get", &self.metrics.get_latency, start, phases);
+        result
+    }
+
+    /// Gets value from a B+ tree by given key, giving up if it takes longer than given timeout
+    ///
+    /// Timer of the spawner of the tree is used, or a timer thread if there is no spawner
+    ///
+    /// Returns Err(BPlusError::LockTimeout) if timeout is elapsed
+    pub async fn get_timeout(&self, key: &K, timeout: Duration) -> Result<Vec<u8>> {
+        self.with_timeout(timeout, self.get(key)).await
+    }
+
+    /// Gets value from a B+ tree by given key, read with given options
+    ///
+    /// Returns Err(BPlusError::LockTimeout) if timeout of options is elapsed
+    pub async fn get_opt(&self, key: &K, options: &ReadOptions) -> Result<Vec<u8>> {
+        self.within(options.timeout, self.get(key)).await
+    }
+
+    /// Inserts given value by given key in the B+ tree, giving up if it takes longer than given timeout
+    ///
+    /// Returns Err(BPlusError::LockTimeout) if timeout is elapsed. Value is not inserted then,
+    /// though it may still be written to a data file, if the write had already started
+    ///
+    /// Timer of the spawner of the tree is used, or a timer thread if there is no spawner
+    pub async fn insert_timeout(
+        &self,
+        key: K,
+        value: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<InsertOutcome> {
+        self.with_timeout(timeout, self.insert(key, value)).await
+    }
+
+    /// Runs operation, giving up if it takes longer than given timeout
+    ///
+    /// Returns Err(BPlusError::LockTimeout) if timeout is elapsed
+    async fn with_timeout<T>(
+        &self,
+        timeout: Duration,
+        operation: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let mut timer = match &self.spawner {
+            Some(spawner) => spawner.sleep(timeout),
+            None => timer_sleep(timeout),
+        };
+        let mut operation = pin!(operation);
+        poll_fn(|cx| match operation.as_mut().poll(cx) {
+            Poll::Ready(result) => Poll::Ready(result),
+            Poll::Pending => timer
+                .as_mut()
+                .poll(cx)
+                .map(|()| Err(BPlusError::LockTimeout)),
+        })
+        .await
+    }
+
+    /// Runs operation, giving up if it takes longer than given timeout, if any
+    async fn within<T>(
+        &self,
+        timeout: Option<Duration>,
+        operation: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        match timeout {
+            Some(timeout) => self.with_timeout(timeout, operation).await,
+            None => operation.await,
+        }
+    }
+
+    /// Gets value from a B+ tree by given key, failing fast if root is locked for writing
+    ///
+    /// Best-effort: only the root of the partition of the key is checked, and its guard is not held during get,
+    /// otherwise a queued writer would block it forever. So get may still wait for a writer,
+    /// that locks the root after the check
+    ///
+    /// Returns Err(BPlusError::LockTimeout) if root could not be locked immediately
+    pub async fn try_get(&self, key: &K) -> Result<Vec<u8>> {
+        let root = &self.partition(key).root;
+        drop(root.try_read().ok_or(BPlusError::LockTimeout)?);
+        self.get(key).await
+    }
+
+    /// Returns version of the entry by given key, which is the sequence number of the change,
+    /// that inserted its value, or None if there is no such key
+    pub async fn version(&self, key: &K) -> Option<u64> {
+        if let Some((value, sequence)) = self.buffer.as_ref().and_then(|buffer| buffer.get(key)) {
+            return value.map(|_| sequence);
+        }
+        if self.surely_absent(key) {
+            return None;
+        }
+        let (_, handler) = self.find_handler(key, None, &mut Phases::default()).await;
+        handler.map(|handler| handler.version)
+    }
+
+    /// Returns whether key is contained in the B+ tree or not, without reading its value
+    pub async fn contains_key(&self, key: &K) -> bool {
+        match self.buffered(key) {
+            Some(buffered) => buffered.is_some(),
+            None => self.contains_in_tree(key).await,
+        }
+    }
+
+    /// Returns whether key is contained in the tree, bypassing the write buffer
+    async fn contains_in_tree(&self, key: &K) -> bool {
+        if self.surely_absent(key) {
+            return false;
+        }
+        self.find_handler(key, None, &mut Phases::default())
+            .await
+            .1
+            .is_some()
+    }
+
+    /// Finds handler of the chunk stored by given key, starting at the leaf of given hint
+    /// if it is valid
+    ///
+    /// Returns link to the leaf, that covers the key, with the handler
+    async fn find_handler(
+        &self,
+        key: &K,
+        hint: Option<&Hint<K>>,
+        phases: &mut Phases,
+    ) -> (Link<K>, Option<ChunkHandler>) {
+        let hinted = match hint {
+            Some(hint) => self.hinted_leaf(key, hint, phases).await,
+            None => None,
+        };
+        let (link, node) = match hinted {
+            Some(mut link) => {
+                let node = self.read_covering(&mut link, key, phases).await;
+                (link, node)
+            }
+            None => self.read_leaf(key, phases).await,
+        };
+        let Node::Leaf(leaf) = &*node else {
+            unreachable!("descent ends in a leaf")
+        };
+        let handler = search_by_key(&leaf.entries, key, |(k, _)| k)
+            .ok()
+            .map(|pos| leaf.entries[pos].1.clone());
+        drop(node);
+        (link, handler)
+    }
+
+    /// Returns all entries with keys in given range, in ascending order of keys
+    ///
+    /// Returns Err(_) if some of the values could not be read
+    pub async fn scan<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, Vec<u8>)>> {
+        let start = Instant::now();
+        let mut phases = Phases::default();
+        let bounds = self.recorded_bounds(&range);
+        // Buffer is read first, so flushed entries are not missed by the scan of the tree
+        let buffered = self.buffer.as_ref().map(|buffer| buffer.range(&range));
+        let window = self.read_ahead.load(Ordering::Relaxed);
+        let result = self.scan_entries(range, window, &mut phases).await;
+        self.finish_operation("scan", &self.metrics.scan_latency, start, phases);
+        let result = result.map(|entries| match buffered {
+            Some(buffered) => overlay(entries, buffered),
+            None => entries,
+        });
+        self.record_scan(bounds, &result);
+        result
+    }
+
+    /// Returns bounds of given range, if scans are recorded, see [`BPlus::with_recorder`]
+    fn recorded_bounds<R: RangeBounds<K>>(&self, range: &R) -> Option<(Bound<K>, Bound<K>)> {
+        self.recorder
+            .as_ref()
+            .map(|_| (range.start_bound().cloned(), range.end_bound().cloned()))
+    }
+
+    /// Records scan of given bounds with given result, if scans are recorded
+    fn record_scan(
+        &self,
+        bounds: Option<(Bound<K>, Bound<K>)>,
+        result: &Result<Vec<(K, Vec<u8>)>>,
+    ) {
+        if let Some((start, end)) = &bounds {
+            self.record(Operation::Scan {
+                start: start.as_ref(),
+                end: end.as_ref(),
+                entries: result.as_ref().ok().map(|entries| entries.len() as u64),
+            });
+        }
+    }
+
+    /// Returns all entries with keys in given range, in ascending order of keys, read with given options
+    ///
+    /// Returns Err(BPlusError::LockTimeout) if timeout of options is elapsed,
+    /// Err(_) if some of the values could not be read
+    pub async fn scan_opt<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: &ReadOptions,
+    ) -> Result<Vec<(K, Vec<u8>)>> {
+        let scan = async {
+            let start = Instant::now();
+            let mut phases = Phases::default();
+            let bounds = self.recorded_bounds(&range);
+            let buffered = self.buffer.as_ref().map(|buffer| buffer.range(&range));
+            let window = options
+                .read_ahead
+                .unwrap_or_else(|| self.read_ahead.load(Ordering::Relaxed));
+            let result = self.scan_entries(range, window, &mut phases).await;
+            self.finish_operation("scan", &self.metrics.scan_latency, start, phases);
+            let result = result.map(|entries| match buffered {
+                Some(buffered) => overlay(entries, buffered),
+                None => entries,
+            });
+            self.record_scan(bounds, &result);
+            result
+        };
+        self.within(options.timeout, scan).await
+    }
+
+    /// Returns all entries with keys in given range, in ascending order of keys, scanning
+    /// up to given number of pieces of the range at once
+    ///
+    /// Range is split by separators of the upper levels of the tree, so pieces hold about
+    /// the same number of leaves. Values of pieces are read at once only on a spawner,
+    /// see [`BPlus::with_spawner`]
+    ///
+    /// Returns Err(_) if some of the values could not be read
+    pub async fn par_scan<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        parallelism: usize,
+    ) -> Result<Vec<(K, Vec<u8>)>> {
+        let start = Instant::now();
+        let mut phases = Phases::default();
+        let buffered = self.buffer.as_ref().map(|buffer| buffer.range(&range));
+        let result = in_span!("par_scan", parallelism = parallelism; async {
+            let splits = self.split_keys(&range, parallelism, &mut phases).await;
+            let mut lower = range.start_bound().cloned();
+            let mut pieces = Vec::with_capacity(splits.len() + 1);
+            for split in splits {
+                pieces.push((lower, Bound::Excluded(split.clone())));
+                lower = Bound::Included(split);
+            }
+            pieces.push((lower, range.end_bound().cloned()));
+
+            let scans = pieces.into_iter().map(|piece| async move {
+                let mut phases = Phases::default();
+                let window = self.read_ahead.load(Ordering::Relaxed);
+                let entries = self.scan_entries(piece, window, &mut phases).await;
+                entries.map(|entries| (entries, phases))
+            });
+            let mut result = Vec::new();
+            for (entries, piece_phases) in try_join_all(scans).await? {
+                result.extend(entries);
+                phases.overlap(piece_phases);
+            }
+            Ok(match buffered {
+                Some(buffered) => overlay(result, buffered),
+                None => result,
+            })
+        });
+        self.finish_operation("par_scan", &self.metrics.scan_latency, start, phases);
+        result
+    }
+
+    /// Returns about every nth entry of the tree in ascending order of keys, starting
+    /// from the first one, e.g. to gather statistics of values without a full scan
+    ///
+    /// Skipped entries are stepped over within leaves, so their values are not read.
+    /// Buffered changes are not sampled
+    ///
+    /// Returns Err(_) if some of the values could not be read
+    pub async fn sample(&self, step: usize) -> Result<Vec<(K, Vec<u8>)>> {
+        let start = Instant::now();
+        let mut phases = Phases::default();
+        let result = self.sample_entries(step.max(1), &mut phases).await;
+        self.finish_operation("sample", &self.metrics.scan_latency, start, phases);
+        result
+    }
+
+    /// Returns up to given number of distinct keys of the tree in ascending order,
+    /// picked uniformly at random, e.g. to spot-check values against a source of truth
+    ///
+    /// Keys are picked by their positions among counts of entries of leaves, so values
+    /// are not read. Buffered changes are not sampled
+    pub async fn sample_random<R: Rng + ?Sized>(&self, n: usize, rng: &mut R) -> Vec<K> {
+        let mut phases = Phases::default();
+        let mut leaves = Vec::new();
+        for partition in &self.partitions {
+            let mut current = partition.root.clone();
+            loop {
+                let node = phases.wait(current.clone().read_owned()).await;
+                let leaf = match &*node {
+                    Node::Internal(internal) => {
+                        current = internal.children[0].clone();
+                        continue;
+                    }
+                    Node::Leaf(leaf) => leaf,
+                };
+                leaves.push((current.clone(), leaf.entries.len()));
+                match leaf.next.clone() {
+                    Some(next) => current = next,
+                    None => break,
+                }
+            }
+        }
+
+        // Positions are picked by Floyd's algorithm, so every subset is equally likely
+        let total: usize = leaves.iter().map(|(_, len)| len).sum();
+        let mut positions = BTreeSet::new();
+        for j in total.saturating_sub(n)..total {
+            let position = rng.gen_range(0..=j);
+            if !positions.insert(position) {
+                positions.insert(j);
+            }
+        }
+
+        let mut keys = Vec::with_capacity(positions.len());
+        let mut positions = positions.into_iter().peekable();
+        let mut before = 0;
+        for (link, len) in leaves {
+            let mut picked = Vec::new();
+            while let Some(position) = positions.next_if(|position| *position < before + len) {
+                picked.push(position - before);
+            }
+            before += len;
+            if picked.is_empty() {
+                continue;
+            }
+            // Leaf may have changed since it was counted, then missing positions are skipped
+            if let Node::Leaf(leaf) = &*phases.wait(link.read_owned()).await {
+                keys.extend(
+                    picked
+                        .into_iter()
+                        .filter_map(|pos| leaf.entries.get(pos).map(|(key, _)| key.clone())),
+                );
+            }
+        }
+        keys
+    }
+
+    /// Returns all keys of the tree in ascending order, without reading values
+    ///
+    /// Buffered changes are not included
+    pub async fn keys(&self) -> Vec<K> {
+        self.map_entries(|key, _| key.clone()).await
+    }
+
+    /// Returns all keys of the tree in ascending order with sizes and locations of their
+    /// values, without reading values, e.g. for inventory of data files
+    ///
+    /// Buffered changes are not included
+    pub async fn entries_meta(&self) -> Vec<EntryMeta<K>> {
+        self.map_entries(|key, handler| {
+            let chunk = self.locate(handler);
+            EntryMeta {
+                key: key.clone(),
+                path: chunk.path,
+                offset: chunk.offset,
+                size: handler.size,
+                kind: handler.kind,
+            }
+        })
+        .await
+    }
+
+    /// Returns stream of all entries of the tree in ascending order of keys, which reads
+    /// one leaf at a time, so entries are not collected at once as by [`BPlus::scan`]
+    ///
+    /// Every key is yielded at most once and keys strictly ascend, even if leaves split
+    /// while the tree is streamed. Changes of the tree are seen by the stream, if they are
+    /// made in leaves, that it did not read yet, see [`BPlus::snapshot_stream`] for a stream
+    /// of the tree as of a moment. Buffered changes are not included
+    ///
+    /// Yields Err(_) for entries, whose values could not be read
+    pub fn stream(&self) -> impl Stream<Item = Result<(K, Vec<u8>)>> + '_ {
+        let cursor = StreamCursor {
+            partition: 0,
+            next: None,
+            entries: VecDeque::new(),
+            last: None,
+        };
+        stream::unfold(cursor, move |mut cursor| async move {
+            while cursor.entries.is_empty() {
+                let mut current = match cursor.next.take() {
+                    Some(next) => next,
+                    None => {
+                        let partition = self.partitions.get(cursor.partition)?;
+                        cursor.partition += 1;
+                        partition.root.clone()
+                    }
+                };
+                loop {
+                    let child = match &*current.read().await {
+                        Node::Internal(internal) => internal.children[0].clone(),
+                        Node::Leaf(leaf) => {
+                            // Keys up to the last read one were yielded from a leaf,
+                            // that was read before it split or was replaced by rebuild
+                            let last = cursor.last.as_ref();
+                            let fresh = leaf.entries.iter().filter(|(key, _)| last < Some(key));
+                            cursor.entries.extend(fresh.cloned());
+                            if let Some((key, _)) = cursor.entries.back() {
+                                cursor.last = Some(key.clone());
+                            }
+                            cursor.next = leaf.next.clone();
+                            break;
+                        }
+                    };
+                    current = child;
+                }
+            }
+            let (key, handler) = cursor.entries.pop_front()?;
+            let entry = self
+                .start_read(handler, Access::Scan)
+                .await
+                .map(|value| (key, value));
+            Some((entry, cursor))
+        })
+    }
+
+    /// Returns stream of all entries of the tree as of the call, in ascending order of keys,
+    /// including buffered changes
+    ///
+    /// Keys and locations of values are pinned at once, while changes of the tree wait,
+    /// and values are read as the stream is polled. Values stay in data files after overwrites
+    /// and removals, so later changes are not seen by the stream. Values, that are moved
+    /// by [`BPlus::rebuild`] meanwhile, are read from their new data files
+    pub async fn snapshot_stream(&self) -> impl Stream<Item = Result<(K, Vec<u8>)>> + '_ {
+        let (pinned, _) = self.pin_entries().await;
+        stream::unfold(pinned.into_iter(), move |mut entries| async move {
+            let (key, (value, _)) = entries.next()?;
+            let value = self.read_pinned(value).await;
+            Some((value.map(|value| (key, value)), entries))
+        })
+    }
+
+    /// Pins all entries of the tree with their versions, including buffered changes,
+    /// while changes of the tree wait
+    ///
+    /// Returns pinned entries by their keys with sequence number of the last change,
+    /// that they include
+    pub(crate) async fn pin_entries(&self) -> (BTreeMap<K, (Pinned, u64)>, u64) {
+        // Changes in flight finish first, and next ones wait until the tree is pinned
+        let _writes = self.writes.write().await;
+        let mut pinned: BTreeMap<_, _> = self
+            .map_entries(|key, handler| {
+                let value = Pinned::Stored(handler.clone());
+                (key.clone(), (value, handler.version))
+            })
+            .await
+            .into_iter()
+            .collect();
+        if let Some(buffer) = &self.buffer {
+            for (key, (value, sequence)) in buffer.changes() {
+                match value {
+                    Some((value, kind)) => {
+                        pinned.insert(key, (Pinned::Buffered(value, kind), sequence))
+                    }
+                    None => pinned.remove(&key),
+                };
+            }
+        }
+        (pinned, self.sequence.load(Ordering::SeqCst))
+    }
+
+    /// Reads pinned value, see [`BPlus::pin_entries`]
+    pub(crate) async fn read_pinned(&self, value: Pinned) -> Result<Vec<u8>> {
+        match value {
+            Pinned::Stored(handler) => self.start_read(handler, Access::Scan).await,
+            Pinned::Buffered(value, _) => Ok(value),
+        }
+    }
+
+    /// Returns results of given function for all entries of the tree in ascending order of keys
+    pub(crate) async fn map_entries<T, F>(&self, mut f: F) -> Vec<T>
+    where
+        F: FnMut(&K, &ChunkHandler) -> T,
+    {
+        let mut result = Vec::new();
+        for partition in &self.partitions {
+            let mut current = Some(partition.root.clone());
+            while let Some(node) = current {
+                current = node.read().await.walk_leaves(|leaf| {
+                    result.extend(leaf.iter().map(|(key, handler)| f(key, handler)));
+                });
+            }
+        }
+        result
+    }
+
+    /// Collects every entry with given step, reading their values ahead of collected ones
+    async fn sample_entries(&self, step: usize, phases: &mut Phases) -> Result<Vec<(K, Vec<u8>)>> {
+        let mut result = ReadAhead::new(self.read_ahead.load(Ordering::Relaxed));
+        // Number of entries to skip before the next sampled one
+        let mut skip = 0;
+        for partition in &self.partitions {
+            let mut current = partition.root.clone();
+            loop {
+                let node = phases.wait(current.clone().read_owned()).await;
+                let leaf = match &*node {
+                    Node::Internal(internal) => {
+                        current = internal.children[0].clone();
+                        continue;
+                    }
+                    Node::Leaf(leaf) => leaf,
+                };
+                let handlers: Vec<_> = leaf
+                    .entries
+                    .iter()
+                    .skip(skip)
+                    .step_by(step)
+                    .map(|(key, handler)| (key.clone(), handler.clone()))
+                    .collect();
+                let len = leaf.entries.len();
+                skip = if len <= skip {
+                    skip - len
+                } else {
+                    (step - (len - skip) % step) % step
+                };
+                let next = leaf.next.clone();
+                drop(node);
+
+                for (key, handler) in handlers {
+                    result
+                        .push(key, self.start_read(handler, Access::Scan), phases)
+                        .await?;
+                }
+                match next {
+                    Some(next) => current = next,
+                    None => break,
+                }
+            }
+        }
+        result.finish(phases).await
+    }
+
+    /// Returns ascending keys in given range, that split it into at most given number of pieces
+    /// with about the same number of leaves
+    ///
+    /// Keys are bounds of partitions and separators of internal nodes, which are read
+    /// level by level from the roots, until there are enough of them
+    async fn split_keys<R: RangeBounds<K>>(
+        &self,
+        range: &R,
+        parallelism: usize,
+        phases: &mut Phases,
+    ) -> Vec<K> {
+        let bound_key = |bound| match bound {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let (start, end) = (bound_key(range.start_bound()), bound_key(range.end_bound()));
+        let first = start.map_or(0, |key| self.partition_index(key));
+        let last = end.map_or(self.partitions.len() - 1, |key| self.partition_index(key));
+
+        let mut keys: Vec<K> = self.bounds[first..last].to_vec();
+        let mut level: Vec<Link<K>> = (first..=last)
+            .map(|i| self.partitions[i].root.clone())
+            .collect();
+        while keys.len() + 1 < parallelism && !level.is_empty() {
+            let mut next = Vec::new();
+            for link in level {
+                let node = phases.wait(link.read_owned()).await;
+                let Node::Internal(internal) = &*node else {
+                    continue;
+                };
+                keys.extend(
+                    internal
+                        .keys
+                        .to_vec()
+                        .into_iter()
+                        .filter(|key| range.contains(key)),
+                );
+                // Only children, that may hold keys in range, are read on the next level
+                let from = start.map_or(0, |key| internal.child_index(key));
+                let to = end.map_or(internal.children.len() - 1, |key| internal.child_index(key));
+                next.extend(internal.children[from..=to].iter().cloned());
+            }
+            level = next;
+        }
+        keys.retain(|key| range.contains(key) && start != Some(key));
+        keys.sort();
+        keys.dedup();
+
+        let pieces = parallelism.max(1);
+        if keys.len() < pieces {
+            return keys;
+        }
+        // Every piece gets the same number of split keys
+        (1..pieces)
+            .map(|i| keys[i * keys.len() / pieces].clone())
+            .collect()
+    }
+
+    /// Collects entries with keys in given range, reading their values ahead of collected ones
+    ///
+    /// Partitions are scanned one after another, as their keys follow each other
+    async fn scan_entries<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        window: usize,
+        phases: &mut Phases,
+    ) -> Result<Vec<(K, Vec<u8>)>> {
+        let mut result = ReadAhead::new(window);
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let first = start.map_or(0, |key| self.bounds.partition_point(|bound| bound <= key));
+
+        for (i, partition) in self.partitions.iter().enumerate().skip(first) {
+            if i > first && is_after_end(&range, &self.bounds[i - 1]) {
+                break;
+            }
+            // Start key lies below the first key of the following partitions
+            let start = if i == first { start } else { None };
+            if self
+                .scan_partition(partition, &range, start, &mut result, phases)
+                .await?
+            {
+                break;
+            }
+        }
+        result.finish(phases).await
+    }
+
+    /// Appends entries of partition with keys in given range to result, starting reads
+    /// of their values
+    ///
+    /// Returns whether the end of the range was reached
+    async fn scan_partition<'a, R: RangeBounds<K>>(
+        &'a self,
+        partition: &Partition<K>,
+        range: &R,
+        start: Option<&K>,
+        result: &mut ReadAhead<'a, K>,
+        phases: &mut Phases,
+    ) -> Result<bool> {
+        let mut current = partition.root.clone();
+
+        loop {
+            let node = match start {
+                Some(key) => self.read_covering(&mut current, key, phases).await,
+                None => phases.wait(current.clone().read_owned()).await,
+            };
+            let leaf = match &*node {
+                Node::Internal(internal) => {
+                    let pos = start.map_or(0, |key| internal.child_index(key));
+                    current = internal.children[pos].clone();
+                    continue;
+                }
+                Node::Leaf(leaf) => leaf,
+            };
+
+            let mut handlers = Vec::new();
+            let mut finished = false;
+            for (key, handler) in &leaf.entries {
+                if !range.contains(key) {
+                    if is_after_end(range, key) {
+                        finished = true;
+                        break;
+                    }
+                    continue;
+                }
+                handlers.push((key.clone(), handler.clone()));
+            }
+            let next = leaf.next.clone();
+            drop(node);
+
+            for (key, handler) in handlers {
+                result
+                    .push(key, self.start_read(handler, Access::Scan), phases)
+                    .await?;
+            }
+
+            match next {
+                Some(next) if !finished => current = next,
+                _ => return Ok(finished),
+            }
+        }
+    }
+
+    /// Returns keys of entries, whose values lie outside of existing data files, in ascending
+    /// order, e.g. as data files were removed or truncated
+    ///
+    /// Buffered changes are not checked
+    ///
+    /// Returns Err(_) if sizes of data files could not be read
+    pub async fn unreachable_keys(&self) -> Result<Vec<K>> {
+        let entries = self
+            .map_entries(|key, handler| (key.clone(), self.locate(handler)))
+            .await;
+        let keys = self
+            .unblock(move || {
+                let mut file_sizes = HashMap::new();
+                entries
+                    .into_iter()
+                    .filter_map(|(key, chunk)| {
+                        let size = *file_sizes.entry(chunk.path.clone()).or_insert_with(|| {
+                            std::fs::metadata(&chunk.path).map_or(0, |m| m.len())
+                        });
+                        (chunk.end() > size).then_some(key)
+                    })
+                    .collect()
+            })
+            .await?;
+        Ok(keys)
+    }
+
+    /// Verifies structure of the tree: ordering of keys in nodes, separators, high keys,
+    /// node occupancy, depth of leaves, links between siblings and that every value lies
+    /// within an existing file
+    ///
+    /// Leaves are not checked for minimal occupancy, as removal does not merge them
+    ///
+    /// Should be called when there are no concurrent modifications, otherwise may report false errors
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) describing the first violation found
+    #[cfg(any(debug_assertions, feature = "invariants"))]
+    pub async fn check_invariants(&self) -> Result<()> {
+        let result = self.find_violation().await;
+        if let Err(BPlusError::Corruption(message)) = &result {
+            self.hooks.emit(TreeEvent::CorruptionDetected {
+                message: message.clone(),
+            });
+        }
+        result
+    }
+
+    /// Checks invariants of the tree, see [`BPlus::check_invariants`]
+    #[cfg(any(debug_assertions, feature = "invariants"))]
+    async fn find_violation(&self) -> Result<()> {
+        let mut file_sizes = HashMap::new();
+        for (i, partition) in self.partitions.iter().enumerate() {
+            let lower = i.checked_sub(1).map(|i| self.bounds[i].clone());
+            let upper = self.bounds.get(i).cloned();
+            self.find_partition_violation(partition, lower, upper, &mut file_sizes)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Checks invariants of partition, which keys lie within given bounds
+    #[cfg(any(debug_assertions, feature = "invariants"))]
+    async fn find_partition_violation(
+        &self,
+        partition: &Partition<K>,
+        lower: Option<K>,
+        upper: Option<K>,
+        file_sizes: &mut HashMap<PathBuf, u64>,
+    ) -> Result<()> {
+        let corruption = |message: &str| Err(BPlusError::Corruption(message.to_string()));
+        let in_bounds = |key: &K, lower: &Option<K>, upper: &Option<K>| {
+            lower.as_ref().is_none_or(|lower| key >= lower)
+                && upper.as_ref().is_none_or(|upper| key < upper)
+        };
+
+        // Children are pushed in reverse, so leaves are visited from left to right
+        let mut stack = vec![(partition.root.clone(), lower, upper, 0)];
+        let mut levels: Vec<Vec<_>> = Vec::new();
+        let mut leaf_depth = None;
+
+        while let Some((link, lower, upper, depth)) = stack.pop() {
+            let node = link.read().await;
+            if node.high_key() != upper.as_ref() {
+                return corruption("high key of node differs from separator of parent");
+            }
+            if levels.len() == depth {
+                levels.push(Vec::new());
+            }
+            levels[depth].push((link.clone(), node.right().cloned()));
+            match &*node {
+                Node::Internal(internal) => {
+                    if internal.children.len() != internal.keys.len() + 1 {
+                        return corruption("internal node has wrong number of children");
+                    }
+                    let min_keys = if depth == 0 { 1 } else { self.t - 1 };
+                    if internal.keys.len() < min_keys || internal.keys.len() > 2 * self.t - 2 {
+                        return corruption("internal node has wrong number of keys");
+                    }
+                    let keys = internal.keys.to_vec();
+                    if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+                        return corruption("keys of internal node are not sorted");
+                    }
+                    if !keys.iter().all(|key| in_bounds(key, &lower, &upper)) {
+                        return corruption("key of internal node violates separator of parent");
+                    }
+                    for (i, child) in internal.children.iter().enumerate().rev() {
+                        let child_lower = if i == 0 {
+                            lower.clone()
+                        } else {
+                            Some(keys[i - 1].clone())
+                        };
+                        let child_upper = keys.get(i).cloned().or(upper.clone());
+                        stack.push((child.clone(), child_lower, child_upper, depth + 1));
+                    }
+                }
+                Node::Leaf(leaf) => {
+                    if *leaf_depth.get_or_insert(depth) != depth {
+                        return corruption("leaves are on different depths");
+                    }
+                    if leaf.entries.len() > 2 * self.t - 1 {
+                        return corruption("leaf has too many entries");
+                    }
+                    if leaf.entries.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                        return corruption("keys of leaf are not sorted");
+                    }
+                    if !leaf
+                        .entries
+                        .iter()
+                        .all(|(key, _)| in_bounds(key, &lower, &upper))
+                    {
+                        return corruption("key of leaf violates separator of parent");
+                    }
+                    for (_, handler) in &leaf.entries {
+                        let Some(location) = self.chunks.get(handler.chunk) else {
+                            return corruption("entry refers to unknown chunk");
+                        };
+                        let chunk = ChunkRef::new(&self.path, location, handler.size);
+                        let file_size = match file_sizes.get(&chunk.path) {
+                            Some(size) => *size,
+                            None => {
+                                let size = std::fs::metadata(&chunk.path).map_or(0, |m| m.len());
+                                file_sizes.insert(chunk.path.clone(), size);
+                                size
+                            }
+                        };
+                        if chunk.end() > file_size {
+                            return corruption("value lies outside of its data file");
+                        }
+                    }
+                }
+            }
+        }
+
+        for level in &levels {
+            for (i, (_, right)) in level.iter().enumerate() {
+                let linked = match (right, level.get(i + 1)) {
+                    (Some(right), Some((expected, _))) => Arc::ptr_eq(right, expected),
+                    (None, None) => true,
+                    _ => false,
+                };
+                if !linked {
+                    return corruption("nodes of a level are not linked in order");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns snapshot of operation counters of the tree
+    pub fn metrics(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            data_files: self.data_file.lock().unwrap().number as u64 + 1,
+            ..self.metrics.snapshot()
+        }
+    }
+
+    /// Returns approximate number of entries in given range and size of their values,
+    /// e.g. to choose parallelism of a scan or to paginate it
+    ///
+    /// Leaves are not read, except roots of partitions, that are single leaves. Range is measured
+    /// in leaves by positions of its bounds among children of internal nodes, and leaves are
+    /// assumed to hold as many entries as the lowest internal nodes hold keys. Size of values
+    /// is the share of the range in data files, which also hold overwritten values.
+    /// Buffered changes are not counted
+    pub async fn estimate_range<R: RangeBounds<K>>(&self, range: R) -> RangeEstimate {
+        let bound_key = |bound| match bound {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let (start, end) = (bound_key(range.start_bound()), bound_key(range.end_bound()));
+        let first = start.map_or(0, |key| self.partition_index(key));
+        let last = end.map_or(self.partitions.len() - 1, |key| self.partition_index(key));
+
+        let (mut entries, mut total) = (0.0, 0.0);
+        for (i, partition) in self.partitions.iter().enumerate() {
+            // Bounds of the range cut only the first and the last partition of it
+            let start = start.filter(|_| i == first);
+            let end = end.filter(|_| i == last);
+            let (in_range, all) = self.estimate_partition(partition, &range, start, end).await;
+            total += all;
+            if (first..=last).contains(&i) {
+                entries += in_range;
+            }
+        }
+
+        let data_bytes = {
+            let data_file = self.data_file.lock().unwrap();
+            data_file.number as u64 * self.max_file_size + data_file.offset
+        };
+        // Every value is written with the header of its record
+        let bytes = if total > 0.0 {
+            (data_bytes as f64 / total - record::HEADER_SIZE as f64).max(0.0) * entries
+        } else {
+            0.0
+        };
+        RangeEstimate {
+            entries: entries.round() as u64,
+            bytes: bytes.round() as u64,
+        }
+    }
+
+    /// Returns approximate numbers of entries of partition between given bounds of range,
+    /// and of all of its entries
+    async fn estimate_partition<R: RangeBounds<K>>(
+        &self,
+        partition: &Partition<K>,
+        range: &R,
+        start: Option<&K>,
+        end: Option<&K>,
+    ) -> (f64, f64) {
+        if let Node::Leaf(leaf) = &*partition.root.read().await {
+            let entries = leaf.entries.iter().filter(|(key, _)| match (start, end) {
+                (None, None) => true,
+                _ => range.contains(key),
+            });
+            return (entries.count() as f64, leaf.entries.len() as f64);
+        }
+        let (counts, per_leaf) = self.leaf_counts(partition).await;
+        let leaves = counts
+            .get(&node_id(&partition.root))
+            .copied()
+            .unwrap_or_default() as f64;
+        let from = match start {
+            Some(key) => self.rank(&partition.root, &counts, key).await,
+            None => 0.0,
+        };
+        let to = match end {
+            Some(key) => self.rank(&partition.root, &counts, key).await,
+            None => leaves,
+        };
+        ((to - from).max(0.0) * per_leaf, leaves * per_leaf)
+    }
+
+    /// Returns numbers of leaves under internal nodes of partition by their ids,
+    /// and approximate number of entries in a leaf
+    async fn leaf_counts(&self, partition: &Partition<K>) -> (HashMap<usize, usize>, f64) {
+        let mut levels = Vec::new();
+        let mut level = vec![partition.root.clone()];
+        for _ in 1..partition.height.load(Ordering::SeqCst) {
+            let mut nodes = Vec::new();
+            for link in &level {
+                if let Node::Internal(internal) = &*link.read().await {
+                    nodes.push((node_id(link), internal.children.clone()));
+                }
+            }
+            level = nodes
+                .iter()
+                .flat_map(|(_, children)| children.iter().cloned())
+                .collect();
+            levels.push(nodes);
+        }
+
+        // Children, that are not counted, are leaves
+        let mut counts = HashMap::new();
+        for nodes in levels.iter().rev() {
+            for (id, children) in nodes {
+                let count = children
+                    .iter()
+                    .map(|child| counts.get(&node_id(child)).copied().unwrap_or(1))
+                    .sum();
+                counts.insert(*id, count);
+            }
+        }
+        let (lowest, leaves) = levels.last().map_or((0, 0), |nodes| {
+            (
+                nodes.len(),
+                nodes.iter().map(|(_, c)| c.len()).sum::<usize>(),
+            )
+        });
+        let per_leaf = if lowest > 0 {
+            (leaves - lowest) as f64 / lowest as f64
+        } else {
+            0.0
+        };
+        (counts, per_leaf)
+    }
+
+    /// Returns approximate number of leaves of subtree with given root before given key,
+    /// given numbers of leaves under its internal nodes
+    ///
+    /// Key is assumed to lie in the middle of its leaf, which is not read
+    async fn rank(&self, root: &Link<K>, counts: &HashMap<usize, usize>, key: &K) -> f64 {
+        let mut rank = 0;
+        let mut current = root.clone();
+        loop {
+            let next = match &*current.read().await {
+                Node::Internal(internal) => {
+                    let pos = internal.child_index(key);
+                    rank += internal.children[..pos]
+                        .iter()
+                        .map(|child| counts.get(&node_id(child)).copied().unwrap_or(1))
+                        .sum::<usize>();
+                    internal.children[pos].clone()
+                }
+                Node::Leaf(_) => break,
+            };
+            if !counts.contains_key(&node_id(&next)) {
+                break;
+            }
+            current = next;
+        }
+        rank as f64 + 0.5
+    }
+
+    /// Returns equi-depth histogram of keys with up to given number of buckets, which hold
+    /// about the same number of entries, e.g. to choose split points of shards or to find skew
+    ///
+    /// Buckets start at lowest keys of leaves, which are found by separators, so leaves are
+    /// not read, except roots of partitions, that are single leaves. Buffered changes
+    /// are not counted
+    pub async fn histogram(&self, buckets: usize) -> Vec<HistogramBucket<K>> {
+        // Consecutive pieces of keys with their lowest keys and approximate numbers of entries
+        let mut pieces = Vec::new();
+        for (i, partition) in self.partitions.iter().enumerate() {
+            let start = i.checked_sub(1).map(|i| self.bounds[i].clone());
+            let leaf_keys = match &*partition.root.read().await {
+                Node::Leaf(leaf) => Some(
+                    leaf.entries
+                        .iter()
+                        .map(|(key, _)| key.clone())
+                        .collect::<Vec<_>>(),
+                ),
+                Node::Internal(_) => None,
+            };
+            match leaf_keys {
+                Some(keys) if keys.is_empty() => pieces.push((start, 0.0)),
+                Some(keys) => {
+                    pieces.push((start, 1.0));
+                    pieces.extend(keys.into_iter().skip(1).map(|key| (Some(key), 1.0)));
+                }
+                None => {
+                    let (bounds, per_leaf) = self.leaf_bounds(partition).await;
+                    pieces.push((start, per_leaf));
+                    pieces.extend(bounds.into_iter().map(|key| (Some(key), per_leaf)));
+                }
+            }
+        }
+
+        let buckets = buckets.max(1);
+        let depth = pieces.iter().map(|(_, entries)| entries).sum::<f64>() / buckets as f64;
+        let mut histogram: Vec<(Option<K>, f64)> = Vec::new();
+        let mut before = 0.0;
+        for (start, entries) in pieces {
+            let full = depth > 0.0 && before >= histogram.len() as f64 * depth;
+            if histogram.is_empty() || (full && histogram.len() < buckets) {
+                histogram.push((start, 0.0));
+            }
+            if let Some((_, bucket)) = histogram.last_mut() {
+                *bucket += entries;
+            }
+            before += entries;
+        }
+        histogram
+            .into_iter()
+            .map(|(start, entries)| HistogramBucket {
+                start,
+                entries: entries.round() as u64,
+            })
+            .collect()
+    }
+
+    /// Returns lowest keys of leaves of partition, except the first one, and approximate
+    /// number of entries in a leaf
+    ///
+    /// Every separator is the lowest key of a leaf, so only internal nodes are read
+    async fn leaf_bounds(&self, partition: &Partition<K>) -> (Vec<K>, f64) {
+        let mut keys = Vec::new();
+        let (mut nodes, mut lowest_keys) = (0, 0);
+        let mut level = vec![partition.root.clone()];
+        for _ in 1..partition.height.load(Ordering::SeqCst) {
+            let mut next = Vec::new();
+            (nodes, lowest_keys) = (0, 0);
+            for link in &level {
+                if let Node::Internal(internal) = &*link.read().await {
+                    let separators = internal.keys.to_vec();
+                    nodes += 1;
+                    lowest_keys += separators.len();
+                    keys.extend(separators);
+                    next.extend(internal.children.iter().cloned());
+                }
+            }
+            level = next;
+        }
+        keys.sort();
+        keys.dedup();
+        // Leaves are assumed to hold as many entries as internal nodes of the lowest level
+        let per_leaf = if nodes > 0 {
+            lowest_keys as f64 / nodes as f64
+        } else {
+            0.0
+        };
+        (keys, per_leaf)
+    }
+
+    /// Returns statistics of the shape of the tree, without reading values
+    pub async fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        let mut fills = Vec::new();
+        let capacity = (2 * self.t - 1) as f64;
+        let mut level = self.roots();
+        while !level.is_empty() {
+            stats.height += 1;
+            let mut next_level = Vec::new();
+            for link in level {
+                match &*link.read().await {
+                    Node::Internal(internal) => {
+                        stats.internal_nodes += 1;
+                        stats.key_bytes +=
+                            internal.keys.len() * mem::size_of::<K>() + internal.keys.prefix_size();
+                        next_level.extend(internal.children.iter().cloned());
+                    }
+                    Node::Leaf(leaf) => {
+                        stats.leaf_nodes += 1;
+                        stats.entries += leaf.entries.len();
+                        stats.key_bytes += leaf.entries.len() * mem::size_of::<K>();
+                        fills.push(leaf.entries.len() as f64 / capacity);
+                    }
+                }
+            }
+            level = next_level;
+        }
+
+        fills.sort_by(f64::total_cmp);
+        // Nearest-rank percentile
+        let percentile = |p: f64| {
+            let rank = (p * fills.len() as f64).ceil() as usize;
+            fills[rank.clamp(1, fills.len()) - 1]
+        };
+        stats.avg_leaf_fill = fills.iter().sum::<f64>() / fills.len() as f64;
+        stats.p50_leaf_fill = percentile(0.5);
+        stats.p90_leaf_fill = percentile(0.9);
+        stats.p99_leaf_fill = percentile(0.99);
+        stats
+    }
+
+    /// Returns approximate heap memory taken by nodes, keys and value handles of the tree
+    pub async fn memory_usage(&self) -> MemoryUsage {
+        // Every Arc allocation also holds strong and weak counters
+        let arc_overhead = 2 * mem::size_of::<usize>();
+        let key_size = mem::size_of::<K>();
+        let mut usage = MemoryUsage::default();
+        let mut level = self.roots();
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+            for link in level {
+                // Latch of a node is kept in an Arc with its gate and lock in two more
+                usage.nodes += 3 * arc_overhead + Latch::<Node<K>>::heap_size();
+                match &*link.peek().await {
+                    Node::Internal(internal) => {
+                        let keys = internal.keys.len() * key_size + internal.keys.prefix_size();
+                        usage.keys += keys;
+                        usage.nodes += (internal.keys.capacity() - internal.keys.len()) * key_size
+                            + internal.children.capacity() * mem::size_of::<Link<K>>();
+                        next_level.extend(internal.children.iter().cloned());
+                    }
+                    Node::Leaf(leaf) => {
+                        let keys = leaf.entries.len() * key_size;
+                        usage.keys += keys;
+                        usage.nodes +=
+                            leaf.entries.capacity() * mem::size_of::<(K, ChunkHandler)>() - keys;
+                    }
+                }
+            }
+            level = next_level;
+        }
+        usage.handles = self.chunks.size();
+        usage.buffer = self.buffer.as_ref().map_or(0, WriteBuffer::bytes);
+        usage.bloom = self.bloom.as_ref().map_or(0, Bloom::size);
+        usage.pages = self
+            .pages
+            .as_ref()
+            .map_or(0, |pages| pages.resident_bytes());
+        if let Some(versions) = &self.versions {
+            let previous = versions.previous.lock().unwrap();
+            usage.versions = previous
+                .values()
+                .map(|handlers| {
+                    key_size
+                        + mem::size_of::<VecDeque<ChunkHandler>>()
+                        + handlers.capacity() * mem::size_of::<ChunkHandler>()
+                })
+                .sum();
+        }
+        usage
+    }
+
+    /// Frees memory of the tree, until it takes at most given number of bytes,
+    /// see [`BPlus::memory_usage`], e.g. in response to memory pressure
+    ///
+    /// Buffered changes are flushed first, then previous values of entries are forgotten,
+    /// then spare capacity of nodes and of locations of values is released, then leaves
+    /// are evicted to the buffer pool, see [`BPlus::with_buffer_pool`]. Without it nodes
+    /// are kept in memory, so the tree may still take more than given number of bytes.
+    /// Values and data files are not cached by the tree, so there is nothing to evict for them
+    ///
+    /// Returns number of freed bytes.
+    /// Returns Err(_) if buffered changes could not be flushed, they stay buffered then,
+    /// or if a leaf could not be evicted, it stays in memory then
+    pub async fn shrink(&self, target: usize) -> Result<usize> {
+        let before = self.memory_usage().await.total();
+        let mut usage = before;
+        if usage > target && self.buffer.is_some() {
+            self.flush_buffer().await?;
+            usage = self.memory_usage().await.total();
+        }
+        if usage > target {
+            if let Some(versions) = &self.versions {
+                let previous = mem::take(&mut *versions.previous.lock().unwrap());
+                for handler in previous.into_values().flatten() {
+                    self.release(&handler);
+                }
+                usage = self.memory_usage().await.total();
+            }
+        }
+        if usage > target {
+            self.shrink_nodes().await;
+            self.chunks.shrink_to_fit();
+            usage = self.memory_usage().await.total();
+        }
+        if usage > target {
+            if let Some(pages) = &self.pages {
+                self.evict_leaves(pages, usage, target).await?;
+                usage = self.memory_usage().await.total();
+                if usage > target {
+                    pages.shrink(pages.resident_bytes().saturating_sub(usage - target))?;
+                    usage = self.memory_usage().await.total();
+                }
+            }
+        }
+        trace_event!(
+            freed = before.saturating_sub(usage),
+            usage = usage,
+            "tree shrunk"
+        );
+        Ok(before.saturating_sub(usage))
+    }
+
+    /// Releases spare capacity of vectors of entries and children of all nodes
+    async fn shrink_nodes(&self) {
+        let mut level = self.roots();
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+            for link in level {
+                match &mut *link.peek_mut().await {
+                    Node::Internal(internal) => {
+                        internal.children.shrink_to_fit();
+                        next_level.extend(internal.children.iter().cloned());
+                    }
+                    Node::Leaf(leaf) => leaf.entries.shrink_to_fit(),
+                }
+            }
+            level = next_level;
+        }
+    }
+
+    /// Evicts leaves to given page file, until the tree takes at most given number of bytes,
+    /// starting from given usage
+    ///
+    /// Clean leaves, which pages hold their current entries, are evicted first, as they are
+    /// not written again. Leaves, that do not fit in a page, are kept in memory
+    ///
+    /// Returns Err(_) if a leaf could not be written, it stays in memory then
+    async fn evict_leaves(
+        &self,
+        pages: &Arc<dyn LeafPages<K>>,
+        mut usage: usize,
+        target: usize,
+    ) -> Result<()> {
+        let entry_size = mem::size_of::<(K, ChunkHandler)>();
+        for clean in [true, false] {
+            for partition in &self.partitions {
+                let mut next = Some(partition.root.clone());
+                while let Some(link) = next {
+                    if usage <= target {
+                        return Ok(());
+                    }
+                    let mut node = link.peek_mut().await;
+                    if let Node::Leaf(leaf) = &mut *node {
+                        let freed = leaf.entries.capacity() * entry_size;
+                        let is_clean = leaf.page.as_ref().is_some_and(|page| page.clean);
+                        if freed > 0 && is_clean == clean {
+                            match leaf.evict(pages) {
+                                Ok(()) => usage = usage.saturating_sub(freed),
+                                Err(BPlusError::PageOverflow { .. }) => {}
+                                Err(err) => return Err(err),
+                            }
+                        }
+                    }
+                    next = node.walk_leaves(|_| {});
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Shrinks the tree to its memory limit, if it exceeds it, once in
+    /// [`MEMORY_CHECK_INTERVAL`] changes, see [`BPlus::set_memory_limit`]
+    ///
+    /// Called after a change, without locks of the tree
+    async fn enforce_memory_limit(&self) {
+        let limit = self.memory_limit.load(Ordering::Relaxed);
+        let sequence = self.last_sequence();
+        let checked = self.memory_checked.load(Ordering::Relaxed);
+        if limit == 0
+            || sequence < checked + MEMORY_CHECK_INTERVAL
+            || self
+                .memory_checked
+                .compare_exchange(checked, sequence, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        let usage = self.memory_usage().await.total();
+        if usage <= limit {
+            return;
+        }
+        self.hooks
+            .emit(TreeEvent::MemoryLimitExceeded { usage, limit });
+        if let Err(_err) = self.shrink(limit).await {
+            trace_warn!(error = %_err, "tree could not shrink to its memory limit");
+        }
+    }
+
+    /// Writes structure of the tree in DOT format
+    ///
+    /// Internal nodes are labeled with their keys, leaves with their key ranges and occupancy.
+    /// Links between siblings are drawn dashed
+    pub async fn dump_dot<W: Write>(&self, writer: &mut W) -> Result<()>
+    where
+        K: Debug,
+    {
+        let id = |link: &Link<K>| Arc::as_ptr(link) as usize;
+        let escape = |label: String| label.replace('\\', "\\\\").replace('"', "\\\"");
+
+        writeln!(writer, "digraph BPlus {{")?;
+        writeln!(writer, "    node [shape=box];")?;
+        let mut queue = VecDeque::from(self.roots());
+        while let Some(link) = queue.pop_front() {
+            let node = link.read().await;
+            match &*node {
+                Node::Internal(internal) => {
+                    let keys: Vec<_> = (internal.keys.to_vec().iter())
+                        .map(|k| format!("{:?}", k))
+                        .collect();
+                    let label = escape(keys.join(", "));
+                    writeln!(writer, "    n{} [label=\"{}\"];", id(&link), label)?;
+                    for child in &internal.children {
+                        writeln!(writer, "    n{} -> n{};", id(&link), id(child))?;
+                        queue.push_back(child.clone());
+                    }
+                    if let Some(right) = &internal.right {
+                        writeln!(
+                            writer,
+                            "    n{} -> n{} [style=dashed, constraint=false];",
+                            id(&link),
+                            id(right)
+                        )?;
+                    }
+                }
+                Node::Leaf(leaf) => {
+                    let range = match (leaf.entries.first(), leaf.entries.last()) {
+                        (Some((first, _)), Some((last, _))) => format!("{:?}..={:?}", first, last),
+                        _ => "empty".to_string(),
+                    };
+                    let occupancy = format!("{}/{}", leaf.entries.len(), 2 * self.t - 1);
+                    let label = format!("{}\\n{}", escape(range), occupancy);
+                    writeln!(
+                        writer,
+                        "    n{} [label=\"{}\", style=rounded];",
+                        id(&link),
+                        label
+                    )?;
+                    if let Some(next) = &leaf.next {
+                        writeln!(
+                            writer,
+                            "    n{} -> n{} [style=dashed, constraint=false];",
+                            id(&link),
+                            id(next)
+                        )?;
+                    }
+                }
+            }
+        }
+        writeln!(writer, "}}")?;
+        Ok(())
+    }
+}
+
+impl<K: BPlusKeySerializable> BPlus<K> {
+    /// Makes inserts of keys, that take more than given number of bytes encoded by
+    /// their [`KeyCodec`], fail with Err(BPlusError::KeyTooLarge { .. })
+    ///
+    /// Unlimited by default
+    pub fn with_max_key_size(mut self, limit: u64) -> Self {
+        let measure: KeySize<K> = |key| match K::WIDTH {
+            Some(width) => width as u64,
+            None => key.to_bytes().len() as u64,
+        };
+        self.max_key_size = Some((limit, measure));
+        self
+    }
+
+    /// Makes tree evict its leaves through a buffer pool, built by given builder, to a page
+    /// file in its directory, once it shrinks, see [`BPlus::shrink`] and
+    /// [`BPlus::set_memory_limit`], so it may hold more entries, than fit in memory.
+    /// Evicted leaves are loaded back, once they are reached
+    ///
+    /// Page file is removed from the directory, once it is opened, so it is never shared
+    /// with other trees and is released with the tree. Leaves are still saved in snapshots,
+    /// leaves, that do not fit in a page, are kept in memory
+    ///
+    /// Returns Err(_) if the page file could not be created
+    pub fn with_buffer_pool(mut self, pool: BufferPoolBuilder) -> Result<Self> {
+        let path = self
+            .path
+            .join(format!("{LEAF_PAGES}{:016x}", rand::random::<u64>()));
+        let pages = pool.open::<K, ChunkHandler>(&path);
+        let removed = std::fs::remove_file(&path);
+        self.pages = Some(Arc::new(pages?));
+        removed?;
+        Ok(self)
+    }
+
+    /// Makes tree record every insert and removal to given audit log
+    ///
+    /// Changes are recorded after they are made, errors of the log are reported
+    /// by [`AuditLog::last_error`] and do not fail changes
+    ///
+    /// Unlike the tree, the log serializes keys by `serde`
+    pub fn with_audit_log(mut self, log: Arc<AuditLog<K>>) -> Self
+    where
+        K: Serialize + DeserializeOwned,
+    {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Makes tree record its inserts, removals, reads, scans, rebuilds and compactions
+    /// to given recorder, so they can be replayed against a fresh tree, see [`crate::recorder`]
+    ///
+    /// Operations are recorded after they complete, changes only if they succeed. Inserts
+    /// of shared values are not recorded. Errors of the recorder are reported by
+    /// [`Recorder::last_error`] and do not fail operations
+    ///
+    /// Unlike the tree, the recorder serializes keys by `serde`
+    pub fn with_recorder(mut self, recorder: Arc<Recorder<K>>) -> Self
+    where
+        K: Serialize + DeserializeOwned,
+    {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Rebuilds links between siblings on every level of BPlusTree after loading from file
+    async fn rebuild_links(&self) {
+        for partition in &self.partitions {
+            link_siblings(&partition.root).await;
+        }
+    }
+
+    /// Rebuilds the tree from its entries into evenly filled nodes and copies their values
+    /// to new data files, removing the old ones, which also hold overwritten values
+    ///
+    /// Changes of the tree wait for the rebuild, while reads see the old tree until it is
+    /// swapped for the new one. Values are moved by changing locations of their chunks,
+    /// so reads find copies of values, unless they located them before the copy. Such reads
+    /// may fail, as old data files are removed after the swap. Saved trees refer to removed data files,
+    /// so the tree has to be saved again. Data files, that are shared with other trees,
+    /// are not removed
+    ///
+    /// Returns Err(_) if values could not be copied, then the tree stays unchanged
+    pub async fn rebuild(&self) -> Result<()> {
+        let result = in_span!("rebuild"; async {
+            self.flush_buffer().await?;
+            let _writes = self.writes.write().await;
+            let _splits = self.splits.write().await;
+            let mut phases = Phases::default();
+
+            // Copied values start a new data file, so all preceding files may be removed
+            let last_old = self.start_data_file().await?;
+
+            let mut roots = Vec::with_capacity(self.partitions.len());
+            for partition in &self.partitions {
+                let entries = self.copy_entries(partition, &mut phases).await?;
+                roots.push(build_subtree(entries, self.t));
+            }
+
+            // Roots are replaced in place, so links to them stay valid
+            let mut guards = Vec::with_capacity(roots.len());
+            for partition in &self.partitions {
+                guards.push(partition.root.write().await);
+            }
+            for (i, (root, guard)) in roots.into_iter().zip(&mut guards).enumerate() {
+                let new = Partition::from_serializable(root, self.bounds.get(i).cloned());
+                link_siblings(&new.root).await;
+                set_fairness(&new.root, self.fairness).await;
+                let partition = &self.partitions[i];
+                partition
+                    .height
+                    .store(new.height.load(Ordering::SeqCst), Ordering::SeqCst);
+                *partition.rightmost.lock().unwrap() = None;
+                **guard = Arc::into_inner(new.root)
+                    .expect("new root is not shared")
+                    .into_inner();
+            }
+            drop(guards);
+            self.dirty.store(true, Ordering::Release);
+            // Previous values are not copied, so their data files may be removed
+            if let Some(versions) = &self.versions {
+                let previous = std::mem::take(&mut *versions.previous.lock().unwrap());
+                for handler in previous.into_values().flatten() {
+                    if self.is_in_blob(&handler) {
+                        self.release(&handler);
+                    }
+                }
             }
-        }
 
-        let prev_guard = prev_guard.unwrap();
-        let prev_node = prev_guard.clone();
-        let leaf_lock = {
-            let pos = last_child_index.unwrap();
-            if let Node::Internal(internal) = prev_node {
-                internal.children[pos].clone()
-            } else {
-                unreachable!();
+            // Other trees may refer to values in shared data files
+            if Arc::strong_count(&self.data_file) > 1 {
+                trace_event!("tree rebuilt, shared data files are kept");
+                return Ok(());
             }
-        };
-
-        let mut leaf = leaf_lock.write().await;
-        drop(prev_guard);
-        let Node::Leaf(leaf_node) = &mut *leaf else {
-            unreachable!()
-        };
-
-        if leaf_node.entries.len() == 2 * self.t - 1 {
-            return Err(());
+            self.remove_data_files(last_old).await?;
+            self.free_space.lock().unwrap().forget_through(last_old);
+            trace_event!(removed_files = last_old + 1, "tree rebuilt");
+            Ok(())
+        });
+        if result.is_ok() {
+            self.record(Operation::Rebuild);
         }
+        result
+    }
 
-        match leaf_node.entries.binary_search_by(|(k, _)| k.cmp(&key)) {
-            Ok(pos) => leaf_node.entries[pos].1 = value, // Обновляем без клонирования
-            Err(pos) => leaf_node.entries.insert(pos, (key.clone(), value)),
+    /// Makes values be appended to a new data file after the current one
+    ///
+    /// Returns number of the last of the previous files
+    ///
+    /// Called with the exclusive writes guard, so no value is being written to previous files
+    pub(crate) async fn start_data_file(&self) -> Result<usize> {
+        let (last_old, store) = {
+            let data_file = self.data_file.lock().unwrap();
+            (data_file.number, data_file.store)
         };
-        Ok(())
+        let dir = self.path.clone();
+        let file = self
+            .unblock(move || DataFile::create(&dir, last_old + 1, store))
+            .await??;
+        self.data_file.lock().unwrap().rotate(file);
+        Metrics::inc(&self.metrics.file_rotations);
+        self.hooks.emit(TreeEvent::FileRotated {
+            file_number: last_old + 1,
+        });
+        Ok(last_old)
     }
-}
 
-impl<K: BPlusKeySerializable> BPlus<K> {
-    /// Rebuilds links in BPlusTree after loading from file
-    async fn rebuild_links(&self) {
-        let leaves = self.collect_leaves().await;
-        if self.offset.load(Ordering::Acquire) == 0 && self.file_number.load(Ordering::Acquire) == 0
-        {
-            return;
-        }
+    /// Removes data files with numbers up to given one, that are not removed yet
+    async fn remove_data_files(&self, last: usize) -> Result<()> {
+        let dir = self.path.clone();
+        self.unblock(move || {
+            for number in 0..=last {
+                match std::fs::remove_file(dir.join(number.to_string())) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
 
-        let key_futures: Vec<_> = leaves
+    /// Returns entries of partition in order of keys, moving their values to the current
+    /// data file, except values in blob files
+    ///
+    /// Entries keep their handlers, as only locations of their chunks are changed
+    ///
+    /// Called with the exclusive writes guard
+    async fn copy_entries(
+        &self,
+        partition: &Partition<K>,
+        phases: &mut Phases,
+    ) -> Result<Vec<(K, ChunkHandler)>> {
+        let entries = self.leaf_entries(partition).await;
+        // Blobs stay in their own files
+        let copied: Vec<_> = entries
             .iter()
-            .map(|leaf| {
-                let leaf = Arc::clone(leaf);
-                async move {
-                    let guard = leaf.read().await;
-                    match &*guard {
-                        Node::Leaf(leaf_data) => leaf_data.entries[0].0.clone(),
-                        _ => unreachable!(),
-                    }
-                }
-            })
+            .filter(|(_, handler)| !self.is_in_blob(handler))
             .collect();
+        for batch in copied.chunks(BULK_BATCH) {
+            let chunks: Vec<_> = batch
+                .iter()
+                .map(|(_, handler)| self.locate(handler))
+                .collect();
+            let sizes: Vec<_> = batch.iter().map(|(_, handler)| handler.size).collect();
+            let access = self.hints(Access::Compaction);
+            let (values, _) = self
+                .unblock(move || ChunkRef::read_many(&chunks, access))
+                .await??;
+            let (file, offsets, _) = self.append_values(values.concat(), &sizes, phases).await?;
+            for ((_, handler), offset) in batch.iter().zip(offsets) {
+                let location = Location {
+                    file: file as u32,
+                    offset,
+                };
+                self.chunks.relocate(handler.chunk, location);
+            }
+        }
+        Ok(entries)
+    }
 
-        let keys = futures::future::join_all(key_futures).await;
-
-        let mut sorted_leaves: Vec<_> = keys.into_iter().zip(leaves.into_iter()).collect();
-
-        sorted_leaves.sort_by(|(a, _), (b, _)| a.cmp(b));
-
-        for i in 0..sorted_leaves.len() - 1 {
-            let current = &sorted_leaves[i].1;
-            let next = sorted_leaves[i + 1].1.clone();
+    /// Returns entries of all leaves of partition in order of keys, reading one leaf at a time
+    pub(crate) async fn leaf_entries(&self, partition: &Partition<K>) -> Vec<(K, ChunkHandler)> {
+        let mut entries = Vec::new();
+        let mut current = Some(partition.root.clone());
+        while let Some(node) = current {
+            current = node
+                .read()
+                .await
+                .walk_leaves(|leaf| entries.extend_from_slice(leaf));
+        }
+        entries
+    }
 
-            let mut guard = current.write().await;
-            if let Node::Leaf(leaf) = &mut *guard {
-                leaf.next = Some(next);
+    /// Calls given function for all entries of the tree in ascending order of keys
+    ///
+    /// Used by builders, while nodes are not locked by anyone else
+    pub(crate) fn for_each_entry(&self, mut f: impl FnMut(&K, &ChunkHandler)) {
+        for partition in &self.partitions {
+            let mut current = Some(partition.root.clone());
+            while let Some(node) = current {
+                current = node
+                    .try_read()
+                    .expect("tree is not locked")
+                    .walk_leaves(|leaf| leaf.iter().for_each(|(key, handler)| f(key, handler)));
             }
         }
     }
 
     /// Collects all leaves from BPlusTree
-    async fn collect_leaves(&self) -> Vec<Arc<RwLock<Node<K>>>> {
+    #[cfg(test)]
+    async fn collect_leaves(&self) -> Vec<Link<K>> {
         let mut leaves = Vec::new();
-        let mut queue = VecDeque::new();
-        queue.push_back(self.root.clone());
+        let mut queue = VecDeque::from(self.roots());
 
         while let Some(node) = queue.pop_front() {
             let guard = node.read().await;
@@ -683,47 +5079,232 @@ impl<K: BPlusKeySerializable> BPlus<K> {
         leaves
     }
 
-    fn open_current_file(path: &Path, number: usize) -> io::Result<Arc<RwLock<File>>> {
-        Ok(Arc::new(RwLock::new(
-            File::open(path.join(number.to_string())).unwrap(),
-        )))
+    /// Saves this tree by the provided path
+    ///
+    /// File is written on the spawner of the tree, if it has one. Its sections and the whole
+    /// file are checksummed, so [`BPlus::load`] detects a truncated or corrupted file
+    pub async fn save(&self, path: &Path) -> Result<()>
+    where
+        K: 'static,
+    {
+        in_span!("save", path = %path.display(); async {
+            self.flush_buffer().await?;
+            let _guard = self.splits.write().await;
+            // Cleared before serializing, so changes made during it keep the tree dirty
+            self.dirty.store(false, Ordering::Release);
+            let serializable = self.serialize().await;
+            // Snapshot does not refer to blobs, that were dead before it was taken
+            let dead_blobs = serializable.free_space.blobs();
+            let path = path.to_path_buf();
+            self.unblock(move || {
+                let _bytes = snapshot::write(&path, &serializable)?;
+                trace_event!(bytes = _bytes, "tree saved");
+                Ok::<_, BPlusError>(())
+            })
+            .await??;
+            self.remove_dead_blobs(dead_blobs).await;
+            Ok(())
+        })
     }
 
-    /// Saves this tree by the provided path
-    pub async fn save(&self, path: &Path) -> io::Result<()> {
-        let _guard = self.latch.write().await;
-        let serializable = self.serialize().await;
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
-        bincode::serialize_into(writer, &serializable).map_err(io::Error::other)
+    /// Returns approximate size in bytes of the file, that [`BPlus::save`] writes,
+    /// e.g. to check free disk space before saving
+    ///
+    /// Size is summed from sizes of keys and value handlers in nodes and of locations
+    /// of values, without serializing the tree. Buffered changes, that save flushes first, are not counted
+    pub async fn estimated_snapshot_size(&self) -> u64 {
+        let sized = |size: bincode::Result<u64>| size.unwrap_or_default();
+        let header = {
+            let data_file = self.data_file.lock().unwrap();
+            SerializableBPlus::<K> {
+                t: self.t,
+                path: self.path.clone(),
+                file_number: data_file.number,
+                offset: data_file.offset,
+                max_file_size: self.max_file_size,
+                bounds: self.bounds.clone(),
+                roots: Vec::new(),
+                chunks: self.chunks.locations(),
+                sequence: self.sequence.load(Ordering::SeqCst),
+                bloom: self.bloom.as_ref().map(Bloom::serialize),
+                store: data_file.store,
+                free_space: self.free_space.lock().unwrap().clone(),
+            }
+        };
+        // Keys of fixed width are packed without lengths
+        let key_size = |key: &K| match key_codec::packed_width::<K>() {
+            Some(width) => width as u64,
+            None => sized(snapshot::options().serialized_size(&Encoded(key))),
+        };
+        // Sizes of variants and lengths of vectors of empty nodes
+        let empty_internal = sized(snapshot::options().serialized_size(
+            &SerializableNode::<K>::Internal(SerializableInternalNode {
+                keys: Vec::new(),
+                children: Vec::new(),
+            }),
+        ));
+        let empty_leaf = sized(
+            snapshot::options().serialized_size(&SerializableNode::<K>::Leaf(SerializableLeaf {
+                entries: Vec::new(),
+            })),
+        );
+
+        let mut size = sized(snapshot::options().serialized_size(&header));
+        let mut level = self.roots();
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+            for link in level {
+                match &*link.read().await {
+                    Node::Internal(internal) => {
+                        size += empty_internal;
+                        size += internal.keys.to_vec().iter().map(key_size).sum::<u64>();
+                        next_level.extend(internal.children.iter().cloned());
+                    }
+                    Node::Leaf(leaf) => {
+                        size += empty_leaf;
+                        size += leaf
+                            .entries
+                            .iter()
+                            .map(|(key, handler)| {
+                                key_size(key) + sized(snapshot::options().serialized_size(handler))
+                            })
+                            .sum::<u64>();
+                    }
+                }
+            }
+            level = next_level;
+        }
+        snapshot::framed_size(size)
     }
 
     /// Loads tree from file by provided path
-    pub async fn load(path: &Path) -> io::Result<Self> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let serializable: SerializableBPlus<K> =
-            bincode::deserialize_from(reader).map_err(io::Error::other)?;
+    ///
+    /// Values are appended at the end of the last data file in the directory of the tree,
+    /// which may be past the position, that was saved, if the tree was changed after the save
+    pub async fn load(path: &Path) -> Result<Self>
+    where
+        K: 'static,
+    {
+        Self::load_from(path, None).await
+    }
+
+    /// Loads tree from file by provided path, reading it on given spawner,
+    /// and makes the tree offload all blocking file I/O to it
+    pub async fn load_with_spawner(path: &Path, spawner: Arc<dyn Spawner>) -> Result<Self>
+    where
+        K: 'static,
+    {
+        Self::load_from(path, Some(spawner)).await
+    }
+
+    /// Loads tree from file by provided path, checking, that values of all its entries lie
+    /// within data files, see [`BPlus::unreachable_keys`]
+    ///
+    /// Returns tree with keys of entries, whose values could not be read, in ascending order.
+    /// If prune is true, such entries are removed from the tree, otherwise their reads fail
+    pub async fn load_validated(path: &Path, prune: bool) -> Result<(Self, Vec<K>)>
+    where
+        K: 'static,
+    {
+        let tree = Self::load(path).await?;
+        let unreachable = tree.unreachable_keys().await?;
+        if !unreachable.is_empty() {
+            trace_warn!(
+                keys = unreachable.len(),
+                pruned = prune,
+                "values of saved entries lie outside of data files"
+            );
+        }
+        if prune {
+            for key in &unreachable {
+                tree.remove_from_tree(key, None).await;
+            }
+        }
+        Ok((tree, unreachable))
+    }
+
+    /// Loads tree from file by provided path, reading it on given spawner if there is one
+    async fn load_from(path: &Path, spawner: Option<Arc<dyn Spawner>>) -> Result<Self>
+    where
+        K: 'static,
+    {
+        in_span!("load", path = %path.display(); async {
+            let snapshot_path = path.to_path_buf();
+            let serializable =
+                unblock(spawner.as_ref(), move || Self::read_snapshot(&snapshot_path)).await??;
+            let mut tree = serializable.deserialize().await?;
+            tree.spawner = spawner;
+            trace_event!(
+                data_files = tree.data_file.lock().unwrap().number + 1,
+                closed_cleanly = tree.closed_cleanly,
+                "tree loaded"
+            );
+            Ok(tree)
+        })
+    }
+
+    /// Reads saved tree from file by provided path
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if the file is truncated or its checksums mismatch
+    fn read_snapshot(path: &Path) -> Result<SerializableBPlus<K>> {
+        snapshot::read(path)
+    }
+
+    /// Checks tree saved by provided path against its data files, without loading it
+    ///
+    /// Finds values outside of data files, overlapping values, unreferenced bytes
+    /// and keys out of order, which would break links between leaves
+    pub async fn fsck(path: &Path) -> Result<FsckReport> {
+        Ok(Self::read_snapshot(path)?.fsck().0)
+    }
+
+    /// Salvages entries of the tree saved by provided path into a new tree in directory by new_path
+    ///
+    /// Entries, whose values could not be read, are skipped
+    ///
+    /// Returns new tree and report of the check of the saved one
+    ///
+    /// Returns Err(_) if new_path is a non-empty directory, as data files there would be truncated
+    pub async fn repair(path: &Path, new_path: PathBuf) -> Result<(Self, FsckReport)> {
+        ensure_empty(&new_path)?;
+        let serializable = Self::read_snapshot(path)?;
+        let t = serializable.t;
+        let bounds = serializable.bounds.clone();
+        if t < 2 {
+            return Err(BPlusError::Corruption(format!("invalid t = {t}")));
+        }
+        let (report, intact) = serializable.fsck();
 
-        Ok(serializable.deserialize().await)
+        let tree = Self::new_partitioned(t, new_path, bounds)?;
+        for (key, kind, chunk) in intact {
+            if let Ok(value) = chunk.read(Some(Access::Scan)) {
+                tree.insert_as(key, value, kind).await?;
+            }
+        }
+        Ok((tree, report))
     }
 }
 
-impl<K: Clone + Ord> Node<K> {
-    /// Splits node into two and returns new node with it first key
-    fn split(&mut self, t: usize) -> (Link<K>, Arc<K>) {
+impl<K: Clone + Ord + 'static> Node<K> {
+    /// Splits node into two and returns new right sibling with separator, that becomes
+    /// high key of this node
+    ///
+    /// Separator of leaves is the shortest key, that divides them, see [`shortest_separator`]
+    fn split(&mut self, t: usize) -> (Link<K>, K) {
         match self {
             Node::Leaf(leaf) => {
                 let mut new_leaf_entries = leaf.entries.split_off(t);
                 new_leaf_entries.reserve_exact(t);
-                let middle_key = new_leaf_entries[0].0.clone();
+                let middle_key = shortest_separator(&leaf.entries[t - 1].0, &new_leaf_entries[0].0);
 
                 let new_leaf = Node::Leaf(Leaf {
                     entries: new_leaf_entries,
                     next: leaf.next.take(),
+                    high_key: leaf.high_key.replace(middle_key.clone()),
+                    page: None,
                 });
 
-                let new_leaf_link = Arc::new(RwLock::new(new_leaf));
+                let new_leaf_link = Arc::new(Latch::new(new_leaf));
                 leaf.next = Some(new_leaf_link.clone());
 
                 (new_leaf_link, middle_key)
@@ -738,23 +5319,249 @@ impl<K: Clone + Ord> Node<K> {
 
                 let new_node = Node::Internal(InternalNode {
                     children: new_node_children,
-                    keys: new_node_keys,
+                    keys: Separators::new(new_node_keys),
+                    high_key: internal_node.high_key.replace(middle_key.clone()),
+                    right: internal_node.right.take(),
                 });
 
-                (Arc::new(RwLock::new(new_node)), middle_key)
+                let new_node_link = Arc::new(Latch::new(new_node));
+                internal_node.right = Some(new_node_link.clone());
+                (new_node_link, middle_key)
+            }
+        }
+    }
+}
+
+impl<K: Clone + Ord + 'static> Partition<K> {
+    /// Splits the root in place: its halves move to two new children,
+    /// so link to the root never changes
+    ///
+    /// Returns new height of the partition
+    fn split_root(&self, root: &mut Node<K>, t: usize, fairness: Fairness) -> usize {
+        let high_key = root.high_key().cloned();
+        let (right, separator) = root.split(t);
+        right.set_fairness(fairness);
+        let placeholder = Node::Leaf(Leaf {
+            entries: Vec::new(),
+            next: None,
+            high_key: None,
+            page: None,
+        });
+        let left = Arc::new(Latch::new(mem::replace(root, placeholder)));
+        left.set_fairness(fairness);
+        *root = Node::Internal(InternalNode {
+            children: vec![left, right],
+            keys: Separators::new(vec![separator]),
+            high_key,
+            right: None,
+        });
+        self.height.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+impl<K: Debug + Clone + 'static> BPlus<K> {
+    /// Returns keys of the tree level by level, down to given depth, and entry counts of leaves
+    ///
+    /// Values are not read; nodes, that are locked for writing, are printed as `<locked>`
+    pub fn pretty_print(&self, max_depth: usize) -> String {
+        let mut out = String::new();
+        self.write_levels(&mut out, max_depth)
+            .expect("Writing to a String can not fail");
+        out
+    }
+
+    /// Writes levels of the tree down to given depth
+    fn write_levels<W: fmt::Write>(&self, out: &mut W, max_depth: usize) -> fmt::Result {
+        let mut level = self.roots();
+        let mut depth = 0;
+        while !level.is_empty() && depth <= max_depth {
+            write!(out, "level {}:", depth)?;
+            let mut next_level = Vec::new();
+            for link in &level {
+                let Some(node) = link.try_read() else {
+                    write!(out, " <locked>")?;
+                    continue;
+                };
+                match &*node {
+                    Node::Internal(internal) => {
+                        write!(out, " {:?}", *node)?;
+                        next_level.extend(internal.children.iter().cloned());
+                    }
+                    Node::Leaf(leaf) => match (leaf.entries.first(), leaf.entries.last()) {
+                        (Some((first, _)), Some((last, _))) => {
+                            write!(out, " {:?}..={:?} ({})", first, last, leaf.entries.len())?
+                        }
+                        _ => write!(out, " empty")?,
+                    },
+                }
             }
+            writeln!(out)?;
+            level = next_level;
+            depth += 1;
+        }
+        if !level.is_empty() {
+            writeln!(out, "...")?;
+        }
+        Ok(())
+    }
+}
+
+impl<K: Debug + Clone + 'static> Debug for BPlus<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "BPlus (t = {})", self.t)?;
+        self.write_levels(f, usize::MAX)
+    }
+}
+
+impl<K: Debug + Clone + 'static> Debug for Node<K> {
+    /// Prints keys of the node, without values
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Internal(internal) => f.debug_list().entries(internal.keys.to_vec()).finish(),
+            Node::Leaf(leaf) => f
+                .debug_list()
+                .entries(leaf.entries.iter().map(|(key, _)| key))
+                .finish(),
+        }
+    }
+}
+
+impl<K> Drop for BPlus<K> {
+    /// Syncs data files and marks directory clean, errors are ignored
+    ///
+    /// Directory is marked clean only if the last save covers current state of the tree
+    fn drop(&mut self) {
+        if !self.flush_on_drop.load(Ordering::Acquire) {
+            return;
+        }
+        let files = self.data_file.lock().unwrap().number;
+        if sync_files(&self.path, files).is_ok() && !self.dirty.load(Ordering::Acquire) {
+            let _ = File::create(self.path.join(CLEAN_MARKER));
+        }
+    }
+}
+
+/// Runs blocking closure on given spawner, or in place if there is no spawner
+///
+/// Closure is started on the spawner before the returned future is polled
+///
+/// Returns Err(_) if spawner dropped the job before it completed
+pub(crate) fn unblock<T, F>(
+    spawner: Option<&Arc<dyn Spawner>>,
+    f: F,
+) -> impl Future<Output = Result<T>> + Send + 'static
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    // Closure is kept only if there is no spawner to run it
+    let job = match spawner {
+        Some(spawner) => {
+            let (sender, receiver) = oneshot::channel();
+            spawner.spawn_blocking(Box::new(move || {
+                let _ = sender.send(f());
+            }));
+            Ok(receiver)
+        }
+        None => Err(f),
+    };
+    async move {
+        match job {
+            Ok(receiver) => receiver.await.map_err(|_| BPlusError::Cancelled),
+            Err(f) => Ok(f()),
+        }
+    }
+}
+
+/// Syncs data files with numbers up to the given one and blob files in the directory
+fn sync_files(path: &Path, last: usize) -> io::Result<()> {
+    let sync = |path: PathBuf| match File::open(path) {
+        Ok(file) => file.sync_all(),
+        // Files, that rebuild removed, have nothing to sync
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    (0..=last).try_for_each(|number| sync(path.join(number.to_string())))?;
+    let blobs = match data_file_numbers(&path.join(BLOBS_DIR)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        blobs => blobs?,
+    };
+    blobs
+        .into_iter()
+        .try_for_each(|id| sync(blob_path(path, id as u64)))?;
+    // Names of new blob files are synced with their directory
+    sync(path.join(BLOBS_DIR))
+}
+
+/// Removes clean marker from the directory, returns whether it was there
+fn take_clean_marker(path: &Path) -> io::Result<bool> {
+    match std::fs::remove_file(path.join(CLEAN_MARKER)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns Err(_) if directory by given path exists and is not empty
+pub(crate) fn ensure_empty(path: &Path) -> Result<()> {
+    let is_empty = match std::fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+        Err(e) => return Err(e.into()),
+    };
+    if !is_empty {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is not empty", path.display()),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Opens file by given path for reading, hinting the OS, that it is read with given pattern
+fn open_for(path: &Path, access: Option<Access>) -> io::Result<File> {
+    let file = File::open(path)?;
+    if let Some(access) = access {
+        advice::advise(&file, access);
+    }
+    Ok(file)
+}
+
+/// Returns path of blob file with given id in data directory by given path
+pub(crate) fn blob_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(BLOBS_DIR).join(id.to_string())
+}
+
+/// Returns numbers of data files in directory by given path in ascending order
+pub(crate) fn data_file_numbers(path: &Path) -> io::Result<Vec<usize>> {
+    let mut numbers = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        if let Some(number) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            numbers.push(number);
         }
     }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
 
-    #[allow(unused_variables, dead_code)]
-    fn remove(&mut self, key: &K, t: usize) -> io::Result<()> {
-        unimplemented!()
+/// Returns whether key lies after the end of given range
+fn is_after_end<K: Ord, R: RangeBounds<K>>(range: &R, key: &K) -> bool {
+    match range.end_bound() {
+        Bound::Included(end) => key > end,
+        Bound::Excluded(end) => key >= end,
+        Bound::Unbounded => false,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::search::search;
     use tempfile::TempDir;
 
     fn create_test_tree(t: usize, name: &str) -> (BPlus<i32>, TempDir) {
@@ -763,12 +5570,50 @@ mod tests {
         (tree, temp_dir)
     }
 
+    /// Latches of plain values have nothing to evict
+    impl Evictable for i32 {}
+
+    /// Returns number of the current data file and offset of the next value in it
+    fn position<K>(tree: &BPlus<K>) -> (usize, u64) {
+        let data_file = tree.data_file.lock().unwrap();
+        (data_file.number, data_file.offset)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_superseded_insert() {
+        let (tree, _temp) = create_test_tree(2, "superseded_insert");
+        tree.insert(1, vec![1]).await.unwrap();
+        tree.insert(1, vec![2; 2]).await.unwrap();
+
+        // Insert numbered before the last change of the key, like a late buffered one
+        let mut phases = Phases::default();
+        let handler = tree
+            .get_chunk_handler(vec![3; 3], ChunkKind::Chunk, &mut phases)
+            .await
+            .unwrap();
+        let (_, outcome) = tree
+            .insert_numbered(1, handler, None, Some(1), &mut phases)
+            .await;
+        assert_eq!(outcome, None);
+        assert_eq!(tree.get(&1).await.unwrap(), vec![2; 2]);
+
+        // Absent key is created with the given number
+        let handler = tree
+            .get_chunk_handler(vec![4], ChunkKind::Chunk, &mut phases)
+            .await
+            .unwrap();
+        let (_, outcome) = tree
+            .insert_numbered(2, handler, None, Some(1), &mut phases)
+            .await;
+        assert_eq!(outcome, Some(InsertOutcome::Created { sequence: 1 }));
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_multiple_inserts() {
         let (tree, _temp) = create_test_tree(2, "multiple_inserts");
 
         for i in 1..=4 {
-            tree.insert(i, vec![i as u8]).await;
+            tree.insert(i, vec![i as u8]).await.unwrap();
         }
 
         for i in 1..=4 {
@@ -787,7 +5632,7 @@ mod tests {
             let tree = tree.clone();
             handles.push(tokio::spawn(async move {
                 let tree = tree.write().await;
-                tree.insert(i, vec![i as u8]).await;
+                tree.insert(i, vec![i as u8]).await.unwrap();
             }));
         }
 
@@ -806,12 +5651,12 @@ mod tests {
     async fn test_root_split() {
         let (tree, _temp) = create_test_tree(2, "root_split");
 
-        tree.insert(1, vec![1]).await;
-        tree.insert(2, vec![2]).await;
-        tree.insert(3, vec![3]).await;
-        tree.insert(4, vec![4]).await;
+        tree.insert(1, vec![1]).await.unwrap();
+        tree.insert(2, vec![2]).await.unwrap();
+        tree.insert(3, vec![3]).await.unwrap();
+        tree.insert(4, vec![4]).await.unwrap();
 
-        let root = tree.root.read().await;
+        let root = tree.partitions[0].root.read().await;
         match &*root {
             Node::Internal(internal) => {
                 assert_eq!(internal.keys.len(), 1);
@@ -828,16 +5673,16 @@ mod tests {
         tree.max_file_size = 100;
 
         let large_data = vec![7; 150];
-        tree.insert(1, large_data.clone()).await;
+        tree.insert(1, large_data.clone()).await.unwrap();
 
         let result = tree.get(&1).await.unwrap();
         assert_eq!(result, large_data);
-        tree.insert(2, large_data.clone()).await;
+        tree.insert(2, large_data.clone()).await.unwrap();
         let result = tree.get(&1).await.unwrap();
         assert_eq!(result, large_data);
 
         assert!(
-            tree.file_number.load(std::sync::atomic::Ordering::SeqCst) >= 1,
+            tree.data_file.lock().unwrap().number >= 1,
             "Should create multiple files"
         );
     }
@@ -855,14 +5700,409 @@ mod tests {
 
         assert_eq!(tree.t, loaded_tree.t);
         assert_eq!(tree.path, loaded_tree.path);
+        assert_eq!(position(&tree), position(&loaded_tree));
+        assert!(loaded_tree.get(&42).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_insert_leaves_tree_unchanged() {
+        let (mut tree, temp) = create_test_tree(2, "failed_insert");
+        tree.max_file_size = 10;
+
+        tree.insert(1, vec![1; 20]).await.unwrap();
+        std::fs::remove_dir_all(temp.path()).unwrap();
+
+        assert!(matches!(
+            tree.insert(2, vec![2; 20]).await,
+            Err(BPlusError::Io(_))
+        ));
+        assert!(tree.contains_key(&1).await);
+        assert!(!tree.contains_key(&2).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_appends_across_rotations() {
+        let (mut tree, temp) = create_test_tree(3, "concurrent_appends");
+        tree.max_file_size = 100;
+        let tree = Arc::new(tree);
+
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let tree = tree.clone();
+                tokio::spawn(async move {
+                    for i in 0..50 {
+                        let key = task * 50 + i;
+                        tree.insert(key, vec![key as u8; 30]).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        for key in 0..400 {
+            assert_eq!(tree.get(&key).await.unwrap(), vec![key as u8; 30]);
+        }
+        // Values neither overlap nor leave holes
+        let (last, _) = position(&tree);
+        let written: u64 = (0..=last)
+            .map(|i| {
+                std::fs::metadata(temp.path().join(i.to_string()))
+                    .unwrap()
+                    .len()
+            })
+            .sum();
+        let headers = (last as u64 + 1) * record::FILE_HEADER_SIZE;
+        assert_eq!(written, headers + 400 * (record::HEADER_SIZE + 30));
+    }
+
+    #[tokio::test]
+    async fn test_insert_after_load_appends_to_current_file() {
+        let (tree, temp) = create_test_tree(2, "append_after_load");
+        let tree_path = temp.path().join("tree.bin");
+        tree.insert(1, vec![1; 10]).await.unwrap();
+        tree.save(&tree_path).await.unwrap();
+        drop(tree);
+
+        let tree = BPlus::<i32>::load(&tree_path).await.unwrap();
+        tree.insert(2, vec![2; 10]).await.unwrap();
+        let end = record::FILE_HEADER_SIZE + 2 * (record::HEADER_SIZE + 10);
+        assert_eq!(position(&tree), (0, end));
+        assert_eq!(tree.get(&1).await.unwrap(), vec![1; 10]);
+        assert_eq!(tree.get(&2).await.unwrap(), vec![2; 10]);
+    }
+
+    #[tokio::test]
+    async fn test_load_recovers_position_of_unsaved_appends() {
+        let (mut tree, temp) = create_test_tree(2, "recover_position");
+        // Files hold three values each
+        tree.max_file_size = record::FILE_HEADER_SIZE + 60;
+        let tree_path = temp.path().join("tree.bin");
+        tree.insert(1, vec![1; 10]).await.unwrap();
+        tree.save(&tree_path).await.unwrap();
+        // Values are appended and files rotated after the save
+        for i in 2..6 {
+            tree.insert(i, vec![i as u8; 10]).await.unwrap();
+        }
+        let appended = position(&tree);
+        assert_eq!(appended.0, 1);
+        drop(tree);
+        // Append was torn by a crash
+        let last = OpenOptions::new()
+            .append(true)
+            .open(temp.path().join("1"))
+            .unwrap();
+        (&last).write_all(b"BPR\x01\0\0\0").unwrap();
+
+        let tree = BPlus::<i32>::load(&tree_path).await.unwrap();
+        assert_eq!(position(&tree), appended);
+        tree.insert(6, vec![6; 10]).await.unwrap();
+        let record = record::HEADER_SIZE + 10;
+        assert_eq!(position(&tree), (appended.0, appended.1 + record));
+        assert_eq!(tree.get(&1).await.unwrap(), vec![1; 10]);
+        assert_eq!(tree.get(&6).await.unwrap(), vec![6; 10]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_rotation_keeps_counters() {
+        let (mut tree, temp) = create_test_tree(2, "failed_rotation");
+        tree.max_file_size = 10;
+
+        tree.insert(1, vec![1; 20]).await.unwrap();
+        let (file_number, offset) = position(&tree);
+        std::fs::remove_dir_all(temp.path()).unwrap();
+
+        assert!(tree.insert(2, vec![2; 20]).await.is_err());
+        assert_eq!(position(&tree), (file_number, offset));
+
+        create_dir_all(temp.path()).unwrap();
+        tree.insert(3, vec![3; 20]).await.unwrap();
+        assert_eq!(position(&tree).0, file_number + 1);
+        assert_eq!(tree.get(&3).await.unwrap(), vec![3; 20]);
+    }
+
+    #[tokio::test]
+    async fn test_moves_right_after_unfinished_split() {
+        let (tree, _temp) = create_test_tree(3, "move_right");
+        for i in 0..8 {
+            tree.insert(i, vec![i as u8]).await.unwrap();
+        }
+
+        // Leaf is split, but separator is not added to the parent yet
+        let leaves = tree.collect_leaves().await;
+        assert_eq!(leaves.len(), 2);
+        let (_, separator) = leaves[1].write().await.split(tree.t);
+        assert_eq!(separator, 6);
+
+        let retries = tree.metrics().latch_retries;
+        assert_eq!(tree.get(&7).await.unwrap(), vec![7]);
+        tree.insert(8, vec![8]).await.unwrap();
+        assert!(tree.remove(&6).await.is_some());
+        assert!(!tree.contains_key(&6).await);
+        assert!(tree.metrics().latch_retries > retries);
+
+        let keys: Vec<_> = tree.scan(4..).await.unwrap();
+        let keys: Vec<_> = keys.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![4, 5, 7, 8]);
+    }
+
+    #[tokio::test]
+    async fn test_contended_root() {
+        let (tree, _temp) = create_test_tree(2, "contended_root");
+        tree.insert(1, vec![1]).await.unwrap();
+
+        let guard = tree.partitions[0].root.write().await;
+        assert!(matches!(
+            tree.try_get(&1).await,
+            Err(BPlusError::LockTimeout)
+        ));
+        assert!(matches!(
+            tree.get_timeout(&1, Duration::from_millis(20)).await,
+            Err(BPlusError::LockTimeout)
+        ));
+        assert!(matches!(
+            tree.insert_timeout(2, vec![2], Duration::from_millis(20))
+                .await,
+            Err(BPlusError::LockTimeout)
+        ));
+        drop(guard);
+
+        assert_eq!(tree.try_get(&1).await.unwrap(), vec![1]);
+        assert!(!tree.contains_key(&2).await);
+    }
+
+    #[cfg(any(debug_assertions, feature = "invariants"))]
+    #[tokio::test]
+    async fn test_check_invariants_detects_corruption() {
+        let (tree, _temp) = create_test_tree(2, "invariants");
+        for i in 0..20 {
+            tree.insert(i, vec![i as u8]).await.unwrap();
+        }
+        tree.check_invariants().await.unwrap();
+
+        let leaves = tree.collect_leaves().await;
+        if let Node::Leaf(leaf) = &mut *leaves[0].write().await {
+            leaf.entries.swap(0, 1);
+        }
+        assert!(matches!(
+            tree.check_invariants().await,
+            Err(BPlusError::Corruption(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_event_hooks() {
+        let (mut tree, _temp) = create_test_tree(2, "event_hooks");
+        tree.max_file_size = 10;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        tree.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+
+        for i in 0..4 {
+            tree.insert(i, vec![i as u8; 20]).await.unwrap();
+        }
+        let emitted = events.lock().unwrap().clone();
+        assert!(emitted.contains(&TreeEvent::FileRotated { file_number: 1 }));
+        assert!(emitted.contains(&TreeEvent::NodeSplit { leaf: true }));
+        assert!(emitted.contains(&TreeEvent::RootHeightChanged { height: 2 }));
+
+        std::fs::File::create(tree.path.join("0")).unwrap();
+        assert!(tree.get(&0).await.is_err());
+        assert!(matches!(
+            events.lock().unwrap().last(),
+            Some(TreeEvent::CorruptionDetected { .. })
+        ));
+    }
+
+    #[test]
+    fn test_search_matches_binary_search() {
+        fn check<K: Ord + Copy + 'static>(keys: &[K], probes: &[K]) {
+            for len in 0..=keys.len() {
+                for probe in probes.iter().chain(&keys[..len]) {
+                    assert_eq!(
+                        search(&keys[..len], probe),
+                        keys[..len].binary_search(probe)
+                    );
+                }
+            }
+        }
+
+        let unsigned: Vec<u64> = (0..100).map(|i| i * 3 + (u64::MAX / 2 - 150)).collect();
+        check(&unsigned, &[0, u64::MAX / 2, u64::MAX / 2 + 1, u64::MAX]);
+        let signed: Vec<i64> = (0..100).map(|i| i * 3 - 150).collect();
+        check(&signed, &[i64::MIN, -1, 0, 1, i64::MAX]);
+        let unsigned: Vec<u32> = (0..100).map(|i| i * 3 + (u32::MAX / 2 - 150)).collect();
+        check(&unsigned, &[0, u32::MAX / 2, u32::MAX / 2 + 1, u32::MAX]);
+        let signed: Vec<i32> = (0..100).map(|i| i * 3 - 150).collect();
+        check(&signed, &[i32::MIN, -1, 0, 1, i32::MAX]);
+        let sizes: Vec<usize> = (0..100).map(|i| i * 3 + 1).collect();
+        check(&sizes, &[0, 2, 500, usize::MAX]);
+        // Evenly spread keys are searched by interpolation, skewed ones are not
+        let even: Vec<u64> = (0..300).map(|i| i * 1_000_003).collect();
+        check(&even, &[0, 1, 150_000_000, u64::MAX]);
+        let skewed: Vec<i64> = (0..300).map(|i| i * i * i - 1_000_000).collect();
+        check(&skewed, &[i64::MIN, -999_999, 0, 26_000_000, i64::MAX]);
+        let bytes: Vec<[u8; 2]> = (0..100).map(|i| [i as u8, 7]).collect();
+        check(&bytes, &[[0, 0], [50, 8], [255, 255]]);
+        let wide: Vec<u128> = (0..100).map(|i| i * 3 + (u128::MAX / 2)).collect();
+        check(&wide, &[0, u128::MAX / 2 + 4, u128::MAX]);
+        let narrow: Vec<i16> = (0..14).map(|i| i * 3 - 20).collect();
+        check(&narrow, &[i16::MIN, -1, 0, 2, i16::MAX]);
+
+        let entries: Vec<(String, usize)> = (0..20).map(|i| (format!("{i:02}"), i)).collect();
+        for len in 0..=entries.len() {
+            for probe in ["", "05", "055", "19", "20"] {
+                let probe = probe.to_string();
+                assert_eq!(
+                    search_by_key(&entries[..len], &probe, |(k, _)| k),
+                    entries[..len].binary_search_by(|(k, _)| k.cmp(&probe))
+                );
+            }
+        }
+
+        // Integer keys of leaves are searched without branches
+        let entries: Vec<(u64, usize)> = (0..40).map(|i| (i * 2 + 1, i as usize)).collect();
+        for len in 0..=entries.len() {
+            for probe in 0..=82 {
+                assert_eq!(
+                    search_by_key(&entries[..len], &probe, |(k, _)| k),
+                    entries[..len].binary_search_by(|(k, _)| k.cmp(&probe))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_separators_compress_common_prefix() {
+        fn check<K: Ord + Clone + Debug + 'static>(
+            separators: &Separators<K>,
+            keys: &[K],
+            probes: &[K],
+        ) {
+            assert_eq!(separators.to_vec(), keys);
+            for probe in probes.iter().chain(keys) {
+                let expected = match keys.binary_search(probe) {
+                    Ok(pos) => pos + 1,
+                    Err(pos) => pos,
+                };
+                assert_eq!(separators.child_index(probe), expected, "{probe:?}");
+            }
+        }
+
+        let mut keys: Vec<String> = ["dir/ä/b", "dir/ä/d", "dir/äb", "dir/äc"]
+            .map(String::from)
+            .to_vec();
+        let probes = ["", "dir", "dir/ä/c", "dir/äa", "dir/äz", "e"].map(String::from);
+        let mut separators = Separators::new(keys.clone());
+        assert!(separators.prefix_size() > 0);
+        check(&separators, &keys, &probes);
+
+        // Separator without the prefix shortens it
+        separators.insert(0, "a".to_string());
+        keys.insert(0, "a".to_string());
+        check(&separators, &keys, &probes);
+
+        let right = separators.split_off(2);
+        assert_eq!(right, keys.split_off(2));
+        check(&separators, &keys, &probes);
+
+        let bytes: Vec<Vec<u8>> = (0..20u8).map(|i| vec![7, 7, i, 0]).collect();
+        let probes = [vec![], vec![7, 7], vec![7, 7, 5], vec![7, 8]];
+        check(&Separators::new(bytes.clone()), &bytes, &probes);
+
+        let separator =
+            |left: &str, right: &str| shortest_separator(&left.to_string(), &right.to_string());
+        assert_eq!(separator("dir/abc", "dir/b"), "dir/b");
+        assert_eq!(separator("dir/abc", "dir/abd"), "dir/abd");
+        assert_eq!(separator("dir", "dir/x/y"), "dir/");
+        assert_eq!(separator("dir/ä", "dir/ö/z"), "dir/ö");
         assert_eq!(
-            tree.file_number.load(Ordering::SeqCst),
-            loaded_tree.file_number.load(Ordering::SeqCst)
-        );
-        assert_eq!(
-            tree.offset.load(Ordering::SeqCst),
-            loaded_tree.offset.load(Ordering::SeqCst)
+            shortest_separator(&vec![1u8, 2, 3], &vec![1u8, 3, 0, 0]),
+            vec![1, 3]
         );
-        assert!(loaded_tree.get(&42).await.is_err());
+        assert_eq!(shortest_separator(&5u64, &9u64), 9);
+
+        let integers: Vec<u64> = (0..20).map(|i| i * 2).collect();
+        let separators = Separators::new(integers.clone());
+        assert_eq!(separators.prefix_size(), 0);
+        check(&separators, &integers, &[0, 3, 100]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_leaf_eviction() {
+        use crate::buffer_pool::BufferPoolBuilder;
+
+        let (tree, _temp) = create_test_tree(2, "leaf_eviction");
+        let tree = tree
+            .with_buffer_pool(BufferPoolBuilder::new(1 << 20))
+            .unwrap();
+        tree.insert(1, vec![1]).await.unwrap();
+        tree.insert(2, vec![2]).await.unwrap();
+        let root = tree.partitions[0].root.clone();
+        let page = |node: &Node<i32>| match node {
+            Node::Leaf(leaf) => leaf.page.as_ref().map(|page| (page.clean, page.evicted)),
+            Node::Internal(_) => unreachable!("tree has one leaf"),
+        };
+
+        tree.shrink(0).await.unwrap();
+        assert_eq!(page(&*root.peek().await), Some((true, true)));
+
+        // Leaf, that was read, stays clean and is evicted again without a write
+        assert_eq!(tree.get(&1).await.unwrap(), vec![1]);
+        assert_eq!(page(&*root.peek().await), Some((true, false)));
+        tree.shrink(0).await.unwrap();
+        assert_eq!(page(&*root.peek().await), Some((true, true)));
+
+        // Leaf, that was changed, is written on its next eviction
+        tree.insert(3, vec![3]).await.unwrap();
+        assert_eq!(page(&*root.peek().await), Some((false, false)));
+        tree.shrink(0).await.unwrap();
+        assert_eq!(page(&*root.peek().await), Some((true, true)));
+        assert_eq!(tree.get(&3).await.unwrap(), vec![3]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_latch_fairness() {
+        for fairness in [
+            Fairness::Fifo,
+            Fairness::WriterPreferring,
+            Fairness::ReaderPreferring,
+        ] {
+            let latch = Arc::new(Latch::new(0));
+            latch.set_fairness(fairness);
+            let reader = latch.read().await;
+            let writer = tokio::spawn(latch.clone().write_owned());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert!(!writer.is_finished());
+
+            // Only reader-preferring latch admits a reader ahead of the waiting writer
+            let admitted = latch.try_read().is_some();
+            assert_eq!(admitted, fairness == Fairness::ReaderPreferring);
+            drop(reader);
+            *writer.await.unwrap() += 1;
+            assert_eq!(*latch.read().await, 1);
+        }
+
+        // Policy is set on a tree, while its root is locked by another task
+        let (tree, _temp) = create_test_tree(2, "locked_fairness");
+        let root = tree.partitions[0].root.clone().write_owned().await;
+        let unlock = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(root);
+        });
+        let tree = tree.with_fairness(Fairness::WriterPreferring).await;
+        unlock.await.unwrap();
+        tree.insert(1, vec![1]).await.unwrap();
+        assert_eq!(tree.get(&1).await.unwrap(), vec![1]);
+
+        // Cancelled writer does not hold back readers
+        let latch = Latch::new(0);
+        let reader = latch.read().await;
+        let timeout = tokio::time::timeout(Duration::from_millis(20), latch.write()).await;
+        assert!(timeout.is_err());
+        assert!(latch.try_read().is_some());
+        drop(reader);
+        assert!(latch.try_read().is_some());
     }
 }
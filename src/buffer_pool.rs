@@ -0,0 +1,813 @@
+//! Paging layer: nodes with stable ids, persisted to a page file and cached in a bounded pool
+//!
+//! It backs [`CowTree`](crate::cow::CowTree), which can hold indexes larger than memory,
+//! and [`BPlus`](crate::bplus_tree::BPlus) evicts its leaves through it,
+//! see [`BPlus::with_buffer_pool`](crate::bplus_tree::BPlus::with_buffer_pool)
+//!
+//! Keys, that are longer than the inline limit, see [`BufferPoolBuilder::max_inline_key`],
+//! keep their first bytes in the page of their node and the rest in a chain of overflow
+//! pages, that belong to the node and are rewritten and freed together with it
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    bplus_tree::{BPlusKeySerializable, ChunkHandler},
+    error::{BPlusError, Result},
+    eviction::Replacer,
+    page::{Page, PageFile, PageId, PageKind, DEFAULT_PAGE_SIZE, PAGE_HEADER_SIZE, SLOT_SIZE},
+};
+
+pub use crate::eviction::EvictionPolicy;
+
+/// Stable identifier of a node: number of the page, that holds it
+pub type NodeId = PageId;
+
+/// Tag of a cell, which key is stored in it whole
+const INLINE_KEY: u8 = 0;
+/// Tag of a cell, which key continues in overflow pages
+const OVERFLOW_KEY: u8 = 1;
+
+/// Value, that can be stored in leaves of paged nodes
+pub trait PagedValue: Serialize + for<'de> Deserialize<'de> + Send + Sync {}
+impl<T: Serialize + for<'de> Deserialize<'de> + Send + Sync> PagedValue for T {}
+
+/// Node, that references its children and the next leaf by their ids
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PagedNode<K, V = ChunkHandler> {
+    /// Internal node with separator keys and ids of its children
+    Internal { keys: Vec<K>, children: Vec<NodeId> },
+    /// Leaf with entries and id of the next leaf
+    Leaf {
+        entries: Vec<(K, V)>,
+        next: Option<NodeId>,
+    },
+}
+
+/// Counters of accesses to the buffer pool
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Number of accesses to nodes, that were cached
+    pub hits: u64,
+    /// Number of accesses to nodes, that were loaded from disk
+    pub misses: u64,
+    /// Number of nodes evicted from the pool
+    pub evictions: u64,
+    /// Number of evicted or flushed nodes, that were written back to disk
+    pub write_backs: u64,
+}
+
+/// Cached node
+struct Frame<K, V> {
+    node: Arc<PagedNode<K, V>>,
+    /// Whether node was modified since it was written to disk
+    dirty: bool,
+    /// Number of pins, node is not evicted while it is pinned
+    pins: usize,
+    /// Overflow pages with keys of the node, as it was last written to disk
+    overflow: Vec<PageId>,
+}
+
+/// Mutable state of the buffer pool
+struct PoolState<K, V> {
+    pages: PageFile,
+    frames: HashMap<NodeId, Frame<K, V>>,
+    replacer: Box<dyn Replacer>,
+    stats: BufferPoolStats,
+}
+
+/// Cache of nodes, that are persisted one per page in a page file
+///
+/// Total size of cached pages is bounded: nodes are evicted by the configured policy,
+/// modified ones are written back to disk before that
+pub struct BufferPool<K: BPlusKeySerializable, V: PagedValue = ChunkHandler> {
+    page_size: usize,
+    /// Maximal total size of cached pages in bytes
+    capacity: usize,
+    /// Whether internal nodes are never evicted
+    pin_internal_nodes: bool,
+    /// Max size of an encoded key, that is stored in the page of its node whole
+    max_inline_key: usize,
+    state: Mutex<PoolState<K, V>>,
+}
+
+/// Builder of the buffer pool
+#[derive(Clone, Debug)]
+pub struct BufferPoolBuilder {
+    page_size: usize,
+    capacity: usize,
+    policy: EvictionPolicy,
+    pin_internal_nodes: bool,
+    max_inline_key: Option<usize>,
+}
+
+impl BufferPoolBuilder {
+    /// Creates builder of pool with given capacity in bytes, default page size and LRU eviction
+    ///
+    /// Capacity is the soft limit of total size of cached pages:
+    /// the last accessed node and pinned nodes are kept cached even if it is exceeded
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            page_size: DEFAULT_PAGE_SIZE,
+            capacity,
+            policy: EvictionPolicy::default(),
+            pin_internal_nodes: false,
+            max_inline_key: None,
+        }
+    }
+
+    /// Sets size of pages in bytes
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Sets policy, by which nodes are chosen for eviction
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets whether internal nodes are never evicted, so only leaves are loaded from disk
+    pub fn pin_internal_nodes(mut self, pin: bool) -> Self {
+        self.pin_internal_nodes = pin;
+        self
+    }
+
+    /// Sets max size of an encoded key, that is stored in the page of its node whole
+    ///
+    /// Longer keys keep that many first bytes in the page and the rest in overflow pages,
+    /// so nodes with long keys, e.g. paths, fit in their pages. Size is capped by the page
+    /// size and is 1/16 of it by default
+    pub fn max_inline_key(mut self, size: usize) -> Self {
+        self.max_inline_key = Some(size);
+        self
+    }
+
+    /// Opens buffer pool over page file by given path, creating the file if needed
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if existing file has a different page size
+    pub fn open<K: BPlusKeySerializable, V: PagedValue>(
+        self,
+        path: &Path,
+    ) -> Result<BufferPool<K, V>> {
+        Ok(BufferPool {
+            page_size: self.page_size,
+            capacity: self.capacity,
+            pin_internal_nodes: self.pin_internal_nodes,
+            max_inline_key: self
+                .max_inline_key
+                .unwrap_or(self.page_size / 16)
+                .min(self.page_size),
+            state: Mutex::new(PoolState {
+                pages: PageFile::open(path, self.page_size)?,
+                frames: HashMap::new(),
+                replacer: self.policy.replacer(self.capacity / self.page_size),
+                stats: BufferPoolStats::default(),
+            }),
+        })
+    }
+}
+
+impl<K: BPlusKeySerializable, V: PagedValue> BufferPool<K, V> {
+    /// Opens buffer pool over page file by given path with default settings,
+    /// creating the file if needed
+    pub fn open(path: &Path, capacity: usize) -> Result<Self> {
+        BufferPoolBuilder::new(capacity).open(path)
+    }
+
+    /// Opens buffer pool over page file by given path with given page size,
+    /// creating the file if needed
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if existing file has a different page size
+    pub fn open_with_page_size(path: &Path, page_size: usize, capacity: usize) -> Result<Self> {
+        BufferPoolBuilder::new(capacity)
+            .page_size(page_size)
+            .open(path)
+    }
+
+    /// Stores new node and returns its id
+    ///
+    /// Node is written to disk only when it is evicted or flushed
+    ///
+    /// Returns Err(BPlusError::PageOverflow { .. }) if node does not fit in a page
+    pub fn allocate(&self, node: PagedNode<K, V>) -> Result<NodeId> {
+        self.check_fits(&node)?;
+        let mut state = self.state.lock().unwrap();
+        let id = state.pages.allocate()?;
+        self.cache(&mut state, id, Arc::new(node), true, Vec::new())?;
+        Ok(id)
+    }
+
+    /// Returns node by given id, loading it from disk if it is not cached
+    ///
+    /// Returns Err(BPlusError::NotFound) if there is no such node
+    pub fn get(&self, id: NodeId) -> Result<Arc<PagedNode<K, V>>> {
+        let mut state = self.state.lock().unwrap();
+        self.load(&mut state, id)
+    }
+
+    /// Replaces node by given id
+    ///
+    /// Returns Err(BPlusError::NotFound) if there is no such node,
+    /// and Err(BPlusError::PageOverflow { .. }) if node does not fit in a page
+    pub fn update(&self, id: NodeId, node: PagedNode<K, V>) -> Result<()> {
+        self.check_fits(&node)?;
+        let mut state = self.state.lock().unwrap();
+        // Overflow pages of the node on disk are freed, once it is written back
+        let overflow = match state.frames.contains_key(&id) {
+            true => Vec::new(),
+            false => {
+                let page = state.pages.read(id)?;
+                if page.kind()? == PageKind::Free {
+                    return Err(BPlusError::NotFound);
+                }
+                overflow_pages(&state.pages, &page)?
+            }
+        };
+        self.cache(&mut state, id, Arc::new(node), true, overflow)
+    }
+
+    /// Pins node by given id, loading it if needed: it is not evicted until it is unpinned
+    /// as many times, as it was pinned
+    ///
+    /// Returns Err(BPlusError::NotFound) if there is no such node
+    pub fn pin(&self, id: NodeId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.load(&mut state, id)?;
+        state.frames.get_mut(&id).unwrap().pins += 1;
+        Ok(())
+    }
+
+    /// Releases one pin of node by given id
+    pub fn unpin(&self, id: NodeId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(frame) = state.frames.get_mut(&id) {
+            frame.pins = frame.pins.saturating_sub(1);
+        }
+        self.evict(&mut state, id)
+    }
+
+    /// Removes node by given id from the pool and releases its page with its overflow pages
+    pub fn free(&self, id: NodeId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let overflow = match state.frames.remove(&id) {
+            Some(frame) => frame.overflow,
+            None => overflow_pages(&state.pages, &state.pages.read(id)?)?,
+        };
+        state.replacer.remove(id);
+        state.pages.free(id)?;
+        for page in overflow {
+            state.pages.free(page)?;
+        }
+        Ok(())
+    }
+
+    /// Writes all modified cached nodes to disk and syncs the page file
+    pub fn flush(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        for (id, frame) in state.frames.iter_mut().filter(|(_, frame)| frame.dirty) {
+            frame.overflow =
+                self.write_back(&mut state.pages, *id, &frame.node, &frame.overflow)?;
+            frame.dirty = false;
+            state.stats.write_backs += 1;
+        }
+        state.pages.sync()
+    }
+
+    /// Allocates page, that is not cached by the pool and does not hold a node
+    pub(crate) fn allocate_page(&self) -> Result<PageId> {
+        self.state.lock().unwrap().pages.allocate()
+    }
+
+    /// Releases page directly, bypassing the cache and without releasing overflow pages
+    /// of a node in it
+    pub(crate) fn free_page(&self, id: PageId) -> Result<()> {
+        self.state.lock().unwrap().pages.free(id)
+    }
+
+    /// Returns ids of overflow pages, that hold keys of node by given id
+    ///
+    /// Returns Err(BPlusError::NotFound) if there is no such node
+    pub(crate) fn overflow_pages(&self, id: NodeId) -> Result<Vec<PageId>> {
+        let mut state = self.state.lock().unwrap();
+        self.load(&mut state, id)?;
+        Ok(state.frames[&id].overflow.clone())
+    }
+
+    /// Reads page directly from the page file, bypassing the cache
+    pub(crate) fn read_page(&self, id: PageId) -> Result<Page> {
+        self.state.lock().unwrap().pages.read(id)
+    }
+
+    /// Writes page directly to the page file and syncs it, bypassing the cache
+    pub(crate) fn write_page(&self, id: PageId, page: &Page) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        state.pages.write(id, page)?;
+        state.pages.sync()
+    }
+
+    /// Returns ids of released pages
+    pub(crate) fn free_pages(&self) -> Result<Vec<PageId>> {
+        self.state.lock().unwrap().pages.free_pages()
+    }
+
+    /// Returns number of pages in the page file, including the header page
+    pub(crate) fn page_count(&self) -> u64 {
+        self.state.lock().unwrap().pages.page_count()
+    }
+
+    /// Returns size of pages in bytes
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Returns total size of cached pages in bytes
+    pub fn resident_bytes(&self) -> usize {
+        self.cached() * self.page_size
+    }
+
+    /// Returns number of cached nodes
+    pub fn cached(&self) -> usize {
+        self.state.lock().unwrap().frames.len()
+    }
+
+    /// Evicts nodes chosen by the policy, until cached pages take at most given number of bytes,
+    /// e.g. in response to memory pressure. Capacity of the pool is not changed
+    ///
+    /// Pinned nodes and, if configured, internal nodes are kept cached, so the pool may still
+    /// take more than given number of bytes
+    ///
+    /// Returns number of freed bytes.
+    /// Returns Err(_) if a modified node could not be written back, it stays cached then
+    pub fn shrink(&self, target: usize) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.frames.len();
+        let evicted = self.evict_to(&mut state, None, target);
+        let freed = (before - state.frames.len()) * self.page_size;
+        evicted.map(|()| freed)
+    }
+
+    /// Returns counters of accesses to the pool
+    pub fn stats(&self) -> BufferPoolStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Returns cached node, or loads it from disk and caches it
+    fn load(&self, state: &mut PoolState<K, V>, id: NodeId) -> Result<Arc<PagedNode<K, V>>> {
+        if let Some(frame) = state.frames.get(&id) {
+            let node = Arc::clone(&frame.node);
+            state.replacer.touch(id);
+            state.stats.hits += 1;
+            return Ok(node);
+        }
+
+        state.stats.misses += 1;
+        let (node, overflow) = Self::decode(&state.pages, &state.pages.read(id)?)?;
+        let node = Arc::new(node);
+        self.cache(state, id, Arc::clone(&node), false, overflow)?;
+        Ok(node)
+    }
+
+    /// Puts node in the cache, keeping pins of the node it replaces, and evicts nodes over capacity
+    ///
+    /// Given overflow pages of the node on disk are kept only, if the node was not cached
+    fn cache(
+        &self,
+        state: &mut PoolState<K, V>,
+        id: NodeId,
+        node: Arc<PagedNode<K, V>>,
+        dirty: bool,
+        overflow: Vec<PageId>,
+    ) -> Result<()> {
+        match state.frames.get_mut(&id) {
+            Some(frame) => {
+                frame.node = node;
+                frame.dirty |= dirty;
+                state.replacer.touch(id);
+            }
+            None => {
+                state.frames.insert(
+                    id,
+                    Frame {
+                        node,
+                        dirty,
+                        pins: 0,
+                        overflow,
+                    },
+                );
+                state.replacer.insert(id);
+            }
+        }
+        self.evict(state, id)
+    }
+
+    /// Evicts nodes chosen by the policy, until cached nodes fit in capacity
+    ///
+    /// Node by given id, pinned nodes and, if configured, internal nodes are not evicted
+    fn evict(&self, state: &mut PoolState<K, V>, keep: NodeId) -> Result<()> {
+        self.evict_to(state, Some(keep), self.capacity)
+    }
+
+    /// Evicts nodes chosen by the policy, until cached nodes take at most given number of bytes
+    ///
+    /// Node by given id, pinned nodes and, if configured, internal nodes are not evicted
+    fn evict_to(
+        &self,
+        state: &mut PoolState<K, V>,
+        keep: Option<NodeId>,
+        target: usize,
+    ) -> Result<()> {
+        while state.frames.len() * self.page_size > target {
+            let frames = &state.frames;
+            let evictable = |id: NodeId| {
+                let frame = &frames[&id];
+                Some(id) != keep
+                    && frame.pins == 0
+                    && !(self.pin_internal_nodes
+                        && matches!(*frame.node, PagedNode::Internal { .. }))
+            };
+            let Some(id) = state.replacer.victim(&evictable) else {
+                break;
+            };
+            let frame = state
+                .frames
+                .remove(&id)
+                .expect("replacer tracks only cached nodes");
+            if frame.dirty {
+                let written = self.write_back(&mut state.pages, id, &frame.node, &frame.overflow);
+                if let Err(err) = written {
+                    // Keeps node cached, so the modification is not lost
+                    state.frames.insert(id, frame);
+                    state.replacer.insert(id);
+                    return Err(err);
+                }
+                state.stats.write_backs += 1;
+            }
+            state.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns Err(BPlusError::PageOverflow { .. }) if node does not fit in a page
+    fn check_fits(&self, node: &PagedNode<K, V>) -> Result<()> {
+        // Overflow pages are written only, when the node is written back
+        self.encode(node, &mut |_| Ok(0))?;
+        Ok(())
+    }
+
+    /// Writes node to the page by given id with new overflow pages for its long keys,
+    /// then frees overflow pages of its previous version
+    ///
+    /// Returns new overflow pages of the node
+    fn write_back(
+        &self,
+        pages: &mut PageFile,
+        id: NodeId,
+        node: &PagedNode<K, V>,
+        previous: &[PageId],
+    ) -> Result<Vec<PageId>> {
+        let mut overflow = Vec::new();
+        let written = self
+            .encode(node, &mut |rest| {
+                let chain = write_overflow(pages, rest)?;
+                overflow.extend_from_slice(&chain);
+                Ok(chain[0])
+            })
+            .and_then(|page| pages.write(id, &page));
+        if let Err(err) = written {
+            for page in overflow {
+                let _ = pages.free(page);
+            }
+            return Err(err);
+        }
+        for &page in previous {
+            pages.free(page)?;
+        }
+        Ok(overflow)
+    }
+
+    /// Lays node out in a slotted page: one cell per entry of a leaf or per separator
+    /// with its right child of an internal node. Page link holds the next leaf or the first child
+    ///
+    /// Cell holds key, encoded by its [`KeyCodec`](crate::key_codec::KeyCodec), followed by its value or child. Rest of
+    /// a key over the inline limit is passed to given function, that stores it in overflow
+    /// pages and returns the first of them
+    fn encode(
+        &self,
+        node: &PagedNode<K, V>,
+        overflow: &mut dyn FnMut(&[u8]) -> Result<PageId>,
+    ) -> Result<Page> {
+        let (kind, link, cells) = match node {
+            PagedNode::Internal { keys, children } => (
+                PageKind::Internal,
+                children.first().copied(),
+                keys.iter()
+                    .zip(children.iter().skip(1))
+                    .map(|(key, child)| self.encode_cell(key, child, overflow))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            PagedNode::Leaf { entries, next } => (
+                PageKind::Leaf,
+                *next,
+                entries
+                    .iter()
+                    .map(|(key, value)| self.encode_cell(key, value, overflow))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+        };
+
+        let size = PAGE_HEADER_SIZE
+            + cells
+                .iter()
+                .map(|cell| cell.len() + SLOT_SIZE)
+                .sum::<usize>();
+        if size > self.page_size {
+            return Err(BPlusError::PageOverflow {
+                size,
+                page_size: self.page_size,
+            });
+        }
+        let mut page = Page::new(kind, self.page_size);
+        page.set_link(link);
+        for cell in &cells {
+            page.push(cell);
+        }
+        Ok(page)
+    }
+
+    /// Returns cell of a page with given key and its value or child
+    ///
+    /// Key over the inline limit is stored as its prefix, length of its rest and the first
+    /// overflow page, that given function stores the rest in
+    fn encode_cell<T: Serialize>(
+        &self,
+        key: &K,
+        value: &T,
+        overflow: &mut dyn FnMut(&[u8]) -> Result<PageId>,
+    ) -> Result<Vec<u8>> {
+        let encoded = key.to_bytes();
+        let mut cell;
+        if encoded.len() <= self.max_inline_key {
+            cell = Vec::with_capacity(1 + encoded.len());
+            cell.push(INLINE_KEY);
+            cell.extend_from_slice(&encoded);
+        } else {
+            let (prefix, rest) = encoded.split_at(self.max_inline_key);
+            cell = vec![OVERFLOW_KEY];
+            cell.extend_from_slice(&(prefix.len() as u16).to_le_bytes());
+            cell.extend_from_slice(prefix);
+            cell.extend_from_slice(&(rest.len() as u64).to_le_bytes());
+            cell.extend_from_slice(&overflow(rest)?.to_le_bytes());
+        }
+        bincode::serialize_into(&mut cell, value)?;
+        Ok(cell)
+    }
+
+    /// Reads node from a slotted page with rests of its long keys from overflow pages
+    ///
+    /// Returns node with ids of its overflow pages.
+    /// Returns Err(BPlusError::NotFound) if page is free
+    fn decode(pages: &PageFile, page: &Page) -> Result<(PagedNode<K, V>, Vec<PageId>)> {
+        let mut overflow = Vec::new();
+        let mut cells = (0..page.len()).map(|index| page.cell(index));
+        let node = match page.kind()? {
+            PageKind::Free => return Err(BPlusError::NotFound),
+            PageKind::Meta | PageKind::Overflow => {
+                return Err(BPlusError::Corruption(
+                    "page does not hold a node".to_string(),
+                ))
+            }
+            PageKind::Leaf => PagedNode::Leaf {
+                entries: cells
+                    .map(|cell| Self::decode_cell(pages, cell?, &mut overflow))
+                    .collect::<Result<_>>()?,
+                next: page.link(),
+            },
+            PageKind::Internal => {
+                let mut keys = Vec::with_capacity(page.len());
+                let mut children = page.link().into_iter().collect::<Vec<_>>();
+                for cell in &mut cells {
+                    let (key, child) = Self::decode_cell(pages, cell?, &mut overflow)?;
+                    keys.push(key);
+                    children.push(child);
+                }
+                PagedNode::Internal { keys, children }
+            }
+        };
+        Ok((node, overflow))
+    }
+
+    /// Reads key and its value or child from a cell of a page, adding overflow pages
+    /// of the key to given ones
+    fn decode_cell<T: DeserializeOwned>(
+        pages: &PageFile,
+        cell: &[u8],
+        overflow: &mut Vec<PageId>,
+    ) -> Result<(K, T)> {
+        match Cell::parse(cell)? {
+            Cell::Inline(mut rest) => {
+                let key = K::decode(&mut rest)?;
+                Ok((key, bincode::deserialize(rest)?))
+            }
+            Cell::Overflow {
+                prefix,
+                len,
+                first,
+                value,
+            } => {
+                let (rest, chain) = read_overflow(pages, first, len)?;
+                overflow.extend(chain);
+                let mut encoded = prefix.to_vec();
+                encoded.extend_from_slice(&rest);
+                Ok((K::from_bytes(&encoded)?, bincode::deserialize(value)?))
+            }
+        }
+    }
+}
+
+/// Page file, that leaves of [`BPlus`](crate::bplus_tree::BPlus) are evicted to
+pub(crate) trait LeafPages<K>: Send + Sync {
+    /// Writes entries of a leaf to given page, or to a new one if there is none,
+    /// and returns the page
+    ///
+    /// Returns Err(BPlusError::PageOverflow { .. }) if entries do not fit in a page
+    fn write(&self, id: Option<NodeId>, entries: &[(K, ChunkHandler)]) -> Result<NodeId>;
+
+    /// Returns entries of a leaf, that were written to given page
+    fn read(&self, id: NodeId) -> Result<Vec<(K, ChunkHandler)>>;
+
+    /// Releases given page, a page, that could not be released, stays in the file
+    fn release(&self, id: NodeId);
+
+    /// Returns total size of cached pages in bytes
+    fn resident_bytes(&self) -> usize;
+
+    /// Evicts cached pages, until they take at most given number of bytes
+    fn shrink(&self, target: usize) -> Result<usize>;
+}
+
+impl<K: BPlusKeySerializable> LeafPages<K> for BufferPool<K> {
+    fn write(&self, id: Option<NodeId>, entries: &[(K, ChunkHandler)]) -> Result<NodeId> {
+        let node = PagedNode::Leaf {
+            entries: entries.to_vec(),
+            next: None,
+        };
+        match id {
+            Some(id) => self.update(id, node).map(|()| id),
+            None => self.allocate(node),
+        }
+    }
+
+    fn read(&self, id: NodeId) -> Result<Vec<(K, ChunkHandler)>> {
+        match &*self.get(id)? {
+            PagedNode::Leaf { entries, .. } => Ok(entries.clone()),
+            PagedNode::Internal { .. } => Err(BPlusError::Corruption(format!(
+                "page {id} does not hold a leaf"
+            ))),
+        }
+    }
+
+    fn release(&self, id: NodeId) {
+        let _ = self.free(id);
+    }
+
+    fn resident_bytes(&self) -> usize {
+        BufferPool::resident_bytes(self)
+    }
+
+    fn shrink(&self, target: usize) -> Result<usize> {
+        BufferPool::shrink(self, target)
+    }
+}
+
+impl<K: BPlusKeySerializable, V: PagedValue> Drop for BufferPool<K, V> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Cell of a page with a node
+enum Cell<'a> {
+    /// Encoded key is stored in the cell whole, followed by the value or child
+    Inline(&'a [u8]),
+    /// Encoded key starts with the prefix, its rest of given length is stored
+    /// in overflow pages from the first one
+    Overflow {
+        prefix: &'a [u8],
+        len: u64,
+        first: PageId,
+        value: &'a [u8],
+    },
+}
+
+impl<'a> Cell<'a> {
+    /// Splits given cell by its tag
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if the cell is malformed
+    fn parse(cell: &'a [u8]) -> Result<Self> {
+        let (&tag, mut rest) = cell
+            .split_first()
+            .ok_or_else(|| BPlusError::Corruption("cell is empty".to_string()))?;
+        match tag {
+            INLINE_KEY => Ok(Cell::Inline(rest)),
+            OVERFLOW_KEY => {
+                let prefix_len = u16::from_le_bytes(take(&mut rest)?) as usize;
+                if rest.len() < prefix_len {
+                    return Err(BPlusError::Corruption("cell is truncated".to_string()));
+                }
+                let (prefix, mut rest) = rest.split_at(prefix_len);
+                let len = u64::from_le_bytes(take(&mut rest)?);
+                let first = u64::from_le_bytes(take(&mut rest)?);
+                Ok(Cell::Overflow {
+                    prefix,
+                    len,
+                    first,
+                    value: rest,
+                })
+            }
+            tag => Err(BPlusError::Corruption(format!("unknown cell tag {tag}"))),
+        }
+    }
+}
+
+/// Takes array of given length from the start of a cell
+fn take<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N]> {
+    if bytes.len() < N {
+        return Err(BPlusError::Corruption("cell is truncated".to_string()));
+    }
+    let (taken, rest) = bytes.split_at(N);
+    *bytes = rest;
+    Ok(taken.try_into().expect("length is taken"))
+}
+
+/// Writes given bytes to a chain of new overflow pages and returns ids of the pages in order
+fn write_overflow(pages: &mut PageFile, bytes: &[u8]) -> Result<Vec<PageId>> {
+    let capacity = pages.page_size() - PAGE_HEADER_SIZE - SLOT_SIZE;
+    let parts: Vec<_> = bytes.chunks(capacity).collect();
+    let chain = (0..parts.len())
+        .map(|_| pages.allocate())
+        .collect::<Result<Vec<_>>>()?;
+    for (i, part) in parts.into_iter().enumerate() {
+        let mut page = Page::new(PageKind::Overflow, pages.page_size());
+        page.push(part);
+        page.set_link(chain.get(i + 1).copied());
+        pages.write(chain[i], &page)?;
+    }
+    Ok(chain)
+}
+
+/// Reads given number of bytes from the chain of overflow pages, that starts with given page
+///
+/// Returns read bytes with ids of the pages in order.
+/// Returns Err(BPlusError::Corruption(_)) if the chain ends early or has a page of other kind
+fn read_overflow(pages: &PageFile, first: PageId, len: u64) -> Result<(Vec<u8>, Vec<PageId>)> {
+    let mut bytes = Vec::new();
+    let mut chain = Vec::new();
+    let mut next = Some(first);
+    while (bytes.len() as u64) < len {
+        let Some(id) = next else {
+            return Err(BPlusError::Corruption(format!(
+                "overflow pages end after {} of {len} bytes of a key",
+                bytes.len()
+            )));
+        };
+        let page = pages.read(id)?;
+        // Every page adds bytes, so a cycle of pages ends with the length
+        if page.kind()? != PageKind::Overflow || page.is_empty() || page.cell(0)?.is_empty() {
+            return Err(BPlusError::Corruption(format!(
+                "page {id} is not an overflow page"
+            )));
+        }
+        bytes.extend_from_slice(page.cell(0)?);
+        chain.push(id);
+        next = page.link();
+    }
+    if bytes.len() as u64 != len {
+        return Err(BPlusError::Corruption(format!(
+            "overflow pages hold {} bytes of a key of {len}",
+            bytes.len()
+        )));
+    }
+    Ok((bytes, chain))
+}
+
+/// Returns ids of overflow pages, that hold keys of the node in given page
+fn overflow_pages(pages: &PageFile, page: &Page) -> Result<Vec<PageId>> {
+    if !matches!(page.kind()?, PageKind::Leaf | PageKind::Internal) {
+        return Ok(Vec::new());
+    }
+    let mut overflow = Vec::new();
+    for index in 0..page.len() {
+        if let Cell::Overflow { len, first, .. } = Cell::parse(page.cell(index)?)? {
+            overflow.extend(read_overflow(pages, first, len)?.1);
+        }
+    }
+    Ok(overflow)
+}
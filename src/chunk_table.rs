@@ -0,0 +1,242 @@
+//! Table of locations of values in data files
+//!
+//! Leaves refer to values by ids of chunks, which are resolved through the table, so values
+//! may be moved to other data files by changing their locations, without changing leaves
+
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+/// Location of a chunk in data files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Location {
+    /// Number of the data file
+    pub(crate) file: u32,
+    /// Offset of the chunk in the data file
+    pub(crate) offset: u64,
+}
+
+/// Location of a chunk, whose offset fits in 32 bits, taking half of the size of [`Location`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CompactLocation {
+    file: u32,
+    offset: u32,
+}
+
+impl From<CompactLocation> for Location {
+    fn from(location: CompactLocation) -> Self {
+        Self {
+            file: location.file,
+            offset: location.offset.into(),
+        }
+    }
+}
+
+impl TryFrom<Location> for CompactLocation {
+    type Error = std::num::TryFromIntError;
+
+    fn try_from(location: Location) -> Result<Self, Self::Error> {
+        Ok(Self {
+            file: location.file,
+            offset: location.offset.try_into()?,
+        })
+    }
+}
+
+/// Locations of chunks, indexed by their ids
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum Locations {
+    /// Locations with 64-bit offsets
+    Wide(Vec<Location>),
+    /// Locations with 32-bit offsets
+    Compact(Vec<CompactLocation>),
+}
+
+impl Default for Locations {
+    fn default() -> Self {
+        Self::Wide(Vec::new())
+    }
+}
+
+impl Locations {
+    /// Creates given locations, that are compact if given so and all their offsets fit in 32 bits
+    pub(crate) fn new(locations: Vec<Location>, compact: bool) -> Self {
+        let mut locations = Self::Wide(locations);
+        if compact {
+            locations.compact();
+        }
+        locations
+    }
+
+    /// Returns location of chunk with given id, or None if there is no such chunk
+    pub(crate) fn get(&self, id: u64) -> Option<Location> {
+        let id = usize::try_from(id).ok()?;
+        match self {
+            Self::Wide(locations) => locations.get(id).copied(),
+            Self::Compact(locations) => locations.get(id).copied().map(Location::from),
+        }
+    }
+
+    /// Returns whether offsets are stored in 32 bits
+    pub(crate) fn is_compact(&self) -> bool {
+        matches!(self, Self::Compact(_))
+    }
+
+    /// Returns number of locations
+    fn len(&self) -> usize {
+        match self {
+            Self::Wide(locations) => locations.len(),
+            Self::Compact(locations) => locations.len(),
+        }
+    }
+
+    /// Adds location of the next chunk
+    fn push(&mut self, location: Location) {
+        if let Self::Compact(locations) = self {
+            match CompactLocation::try_from(location) {
+                Ok(location) => return locations.push(location),
+                Err(_) => self.widen(),
+            }
+        }
+        if let Self::Wide(locations) = self {
+            locations.push(location);
+        }
+    }
+
+    /// Sets location of chunk with given id
+    fn set(&mut self, id: u64, location: Location) {
+        let id = id as usize;
+        if let Self::Compact(locations) = self {
+            match CompactLocation::try_from(location) {
+                Ok(location) => return locations[id] = location,
+                Err(_) => self.widen(),
+            }
+        }
+        if let Self::Wide(locations) = self {
+            locations[id] = location;
+        }
+    }
+
+    /// Stores offsets in 32 bits, if all of them fit
+    fn compact(&mut self) {
+        if let Self::Wide(locations) = self {
+            let compact: Result<Vec<_>, _> = locations
+                .iter()
+                .map(|&location| CompactLocation::try_from(location))
+                .collect();
+            if let Ok(compact) = compact {
+                *self = Self::Compact(compact);
+            }
+        }
+    }
+
+    /// Stores offsets in 64 bits
+    fn widen(&mut self) {
+        if let Self::Compact(locations) = self {
+            *self = Self::Wide(locations.iter().map(|&location| location.into()).collect());
+        }
+    }
+
+    /// Releases spare capacity
+    fn shrink_to_fit(&mut self) {
+        match self {
+            Self::Wide(locations) => locations.shrink_to_fit(),
+            Self::Compact(locations) => locations.shrink_to_fit(),
+        }
+    }
+
+    /// Returns number of bytes taken by locations
+    fn size(&self) -> usize {
+        match self {
+            Self::Wide(locations) => locations.capacity() * std::mem::size_of::<Location>(),
+            Self::Compact(locations) => {
+                locations.capacity() * std::mem::size_of::<CompactLocation>()
+            }
+        }
+    }
+}
+
+/// Locations of chunks by their ids
+///
+/// Ids are not reused, so a chunk id, that was read from a leaf, is never resolved to a
+/// location of another value. Locations of overwritten values are kept until the tree is
+/// loaded again, like their bytes in data files are
+#[derive(Default)]
+pub(crate) struct ChunkTable {
+    locations: RwLock<Locations>,
+}
+
+impl ChunkTable {
+    /// Creates table with given locations, indexed by ids of chunks
+    pub(crate) fn from_locations(locations: Locations) -> Self {
+        Self {
+            locations: RwLock::new(locations),
+        }
+    }
+
+    /// Adds chunks, that lie in given file at given offsets
+    ///
+    /// Returns id of the first chunk, other ones follow it
+    pub(crate) fn insert(&self, file: usize, offsets: &[u64]) -> u64 {
+        let mut locations = self.locations.write().unwrap();
+        let first = locations.len() as u64;
+        for &offset in offsets {
+            locations.push(Location {
+                file: file as u32,
+                offset,
+            });
+        }
+        first
+    }
+
+    /// Adds chunk with given location, e.g. one of another tree, and returns its id
+    pub(crate) fn adopt(&self, location: Location) -> u64 {
+        let mut locations = self.locations.write().unwrap();
+        locations.push(location);
+        locations.len() as u64 - 1
+    }
+
+    /// Returns location of chunk with given id, or None if there is no such chunk
+    pub(crate) fn get(&self, id: u64) -> Option<Location> {
+        self.locations.read().unwrap().get(id)
+    }
+
+    /// Moves chunk with given id to given location
+    pub(crate) fn relocate(&self, id: u64, location: Location) {
+        self.locations.write().unwrap().set(id, location);
+    }
+
+    /// Moves chunk with given id to given location, if it still lies at the expected one
+    ///
+    /// Returns whether the chunk was moved
+    pub(crate) fn relocate_from(&self, id: u64, expected: Location, location: Location) -> bool {
+        let mut locations = self.locations.write().unwrap();
+        if locations.get(id) != Some(expected) {
+            return false;
+        }
+        locations.set(id, location);
+        true
+    }
+
+    /// Makes table store offsets in 32 bits, if all of them fit
+    ///
+    /// Offset, that does not fit in 32 bits, makes the table store offsets in 64 bits again
+    pub(crate) fn compact(&self) {
+        self.locations.write().unwrap().compact();
+    }
+
+    /// Releases spare capacity of the table
+    pub(crate) fn shrink_to_fit(&self) {
+        self.locations.write().unwrap().shrink_to_fit();
+    }
+
+    /// Returns locations of all chunks, indexed by their ids
+    pub(crate) fn locations(&self) -> Locations {
+        self.locations.read().unwrap().clone()
+    }
+
+    /// Returns number of bytes taken by locations
+    pub(crate) fn size(&self) -> usize {
+        self.locations.read().unwrap().size()
+    }
+}
@@ -0,0 +1,366 @@
+//! Compaction of data files
+//!
+//! Values of overwritten and removed entries stay in data files as dead records, see
+//! [`crate::free_space`]. Compaction copies live values out of files, that hold the most
+//! dead bytes, to the current data file and removes those files
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use tokio::runtime::Handle;
+
+use crate::{
+    advice::Access,
+    bplus_tree::{
+        data_file_numbers, BPlus, BPlusKeySerializable, ChunkHandler, ChunkRef, BULK_BATCH,
+    },
+    chunk_table::Location,
+    error::Result,
+    events::TreeEvent,
+    free_space::Extent,
+    metrics::Phases,
+    record,
+    recorder::Operation,
+    runtime::Spawner,
+};
+
+/// Space taken by data files of the tree, in bytes, see [`BPlus::space_usage`]
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SpaceUsage {
+    /// Records of values, that entries and their previous versions refer to
+    pub live: u64,
+    /// All data files in the directory of the tree
+    pub total: u64,
+    /// Space taken by every data file, in ascending order of their numbers
+    pub files: Vec<FileUsage>,
+}
+
+impl SpaceUsage {
+    /// Returns number of bytes of records, that nothing refers to, e.g. of overwritten values
+    pub fn dead(&self) -> u64 {
+        self.total.saturating_sub(self.live)
+    }
+
+    /// Returns share of dead bytes in data files, 0 if there are none
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.dead() as f64 / self.total as f64
+    }
+}
+
+/// Space taken by a data file of the tree, in bytes, see [`SpaceUsage::files`]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct FileUsage {
+    /// Number of the data file
+    pub file: usize,
+    /// Records of values in the file, that entries and their previous versions refer to
+    pub live: u64,
+    /// All records in the file
+    pub total: u64,
+}
+
+impl FileUsage {
+    /// Returns number of bytes of records in the file, that nothing refers to
+    pub fn dead(&self) -> u64 {
+        self.total.saturating_sub(self.live)
+    }
+
+    /// Returns share of dead bytes in the file, 0 if there are none
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.dead() as f64 / self.total as f64
+    }
+}
+
+/// Result of a compaction of data files, see [`BPlus::compact`]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Number of values, that were copied to new data files
+    pub relocated: usize,
+    /// Number of data files, that were removed
+    pub removed_files: usize,
+}
+
+impl<K: BPlusKeySerializable> BPlus<K> {
+    /// Removes data file with given number, if it is not removed yet
+    async fn remove_data_file(&self, number: usize) -> Result<()> {
+        let path = self.path.join(number.to_string());
+        self.unblock(move || match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        })
+        .await??;
+        Ok(())
+    }
+
+    /// Returns space taken by records in data files and by records, that the tree refers to,
+    /// in total and for every data file
+    ///
+    /// Values in the write buffer are not written yet, so they are not counted. Data files
+    /// of a directory, that is shared with other trees, are counted as a whole, while only
+    /// values of this tree are live. Blob files are not counted, as they hold no dead bytes,
+    /// see [`BPlus::with_blob_threshold`]
+    ///
+    /// Returns Err(_) if data files could not be listed
+    pub async fn space_usage(&self) -> Result<SpaceUsage> {
+        let mut handlers = self.live_chunks().await;
+        handlers.retain(|handler| !self.is_in_blob(handler));
+        let live = handlers
+            .iter()
+            .map(|handler| record::HEADER_SIZE + handler.size as u64)
+            .sum();
+        let files = self.file_usage(&handlers).await?;
+        let total = files.iter().map(|file| file.total).sum();
+        Ok(SpaceUsage { live, total, files })
+    }
+
+    /// Returns extents of records in data files, that the tree no longer refers to,
+    /// by numbers of the files, in order of offsets
+    ///
+    /// Records of overwritten and removed values are recorded, once they are not kept
+    /// as previous versions, and forgotten with their files, when those are compacted.
+    /// Values of trees, that share data files, are dead only for this tree
+    pub fn free_space(&self) -> BTreeMap<usize, Vec<Extent>> {
+        self.free_space.lock().unwrap().extents()
+    }
+
+    /// Returns space taken by every data file in the directory of the tree, given handlers
+    /// of live values
+    ///
+    /// Returns Err(_) if data files could not be listed
+    async fn file_usage(&self, handlers: &[ChunkHandler]) -> Result<Vec<FileUsage>> {
+        let mut live = HashMap::new();
+        for handler in handlers {
+            if let Some(location) = self.chunks.get(handler.chunk) {
+                *live.entry(location.file as usize).or_insert(0) +=
+                    record::HEADER_SIZE + handler.size as u64;
+            }
+        }
+        let dir = self.path.clone();
+        let mut files = self
+            .unblock(move || -> io::Result<Vec<FileUsage>> {
+                let mut files = Vec::new();
+                for number in data_file_numbers(&dir)? {
+                    match std::fs::metadata(dir.join(number.to_string())) {
+                        Ok(metadata) => files.push(FileUsage {
+                            file: number,
+                            live: 0,
+                            total: metadata.len().saturating_sub(record::FILE_HEADER_SIZE),
+                        }),
+                        // File was removed by a concurrent compaction
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(files)
+            })
+            .await??;
+        for file in &mut files {
+            file.live = live.get(&file.file).copied().unwrap_or(0);
+        }
+        Ok(files)
+    }
+
+    /// Returns handlers of values, that entries and their previous versions refer to,
+    /// each chunk once
+    async fn live_chunks(&self) -> Vec<ChunkHandler> {
+        let mut handlers = Vec::new();
+        for partition in &self.partitions {
+            let entries = self.leaf_entries(partition).await;
+            handlers.extend(entries.into_iter().map(|(_, handler)| handler));
+        }
+        // Versions are collected after leaves, so a value, that was moved from a leaf to
+        // versions meanwhile, is collected at least once
+        if let Some(versions) = &self.versions {
+            let previous = versions.previous.lock().unwrap();
+            handlers.extend(previous.values().flatten().cloned());
+        }
+        let mut seen = HashSet::new();
+        handlers.retain(|handler| seen.insert(handler.chunk));
+        handlers
+    }
+
+    /// Moves values from data files with free space, see [`BPlus::free_space`], to new ones
+    /// in batches and removes those files, so space of overwritten and removed values
+    /// is reclaimed
+    ///
+    /// Unlike [`BPlus::rebuild`], changes of the tree wait only for the current batch, and
+    /// nodes are not repacked. [`TreeEvent::CompactionProgress`] is emitted after every batch
+    /// and [`TreeEvent::CompactionFinished`] at the end. Reads, that located values before
+    /// they were moved, may fail like during a rebuild, and the tree has to be saved again.
+    /// Data files, that are shared with other trees, are not compacted
+    ///
+    /// Returns Err(_) if values could not be copied, then files, that were not compacted
+    /// yet, are kept and values, that were copied, are read from new ones
+    pub async fn compact(&self) -> Result<CompactionReport> {
+        self.compact_files(usize::MAX).await
+    }
+
+    /// Compacts at most given number of data files, that have the most dead bytes,
+    /// see [`BPlus::compact`] and [`BPlus::free_space`]
+    ///
+    /// Files are compacted one by one, greatest garbage first, and every file is removed
+    /// as soon as its values are moved, so a compaction, that fails midway, still reclaims
+    /// space of files, that were compacted before. Files without dead bytes are kept
+    ///
+    /// Returns Err(_) if values could not be copied or a compacted file could not be removed
+    pub async fn compact_files(&self, limit: usize) -> Result<CompactionReport> {
+        let result = in_span!("compact"; async {
+            let _compaction = self.compaction.lock().await;
+            // Other trees may refer to values in shared data files
+            if Arc::strong_count(&self.data_file) > 1 {
+                trace_event!("data files are shared, compaction skipped");
+                return Ok(CompactionReport::default());
+            }
+            self.flush_buffer().await?;
+            // Changes in flight finish before the rotation, so every value in previous files
+            // is already referred to by an entry
+            let last_old = {
+                let _writes = self.writes.write().await;
+                self.start_data_file().await?
+            };
+
+            let mut victims: Vec<_> = self
+                .free_space
+                .lock()
+                .unwrap()
+                .dead()
+                .into_iter()
+                .filter(|&(file, _)| file <= last_old)
+                .collect();
+            victims.sort_by_key(|&(file, dead)| (Reverse(dead), file));
+            victims.truncate(limit);
+            let mut chunks: HashMap<_, Vec<_>> =
+                victims.iter().map(|&(file, _)| (file, Vec::new())).collect();
+            for handler in self.live_chunks().await {
+                let Some(location) = self.chunks.get(handler.chunk) else {
+                    continue;
+                };
+                if let Some(file) = chunks.get_mut(&(location.file as usize)) {
+                    file.push((location.offset, handler));
+                }
+            }
+
+            let total = chunks.values().map(Vec::len).sum();
+            let mut report = CompactionReport::default();
+            let mut phases = Phases::default();
+            for &(victim, _dead) in &victims {
+                let mut file = chunks.remove(&victim).unwrap_or_default();
+                // Values are read in order of their records
+                file.sort_unstable_by_key(|&(offset, _)| offset);
+                for batch in file.chunks(BULK_BATCH) {
+                    // Rebuild does not run in the middle of a batch
+                    let _writes = self.writes.read().await;
+                    let refs: Vec<_> = batch
+                        .iter()
+                        .map(|(_, handler)| self.locate(handler))
+                        .collect();
+                    let sizes: Vec<_> = batch.iter().map(|(_, handler)| handler.size).collect();
+                    let access = self.hints(Access::Compaction);
+                    let (values, _) = self
+                        .unblock(move || ChunkRef::read_many(&refs, access))
+                        .await??;
+                    let (number, offsets, _) =
+                        self.append_values(values.concat(), &sizes, &mut phases).await?;
+                    // Values are released under the lock of free space, so none is released,
+                    // while copies are checked
+                    let mut free_space = self.free_space.lock().unwrap();
+                    let dead = free_space.dead_offsets(victim);
+                    for (&(old, ref handler), offset) in batch.iter().zip(offsets) {
+                        let old = Location {
+                            file: victim as u32,
+                            offset: old,
+                        };
+                        let location = Location {
+                            file: number as u32,
+                            offset,
+                        };
+                        // Value was overwritten or moved since the scan, so its copy is dead
+                        if dead.contains(&old.offset)
+                            || !self.chunks.relocate_from(handler.chunk, old, location)
+                        {
+                            free_space.record(location, record::HEADER_SIZE + handler.size as u64);
+                        }
+                    }
+                    drop(free_space);
+                    self.dirty.store(true, Ordering::Release);
+                    report.relocated += batch.len();
+                    self.hooks.emit(TreeEvent::CompactionProgress {
+                        relocated: report.relocated,
+                        total,
+                    });
+                }
+                self.remove_data_file(victim).await?;
+                self.free_space.lock().unwrap().forget(victim);
+                report.removed_files += 1;
+                trace_event!(file = victim, dead = _dead, "data file compacted");
+            }
+
+            // Tree has to be saved again after compaction, so dead blobs are not needed either
+            let dead_blobs = self.free_space.lock().unwrap().blobs();
+            self.remove_dead_blobs(dead_blobs).await;
+
+            trace_event!(relocated = report.relocated, removed_files = report.removed_files, "data files compacted");
+            self.hooks.emit(TreeEvent::CompactionFinished {
+                relocated: report.relocated,
+                removed_files: report.removed_files,
+            });
+            Ok(report)
+        });
+        if result.is_ok() {
+            self.record(Operation::Compact { limit });
+        }
+        result
+    }
+
+    /// Starts background task, that checks data files every given interval and compacts
+    /// them, once share of dead bytes reaches given threshold, see [`BPlus::compact`]
+    ///
+    /// Task runs on the spawner of the tree, or on the ambient tokio runtime if there is none,
+    /// and stops after the tree is dropped. Failed compactions are retried on the next check
+    ///
+    /// Returns Err(_) if tree has no spawner and is called outside of the tokio runtime context
+    ///
+    /// Panics if threshold does not lie in (0, 1)
+    pub fn start_compaction(self: &Arc<Self>, threshold: f64, interval: Duration) -> Result<()> {
+        assert!(
+            threshold > 0.0 && threshold < 1.0,
+            "Threshold of dead bytes must lie in (0, 1)"
+        );
+        let spawner: Arc<dyn Spawner> = match &self.spawner {
+            Some(spawner) => spawner.clone(),
+            None => Arc::new(Handle::try_current().map_err(io::Error::other)?),
+        };
+        let tree = Arc::downgrade(self);
+        let timer = spawner.clone();
+        spawner.spawn(Box::pin(async move {
+            loop {
+                timer.sleep(interval).await;
+                // Tree is held only during a check, so the task does not keep it alive
+                let Some(tree) = tree.upgrade() else {
+                    return;
+                };
+                let result = match tree.space_usage().await {
+                    Ok(usage) if usage.dead_ratio() >= threshold => {
+                        tree.compact().await.map(|_| ())
+                    }
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err),
+                };
+                if let Err(_err) = result {
+                    trace_warn!(error = %_err, "background compaction failed");
+                }
+            }
+        }));
+        Ok(())
+    }
+}
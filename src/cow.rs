@@ -0,0 +1,607 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    io, mem,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bplus_tree::BPlusKeySerializable,
+    buffer_pool::{BufferPool, BufferPoolBuilder, NodeId, PagedNode, PagedValue},
+    error::{BPlusError, Result},
+    page::{Page, PageId, PageKind},
+    search::{search, search_by_key},
+};
+
+/// Pages, that hold meta records of even and odd versions
+const META_PAGES: [PageId; 2] = [1, 2];
+
+/// Root pointer of a committed version, written to the meta page of its parity
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    version: u64,
+    root: Option<NodeId>,
+    t: usize,
+}
+
+/// State shared by the tree, its snapshots and write transactions
+struct Shared<K: BPlusKeySerializable, V: PagedValue> {
+    pool: BufferPool<K, V>,
+    t: usize,
+    versions: Mutex<Versions>,
+    /// Held by the only active write transaction
+    writer: Mutex<()>,
+}
+
+/// Published versions of the tree
+struct Versions {
+    /// Root of the latest version, None if the tree is empty
+    root: Option<NodeId>,
+    version: u64,
+    /// Number of open snapshots by their version
+    readers: BTreeMap<u64, usize>,
+    /// Pages, that are reachable only from versions up to the given one
+    garbage: Vec<(u64, Vec<NodeId>)>,
+}
+
+/// Copy-on-write B+ tree: every commit writes changed nodes to new pages and publishes
+/// a new immutable root, pages of older versions are kept until no snapshot can reach them
+///
+/// Values are stored inline in leaves, so a leaf with all its values must fit in a page
+pub struct CowTree<K: BPlusKeySerializable, V: PagedValue> {
+    shared: Arc<Shared<K, V>>,
+}
+
+/// Immutable version of the tree, that stays readable while it is alive
+pub struct Snapshot<K: BPlusKeySerializable, V: PagedValue> {
+    shared: Arc<Shared<K, V>>,
+    root: Option<NodeId>,
+    version: u64,
+}
+
+/// Write transaction: changes become visible to new snapshots on commit
+/// and are discarded if transaction is dropped without commit
+///
+/// Only one write transaction is active at a time
+pub struct WriteTxn<'a, K: BPlusKeySerializable, V: PagedValue> {
+    shared: &'a Shared<K, V>,
+    _writer: MutexGuard<'a, ()>,
+    root: Option<NodeId>,
+    /// Pages allocated by this transaction, they are not visible to readers and are changed in place
+    fresh: HashSet<NodeId>,
+    /// Pages of the base version, that were replaced by this transaction
+    obsolete: Vec<NodeId>,
+}
+
+impl<K: BPlusKeySerializable, V: PagedValue + Clone> CowTree<K, V> {
+    /// Creates empty tree with given t in a new page file by given path, that is cached by given pool
+    ///
+    /// t represents minimal and maximal quantity of keys in node
+    ///
+    /// Returns Err(_) if page file already exists
+    pub fn create(path: &Path, t: usize, pool: BufferPoolBuilder) -> Result<Self> {
+        if path.exists() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+        let pool = pool.open(path)?;
+        for id in META_PAGES {
+            let allocated = pool.allocate_page()?;
+            if allocated != id {
+                return Err(BPlusError::Corruption(format!(
+                    "page {allocated} was allocated for meta page {id}"
+                )));
+            }
+        }
+        let meta = Meta {
+            version: 0,
+            root: None,
+            t,
+        };
+        Self::write_meta(&pool, &meta)?;
+        Ok(Self::with_meta(pool, meta, Vec::new()))
+    }
+
+    /// Opens tree in a page file by given path at its latest durably committed version
+    ///
+    /// Pages, that are reachable only from the previous version, are freed after the next commit,
+    /// and pages, that are not reachable from either of them, are freed right away
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if neither of meta pages is valid
+    pub fn open(path: &Path, pool: BufferPoolBuilder) -> Result<Self> {
+        let pool = pool.open(path)?;
+        if pool.page_count() <= META_PAGES[1] {
+            return Err(BPlusError::Corruption(
+                "page file has no meta pages".to_string(),
+            ));
+        }
+        // Meta page, which write was torn, fails its checksum and older version is used
+        let mut metas: Vec<_> = META_PAGES
+            .into_iter()
+            .filter_map(|id| Self::read_meta(&pool, id).ok())
+            .collect();
+        metas.sort_by_key(|meta| Reverse(meta.version));
+        let mut metas = metas.into_iter();
+        let meta = metas
+            .next()
+            .ok_or_else(|| BPlusError::Corruption("no valid meta page".to_string()))?;
+        let previous = metas.find(|previous| previous.version + 1 == meta.version);
+
+        let obsolete = Self::reclaim(&pool, meta.root, previous.as_ref().and_then(|p| p.root))?;
+        let garbage = match previous {
+            Some(previous) if !obsolete.is_empty() => vec![(previous.version, obsolete)],
+            _ => Vec::new(),
+        };
+        Ok(Self::with_meta(pool, meta, garbage))
+    }
+
+    /// Frees pages, that are neither free nor reachable from given roots of the latest
+    /// and the previous versions, such as pages of interrupted transactions
+    ///
+    /// Returns nodes, that are reachable only from the previous version
+    fn reclaim(
+        pool: &BufferPool<K, V>,
+        root: Option<NodeId>,
+        previous: Option<NodeId>,
+    ) -> Result<Vec<NodeId>> {
+        let live = Self::reachable(pool, root)?;
+        let previous = Self::reachable(pool, previous)?;
+        let mut kept: HashSet<_> = pool.free_pages()?.into_iter().collect();
+        for &id in live.iter().chain(&previous) {
+            kept.extend(pool.overflow_pages(id)?);
+        }
+        // Every page is checked on its own, so overflow pages of freed nodes are not freed twice
+        for id in META_PAGES[1] + 1..pool.page_count() {
+            if !live.contains(&id) && !previous.contains(&id) && !kept.contains(&id) {
+                pool.free_page(id)?;
+            }
+        }
+        Ok(previous.difference(&live).copied().collect())
+    }
+
+    /// Returns ids of all nodes of the tree with given root
+    fn reachable(pool: &BufferPool<K, V>, root: Option<NodeId>) -> Result<HashSet<NodeId>> {
+        let mut nodes = HashSet::new();
+        let mut stack: Vec<_> = root.into_iter().collect();
+        while let Some(id) = stack.pop() {
+            if !nodes.insert(id) {
+                continue;
+            }
+            if let PagedNode::Internal { children, .. } = &*pool.get(id)? {
+                stack.extend(children);
+            }
+        }
+        Ok(nodes)
+    }
+
+    fn with_meta(pool: BufferPool<K, V>, meta: Meta, garbage: Vec<(u64, Vec<NodeId>)>) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                pool,
+                t: meta.t,
+                versions: Mutex::new(Versions {
+                    root: meta.root,
+                    version: meta.version,
+                    readers: BTreeMap::new(),
+                    garbage,
+                }),
+                writer: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Reads meta record from the page by given id
+    fn read_meta(pool: &BufferPool<K, V>, id: PageId) -> Result<Meta> {
+        let page = pool.read_page(id)?;
+        if page.kind()? != PageKind::Meta || page.is_empty() {
+            return Err(BPlusError::Corruption(format!(
+                "page {id} is not a meta page"
+            )));
+        }
+        Ok(bincode::deserialize(page.cell(0)?)?)
+    }
+
+    /// Writes meta record to the meta page of its version parity and syncs it
+    fn write_meta(pool: &BufferPool<K, V>, meta: &Meta) -> Result<()> {
+        let mut page = Page::new(PageKind::Meta, pool.page_size());
+        page.push(&bincode::serialize(meta)?);
+        pool.write_page(META_PAGES[(meta.version % 2) as usize], &page)
+    }
+
+    /// Returns number of the latest committed version
+    pub fn version(&self) -> u64 {
+        self.shared.versions.lock().unwrap().version
+    }
+
+    /// Opens snapshot of the latest committed version
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        let mut versions = self.shared.versions.lock().unwrap();
+        let version = versions.version;
+        *versions.readers.entry(version).or_default() += 1;
+        Snapshot {
+            shared: Arc::clone(&self.shared),
+            root: versions.root,
+            version,
+        }
+    }
+
+    /// Starts write transaction, waiting for the active one to finish
+    pub fn begin_write(&self) -> WriteTxn<'_, K, V> {
+        let writer = self.shared.writer.lock().unwrap();
+        let root = self.shared.versions.lock().unwrap().root;
+        WriteTxn {
+            shared: &self.shared,
+            _writer: writer,
+            root,
+            fresh: HashSet::new(),
+            obsolete: Vec::new(),
+        }
+    }
+
+    /// Returns number of pages of old versions, that are kept for open snapshots
+    pub fn retained_pages(&self) -> usize {
+        let versions = self.shared.versions.lock().unwrap();
+        versions.garbage.iter().map(|(_, pages)| pages.len()).sum()
+    }
+
+    /// Evicts cached nodes, until they take at most given number of bytes, see [`BufferPool::shrink`]
+    ///
+    /// Returns number of freed bytes
+    pub fn shrink(&self, target: usize) -> Result<usize> {
+        self.shared.pool.shrink(target)
+    }
+}
+
+impl<K: BPlusKeySerializable, V: PagedValue + Clone> Shared<K, V> {
+    /// Returns value by given key in the tree with given root
+    fn get(&self, root: Option<NodeId>, key: &K) -> Result<Option<V>> {
+        let Some(mut id) = root else {
+            return Ok(None);
+        };
+        loop {
+            match &*self.pool.get(id)? {
+                PagedNode::Internal { keys, children } => {
+                    id = children[child_index(keys, key)];
+                }
+                PagedNode::Leaf { entries, .. } => {
+                    return Ok(search_by_key(entries, key, |(k, _)| k)
+                        .ok()
+                        .map(|pos| entries[pos].1.clone()));
+                }
+            }
+        }
+    }
+
+    /// Appends entries of subtree with keys in given range to the result
+    ///
+    /// Returns whether an entry after the end of the range was reached
+    fn scan<R: RangeBounds<K>>(
+        &self,
+        id: NodeId,
+        range: &R,
+        result: &mut Vec<(K, V)>,
+    ) -> Result<bool> {
+        match &*self.pool.get(id)? {
+            PagedNode::Internal { keys, children } => {
+                let start = match range.start_bound() {
+                    Bound::Included(key) | Bound::Excluded(key) => child_index(keys, key),
+                    Bound::Unbounded => 0,
+                };
+                for child in &children[start..] {
+                    if self.scan(*child, range, result)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            PagedNode::Leaf { entries, .. } => {
+                for (key, value) in entries {
+                    if range.contains(key) {
+                        result.push((key.clone(), value.clone()));
+                    } else if is_after_end(range, key) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl<K: BPlusKeySerializable, V: PagedValue> Shared<K, V> {
+    /// Frees pages, that are not reachable from any open snapshot or the two latest versions
+    ///
+    /// Previous version is kept, as the tree falls back to it if the last meta page is torn
+    ///
+    /// Best-effort: pages, that could not be freed, are queued again and retried later
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn collect_garbage(&self, versions: &mut Versions) {
+        let durable = versions.version.saturating_sub(1);
+        let oldest = versions
+            .readers
+            .keys()
+            .next()
+            .map_or(durable, |oldest| durable.min(*oldest));
+        let (free, keep) = mem::take(&mut versions.garbage)
+            .into_iter()
+            .partition(|(version, _)| *version < oldest);
+        versions.garbage = keep;
+        for (version, pages) in free {
+            let mut failed = Vec::new();
+            for id in pages {
+                if let Err(error) = self.pool.free(id) {
+                    trace_warn!(page = id, error = %error, "page of an old version was not freed");
+                    failed.push(id);
+                }
+            }
+            if !failed.is_empty() {
+                versions.garbage.push((version, failed));
+            }
+        }
+    }
+}
+
+impl<K: BPlusKeySerializable, V: PagedValue + Clone> Snapshot<K, V> {
+    /// Returns number of the version of this snapshot
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns value by given key
+    ///
+    /// Returns Err(_) if some node could not be read
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.shared.get(self.root, key)
+    }
+
+    /// Returns all entries with keys in given range, in ascending order of keys
+    ///
+    /// Returns Err(_) if some node could not be read
+    pub fn scan<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>> {
+        let mut result = Vec::new();
+        if let Some(root) = self.root {
+            self.shared.scan(root, &range, &mut result)?;
+        }
+        Ok(result)
+    }
+}
+
+impl<K: BPlusKeySerializable, V: PagedValue> Drop for Snapshot<K, V> {
+    fn drop(&mut self) {
+        let mut versions = self.shared.versions.lock().unwrap();
+        if let Some(readers) = versions.readers.get_mut(&self.version) {
+            *readers -= 1;
+            if *readers == 0 {
+                versions.readers.remove(&self.version);
+            }
+        }
+        self.shared.collect_garbage(&mut versions);
+    }
+}
+
+impl<K: BPlusKeySerializable, V: PagedValue + Clone> WriteTxn<'_, K, V> {
+    /// Returns value by given key, including changes of this transaction
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.shared.get(self.root, key)
+    }
+
+    /// Inserts value by given key, replacing the previous one
+    ///
+    /// Returns Err(BPlusError::PageOverflow { .. }) if leaf with the value does not fit in a page
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        let Some(root) = self.root else {
+            let leaf = PagedNode::Leaf {
+                entries: vec![(key, value)],
+                next: None,
+            };
+            self.root = Some(self.allocate(leaf)?);
+            return Ok(());
+        };
+
+        let (root, split) = self.insert_into(root, key, value)?;
+        self.root = Some(match split {
+            Some((separator, right)) => self.allocate(PagedNode::Internal {
+                keys: vec![separator],
+                children: vec![root, right],
+            })?,
+            None => root,
+        });
+        Ok(())
+    }
+
+    /// Removes value by given key, leaves are not merged
+    ///
+    /// Returns whether the key was present
+    pub fn remove(&mut self, key: &K) -> Result<bool> {
+        let Some(root) = self.root else {
+            return Ok(false);
+        };
+        match self.remove_from(root, key)? {
+            Some(root) => {
+                self.root = Some(root);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Durably publishes changes of this transaction as a new version and returns its number
+    ///
+    /// New pages are synced first, then the root pointer is written to the meta page,
+    /// so after a crash the tree opens either at the previous or at the new version
+    ///
+    /// Once the meta page is written, commit succeeds, even if pages of old versions are not freed
+    pub fn commit(mut self) -> Result<u64> {
+        self.shared.pool.flush()?;
+        let mut versions = self.shared.versions.lock().unwrap();
+        let base = versions.version;
+        let meta = Meta {
+            version: base + 1,
+            root: self.root,
+            t: self.shared.t,
+        };
+        CowTree::write_meta(&self.shared.pool, &meta)?;
+        versions.root = self.root;
+        versions.version += 1;
+        versions.garbage.push((base, mem::take(&mut self.obsolete)));
+        // Pages are published and must not be freed on drop
+        self.fresh.clear();
+        self.shared.collect_garbage(&mut versions);
+        Ok(versions.version)
+    }
+
+    /// Inserts value in subtree with given root
+    ///
+    /// Returns new id of the root of subtree, and separator with the new right sibling if it was split
+    fn insert_into(
+        &mut self,
+        id: NodeId,
+        key: K,
+        value: V,
+    ) -> Result<(NodeId, Option<(K, NodeId)>)> {
+        let t = self.shared.t;
+        match &*self.shared.pool.get(id)? {
+            PagedNode::Leaf { entries, .. } => {
+                let mut entries = entries.clone();
+                match search_by_key(&entries, &key, |(k, _)| k) {
+                    Ok(pos) => entries[pos].1 = value,
+                    Err(pos) => entries.insert(pos, (key, value)),
+                }
+                if entries.len() < 2 * t {
+                    return Ok((
+                        self.write(
+                            id,
+                            PagedNode::Leaf {
+                                entries,
+                                next: None,
+                            },
+                        )?,
+                        None,
+                    ));
+                }
+                let right = entries.split_off(t);
+                let separator = right[0].0.clone();
+                let left = self.write(
+                    id,
+                    PagedNode::Leaf {
+                        entries,
+                        next: None,
+                    },
+                )?;
+                let right = self.allocate(PagedNode::Leaf {
+                    entries: right,
+                    next: None,
+                })?;
+                Ok((left, Some((separator, right))))
+            }
+            PagedNode::Internal { keys, children } => {
+                let pos = child_index(keys, &key);
+                let (child, split) = self.insert_into(children[pos], key, value)?;
+                let mut keys = keys.clone();
+                let mut children = children.clone();
+                children[pos] = child;
+                if let Some((separator, right)) = split {
+                    keys.insert(pos, separator);
+                    children.insert(pos + 1, right);
+                }
+                if keys.len() < 2 * t {
+                    return Ok((
+                        self.write(id, PagedNode::Internal { keys, children })?,
+                        None,
+                    ));
+                }
+                let right_keys = keys.split_off(t + 1);
+                let separator = keys.pop().unwrap();
+                let right_children = children.split_off(t + 1);
+                let left = self.write(id, PagedNode::Internal { keys, children })?;
+                let right = self.allocate(PagedNode::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                })?;
+                Ok((left, Some((separator, right))))
+            }
+        }
+    }
+
+    /// Removes key from subtree with given root
+    ///
+    /// Returns new id of the root of subtree, or None if key is not present
+    fn remove_from(&mut self, id: NodeId, key: &K) -> Result<Option<NodeId>> {
+        match &*self.shared.pool.get(id)? {
+            PagedNode::Leaf { entries, .. } => {
+                let Ok(pos) = search_by_key(entries, key, |(k, _)| k) else {
+                    return Ok(None);
+                };
+                let mut entries = entries.clone();
+                entries.remove(pos);
+                Ok(Some(self.write(
+                    id,
+                    PagedNode::Leaf {
+                        entries,
+                        next: None,
+                    },
+                )?))
+            }
+            PagedNode::Internal { keys, children } => {
+                let pos = child_index(keys, key);
+                let Some(child) = self.remove_from(children[pos], key)? else {
+                    return Ok(None);
+                };
+                let mut children = children.clone();
+                children[pos] = child;
+                let node = PagedNode::Internal {
+                    keys: keys.clone(),
+                    children,
+                };
+                Ok(Some(self.write(id, node)?))
+            }
+        }
+    }
+
+    /// Writes new content of a node: in place if node was created by this transaction,
+    /// otherwise to a new page. Returns id of the written node
+    fn write(&mut self, id: NodeId, node: PagedNode<K, V>) -> Result<NodeId> {
+        if self.fresh.contains(&id) {
+            self.shared.pool.update(id, node)?;
+            return Ok(id);
+        }
+        self.obsolete.push(id);
+        self.allocate(node)
+    }
+
+    /// Writes node to a new page
+    fn allocate(&mut self, node: PagedNode<K, V>) -> Result<NodeId> {
+        let id = self.shared.pool.allocate(node)?;
+        self.fresh.insert(id);
+        Ok(id)
+    }
+}
+
+impl<K: BPlusKeySerializable, V: PagedValue> Drop for WriteTxn<'_, K, V> {
+    fn drop(&mut self) {
+        // Pages of an aborted transaction are not reachable from any version
+        for id in self.fresh.drain() {
+            let _ = self.shared.pool.free(id);
+        }
+    }
+}
+
+/// Returns index of child of internal node with given keys, that may contain given key
+fn child_index<K: Ord + 'static>(keys: &[K], key: &K) -> usize {
+    match search(keys, key) {
+        Ok(pos) => pos + 1,
+        Err(pos) => pos,
+    }
+}
+
+/// Returns whether given key is after the end of given range
+fn is_after_end<K: Ord, R: RangeBounds<K>>(range: &R, key: &K) -> bool {
+    match range.end_bound() {
+        Bound::Included(end) => key > end,
+        Bound::Excluded(end) => key >= end,
+        Bound::Unbounded => false,
+    }
+}
@@ -0,0 +1,69 @@
+use std::io;
+
+use thiserror::Error;
+
+/// Errors, that can be returned by the B+ tree
+#[derive(Debug, Error)]
+pub enum BPlusError {
+    /// There is no value by the given key
+    #[error("key not found")]
+    NotFound,
+    /// Tree or its data files are in inconsistent state
+    #[error("tree is corrupted: {0}")]
+    Corruption(String),
+    /// Underlying I/O operation failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Tree or stored value could not be (de)serialized
+    #[error("serialization failed: {0}")]
+    Serialization(#[from] bincode::Error),
+    /// Node latch was not acquired in time
+    #[error("timed out waiting for a lock")]
+    LockTimeout,
+    /// Background task was dropped before it completed
+    #[error("background task was cancelled")]
+    Cancelled,
+    /// Storage is closed and does not accept operations
+    #[error("storage is closed")]
+    Closed,
+    /// Node does not fit in one page
+    #[error("node of {size} bytes does not fit in a page of {page_size} bytes")]
+    PageOverflow { size: usize, page_size: usize },
+    /// Key is larger than the configured limit
+    #[error("key of {size} bytes exceeds limit of {limit} bytes")]
+    KeyTooLarge { size: u64, limit: u64 },
+    /// Value is larger than the configured limit
+    #[error("value of {size} bytes exceeds limit of {limit} bytes")]
+    ValueTooLarge { size: u64, limit: u64 },
+    /// Entry was changed since the expected version
+    #[error("expected version {expected:?} of the entry, found {actual:?}")]
+    VersionConflict {
+        expected: Option<u64>,
+        actual: Option<u64>,
+    },
+    /// Key is already in the tree, while it was expected to be absent
+    #[error("key already exists")]
+    KeyExists,
+    /// File was written in a version of the format, that this crate does not read
+    #[error("format version {version} is not supported, supported is {supported}")]
+    UnsupportedVersion { version: u32, supported: u32 },
+}
+
+/// Result type of the B+ tree operations
+pub type Result<T> = std::result::Result<T, BPlusError>;
+
+impl From<BPlusError> for io::Error {
+    fn from(error: BPlusError) -> Self {
+        match error {
+            BPlusError::Io(e) => e,
+            BPlusError::NotFound => io::Error::new(io::ErrorKind::NotFound, error),
+            BPlusError::LockTimeout => io::Error::new(io::ErrorKind::TimedOut, error),
+            BPlusError::KeyExists => io::Error::new(io::ErrorKind::AlreadyExists, error),
+            BPlusError::Corruption(_) => io::Error::new(io::ErrorKind::InvalidData, error),
+            BPlusError::UnsupportedVersion { .. } => {
+                io::Error::new(io::ErrorKind::Unsupported, error)
+            }
+            error => io::Error::other(error),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::sync::{Arc, Mutex};
+
+/// Event, that happened to the tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TreeEvent {
+    /// Node was split in two
+    NodeSplit {
+        /// Whether split node is a leaf
+        leaf: bool,
+    },
+    /// Root was split, so the tree grew by one level
+    RootHeightChanged {
+        /// New number of levels of the tree, including leaves
+        height: usize,
+    },
+    /// Values are now written to a new data file
+    FileRotated {
+        /// Number of the new data file
+        file_number: usize,
+    },
+    /// Corruption of the tree structure or its data files was detected
+    CorruptionDetected {
+        /// Description of the corruption
+        message: String,
+    },
+    /// Batch of values was moved to new data files by a compaction
+    CompactionProgress {
+        /// Number of values moved so far
+        relocated: usize,
+        /// Number of values, that the compaction moves
+        total: usize,
+    },
+    /// Compaction moved all values and removed old data files
+    CompactionFinished {
+        /// Number of moved values
+        relocated: usize,
+        /// Number of removed data files
+        removed_files: usize,
+    },
+    /// Memory of the tree exceeded its limit, so the tree is shrunk
+    MemoryLimitExceeded {
+        /// Bytes taken by the tree
+        usage: usize,
+        /// Limit of memory in bytes
+        limit: usize,
+    },
+}
+
+/// Callback, that is called on every event of the tree
+pub type EventHook = dyn Fn(&TreeEvent) + Send + Sync;
+
+/// Registered event hooks of the tree
+#[derive(Default)]
+pub(crate) struct Hooks {
+    hooks: Mutex<Vec<Arc<EventHook>>>,
+}
+
+impl Hooks {
+    /// Registers new hook
+    pub(crate) fn register(&self, hook: Arc<EventHook>) {
+        self.hooks.lock().unwrap().push(hook);
+    }
+
+    /// Calls all registered hooks with given event
+    pub(crate) fn emit(&self, event: TreeEvent) {
+        // Hooks are called without holding the lock, so they may register other hooks
+        let hooks = self.hooks.lock().unwrap().clone();
+        for hook in hooks {
+            hook(&event);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use crate::buffer_pool::NodeId;
+
+/// Policy, by which buffer pool chooses nodes to evict
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts least recently used node
+    #[default]
+    Lru,
+    /// Approximates LRU with a reference bit per node and a clock hand sweeping over them
+    Clock,
+    /// 2Q: nodes accessed once live in a FIFO queue and are evicted first,
+    /// so a long scan does not flush nodes, that are accessed repeatedly
+    TwoQueue,
+}
+
+/// Tracks accesses to cached nodes and chooses victims for eviction
+pub(crate) trait Replacer: Send {
+    /// Starts tracking newly cached node
+    fn insert(&mut self, id: NodeId);
+
+    /// Records access to a cached node
+    fn touch(&mut self, id: NodeId);
+
+    /// Stops tracking node, that left the cache
+    fn remove(&mut self, id: NodeId);
+
+    /// Chooses node to evict among those, for which `evictable` returns true, and stops tracking it
+    fn victim(&mut self, evictable: &dyn Fn(NodeId) -> bool) -> Option<NodeId>;
+}
+
+impl EvictionPolicy {
+    /// Creates replacer, that implements this policy for a pool of given number of frames
+    pub(crate) fn replacer(self, frames: usize) -> Box<dyn Replacer> {
+        match self {
+            EvictionPolicy::Lru => Box::<Lru>::default(),
+            EvictionPolicy::Clock => Box::<Clock>::default(),
+            EvictionPolicy::TwoQueue => Box::new(TwoQueue::new(frames)),
+        }
+    }
+}
+
+/// Nodes ordered by tick of their last access
+#[derive(Default)]
+struct Lru {
+    order: BTreeMap<u64, NodeId>,
+    ticks: HashMap<NodeId, u64>,
+    tick: u64,
+}
+
+impl Replacer for Lru {
+    fn insert(&mut self, id: NodeId) {
+        self.touch(id);
+    }
+
+    fn touch(&mut self, id: NodeId) {
+        self.remove(id);
+        self.tick += 1;
+        self.order.insert(self.tick, id);
+        self.ticks.insert(id, self.tick);
+    }
+
+    fn remove(&mut self, id: NodeId) {
+        if let Some(tick) = self.ticks.remove(&id) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(NodeId) -> bool) -> Option<NodeId> {
+        let id = *self.order.values().find(|id| evictable(**id))?;
+        self.remove(id);
+        Some(id)
+    }
+}
+
+/// Ring of nodes with reference bits
+#[derive(Default)]
+struct Clock {
+    ring: Vec<NodeId>,
+    /// Position in the ring and reference bit of every node
+    entries: HashMap<NodeId, (usize, bool)>,
+    hand: usize,
+}
+
+impl Replacer for Clock {
+    fn insert(&mut self, id: NodeId) {
+        if let Some((_, referenced)) = self.entries.get_mut(&id) {
+            *referenced = true;
+            return;
+        }
+        self.entries.insert(id, (self.ring.len(), true));
+        self.ring.push(id);
+    }
+
+    fn touch(&mut self, id: NodeId) {
+        if let Some((_, referenced)) = self.entries.get_mut(&id) {
+            *referenced = true;
+        }
+    }
+
+    fn remove(&mut self, id: NodeId) {
+        let Some((position, _)) = self.entries.remove(&id) else {
+            return;
+        };
+        self.ring.swap_remove(position);
+        if let Some(moved) = self.ring.get(position) {
+            self.entries.get_mut(moved).unwrap().0 = position;
+        }
+        if self.hand >= self.ring.len() {
+            self.hand = 0;
+        }
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(NodeId) -> bool) -> Option<NodeId> {
+        // Second pass finds a victim, if there is an evictable node
+        for _ in 0..2 * self.ring.len() {
+            let id = self.ring[self.hand];
+            self.hand = (self.hand + 1) % self.ring.len();
+            if !evictable(id) {
+                continue;
+            }
+            let referenced = &mut self.entries.get_mut(&id).unwrap().1;
+            if *referenced {
+                *referenced = false;
+            } else {
+                self.remove(id);
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+/// Simplified 2Q: FIFO of nodes accessed once, LRU of nodes accessed again,
+/// and ghost FIFO of ids recently evicted from the first queue
+struct TwoQueue {
+    /// Nodes accessed once, evicted first while there are more than `in_limit` of them
+    fresh: Lru,
+    /// Nodes, that were accessed again
+    hot: Lru,
+    ghosts: VecDeque<NodeId>,
+    ghost_ids: HashSet<NodeId>,
+    in_limit: usize,
+    ghost_limit: usize,
+}
+
+impl TwoQueue {
+    fn new(frames: usize) -> Self {
+        Self {
+            fresh: Lru::default(),
+            hot: Lru::default(),
+            ghosts: VecDeque::new(),
+            ghost_ids: HashSet::new(),
+            in_limit: (frames / 4).max(1),
+            ghost_limit: (frames / 2).max(1),
+        }
+    }
+
+    /// Remembers id of node evicted from the fresh queue
+    fn remember(&mut self, id: NodeId) {
+        if self.ghost_ids.insert(id) {
+            self.ghosts.push_back(id);
+        }
+        while self.ghosts.len() > self.ghost_limit {
+            let old = self.ghosts.pop_front().unwrap();
+            self.ghost_ids.remove(&old);
+        }
+    }
+}
+
+impl Replacer for TwoQueue {
+    fn insert(&mut self, id: NodeId) {
+        if self.hot.ticks.contains_key(&id) || self.fresh.ticks.contains_key(&id) {
+            return self.touch(id);
+        }
+        if self.ghost_ids.remove(&id) {
+            self.ghosts.retain(|ghost| *ghost != id);
+            self.hot.insert(id);
+        } else {
+            // Fresh queue is FIFO, so nodes are not moved on later accesses
+            self.fresh.insert(id);
+        }
+    }
+
+    fn touch(&mut self, id: NodeId) {
+        if self.hot.ticks.contains_key(&id) {
+            self.hot.touch(id);
+        }
+    }
+
+    fn remove(&mut self, id: NodeId) {
+        self.fresh.remove(id);
+        self.hot.remove(id);
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(NodeId) -> bool) -> Option<NodeId> {
+        if self.fresh.ticks.len() > self.in_limit || self.hot.ticks.is_empty() {
+            if let Some(id) = self.fresh.victim(evictable) {
+                self.remember(id);
+                return Some(id);
+            }
+        }
+        self.hot.victim(evictable).or_else(|| {
+            let id = self.fresh.victim(evictable)?;
+            self.remember(id);
+            Some(id)
+        })
+    }
+}
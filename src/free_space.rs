@@ -0,0 +1,103 @@
+//! Map of free space in data files
+//!
+//! Records of values, that the tree no longer refers to, e.g. of overwritten and removed
+//! values, are remembered as dead extents of their data files. So compaction knows, which
+//! files hold the most garbage and where it lies, until the files are removed. Dead blob
+//! files are remembered, until they are removed after a save or by compaction
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chunk_table::Location;
+
+/// Range of bytes in a data file, taken by a record, that nothing refers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extent {
+    /// Offset of the record in the data file
+    pub offset: u64,
+    /// Length of the record with its header
+    pub len: u64,
+}
+
+/// Dead extents of data files, by numbers of the files, and dead blob files
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct FreeSpace {
+    files: BTreeMap<usize, Vec<Extent>>,
+    /// Ids of blob files, that the tree no longer refers to
+    blobs: BTreeSet<u64>,
+}
+
+impl FreeSpace {
+    /// Records, that record of given length at given location is dead
+    pub(crate) fn record(&mut self, location: Location, len: u64) {
+        self.files
+            .entry(location.file as usize)
+            .or_default()
+            .push(Extent {
+                offset: location.offset,
+                len,
+            });
+    }
+
+    /// Records, that blob file with given id is dead
+    pub(crate) fn record_blob(&mut self, id: u64) {
+        self.blobs.insert(id);
+    }
+
+    /// Returns ids of dead blob files
+    pub(crate) fn blobs(&self) -> Vec<u64> {
+        self.blobs.iter().copied().collect()
+    }
+
+    /// Keeps only given sorted ids of dead blob files, e.g. ones, that were dead before
+    /// a snapshot was taken
+    pub(crate) fn retain_blobs(&mut self, ids: &[u64]) {
+        self.blobs.retain(|id| ids.binary_search(id).is_ok());
+    }
+
+    /// Forgets given dead blob files, e.g. after they are removed
+    pub(crate) fn forget_blobs(&mut self, ids: &[u64]) {
+        for id in ids {
+            self.blobs.remove(id);
+        }
+    }
+
+    /// Returns offsets of dead records in data file with given number
+    pub(crate) fn dead_offsets(&self, file: usize) -> HashSet<u64> {
+        self.files
+            .get(&file)
+            .map(|extents| extents.iter().map(|extent| extent.offset).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns numbers of dead bytes by numbers of data files, that have them
+    pub(crate) fn dead(&self) -> BTreeMap<usize, u64> {
+        self.files
+            .iter()
+            .map(|(&file, extents)| (file, extents.iter().map(|extent| extent.len).sum()))
+            .collect()
+    }
+
+    /// Forgets dead extents of data file with given number, e.g. after it is removed
+    pub(crate) fn forget(&mut self, file: usize) {
+        self.files.remove(&file);
+    }
+
+    /// Forgets dead extents of data files with numbers up to given one
+    pub(crate) fn forget_through(&mut self, last: usize) {
+        self.files = self.files.split_off(&(last + 1));
+    }
+
+    /// Returns dead extents of every data file, in order of their records
+    pub(crate) fn extents(&self) -> BTreeMap<usize, Vec<Extent>> {
+        self.files
+            .iter()
+            .map(|(&file, extents)| {
+                let mut extents = extents.clone();
+                extents.sort_unstable_by_key(|extent| extent.offset);
+                (file, extents)
+            })
+            .collect()
+    }
+}
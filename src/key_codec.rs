@@ -0,0 +1,490 @@
+//! Encoding of keys, by which the tree persists them
+//!
+//! Keys are written to snapshots, archives, pages of a [`crate::buffer_pool::BufferPool`]
+//! and replication streams by their [`KeyCodec`], not by their `serde` implementations.
+//! So the disk format of keys is stated by the codec and does not change with versions
+//! of `serde` or `bincode`, and a key type may be persisted without implementing them
+//!
+//! Encodings keep order of keys, so encoded keys may be compared as bytes:
+//!
+//! | Type                          | Encoding                                                  |
+//! |-------------------------------|-----------------------------------------------------------|
+//! | `u8` ... `u128`, `usize`      | big-endian, `usize` as `u64`                              |
+//! | `i8` ... `i128`, `isize`      | big-endian with flipped sign bit, `isize` as `i64`        |
+//! | `bool`                        | 1 byte, 0 or 1                                            |
+//! | `char`                        | as `u32`                                                  |
+//! | `[u8; N]`                     | bytes as they are                                         |
+//! | `Vec<u8>`, `String`           | bytes with 0 escaped as 0, 255, terminated by 0, 0        |
+//! | `Option<T>`                   | 0 for None, 1 followed by the value for Some              |
+//! | tuples of up to 4 items       | encodings of items one after another                      |
+//!
+//! Keys of fixed width, see [`KeyCodec::WIDTH`], are written without lengths: keys of a node
+//! are packed into one byte string, that is split into keys by their width on read
+
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::fmt;
+
+use crate::error::{BPlusError, Result};
+
+/// Order-preserving encoding of keys, that the tree persists
+///
+/// Implementations must keep two properties, that the tree and composite keys rely on:
+/// for every two keys `a < b` exactly when encoding of `a` is lexicographically less than
+/// encoding of `b`, and no encoding is a prefix of another one, so encodings of items
+/// of a tuple may be concatenated
+pub trait KeyCodec: Sized {
+    /// Length of every encoding in bytes, if all keys are encoded to the same length
+    const WIDTH: Option<usize> = None;
+
+    /// Appends encoding of the key to given buffer
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decodes key from the start of given bytes and advances them past its encoding
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if bytes do not start with an encoded key
+    fn decode(bytes: &mut &[u8]) -> Result<Self>;
+
+    /// Returns encoding of the key
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+
+    /// Decodes key, that given bytes encode
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if bytes are not an encoding of one key
+    fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let key = Self::decode(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(BPlusError::Corruption(format!(
+                "{} bytes follow an encoded key",
+                bytes.len()
+            )));
+        }
+        Ok(key)
+    }
+}
+
+/// Takes given number of bytes from the start of encoded keys
+///
+/// Returns Err(BPlusError::Corruption(_)) if there are fewer of them
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        return Err(BPlusError::Corruption(format!(
+            "encoded key is truncated, {len} bytes expected, {} left",
+            bytes.len()
+        )));
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+/// Takes array of given length from the start of encoded keys
+fn take_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N]> {
+    Ok(take(bytes, N)?.try_into().expect("length is taken"))
+}
+
+macro_rules! unsigned_codec {
+    ($($int:ty),*) => {$(
+        impl KeyCodec for $int {
+            const WIDTH: Option<usize> = Some(size_of::<$int>());
+
+            fn encode(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode(bytes: &mut &[u8]) -> Result<Self> {
+                Ok(Self::from_be_bytes(take_array(bytes)?))
+            }
+        }
+    )*};
+}
+
+unsigned_codec!(u8, u16, u32, u64, u128);
+
+/// Flipped sign bit puts negative numbers before positive ones, keeping order within both
+macro_rules! signed_codec {
+    ($($int:ty => $unsigned:ty),*) => {$(
+        impl KeyCodec for $int {
+            const WIDTH: Option<usize> = <$unsigned>::WIDTH;
+
+            fn encode(&self, buf: &mut Vec<u8>) {
+                ((*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1))).encode(buf);
+            }
+
+            fn decode(bytes: &mut &[u8]) -> Result<Self> {
+                Ok((<$unsigned>::decode(bytes)? ^ (1 << (<$unsigned>::BITS - 1))) as $int)
+            }
+        }
+    )*};
+}
+
+signed_codec!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+/// Encoded as `u64`, so the encoding is the same on every platform
+impl KeyCodec for usize {
+    const WIDTH: Option<usize> = u64::WIDTH;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u64).encode(buf);
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self> {
+        let value = u64::decode(bytes)?;
+        usize::try_from(value)
+            .map_err(|_| BPlusError::Corruption(format!("key {value} does not fit in usize")))
+    }
+}
+
+/// Encoded as `i64`, so the encoding is the same on every platform
+impl KeyCodec for isize {
+    const WIDTH: Option<usize> = i64::WIDTH;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as i64).encode(buf);
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self> {
+        let value = i64::decode(bytes)?;
+        isize::try_from(value)
+            .map_err(|_| BPlusError::Corruption(format!("key {value} does not fit in isize")))
+    }
+}
+
+impl KeyCodec for bool {
+    const WIDTH: Option<usize> = u8::WIDTH;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self> {
+        match u8::decode(bytes)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(BPlusError::Corruption(format!(
+                "byte {byte} does not encode bool"
+            ))),
+        }
+    }
+}
+
+impl KeyCodec for char {
+    const WIDTH: Option<usize> = u32::WIDTH;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u32).encode(buf);
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self> {
+        let value = u32::decode(bytes)?;
+        char::from_u32(value)
+            .ok_or_else(|| BPlusError::Corruption(format!("{value} does not encode char")))
+    }
+}
+
+/// Array has fixed length, so its bytes are neither escaped nor terminated
+impl<const N: usize> KeyCodec for [u8; N] {
+    const WIDTH: Option<usize> = Some(N);
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self> {
+        take_array(bytes)
+    }
+}
+
+/// Appends bytes of variable length, escaping zeros, so the terminator is not a prefix
+/// of any continuation and shorter bytes are ordered before the longer ones they start
+fn encode_escaped(value: &[u8], buf: &mut Vec<u8>) {
+    for &byte in value {
+        buf.push(byte);
+        if byte == 0 {
+            buf.push(0xFF);
+        }
+    }
+    buf.extend_from_slice(&[0, 0]);
+}
+
+/// Decodes bytes, that [`encode_escaped`] wrote
+fn decode_escaped(bytes: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut value = Vec::new();
+    loop {
+        match u8::decode(bytes)? {
+            0 => match u8::decode(bytes)? {
+                0 => return Ok(value),
+                0xFF => value.push(0),
+                byte => {
+                    return Err(BPlusError::Corruption(format!(
+                        "zero is followed by {byte} in encoded key"
+                    )))
+                }
+            },
+            byte => value.push(byte),
+        }
+    }
+}
+
+impl KeyCodec for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_escaped(self, buf);
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self> {
+        decode_escaped(bytes)
+    }
+}
+
+/// UTF-8 keeps order of code points, so strings are ordered as their bytes
+impl KeyCodec for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_escaped(self.as_bytes(), buf);
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(decode_escaped(bytes)?)
+            .map_err(|e| BPlusError::Corruption(format!("encoded key is not UTF-8: {e}")))
+    }
+}
+
+impl<T: KeyCodec> KeyCodec for Option<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            None => buf.push(0),
+            Some(value) => {
+                buf.push(1);
+                value.encode(buf);
+            }
+        }
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self> {
+        match bool::decode(bytes)? {
+            false => Ok(None),
+            true => Ok(Some(T::decode(bytes)?)),
+        }
+    }
+}
+
+/// Returns sum of given widths, or None if some of them are not fixed
+const fn total_width(widths: &[Option<usize>]) -> Option<usize> {
+    let mut total = 0;
+    let mut i = 0;
+    while i < widths.len() {
+        match widths[i] {
+            Some(width) => total += width,
+            None => return None,
+        }
+        i += 1;
+    }
+    Some(total)
+}
+
+macro_rules! tuple_codec {
+    ($(($($item:ident),+)),*) => {$(
+        impl<$($item: KeyCodec),+> KeyCodec for ($($item,)+) {
+            const WIDTH: Option<usize> = total_width(&[$($item::WIDTH),+]);
+
+            #[allow(non_snake_case)]
+            fn encode(&self, buf: &mut Vec<u8>) {
+                let ($($item,)+) = self;
+                $($item.encode(buf);)+
+            }
+
+            fn decode(bytes: &mut &[u8]) -> Result<Self> {
+                Ok(($($item::decode(bytes)?,)+))
+            }
+        }
+    )*};
+}
+
+tuple_codec!((A), (A, B), (A, B, C), (A, B, C, D));
+
+/// Returns width of keys, that are packed without lengths, or None if keys are written
+/// with their lengths
+///
+/// Keys of zero width are written with lengths, so their number is kept
+pub(crate) fn packed_width<K: KeyCodec>() -> Option<usize> {
+    K::WIDTH.filter(|&width| width > 0)
+}
+
+/// Returns given keys, packed into one byte string
+fn pack<'a, K: KeyCodec + 'a>(keys: impl ExactSizeIterator<Item = &'a K>, width: usize) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(keys.len() * width);
+    for key in keys {
+        key.encode(&mut packed);
+    }
+    packed
+}
+
+/// Splits byte string, that [`pack`] wrote, into keys
+///
+/// Returns Err(BPlusError::Corruption(_)) if it does not consist of whole encoded keys
+fn unpack<K: KeyCodec>(bytes: &[u8], width: usize) -> Result<Vec<K>> {
+    if !bytes.len().is_multiple_of(width) {
+        return Err(BPlusError::Corruption(format!(
+            "{} bytes of packed keys are not a multiple of their width {width}",
+            bytes.len()
+        )));
+    }
+    bytes.chunks_exact(width).map(K::from_bytes).collect()
+}
+
+/// Byte string, that is serialized as it is
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// Key, that is serialized as bytes of its encoding
+pub(crate) struct Encoded<'a, K>(pub &'a K);
+
+impl<K: KeyCodec> Serialize for Encoded<'_, K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Bytes(&self.0.to_bytes()).serialize(serializer)
+    }
+}
+
+/// Visitor of a byte string, that is read by given function
+struct BytesVisitor<T>(fn(&[u8]) -> Result<T>);
+
+impl<T> Visitor<'_> for BytesVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("encoded keys")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> std::result::Result<T, E> {
+        (self.0)(bytes).map_err(E::custom)
+    }
+}
+
+/// Key, that is deserialized from bytes of its encoding
+struct Decoded<K>(K);
+
+impl<'de, K: KeyCodec> Deserialize<'de> for Decoded<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_bytes(BytesVisitor(|bytes| K::from_bytes(bytes).map(Decoded)))
+    }
+}
+
+/// Keys of fixed width, that are deserialized from one byte string, see [`pack`]
+struct Packed<K>(Vec<K>);
+
+impl<'de, K: KeyCodec> Deserialize<'de> for Packed<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_bytes(BytesVisitor(|bytes| {
+            let width = packed_width::<K>().expect("packed keys have fixed width");
+            unpack(bytes, width).map(Packed)
+        }))
+    }
+}
+
+/// Values of entries, that are serialized without their keys
+struct Values<'a, K, V>(&'a [(K, V)]);
+
+impl<K, V: Serialize> Serialize for Values<'_, K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|(_, value)| value))
+    }
+}
+
+/// Serializes key field by its [`KeyCodec`], for `#[serde(with = "...")]`
+pub(crate) mod key {
+    use super::*;
+
+    pub(crate) fn serialize<K: KeyCodec, S: Serializer>(
+        key: &K,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        Encoded(key).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, K: KeyCodec, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<K, D::Error> {
+        Ok(Decoded::deserialize(deserializer)?.0)
+    }
+}
+
+/// Serializes vector of keys by their [`KeyCodec`], for `#[serde(with = "...")]`
+///
+/// Keys of fixed width are packed into one byte string
+pub(crate) mod keys {
+    use super::*;
+
+    pub(crate) fn serialize<K: KeyCodec, S: Serializer>(
+        keys: &[K],
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match packed_width::<K>() {
+            Some(width) => Bytes(&pack(keys.iter(), width)).serialize(serializer),
+            None => serializer.collect_seq(keys.iter().map(Encoded)),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, K: KeyCodec, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Vec<K>, D::Error> {
+        if packed_width::<K>().is_some() {
+            return Ok(Packed::deserialize(deserializer)?.0);
+        }
+        let keys = Vec::<Decoded<K>>::deserialize(deserializer)?;
+        Ok(keys.into_iter().map(|key| key.0).collect())
+    }
+}
+
+/// Serializes vector of entries, whose keys are serialized by their [`KeyCodec`] and
+/// values by `serde`, for `#[serde(with = "...")]`
+///
+/// Keys of fixed width are packed into one byte string, that is followed by the values
+pub(crate) mod entries {
+    use super::*;
+
+    pub(crate) fn serialize<K: KeyCodec, V: Serialize, S: Serializer>(
+        entries: &[(K, V)],
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match packed_width::<K>() {
+            Some(width) => {
+                let packed = pack(entries.iter().map(|(key, _)| key), width);
+                (Bytes(&packed), Values(entries)).serialize(serializer)
+            }
+            None => {
+                serializer.collect_seq(entries.iter().map(|(key, value)| (Encoded(key), value)))
+            }
+        }
+    }
+
+    pub(crate) fn deserialize<'de, K: KeyCodec, V: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Vec<(K, V)>, D::Error> {
+        if packed_width::<K>().is_some() {
+            let (Packed(keys), values) = <(Packed<K>, Vec<V>)>::deserialize(deserializer)?;
+            if keys.len() != values.len() {
+                return Err(de::Error::custom(format!(
+                    "{} packed keys have {} values",
+                    keys.len(),
+                    values.len()
+                )));
+            }
+            return Ok(keys.into_iter().zip(values).collect());
+        }
+        let entries = Vec::<(Decoded<K>, V)>::deserialize(deserializer)?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, value)| (key.0, value))
+            .collect())
+    }
+}
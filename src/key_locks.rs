@@ -0,0 +1,108 @@
+//! Locks of keys and ranges of keys, that callers hold to keep other writers away
+//!
+//! Changes of the tree wait, while their keys are locked by another owner. Exclusive locks
+//! exclude overlapping locks, while shared ones exclude only exclusive ones.
+//! Lock is taken, when no change is in flight, see [`crate::bplus_tree::BPlus::lock_range`]
+//! and [`crate::bplus_tree::BPlus::freeze_range`]
+
+use std::{
+    ops::{Bound, RangeBounds},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::sync::{futures::Notified, Notify};
+
+/// Range of keys, that is locked
+pub(crate) type Range<K> = (Bound<K>, Bound<K>);
+
+/// Range of keys, that is locked by its owner
+struct Held<K> {
+    /// Identifier of the owner
+    owner: u64,
+    range: Range<K>,
+    /// Whether other shared locks may overlap the range
+    shared: bool,
+}
+
+/// Ranges of keys, that are locked by their owners
+pub(crate) struct KeyLocks<K> {
+    /// Locked ranges
+    locked: Mutex<Vec<Held<K>>>,
+    /// Identifier of the next owner
+    next_owner: AtomicU64,
+    /// Wakes changes and locks, that wait for a range to be unlocked
+    released: Notify,
+}
+
+impl<K: Ord> KeyLocks<K> {
+    /// Returns whether given key is locked by an owner other than given one
+    pub(crate) fn is_locked(&self, key: &K, owner: Option<u64>) -> bool {
+        let locked = self.locked.lock().unwrap();
+        locked
+            .iter()
+            .any(|held| Some(held.owner) != owner && held.range.contains(key))
+    }
+
+    /// Locks given range, if it does not overlap with conflicting locks,
+    /// returns identifier of its owner
+    pub(crate) fn try_lock(&self, range: Range<K>, shared: bool) -> Option<u64> {
+        let mut locked = self.locked.lock().unwrap();
+        let conflicts = |held: &Held<K>| !(shared && held.shared) && overlap(&held.range, &range);
+        if locked.iter().any(conflicts) {
+            return None;
+        }
+        let owner = self.next_owner.fetch_add(1, Ordering::Relaxed);
+        locked.push(Held {
+            owner,
+            range,
+            shared,
+        });
+        Some(owner)
+    }
+}
+
+impl<K> KeyLocks<K> {
+    /// Unlocks range of given owner, waking changes and locks, that wait for it
+    pub(crate) fn unlock(&self, owner: u64) {
+        self.locked
+            .lock()
+            .unwrap()
+            .retain(|held| held.owner != owner);
+        self.released.notify_waiters();
+    }
+
+    /// Returns future, that is woken by the next unlock
+    ///
+    /// Created before the check of locks, so an unlock after the check is not missed
+    pub(crate) fn released(&self) -> Notified<'_> {
+        self.released.notified()
+    }
+}
+
+impl<K> Default for KeyLocks<K> {
+    fn default() -> Self {
+        Self {
+            locked: Mutex::new(Vec::new()),
+            next_owner: AtomicU64::new(0),
+            released: Notify::new(),
+        }
+    }
+}
+
+/// Returns whether two ranges have a common key
+fn overlap<K: Ord>(a: &Range<K>, b: &Range<K>) -> bool {
+    !below(&a.1, &b.0) && !below(&b.1, &a.0)
+}
+
+/// Returns whether range, that ends at given end, holds no key of range, that starts at given start
+fn below<K: Ord>(end: &Bound<K>, start: &Bound<K>) -> bool {
+    match (end, start) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(end), Bound::Included(start)) => end < start,
+        (Bound::Included(end) | Bound::Excluded(end), Bound::Excluded(start))
+        | (Bound::Excluded(end), Bound::Included(start)) => end <= start,
+    }
+}
@@ -0,0 +1,480 @@
+//! Latches of nodes, that admit readers and writers in order of a fairness policy
+//!
+//! FIFO latches lock the node directly, as the lock is fair itself. Under other policies
+//! a gate of the latch decides, which of the waiting tasks enters next, and only admitted
+//! tasks lock the node, so the lock behind the gate is never contended
+//!
+//! Node, which parts are evicted from memory, is loaded back, once it is locked,
+//! see [`Evictable`]
+
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    mem,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::{Poll, Waker},
+};
+
+use tokio::sync::{
+    OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+
+/// Order, in which latches of nodes admit waiting readers and writers
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum Fairness {
+    /// Admits tasks in order of their arrival, so a reader waits behind a waiting writer
+    #[default]
+    Fifo,
+    /// Admits waiting writers before waiting readers, so a stream of readers
+    /// does not starve splits
+    WriterPreferring,
+    /// Admits readers, while the node is not written, so writers wait until there are no readers
+    ReaderPreferring,
+}
+
+/// Task, that waits to be admitted by a gate
+struct Waiter {
+    id: u64,
+    write: bool,
+    /// Waker of the task; None until it is polled
+    waker: Option<Waker>,
+}
+
+/// Admitted and waiting tasks of a gate
+#[derive(Default)]
+struct GateState {
+    fairness: Fairness,
+    /// Number of admitted readers
+    readers: usize,
+    /// Whether a writer is admitted
+    writer: bool,
+    /// Waiting tasks in order of their arrival
+    queue: VecDeque<Waiter>,
+    /// Identifiers of admitted tasks, that were not polled since
+    admitted: Vec<u64>,
+    next_id: u64,
+}
+
+impl GateState {
+    /// Admits waiting tasks, that the policy lets in
+    fn admit(&mut self) {
+        while !self.writer {
+            let next = match self.fairness {
+                Fairness::Fifo => (!self.queue.is_empty()).then_some(0),
+                Fairness::WriterPreferring => self.first(true).or_else(|| self.first(false)),
+                Fairness::ReaderPreferring => self.first(false).or_else(|| self.first(true)),
+            };
+            let Some(pos) = next else {
+                break;
+            };
+            if self.queue[pos].write {
+                if self.readers > 0 {
+                    break;
+                }
+                self.writer = true;
+            } else {
+                self.readers += 1;
+            }
+            let waiter = self.queue.remove(pos).unwrap();
+            self.admitted.push(waiter.id);
+            if let Some(waker) = waiter.waker {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Returns position of the first waiting writer or reader
+    fn first(&self, write: bool) -> Option<usize> {
+        self.queue.iter().position(|waiter| waiter.write == write)
+    }
+
+    /// Releases admitted reader or writer and admits the next tasks
+    fn release(&mut self, write: bool) {
+        if write {
+            self.writer = false;
+        } else {
+            self.readers -= 1;
+        }
+        self.admit();
+    }
+
+    /// Queues task and admits tasks, that the policy lets in, returns identifier of the task
+    fn enqueue(&mut self, write: bool) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push_back(Waiter {
+            id,
+            write,
+            waker: None,
+        });
+        self.admit();
+        id
+    }
+
+    /// Returns whether given task was admitted, forgetting it then
+    fn take_admitted(&mut self, id: u64) -> bool {
+        match self.admitted.iter().position(|admitted| *admitted == id) {
+            Some(pos) => {
+                self.admitted.swap_remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Admission control in front of a lock of a node
+#[derive(Default)]
+struct Gate {
+    state: Mutex<GateState>,
+}
+
+impl Gate {
+    /// Waits until the policy admits a reader or a writer
+    async fn enter(self: &Arc<Self>, write: bool) -> Ticket {
+        let id = self.state.lock().unwrap().enqueue(write);
+        let mut entry = Entry {
+            gate: self,
+            id,
+            write,
+            done: false,
+        };
+        poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.take_admitted(id) {
+                entry.done = true;
+                return Poll::Ready(());
+            }
+            if let Some(waiter) = state.queue.iter_mut().find(|waiter| waiter.id == id) {
+                waiter.waker = Some(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await;
+        Ticket {
+            gate: self.clone(),
+            write,
+        }
+    }
+
+    /// Admits a reader or a writer, if the policy lets it in right away
+    fn try_enter(self: &Arc<Self>, write: bool) -> Option<Ticket> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.enqueue(write);
+        if state.take_admitted(id) {
+            return Some(Ticket {
+                gate: self.clone(),
+                write,
+            });
+        }
+        state.queue.retain(|waiter| waiter.id != id);
+        state.admit();
+        None
+    }
+}
+
+/// Waiting task, that leaves the gate, if it is cancelled
+struct Entry<'a> {
+    gate: &'a Gate,
+    id: u64,
+    write: bool,
+    /// Whether the task noticed its admission
+    done: bool,
+}
+
+impl Drop for Entry<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut state = self.gate.state.lock().unwrap();
+        if state.take_admitted(self.id) {
+            state.release(self.write);
+        } else {
+            state.queue.retain(|waiter| waiter.id != self.id);
+            // Cancelled writer may have held back readers
+            state.admit();
+        }
+    }
+}
+
+/// Admission of a reader or a writer, that is released on drop
+struct Ticket {
+    gate: Arc<Gate>,
+    write: bool,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.gate.state.lock().unwrap().release(self.write);
+    }
+}
+
+/// Value, that may have parts evicted from memory
+pub(crate) trait Evictable {
+    /// Returns whether parts of the value are evicted
+    fn is_evicted(&self) -> bool {
+        false
+    }
+
+    /// Loads evicted parts of the value back into memory
+    fn load(&mut self) {}
+
+    /// Notes, that the value is locked for writing, so it may be changed
+    fn touch(&mut self) {}
+}
+
+/// Read-write lock of a node, that admits tasks in order of its fairness policy
+pub(crate) struct Latch<T> {
+    inner: Arc<RwLock<T>>,
+    /// Policy of the latch, see [`Fairness`]
+    fairness: AtomicU8,
+    /// Gate of a policy other than FIFO; created, once such a policy is set
+    gate: OnceLock<Arc<Gate>>,
+}
+
+impl<T> Latch<T> {
+    /// Creates latch of given value with the default policy
+    pub(crate) fn new(value: T) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(value)),
+            fairness: AtomicU8::new(Fairness::Fifo as u8),
+            gate: OnceLock::new(),
+        }
+    }
+
+    /// Sets policy, by which the latch admits tasks, that wait from now on
+    pub(crate) fn set_fairness(&self, fairness: Fairness) {
+        if fairness != Fairness::Fifo || self.gate.get().is_some() {
+            let mut state = self.gate.get_or_init(Arc::default).state.lock().unwrap();
+            state.fairness = fairness;
+            state.admit();
+        }
+        self.fairness.store(fairness as u8, Ordering::Release);
+    }
+
+    /// Returns gate, that admits tasks under the current policy, or None for FIFO latch
+    fn gate(&self) -> Option<&Arc<Gate>> {
+        match self.fairness.load(Ordering::Acquire) {
+            fairness if fairness == Fairness::Fifo as u8 => None,
+            _ => self.gate.get(),
+        }
+    }
+
+    /// Waits until the gate admits a reader or a writer, returns None for FIFO latch
+    async fn enter(&self, write: bool) -> Option<Ticket> {
+        match self.gate() {
+            Some(gate) => Some(gate.enter(write).await),
+            None => None,
+        }
+    }
+
+    /// Admits a reader or a writer, if the gate lets it in right away
+    ///
+    /// Returns Some(None) for FIFO latch and None, if the task is not admitted
+    fn try_enter(&self, write: bool) -> Option<Option<Ticket>> {
+        match self.gate() {
+            Some(gate) => Some(Some(gate.try_enter(write)?)),
+            None => Some(None),
+        }
+    }
+
+    /// Returns heap memory taken by the latch and its lock with the value, without counters
+    /// of their Arcs and gates of policies other than FIFO
+    pub(crate) fn heap_size() -> usize {
+        mem::size_of::<Self>() + mem::size_of::<RwLock<T>>()
+    }
+
+    /// Returns the value
+    ///
+    /// Panics, if the latch is locked
+    pub(crate) fn into_inner(self) -> T {
+        Arc::into_inner(self.inner)
+            .expect("latch is not locked")
+            .into_inner()
+    }
+}
+
+impl<T: Evictable> Latch<T> {
+    /// Locks value for reading, loading its evicted parts
+    pub(crate) async fn read(&self) -> LatchReadGuard<'_, T> {
+        let guard = self.peek().await;
+        if !guard.is_evicted() {
+            return guard;
+        }
+        drop(guard);
+        let LatchWriteGuard { mut guard, _ticket } = self.peek_mut().await;
+        if guard.is_evicted() {
+            guard.load();
+        }
+        LatchReadGuard {
+            guard: guard.downgrade(),
+            _ticket,
+        }
+    }
+
+    /// Locks value for writing, loading its evicted parts
+    pub(crate) async fn write(&self) -> LatchWriteGuard<'_, T> {
+        let mut guard = self.peek_mut().await;
+        if guard.is_evicted() {
+            guard.load();
+        }
+        guard.touch();
+        guard
+    }
+
+    /// Locks value for reading, if the policy admits a reader right away,
+    /// loading its evicted parts
+    pub(crate) fn try_read(&self) -> Option<LatchReadGuard<'_, T>> {
+        let guard = LatchReadGuard {
+            _ticket: self.try_enter(false)?,
+            guard: self.inner.try_read().ok()?,
+        };
+        if !guard.is_evicted() {
+            return Some(guard);
+        }
+        drop(guard);
+        let _ticket = self.try_enter(true)?;
+        let mut guard = self.inner.try_write().ok()?;
+        if guard.is_evicted() {
+            guard.load();
+        }
+        Some(LatchReadGuard {
+            guard: guard.downgrade(),
+            _ticket,
+        })
+    }
+
+    /// Locks value for reading with a guard, that holds the latch, loading its evicted parts
+    pub(crate) async fn read_owned(self: Arc<Self>) -> OwnedLatchReadGuard<T> {
+        #[cfg(feature = "simulation")]
+        crate::simulation::yield_point().await;
+        let guard = OwnedLatchReadGuard {
+            _ticket: self.enter(false).await,
+            guard: self.inner.clone().read_owned().await,
+        };
+        if !guard.is_evicted() {
+            return guard;
+        }
+        drop(guard);
+        let _ticket = self.enter(true).await;
+        let mut guard = self.inner.clone().write_owned().await;
+        if guard.is_evicted() {
+            guard.load();
+        }
+        OwnedLatchReadGuard {
+            guard: guard.downgrade(),
+            _ticket,
+        }
+    }
+
+    /// Locks value for writing with a guard, that holds the latch, loading its evicted parts
+    pub(crate) async fn write_owned(self: Arc<Self>) -> OwnedLatchWriteGuard<T> {
+        #[cfg(feature = "simulation")]
+        crate::simulation::yield_point().await;
+        let mut guard = OwnedLatchWriteGuard {
+            _ticket: self.enter(true).await,
+            guard: self.inner.clone().write_owned().await,
+        };
+        if guard.is_evicted() {
+            guard.load();
+        }
+        guard.touch();
+        guard
+    }
+
+    /// Locks value for reading as it is in memory, without loading its evicted parts
+    pub(crate) async fn peek(&self) -> LatchReadGuard<'_, T> {
+        #[cfg(feature = "simulation")]
+        crate::simulation::yield_point().await;
+        LatchReadGuard {
+            _ticket: self.enter(false).await,
+            guard: self.inner.read().await,
+        }
+    }
+
+    /// Locks value for writing as it is in memory, without loading its evicted parts
+    /// or noting, that it may be changed
+    pub(crate) async fn peek_mut(&self) -> LatchWriteGuard<'_, T> {
+        #[cfg(feature = "simulation")]
+        crate::simulation::yield_point().await;
+        LatchWriteGuard {
+            _ticket: self.enter(true).await,
+            guard: self.inner.write().await,
+        }
+    }
+}
+
+/// Guard of a value, that is locked for reading
+///
+/// Value is unlocked before the gate admits the next task
+pub(crate) struct LatchReadGuard<'a, T> {
+    guard: RwLockReadGuard<'a, T>,
+    _ticket: Option<Ticket>,
+}
+
+/// Guard of a value, that is locked for writing
+pub(crate) struct LatchWriteGuard<'a, T> {
+    guard: RwLockWriteGuard<'a, T>,
+    _ticket: Option<Ticket>,
+}
+
+/// Guard of a value, that is locked for reading, and holds its latch
+pub(crate) struct OwnedLatchReadGuard<T> {
+    guard: OwnedRwLockReadGuard<T>,
+    _ticket: Option<Ticket>,
+}
+
+/// Guard of a value, that is locked for writing, and holds its latch
+pub(crate) struct OwnedLatchWriteGuard<T> {
+    guard: OwnedRwLockWriteGuard<T>,
+    _ticket: Option<Ticket>,
+}
+
+impl<T> Deref for LatchReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> Deref for LatchWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for LatchWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Deref for OwnedLatchReadGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> Deref for OwnedLatchWriteGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for OwnedLatchWriteGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
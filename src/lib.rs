@@ -1 +1,35 @@
+#[macro_use]
+mod trace;
+
+mod advice;
+pub mod audit;
+pub mod blocking;
+mod bloom;
 pub mod bplus_tree;
+pub mod buffer_pool;
+mod chunk_table;
+mod compaction;
+pub mod cow;
+pub mod error;
+pub mod events;
+mod eviction;
+mod free_space;
+pub mod key_codec;
+mod key_locks;
+mod latch;
+pub mod map;
+mod merkle;
+pub mod metrics;
+pub mod multimap;
+pub mod page;
+mod prefix;
+mod record;
+pub mod recorder;
+mod replication;
+pub mod runtime;
+mod search;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+mod snapshot;
+pub mod store;
+mod write_buffer;
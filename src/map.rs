@@ -0,0 +1,582 @@
+//! Ordered map on a B+ tree, that keeps keys and values in memory
+//!
+//! Unlike [`BPlus`](crate::bplus_tree::BPlus), map has no data files and no async API, so
+//! it needs neither a runtime nor a directory. Nodes are searched and split like nodes of
+//! the disk tree. Nodes are owned by their parents, so the map is only changed through
+//! `&mut self` and needs no latches
+
+use std::{
+    fmt,
+    ops::{Bound, RangeBounds},
+    slice,
+};
+
+use crate::{
+    prefix::shortest_separator,
+    search::{search, search_by_key},
+};
+
+/// Default t of a map
+pub const DEFAULT_T: usize = 32;
+
+/// Node of a map, like [`PagedNode`](crate::buffer_pool::PagedNode) with children owned inline
+#[derive(Clone)]
+enum Node<K, V> {
+    /// Internal node with separator keys and its children, one more than separators
+    Internal {
+        keys: Vec<K>,
+        children: Vec<Node<K, V>>,
+    },
+    /// Leaf with sorted entries
+    Leaf { entries: Vec<(K, V)> },
+}
+
+/// Ordered map from keys to values on a B+ tree
+///
+/// Leaves hold from t - 1 to 2t - 1 entries, internal nodes hold from t to 2t children,
+/// except for the root
+#[derive(Clone)]
+pub struct BPlusMap<K, V> {
+    root: Node<K, V>,
+    /// Number of entries in the map
+    len: usize,
+    t: usize,
+}
+
+impl<K: Ord + Clone + 'static, V> BPlusMap<K, V> {
+    /// Creates empty map with t = [`DEFAULT_T`]
+    pub fn new() -> Self {
+        Self::with_t(DEFAULT_T)
+    }
+
+    /// Creates empty map with given t
+    ///
+    /// Panics if t is less than 2
+    pub fn with_t(t: usize) -> Self {
+        assert!(t >= 2, "t of a map must be at least 2, got {t}");
+        Self {
+            root: Node::Leaf {
+                entries: Vec::new(),
+            },
+            len: 0,
+            t,
+        }
+    }
+
+    /// Returns number of entries in the map
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the map has no entries
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Removes all entries, keeping t
+    pub fn clear(&mut self) {
+        *self = Self::with_t(self.t);
+    }
+
+    /// Returns value by given key, or None if there is no such key
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let mut node = &self.root;
+        loop {
+            match node {
+                Node::Internal { keys, children } => node = &children[child_index(keys, key)],
+                Node::Leaf { entries } => {
+                    let pos = search_by_key(entries, key, |(k, _)| k).ok()?;
+                    return Some(&entries[pos].1);
+                }
+            }
+        }
+    }
+
+    /// Returns mutable reference to value by given key, or None if there is no such key
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let mut node = &mut self.root;
+        loop {
+            match node {
+                Node::Internal { keys, children } => node = &mut children[child_index(keys, key)],
+                Node::Leaf { entries } => {
+                    let pos = search_by_key(entries, key, |(k, _)| k).ok()?;
+                    return Some(&mut entries[pos].1);
+                }
+            }
+        }
+    }
+
+    /// Returns whether the map has given key
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns entry with the least key, or None if the map is empty
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.iter().next()
+    }
+
+    /// Returns entry with the greatest key, or None if the map is empty
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        let mut node = &self.root;
+        loop {
+            match node {
+                Node::Internal { children, .. } => node = children.last()?,
+                Node::Leaf { entries } => return entries.last().map(|(k, v)| (k, v)),
+            }
+        }
+    }
+
+    /// Inserts value by given key
+    ///
+    /// Returns the replaced value, or None if there was no such key
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let (old, split) = insert_into(&mut self.root, key, value, self.t);
+        if let Some((separator, right)) = split {
+            let left = std::mem::replace(
+                &mut self.root,
+                Node::Leaf {
+                    entries: Vec::new(),
+                },
+            );
+            self.root = Node::Internal {
+                keys: vec![separator],
+                children: vec![left, right],
+            };
+        }
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Removes entry by given key and returns its value, or None if there was no such key
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let value = remove_from(&mut self.root, key, self.t)?;
+        self.len -= 1;
+        // Root, that was left with one child after a merge, is replaced by it
+        if let Node::Internal { children, .. } = &mut self.root {
+            if children.len() == 1 {
+                self.root = children.pop().unwrap();
+            }
+        }
+        Some(value)
+    }
+
+    /// Returns iterator over entries in order of keys
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        self.range(..)
+    }
+
+    /// Returns iterator over entries, which keys lie in given range, in order of keys
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Iter<'_, K, V> {
+        let mut iter = Iter {
+            parents: Vec::new(),
+            entries: [].iter(),
+            end: range.end_bound().cloned(),
+        };
+        let start = range.start_bound();
+        let mut node = &self.root;
+        loop {
+            match node {
+                Node::Internal { keys, children } => {
+                    let index = match start {
+                        Bound::Included(key) | Bound::Excluded(key) => child_index(keys, key),
+                        Bound::Unbounded => 0,
+                    };
+                    iter.parents.push(children[index + 1..].iter());
+                    node = &children[index];
+                }
+                Node::Leaf { entries } => {
+                    let pos = match start {
+                        Bound::Included(key) => {
+                            search_by_key(entries, key, |(k, _)| k).unwrap_or_else(|pos| pos)
+                        }
+                        Bound::Excluded(key) => match search_by_key(entries, key, |(k, _)| k) {
+                            Ok(pos) => pos + 1,
+                            Err(pos) => pos,
+                        },
+                        Bound::Unbounded => 0,
+                    };
+                    iter.entries = entries[pos..].iter();
+                    return iter;
+                }
+            }
+        }
+    }
+
+    /// Returns iterator over keys in order
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Returns iterator over values in order of their keys
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Returns height of the tree, 1 for a single leaf
+    pub fn height(&self) -> usize {
+        let mut height = 1;
+        let mut node = &self.root;
+        while let Node::Internal { children, .. } = node {
+            node = &children[0];
+            height += 1;
+        }
+        height
+    }
+}
+
+/// Separator and the new right sibling of a split node
+type Split<K, V> = Option<(K, Node<K, V>)>;
+
+/// Returns index of the child of internal node with given separators, that covers given key
+fn child_index<K: Ord + 'static>(keys: &[K], key: &K) -> usize {
+    match search(keys, key) {
+        Ok(pos) => pos + 1,
+        Err(pos) => pos,
+    }
+}
+
+/// Inserts value in subtree with given root
+///
+/// Returns the replaced value, and separator with the new right sibling if the root was split
+fn insert_into<K: Ord + Clone + 'static, V>(
+    node: &mut Node<K, V>,
+    key: K,
+    value: V,
+    t: usize,
+) -> (Option<V>, Split<K, V>) {
+    match node {
+        Node::Leaf { entries } => {
+            match search_by_key(entries, &key, |(k, _)| k) {
+                Ok(pos) => return (Some(std::mem::replace(&mut entries[pos].1, value)), None),
+                Err(pos) => entries.insert(pos, (key, value)),
+            }
+            if entries.len() < 2 * t {
+                return (None, None);
+            }
+            let right = entries.split_off(t);
+            let separator = shortest_separator(&entries[t - 1].0, &right[0].0);
+            (None, Some((separator, Node::Leaf { entries: right })))
+        }
+        Node::Internal { keys, children } => {
+            let index = child_index(keys, &key);
+            let (old, split) = insert_into(&mut children[index], key, value, t);
+            if let Some((separator, right)) = split {
+                keys.insert(index, separator);
+                children.insert(index + 1, right);
+            }
+            if children.len() <= 2 * t {
+                return (old, None);
+            }
+            let right_children = children.split_off(t);
+            let right_keys = keys.split_off(t);
+            let separator = keys.pop().unwrap();
+            let right = Node::Internal {
+                keys: right_keys,
+                children: right_children,
+            };
+            (old, Some((separator, right)))
+        }
+    }
+}
+
+/// Removes entry by given key from subtree with given root and returns its value
+///
+/// Children, that are left too small, borrow from a sibling or are merged with it, so only
+/// the root of subtree may be left too small
+fn remove_from<K: Ord + Clone + 'static, V>(node: &mut Node<K, V>, key: &K, t: usize) -> Option<V> {
+    match node {
+        Node::Leaf { entries } => {
+            let pos = search_by_key(entries, key, |(k, _)| k).ok()?;
+            Some(entries.remove(pos).1)
+        }
+        Node::Internal { keys, children } => {
+            let index = child_index(keys, key);
+            let value = remove_from(&mut children[index], key, t)?;
+            if is_underfull(&children[index], t) {
+                rebalance(keys, children, index, t);
+            }
+            Some(value)
+        }
+    }
+}
+
+/// Returns whether non-root node holds too few entries or children
+fn is_underfull<K, V>(node: &Node<K, V>, t: usize) -> bool {
+    match node {
+        Node::Internal { children, .. } => children.len() < t,
+        Node::Leaf { entries } => entries.len() < t - 1,
+    }
+}
+
+/// Returns whether node may give an entry or a child to its sibling without becoming too small
+fn can_lend<K, V>(node: &Node<K, V>, t: usize) -> bool {
+    match node {
+        Node::Internal { children, .. } => children.len() > t,
+        Node::Leaf { entries } => entries.len() > t - 1,
+    }
+}
+
+/// Refills child by given index, that became too small, from one of its siblings, or merges
+/// it with a sibling, if neither of them has enough to lend
+fn rebalance<K: Clone + 'static, V>(
+    keys: &mut Vec<K>,
+    children: &mut Vec<Node<K, V>>,
+    index: usize,
+    t: usize,
+) {
+    if index > 0 && can_lend(&children[index - 1], t) {
+        let (left, right) = children.split_at_mut(index);
+        rotate_right(&mut keys[index - 1], &mut left[index - 1], &mut right[0]);
+    } else if index + 1 < children.len() && can_lend(&children[index + 1], t) {
+        let (left, right) = children.split_at_mut(index + 1);
+        rotate_left(&mut keys[index], &mut left[index], &mut right[0]);
+    } else {
+        // Too small child is merged with its left sibling, or with the right one if it is first
+        let index = index.max(1);
+        let separator = keys.remove(index - 1);
+        let right = children.remove(index);
+        merge(&mut children[index - 1], separator, right);
+    }
+}
+
+/// Moves the last entry or child of left node to the start of its right sibling
+fn rotate_right<K: Clone + 'static, V>(
+    separator: &mut K,
+    left: &mut Node<K, V>,
+    right: &mut Node<K, V>,
+) {
+    match (left, right) {
+        (Node::Leaf { entries: left }, Node::Leaf { entries: right }) => {
+            right.insert(0, left.pop().unwrap());
+            *separator = right[0].0.clone();
+        }
+        (
+            Node::Internal {
+                keys: left_keys,
+                children: left_children,
+            },
+            Node::Internal {
+                keys: right_keys,
+                children: right_children,
+            },
+        ) => {
+            let key = std::mem::replace(separator, left_keys.pop().unwrap());
+            right_keys.insert(0, key);
+            right_children.insert(0, left_children.pop().unwrap());
+        }
+        _ => unreachable!("Siblings are on the same level"),
+    }
+}
+
+/// Moves the first entry or child of right node to the end of its left sibling
+fn rotate_left<K: Clone + 'static, V>(
+    separator: &mut K,
+    left: &mut Node<K, V>,
+    right: &mut Node<K, V>,
+) {
+    match (left, right) {
+        (Node::Leaf { entries: left }, Node::Leaf { entries: right }) => {
+            left.push(right.remove(0));
+            *separator = right[0].0.clone();
+        }
+        (
+            Node::Internal {
+                keys: left_keys,
+                children: left_children,
+            },
+            Node::Internal {
+                keys: right_keys,
+                children: right_children,
+            },
+        ) => {
+            let key = std::mem::replace(separator, right_keys.remove(0));
+            left_keys.push(key);
+            left_children.push(right_children.remove(0));
+        }
+        _ => unreachable!("Siblings are on the same level"),
+    }
+}
+
+/// Appends right node to its left sibling, separated by given key
+fn merge<K, V>(left: &mut Node<K, V>, separator: K, right: Node<K, V>) {
+    match (left, right) {
+        (Node::Leaf { entries: left }, Node::Leaf { entries: right }) => left.extend(right),
+        (
+            Node::Internal {
+                keys: left_keys,
+                children: left_children,
+            },
+            Node::Internal {
+                keys: right_keys,
+                children: right_children,
+            },
+        ) => {
+            left_keys.push(separator);
+            left_keys.extend(right_keys);
+            left_children.extend(right_children);
+        }
+        _ => unreachable!("Siblings are on the same level"),
+    }
+}
+
+impl<K: Ord + Clone + 'static, V> Default for BPlusMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for BPlusMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        let mut stack = vec![&self.root];
+        while let Some(node) = stack.pop() {
+            match node {
+                Node::Internal { children, .. } => stack.extend(children.iter().rev()),
+                Node::Leaf { entries } => {
+                    map.entries(entries.iter().map(|(k, v)| (k, v)));
+                }
+            }
+        }
+        map.finish()
+    }
+}
+
+impl<K: Ord + Clone + 'static, V> FromIterator<(K, V)> for BPlusMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Ord + Clone + 'static, V> Extend<(K, V)> for BPlusMap<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<'a, K: Ord + Clone + 'static, V> IntoIterator for &'a BPlusMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K, V> IntoIterator for BPlusMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            parents: Vec::new(),
+            entries: Vec::new().into_iter(),
+            next: Some(self.root),
+        }
+    }
+}
+
+/// Iterator over entries of a map in order of keys, see [`BPlusMap::range`]
+pub struct Iter<'a, K, V> {
+    /// Siblings, that follow the nodes on the path to the current leaf, from the root down
+    parents: Vec<slice::Iter<'a, Node<K, V>>>,
+    /// Entries of the current leaf, that are not returned yet
+    entries: slice::Iter<'a, (K, V)>,
+    /// Upper bound of returned keys
+    end: Bound<K>,
+}
+
+impl<'a, K: Ord, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, value)) = self.entries.next() {
+                let within = match &self.end {
+                    Bound::Included(end) => key <= end,
+                    Bound::Excluded(end) => key < end,
+                    Bound::Unbounded => true,
+                };
+                if !within {
+                    self.parents.clear();
+                    self.entries = [].iter();
+                    return None;
+                }
+                return Some((key, value));
+            }
+            // Leaf is exhausted, so the next one is the leftmost leaf of the nearest sibling
+            let mut node = loop {
+                match self.parents.last_mut()?.next() {
+                    Some(node) => break node,
+                    None => {
+                        self.parents.pop();
+                    }
+                }
+            };
+            loop {
+                match node {
+                    Node::Internal { children, .. } => {
+                        self.parents.push(children[1..].iter());
+                        node = &children[0];
+                    }
+                    Node::Leaf { entries } => {
+                        self.entries = entries.iter();
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Iterator, that moves entries out of a map in order of keys
+pub struct IntoIter<K, V> {
+    /// Siblings, that follow the nodes on the path to the current leaf, from the root down
+    parents: Vec<std::vec::IntoIter<Node<K, V>>>,
+    /// Entries of the current leaf, that are not returned yet
+    entries: std::vec::IntoIter<(K, V)>,
+    /// Root of the map, until the first call
+    next: Option<Node<K, V>>,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(entry);
+            }
+            let mut node = match self.next.take() {
+                Some(root) => root,
+                None => loop {
+                    match self.parents.last_mut()?.next() {
+                        Some(node) => break node,
+                        None => {
+                            self.parents.pop();
+                        }
+                    }
+                },
+            };
+            loop {
+                match node {
+                    Node::Internal { children, .. } => {
+                        let mut children = children.into_iter();
+                        node = children.next().unwrap();
+                        self.parents.push(children);
+                    }
+                    Node::Leaf { entries } => {
+                        self.entries = entries.into_iter();
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Hashes of contents of a tree, that are the same for equal contents of any two trees
+//!
+//! Entries are hashed into buckets by hashes of their keys, and buckets are leaves of a
+//! complete binary tree of hashes. Hash of a node is the wrapping sum of hashes of entries
+//! below it, so a change of an entry updates one hash on every level, and the shape of the
+//! tree of hashes does not depend on the shape of the B+ tree
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    advice::Access,
+    bplus_tree::{BPlus, BPlusKey, BPlusKeySerializable, ChunkHandler},
+    error::Result,
+    key_codec::KeyCodec,
+};
+
+/// Max depth of a tree of hashes
+pub(crate) const MAX_DEPTH: u32 = 24;
+
+const SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// Returns hash of given bytes, that is the same on every platform and in every process
+pub(crate) fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash = mix(bytes.len() as u64);
+    let mut words = bytes.chunks_exact(8);
+    for word in &mut words {
+        hash = mix(hash ^ u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    let mut tail = [0; 8];
+    tail[..words.remainder().len()].copy_from_slice(words.remainder());
+    mix(hash ^ u64::from_le_bytes(tail))
+}
+
+/// Mixes bits of given value, so close values get unrelated hashes
+pub(crate) fn mix(value: u64) -> u64 {
+    let mut x = value.wrapping_add(SEED);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Function, that hashes a key, see [`hash_bytes`]
+pub(crate) type KeyHash<K> = fn(&K) -> u64;
+
+/// Returns hash of encoded form of given key, so it is the same in every process
+pub(crate) fn key_hash<K: KeyCodec>(key: &K) -> u64 {
+    hash_bytes(&key.to_bytes())
+}
+
+/// Result of reconciliation of a tree with another one, see [`BPlus::sync_from`]
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Number of buckets of the tree of hashes, in which the trees differed
+    pub buckets: usize,
+    /// Number of entries, that were copied from the other tree
+    pub inserted: usize,
+    /// Number of entries, that were removed, as the other tree has no such keys
+    pub removed: usize,
+}
+
+/// Tree of hashes of entries of a B+ tree
+pub(crate) struct Merkle<K> {
+    /// Number of levels below the root
+    depth: u32,
+    key_hash: KeyHash<K>,
+    /// Hashes of nodes level by level, children of node i are 2i + 1 and 2i + 2
+    hashes: Vec<AtomicU64>,
+}
+
+impl<K> Merkle<K> {
+    /// Creates tree of hashes of no entries, with 2^depth buckets
+    pub(crate) fn new(depth: u32, key_hash: KeyHash<K>) -> Self {
+        let depth = depth.min(MAX_DEPTH);
+        Self {
+            depth,
+            key_hash,
+            hashes: (0..(2 << depth) - 1).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Replaces hash of an entry by given key, given digests of its removed and inserted values
+    pub(crate) fn update(&self, key: &K, removed: Option<u64>, inserted: Option<u64>) {
+        let key_hash = (self.key_hash)(key);
+        let entry = |digest: u64| mix(key_hash ^ mix(digest));
+        let delta = inserted
+            .map_or(0, entry)
+            .wrapping_sub(removed.map_or(0, entry));
+        if delta == 0 {
+            return;
+        }
+        let mut node = (1 << self.depth) - 1 + self.bucket_of_hash(key_hash);
+        loop {
+            self.hashes[node].fetch_add(delta, Ordering::Relaxed);
+            if node == 0 {
+                return;
+            }
+            node = (node - 1) / 2;
+        }
+    }
+
+    /// Returns number of levels below the root
+    pub(crate) fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Returns hash of node with given index among nodes of given level, None if there is none
+    pub(crate) fn hash(&self, level: u32, index: usize) -> Option<u64> {
+        if level > self.depth || index >= 1 << level {
+            return None;
+        }
+        Some(self.hashes[(1 << level) - 1 + index].load(Ordering::Relaxed))
+    }
+
+    /// Returns bucket of given key
+    pub(crate) fn bucket(&self, key: &K) -> usize {
+        self.bucket_of_hash((self.key_hash)(key))
+    }
+
+    /// Returns bucket of a key with given hash, given by its highest bits
+    fn bucket_of_hash(&self, key_hash: u64) -> usize {
+        key_hash.checked_shr(64 - self.depth).unwrap_or(0) as usize
+    }
+}
+
+/// Returns buckets, that hold different entries in two trees of hashes of given depth,
+/// given hashes of their nodes by levels and indexes
+///
+/// Only children of differing nodes are compared, so few differences take few comparisons
+pub(crate) fn diff<A, B>(depth: u32, mut local: A, mut remote: B) -> Vec<usize>
+where
+    A: FnMut(u32, usize) -> u64,
+    B: FnMut(u32, usize) -> u64,
+{
+    let mut differing = vec![0];
+    for level in 0..=depth {
+        differing.retain(|&index| local(level, index) != remote(level, index));
+        if level < depth {
+            differing = differing
+                .into_iter()
+                .flat_map(|index| [2 * index, 2 * index + 1])
+                .collect();
+        }
+    }
+    differing
+}
+
+impl<K: BPlusKey> BPlus<K> {
+    /// Replaces hash of the entry by given key in hashes of the tree, if they are kept,
+    /// given handlers of its removed and inserted values
+    pub(crate) fn update_merkle(
+        &self,
+        key: &K,
+        removed: Option<&ChunkHandler>,
+        inserted: Option<&ChunkHandler>,
+    ) {
+        if let Some(merkle) = &self.merkle {
+            let digest = |handler: &ChunkHandler| handler.digest;
+            merkle.update(key, removed.map(digest), inserted.map(digest));
+        }
+    }
+
+    /// Returns hash of all entries of the tree, see [`BPlus::with_merkle`]
+    ///
+    /// Trees with equal entries have equal hashes, whatever their shapes and data files.
+    /// Buffered changes are hashed, once they are flushed. Hash is exact, when no change
+    /// is in flight. Returns None if hashes are not kept
+    pub fn root_hash(&self) -> Option<u64> {
+        self.merkle_hash(0, 0)
+    }
+
+    /// Returns hash of entries below node with given index among 2^level nodes of given level
+    /// of the tree of hashes, see [`BPlus::with_merkle`]
+    ///
+    /// Returns None if hashes are not kept or there is no such node
+    pub fn merkle_hash(&self, level: u32, index: usize) -> Option<u64> {
+        self.merkle.as_ref()?.hash(level, index)
+    }
+
+    /// Returns buckets of the tree of hashes, in which entries of this and given tree differ,
+    /// comparing only children of differing nodes, see [`BPlus::bucket`]
+    ///
+    /// Returns None if either tree keeps no hashes or their trees of hashes differ in depth
+    pub fn merkle_diff(&self, other: &BPlus<K>) -> Option<Vec<usize>> {
+        let (local, remote) = (self.merkle.as_ref()?, other.merkle.as_ref()?);
+        if local.depth() != remote.depth() {
+            return None;
+        }
+        let hash = |merkle: &Merkle<_>, level, index| merkle.hash(level, index).unwrap();
+        Some(diff(
+            local.depth(),
+            |level, index| hash(local, level, index),
+            |level, index| hash(remote, level, index),
+        ))
+    }
+
+    /// Makes entries of this tree equal to entries of given tree, copying only entries of buckets,
+    /// in which their hashes differ, see [`BPlus::merkle_diff`]
+    ///
+    /// Buffered changes of both trees are flushed, before hashes are compared and after copied
+    /// entries are inserted, so hashes are equal then. Values are compared by their hashes,
+    /// so only missing and changed values are read from the other tree. Changes, that are made
+    /// to either tree meanwhile, may be left to the next reconciliation
+    ///
+    /// Returns Err(_) if the trees do not keep hashes of the same depth, or some values could not
+    /// be read or written
+    pub async fn sync_from(&self, remote: &BPlus<K>) -> Result<SyncReport> {
+        self.flush_buffer().await?;
+        remote.flush_buffer().await?;
+        let buckets = self.merkle_diff(remote).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "trees do not keep hashes of the same depth",
+            )
+        })?;
+        let mut report = SyncReport {
+            buckets: buckets.len(),
+            ..SyncReport::default()
+        };
+        if buckets.is_empty() {
+            return Ok(report);
+        }
+        let buckets: BTreeSet<_> = buckets.into_iter().collect();
+        let merkle = self.merkle.as_ref().expect("hashes were compared");
+        let differing = |key: &K| buckets.contains(&merkle.bucket(key));
+
+        let mut local: BTreeMap<_, _> = self
+            .map_entries(|key, handler| differing(key).then(|| (key.clone(), handler.digest)))
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        let copied = remote
+            .map_entries(|key, handler| differing(key).then(|| (key.clone(), handler.clone())))
+            .await;
+        for (key, handler) in copied.into_iter().flatten() {
+            if local.remove(&key) == Some(handler.digest) {
+                continue;
+            }
+            let kind = handler.kind;
+            let value = remote.start_read(handler, Access::Point).await?;
+            self.insert_as(key, value, kind).await?;
+            report.inserted += 1;
+        }
+        for key in local.into_keys() {
+            report.removed += self.remove(&key).await.is_some() as usize;
+        }
+        self.flush_buffer().await?;
+        Ok(report)
+    }
+
+    /// Returns bucket of the tree of hashes, that holds given key, see [`BPlus::with_merkle`]
+    ///
+    /// Returns None if hashes are not kept
+    pub fn bucket(&self, key: &K) -> Option<usize> {
+        Some(self.merkle.as_ref()?.bucket(key))
+    }
+}
+
+impl<K: BPlusKeySerializable> BPlus<K> {
+    /// Makes tree keep hashes of its entries in a tree of hashes with 2^depth buckets,
+    /// see [`BPlus::root_hash`]
+    ///
+    /// Keys are distributed among buckets by hashes of their serialized form, so trees, that
+    /// are compared, should use the same depth. Depth is limited to 24, hashes of existing
+    /// entries are computed at once. Hashes are not kept by default
+    pub fn with_merkle(mut self, depth: u32) -> Self {
+        let merkle = Merkle::new(depth, key_hash::<K>);
+        self.for_each_entry(|key, handler| merkle.update(key, None, Some(handler.digest)));
+        self.merkle = Some(merkle);
+        self
+    }
+}
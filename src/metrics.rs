@@ -0,0 +1,379 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "metrics")]
+pub use prometheus_exporter::PrometheusCollector;
+
+/// Counters of operations of the tree, updated as operations go
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) gets: AtomicU64,
+    pub(crate) inserts: AtomicU64,
+    pub(crate) overwrites: AtomicU64,
+    pub(crate) splits: AtomicU64,
+    pub(crate) optimistic_fallbacks: AtomicU64,
+    pub(crate) appends: AtomicU64,
+    pub(crate) hint_hits: AtomicU64,
+    pub(crate) bloom_skips: AtomicU64,
+    pub(crate) coalesced_reads: AtomicU64,
+    pub(crate) latch_retries: AtomicU64,
+    pub(crate) file_rotations: AtomicU64,
+    pub(crate) bytes_written: AtomicU64,
+    pub(crate) bytes_read: AtomicU64,
+    pub(crate) slow_operations: AtomicU64,
+    pub(crate) get_latency: Histogram,
+    pub(crate) insert_latency: Histogram,
+    pub(crate) scan_latency: Histogram,
+    pub(crate) io_latency: Histogram,
+}
+
+/// Number of buckets per power of two, bounds relative error of percentiles by 1/SUB_BUCKETS
+const SUB_BUCKETS: u64 = 8;
+/// log2 of SUB_BUCKETS
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+/// Number of buckets, enough to hold any u64 number of microseconds
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS as u64 + 1) * SUB_BUCKETS) as usize;
+
+/// Histogram of durations in microseconds with log-linear buckets, like HDR histograms:
+/// every power of two is split into SUB_BUCKETS equal buckets
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+/// Snapshot of latencies of one kind of operations, in microseconds
+///
+/// Percentiles are upper bounds of histogram buckets, so they are precise up to 12.5%
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// Number of recorded operations
+    pub count: u64,
+    /// Mean latency
+    pub mean_us: u64,
+    /// Median latency
+    pub p50_us: u64,
+    /// 90th percentile of latency
+    pub p90_us: u64,
+    /// 99th percentile of latency
+    pub p99_us: u64,
+    /// 99.9th percentile of latency
+    pub p999_us: u64,
+    /// Maximal latency
+    pub max_us: u64,
+}
+
+/// Snapshot of operation counters of the tree
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Number of values read by key
+    pub gets: u64,
+    /// Number of inserted values, including overwrites
+    pub inserts: u64,
+    /// Number of inserts, that replaced value of an existing key
+    pub overwrites: u64,
+    /// Number of node splits
+    pub splits: u64,
+    /// Number of inserts, that found their leaf full and retook it to split
+    pub optimistic_fallbacks: u64,
+    /// Number of inserts of keys above all others, appended to the rightmost leaf without descent
+    pub appends: u64,
+    /// Number of operations, that started at the leaf of a valid hint instead of the root
+    pub hint_hits: u64,
+    /// Number of lookups of absent keys, that the bloom filter answered without descent
+    pub bloom_skips: u64,
+    /// Number of values of multi-gets, that were read in one call with the preceding value
+    pub coalesced_reads: u64,
+    /// Number of descents, that moved right or were restarted, because node was split under them
+    pub latch_retries: u64,
+    /// Number of switches to a new data file
+    pub file_rotations: u64,
+    /// Number of value bytes written to data files
+    pub bytes_written: u64,
+    /// Number of value bytes read from data files
+    pub bytes_read: u64,
+    /// Number of operations, that took longer than the slow operation threshold
+    pub slow_operations: u64,
+    /// Number of data files of the tree
+    pub data_files: u64,
+    /// Latencies of reads by key, including reading of the value
+    pub get_latency: LatencySnapshot,
+    /// Latencies of inserts, including writing of the value
+    pub insert_latency: LatencySnapshot,
+    /// Latencies of range scans
+    pub scan_latency: LatencySnapshot,
+    /// Durations of single reads and writes of values in data files
+    pub io_latency: LatencySnapshot,
+}
+
+impl Metrics {
+    /// Increments given counter by one
+    pub(crate) fn inc(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    /// Increments given counter by n
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns current values of all counters
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            gets: load(&self.gets),
+            inserts: load(&self.inserts),
+            overwrites: load(&self.overwrites),
+            splits: load(&self.splits),
+            optimistic_fallbacks: load(&self.optimistic_fallbacks),
+            appends: load(&self.appends),
+            hint_hits: load(&self.hint_hits),
+            bloom_skips: load(&self.bloom_skips),
+            coalesced_reads: load(&self.coalesced_reads),
+            latch_retries: load(&self.latch_retries),
+            file_rotations: load(&self.file_rotations),
+            bytes_written: load(&self.bytes_written),
+            bytes_read: load(&self.bytes_read),
+            slow_operations: load(&self.slow_operations),
+            data_files: 0,
+            get_latency: self.get_latency.snapshot(),
+            insert_latency: self.insert_latency.snapshot(),
+            scan_latency: self.scan_latency.snapshot(),
+            io_latency: self.io_latency.snapshot(),
+        }
+    }
+}
+
+/// Time spent by one operation waiting for latches and doing disk I/O
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct Phases {
+    pub(crate) latch_wait: Duration,
+    pub(crate) io: Duration,
+}
+
+impl Phases {
+    /// Awaits acquisition of a latch, adding time spent to latch wait
+    pub(crate) async fn wait<F: Future>(&mut self, latch: F) -> F::Output {
+        let start = Instant::now();
+        let guard = latch.await;
+        self.latch_wait += start.elapsed();
+        guard
+    }
+
+    /// Adds phases of an operation, that ran at the same time, keeping the longest of each phase
+    pub(crate) fn overlap(&mut self, other: Phases) {
+        self.latch_wait = self.latch_wait.max(other.latch_wait);
+        self.io = self.io.max(other.io);
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    /// Records one duration
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[Self::bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(micros, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Returns index of bucket, that holds given value
+    fn bucket(value: u64) -> usize {
+        if value < SUB_BUCKETS {
+            return value as usize;
+        }
+        let exponent = 63 - value.leading_zeros();
+        let shift = exponent - SUB_BUCKET_BITS;
+        let sub_bucket = (value >> shift) - SUB_BUCKETS;
+        ((u64::from(shift) + 1) * SUB_BUCKETS + sub_bucket) as usize
+    }
+
+    /// Returns the greatest value, that falls into bucket with given index
+    fn upper_bound(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKETS {
+            return bucket;
+        }
+        let shift = bucket / SUB_BUCKETS - 1;
+        let lower = (SUB_BUCKETS + bucket % SUB_BUCKETS) << shift;
+        lower.saturating_add((1 << shift) - 1)
+    }
+
+    /// Returns current percentiles of recorded durations
+    pub(crate) fn snapshot(&self) -> LatencySnapshot {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let count: u64 = counts.iter().sum();
+        if count == 0 {
+            return LatencySnapshot::default();
+        }
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |p: f64| {
+            let rank = ((count as f64 * p).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (bucket, &n) in counts.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    return Self::upper_bound(bucket).min(max);
+                }
+            }
+            max
+        };
+        LatencySnapshot {
+            count,
+            mean_us: self.sum.load(Ordering::Relaxed) / self.count.load(Ordering::Relaxed).max(1),
+            p50_us: percentile(0.5),
+            p90_us: percentile(0.9),
+            p99_us: percentile(0.99),
+            p999_us: percentile(0.999),
+            max_us: max,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+mod prometheus_exporter {
+    use std::sync::{Arc, Mutex};
+
+    use prometheus::{core::Desc, proto::MetricFamily, Collector, IntCounter, IntGauge};
+
+    use super::MetricsSnapshot;
+    use crate::bplus_tree::{BPlus, BPlusKey};
+
+    /// Reads one counter from the snapshot
+    type Field = fn(&MetricsSnapshot) -> u64;
+
+    /// Prometheus collector, that exports operation counters of the tree on every scrape
+    pub struct PrometheusCollector<K> {
+        /// Tree, whose metrics are exported
+        tree: Arc<BPlus<K>>,
+        /// Exported counters and fields of snapshot they are read from
+        counters: Vec<(IntCounter, Field)>,
+        /// Number of data files
+        data_files: IntGauge,
+        /// Serializes concurrent scrapes, as counters are reset on each of them
+        scrape: Mutex<()>,
+    }
+
+    impl<K: BPlusKey> PrometheusCollector<K> {
+        /// Creates collector for given tree, all metric names are prefixed with `bplus_`
+        ///
+        /// Returns Err(_) if metrics could not be created
+        pub fn new(tree: Arc<BPlus<K>>) -> prometheus::Result<Self> {
+            let fields: [(&str, &str, Field); 14] = [
+                ("gets", "Values read by key", |m| m.gets),
+                ("inserts", "Inserted values, including overwrites", |m| {
+                    m.inserts
+                }),
+                (
+                    "overwrites",
+                    "Inserts, that replaced an existing value",
+                    |m| m.overwrites,
+                ),
+                ("splits", "Node splits", |m| m.splits),
+                (
+                    "optimistic_fallbacks",
+                    "Inserts, that retook a full leaf to split it",
+                    |m| m.optimistic_fallbacks,
+                ),
+                (
+                    "appends",
+                    "Inserts appended to the rightmost leaf without descent",
+                    |m| m.appends,
+                ),
+                (
+                    "hint_hits",
+                    "Operations started at the leaf of a valid hint",
+                    |m| m.hint_hits,
+                ),
+                (
+                    "bloom_skips",
+                    "Lookups of absent keys answered by the bloom filter",
+                    |m| m.bloom_skips,
+                ),
+                (
+                    "coalesced_reads",
+                    "Values of multi-gets read in one call with the preceding value",
+                    |m| m.coalesced_reads,
+                ),
+                (
+                    "latch_retries",
+                    "Descents, that moved right or restarted",
+                    |m| m.latch_retries,
+                ),
+                ("file_rotations", "Switches to a new data file", |m| {
+                    m.file_rotations
+                }),
+                ("written_bytes", "Value bytes written to data files", |m| {
+                    m.bytes_written
+                }),
+                ("read_bytes", "Value bytes read from data files", |m| {
+                    m.bytes_read
+                }),
+                (
+                    "slow_operations",
+                    "Operations slower than the threshold",
+                    |m| m.slow_operations,
+                ),
+            ];
+            let counters = fields
+                .into_iter()
+                .map(|(name, help, field)| {
+                    Ok((
+                        IntCounter::new(format!("bplus_{}_total", name), help)?,
+                        field,
+                    ))
+                })
+                .collect::<prometheus::Result<_>>()?;
+
+            Ok(Self {
+                tree,
+                counters,
+                data_files: IntGauge::new("bplus_data_files", "Number of data files")?,
+                scrape: Mutex::new(()),
+            })
+        }
+    }
+
+    impl<K: BPlusKey> Collector for PrometheusCollector<K> {
+        fn desc(&self) -> Vec<&Desc> {
+            self.counters
+                .iter()
+                .flat_map(|(counter, _)| counter.desc())
+                .chain(self.data_files.desc())
+                .collect()
+        }
+
+        fn collect(&self) -> Vec<MetricFamily> {
+            let _scrape = self.scrape.lock().unwrap();
+            let metrics = self.tree.metrics();
+            let mut families = Vec::new();
+            for (counter, field) in &self.counters {
+                counter.reset();
+                counter.inc_by(field(&metrics));
+                families.extend(counter.collect());
+            }
+            self.data_files.set(metrics.data_files as i64);
+            families.extend(self.data_files.collect());
+            families
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Tree, that maps one key to several values
+//!
+//! Inserting an existing key appends another value instead of overwriting it. Values of a key
+//! are kept in order of their inserts, under composite keys of the key and a sequence number,
+//! which is increasing over the whole tree
+
+use std::{
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    bplus_tree::{BPlus, BPlusKey, BPlusKeySerializable},
+    error::{BPlusError, Result},
+};
+
+/// B+ tree, that keeps every value inserted by a key, instead of the last one
+pub struct MultiBPlus<K> {
+    /// Tree, that holds values by their keys and sequence numbers
+    tree: BPlus<(K, u64)>,
+    /// Sequence number of the next insert
+    next: AtomicU64,
+}
+
+impl<K: BPlusKey> MultiBPlus<K> {
+    /// Creates empty tree with given t, that writes values to directory by given path
+    pub fn new(t: usize, path: PathBuf) -> Result<Self> {
+        Ok(Self {
+            tree: BPlus::new(t, path)?,
+            next: AtomicU64::new(0),
+        })
+    }
+
+    /// Appends given value to values of given key
+    ///
+    /// Returns Err(_) if value could not be written to a file
+    pub async fn insert(&self, key: K, value: Vec<u8>) -> Result<()> {
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed);
+        self.tree.insert((key, sequence), value).await?;
+        Ok(())
+    }
+
+    /// Returns all values of given key in order of their inserts, empty if there are none
+    ///
+    /// Returns Err(_) if some of the values could not be read
+    pub async fn get_all(&self, key: &K) -> Result<Vec<Vec<u8>>> {
+        let entries = self.tree.scan(values_of(key)).await?;
+        Ok(entries.into_iter().map(|(_, value)| value).collect())
+    }
+
+    /// Removes value of given key at given position in order of inserts and returns it,
+    /// so following values of the key move one position down
+    ///
+    /// Returns Err(BPlusError::NotFound) if key has no value at given position
+    pub async fn remove_at(&self, key: &K, index: usize) -> Result<Vec<u8>> {
+        let entries = self.tree.scan(values_of(key)).await?;
+        let (key, value) = entries.into_iter().nth(index).ok_or(BPlusError::NotFound)?;
+        // Value was removed by another call after the scan
+        if self.tree.remove(&key).await.is_none() {
+            return Err(BPlusError::NotFound);
+        }
+        Ok(value)
+    }
+
+    /// Removes all values of given key and returns their number
+    pub async fn remove_all(&self, key: &K) -> Result<usize> {
+        let entries = self.tree.scan(values_of(key)).await?;
+        let mut removed = 0;
+        for (key, _) in entries {
+            removed += self.tree.remove(&key).await.is_some() as usize;
+        }
+        Ok(removed)
+    }
+
+    /// Syncs all data files to disk
+    pub async fn sync(&self) -> Result<()> {
+        self.tree.sync().await
+    }
+
+    /// Returns the underlying tree, that holds values by keys and their sequence numbers
+    pub fn tree(&self) -> &BPlus<(K, u64)> {
+        &self.tree
+    }
+}
+
+impl<K: BPlusKeySerializable + 'static> MultiBPlus<K> {
+    /// Saves tree to file by given path, see [`BPlus::save`]
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        self.tree.save(path).await
+    }
+
+    /// Loads tree from file by given path, see [`BPlus::load`]
+    ///
+    /// Values, that are inserted after loading, follow all loaded values of their keys
+    pub async fn load(path: &Path) -> Result<Self> {
+        let tree = BPlus::load(path).await?;
+        let next = tree
+            .keys()
+            .await
+            .iter()
+            .map(|(_, sequence)| sequence + 1)
+            .max()
+            .unwrap_or(0);
+        Ok(Self {
+            tree,
+            next: AtomicU64::new(next),
+        })
+    }
+}
+
+/// Returns range of composite keys of all values of given key
+fn values_of<K: Clone>(key: &K) -> RangeInclusive<(K, u64)> {
+    (key.clone(), 0)..=(key.clone(), u64::MAX)
+}
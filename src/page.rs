@@ -0,0 +1,357 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use crate::error::{BPlusError, Result};
+
+/// Number of a page in the page file
+pub type PageId = u64;
+
+/// Default size of a page in bytes
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+/// Minimal supported page size in bytes
+pub const MIN_PAGE_SIZE: usize = 512;
+/// Maximal supported page size in bytes, offsets within a page are 16-bit
+pub const MAX_PAGE_SIZE: usize = 32 * 1024;
+
+/// Magic bytes at the start of the page file
+const MAGIC: &[u8; 8] = b"BPLSPAGE";
+/// Size of the file header, stored in page 0
+const FILE_HEADER_SIZE: usize = 32;
+/// Size of the header of every page:
+/// checksum (4), kind (1), slot count (2), start of cells (2), link (8)
+pub const PAGE_HEADER_SIZE: usize = 17;
+/// Size of one entry of the slot directory: offset (2) and length (2) of a cell
+pub const SLOT_SIZE: usize = 4;
+/// Value of a link, that points nowhere
+const NO_LINK: u64 = u64::MAX;
+
+/// Kind of the page content
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageKind {
+    /// Page is in the free list
+    Free,
+    /// Page holds a leaf
+    Leaf,
+    /// Page holds an internal node
+    Internal,
+    /// Page holds metadata of the file owner, e.g. pointer to the root
+    Meta,
+    /// Page holds part of a key, that does not fit in the page of its node,
+    /// and links to the page with the next part
+    Overflow,
+}
+
+/// Page with slotted layout: header, directory of slots growing forward after it,
+/// and variable-length cells growing backward from the end of the page
+#[derive(Clone, Debug)]
+pub struct Page {
+    data: Vec<u8>,
+}
+
+impl Page {
+    /// Creates empty page of given kind and size
+    pub fn new(kind: PageKind, page_size: usize) -> Self {
+        let mut page = Self {
+            data: vec![0; page_size],
+        };
+        page.data[4] = kind as u8;
+        page.set_u16(7, page_size as u16);
+        page.set_link(None);
+        page
+    }
+
+    /// Returns kind of the page
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if kind is unknown
+    pub fn kind(&self) -> Result<PageKind> {
+        match self.data[4] {
+            0 => Ok(PageKind::Free),
+            1 => Ok(PageKind::Leaf),
+            2 => Ok(PageKind::Internal),
+            3 => Ok(PageKind::Meta),
+            4 => Ok(PageKind::Overflow),
+            kind => Err(BPlusError::Corruption(format!("unknown page kind {kind}"))),
+        }
+    }
+
+    /// Returns page, this page links to: next leaf, first child, next overflow page
+    /// or next free page
+    pub fn link(&self) -> Option<PageId> {
+        let link = u64::from_le_bytes(self.data[9..17].try_into().unwrap());
+        (link != NO_LINK).then_some(link)
+    }
+
+    /// Sets page, this page links to
+    pub fn set_link(&mut self, link: Option<PageId>) {
+        self.data[9..17].copy_from_slice(&link.unwrap_or(NO_LINK).to_le_bytes());
+    }
+
+    /// Returns number of cells in the page
+    pub fn len(&self) -> usize {
+        self.get_u16(5) as usize
+    }
+
+    /// Returns whether the page has no cells
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns cell by given index
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if slot points outside of the page
+    pub fn cell(&self, index: usize) -> Result<&[u8]> {
+        let slot = PAGE_HEADER_SIZE + index * SLOT_SIZE;
+        let offset = self.get_u16(slot) as usize;
+        let len = self.get_u16(slot + 2) as usize;
+        self.data
+            .get(offset..offset + len)
+            .ok_or_else(|| BPlusError::Corruption(format!("slot {index} is out of page")))
+    }
+
+    /// Returns number of bytes left for new cells together with their slots
+    pub fn free_space(&self) -> usize {
+        self.get_u16(7) as usize - (PAGE_HEADER_SIZE + self.len() * SLOT_SIZE)
+    }
+
+    /// Appends cell to the page
+    ///
+    /// Returns false if there is not enough free space for it
+    pub fn push(&mut self, cell: &[u8]) -> bool {
+        if cell.len() + SLOT_SIZE > self.free_space() {
+            return false;
+        }
+        let len = self.len();
+        let offset = self.get_u16(7) as usize - cell.len();
+        self.data[offset..offset + cell.len()].copy_from_slice(cell);
+        let slot = PAGE_HEADER_SIZE + len * SLOT_SIZE;
+        self.set_u16(slot, offset as u16);
+        self.set_u16(slot + 2, cell.len() as u16);
+        self.set_u16(5, len as u16 + 1);
+        self.set_u16(7, offset as u16);
+        true
+    }
+
+    fn get_u16(&self, at: usize) -> u16 {
+        u16::from_le_bytes([self.data[at], self.data[at + 1]])
+    }
+
+    fn set_u16(&mut self, at: usize, value: u16) {
+        self.data[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// File of fixed-size pages with a free list of released pages
+///
+/// Page 0 holds the file header, every other page is protected by a checksum
+pub struct PageFile {
+    file: File,
+    page_size: usize,
+    /// Number of pages in the file, including the header page
+    page_count: u64,
+    /// First page of the free list
+    free_head: Option<PageId>,
+}
+
+impl PageFile {
+    /// Opens page file by given path, creating it with given page size if it does not exist
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if existing file is not a page file,
+    /// or its page size differs from the given one
+    pub fn open(path: &Path, page_size: usize) -> Result<Self> {
+        if !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("page size {page_size} is out of supported range"),
+            )
+            .into());
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            let pages = Self {
+                file,
+                page_size,
+                page_count: 1,
+                free_head: None,
+            };
+            pages.file.set_len(page_size as u64)?;
+            pages.write_header()?;
+            return Ok(pages);
+        }
+
+        let mut header = [0; FILE_HEADER_SIZE];
+        file.read_exact_at(&mut header, 0)?;
+        let checksum = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        if checksum != crc32(&header[4..]) || &header[4..12] != MAGIC {
+            return Err(BPlusError::Corruption(
+                "invalid page file header".to_string(),
+            ));
+        }
+        let saved_page_size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+        if saved_page_size != page_size {
+            return Err(BPlusError::Corruption(format!(
+                "page size is {saved_page_size}, not {page_size}"
+            )));
+        }
+        let free_head = u64::from_le_bytes(header[24..32].try_into().unwrap());
+        Ok(Self {
+            file,
+            page_size,
+            page_count: u64::from_le_bytes(header[16..24].try_into().unwrap()),
+            free_head: (free_head != NO_LINK).then_some(free_head),
+        })
+    }
+
+    /// Returns size of pages in bytes
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Returns number of pages in the file, including the header page
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    /// Allocates page, reusing released pages first
+    ///
+    /// Allocated page is empty and free until something is written to it
+    pub fn allocate(&mut self) -> Result<PageId> {
+        let id = match self.free_head {
+            Some(id) => {
+                self.free_head = self.read(id)?.link();
+                id
+            }
+            None => {
+                let id = self.page_count;
+                self.write(id, &Page::new(PageKind::Free, self.page_size))?;
+                self.page_count += 1;
+                id
+            }
+        };
+        self.write_header()?;
+        Ok(id)
+    }
+
+    /// Releases page, so it is reused by later allocations
+    pub fn free(&mut self, id: PageId) -> Result<()> {
+        self.check_id(id)?;
+        let mut page = Page::new(PageKind::Free, self.page_size);
+        page.set_link(self.free_head);
+        self.write(id, &page)?;
+        self.free_head = Some(id);
+        self.write_header()
+    }
+
+    /// Returns ids of released pages in order of the free list
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if the free list has a cycle
+    pub fn free_pages(&self) -> Result<Vec<PageId>> {
+        let mut pages = Vec::new();
+        let mut next = self.free_head;
+        while let Some(id) = next {
+            if pages.len() as u64 >= self.page_count {
+                return Err(BPlusError::Corruption("free list has a cycle".to_string()));
+            }
+            pages.push(id);
+            next = self.read(id)?.link();
+        }
+        Ok(pages)
+    }
+
+    /// Reads page by given id
+    ///
+    /// Returns Err(BPlusError::NotFound) if there is no such page,
+    /// and Err(BPlusError::Corruption(_)) if its checksum does not match
+    pub fn read(&self, id: PageId) -> Result<Page> {
+        self.check_id(id)?;
+        let mut data = vec![0; self.page_size];
+        self.file
+            .read_exact_at(&mut data, id * self.page_size as u64)?;
+        let checksum = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        if checksum != crc32(&data[4..]) {
+            return Err(BPlusError::Corruption(format!(
+                "checksum mismatch in page {id}"
+            )));
+        }
+        Ok(Page { data })
+    }
+
+    /// Writes page by given id, updating its checksum
+    pub fn write(&self, id: PageId, page: &Page) -> Result<()> {
+        let mut data = page.data.clone();
+        let checksum = crc32(&data[4..]);
+        data[0..4].copy_from_slice(&checksum.to_le_bytes());
+        self.file.write_all_at(&data, id * self.page_size as u64)?;
+        Ok(())
+    }
+
+    /// Syncs the page file to disk
+    pub fn sync(&self) -> Result<()> {
+        Ok(self.file.sync_all()?)
+    }
+
+    /// Returns Err(BPlusError::NotFound) if given id is not an id of a data page
+    fn check_id(&self, id: PageId) -> Result<()> {
+        if id == 0 || id >= self.page_count {
+            return Err(BPlusError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Writes file header to page 0
+    fn write_header(&self) -> Result<()> {
+        let mut header = [0; FILE_HEADER_SIZE];
+        header[4..12].copy_from_slice(MAGIC);
+        header[12..16].copy_from_slice(&(self.page_size as u32).to_le_bytes());
+        header[16..24].copy_from_slice(&self.page_count.to_le_bytes());
+        header[24..32].copy_from_slice(&self.free_head.unwrap_or(NO_LINK).to_le_bytes());
+        let checksum = crc32(&header[4..]);
+        header[0..4].copy_from_slice(&checksum.to_le_bytes());
+        self.file.write_all_at(&header, 0)?;
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE) of every byte, so checksums are computed a byte at a time
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes CRC-32 (IEEE) of given bytes
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    crc32_extend(0, data)
+}
+
+/// Computes CRC-32 (IEEE) of bytes, whose prefix has given checksum, followed by given bytes
+pub(crate) fn crc32_extend(checksum: u32, data: &[u8]) -> u32 {
+    let mut crc = !checksum;
+    for &byte in data {
+        crc = (crc >> 8) ^ CRC_TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize];
+    }
+    !crc
+}
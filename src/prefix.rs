@@ -0,0 +1,196 @@
+//! Prefix compression of separators of internal nodes
+//!
+//! Separators of byte string keys (`Vec<u8>` and `String`) in one node usually share a long
+//! prefix, e.g. a common directory of paths. It is stored once per node, and only the rest of
+//! every separator is kept. Separators of other keys are stored as they are.
+//!
+//! When a leaf splits, separator of byte string keys is truncated to the shortest prefix,
+//! that still divides the halves
+
+use std::any::Any;
+
+use crate::search::{search, search_by};
+
+/// Sorted separators of an internal node
+#[derive(Clone)]
+pub(crate) struct Separators<K> {
+    /// Common prefix of all separators, which is removed from them; None if not compressed
+    prefix: Option<K>,
+    /// Separators, or their suffixes after the prefix
+    keys: Vec<K>,
+}
+
+impl<K: Ord + Clone + 'static> Separators<K> {
+    /// Creates separators from given sorted keys, compressing their common prefix
+    pub(crate) fn new(keys: Vec<K>) -> Self {
+        let prefix_len = match (keys.first(), keys.last()) {
+            (Some(first), Some(last)) if keys.len() > 1 => common_prefix(first, last),
+            _ => None,
+        };
+        let Some(prefix_len) = prefix_len.filter(|&len| len > 0) else {
+            return Self { prefix: None, keys };
+        };
+        let prefix = slice(&keys[0], 0, prefix_len);
+        let mut suffixes = Vec::with_capacity(keys.capacity());
+        suffixes.extend(keys.iter().map(|key| slice(key, prefix_len, usize::MAX)));
+        Self {
+            prefix: Some(prefix),
+            keys: suffixes,
+        }
+    }
+
+    /// Returns index of the child, that covers given key
+    pub(crate) fn child_index(&self, key: &K) -> usize {
+        let pos = match &self.prefix {
+            None => search(&self.keys, key),
+            Some(prefix) => {
+                let (prefix, key) = (bytes(prefix), bytes(key));
+                match key.strip_prefix(prefix) {
+                    Some(rest) => search_by(&self.keys, |k| bytes(k).cmp(rest)),
+                    // Key without the prefix is below or above all separators
+                    None if key < prefix => Err(0),
+                    None => Err(self.keys.len()),
+                }
+            }
+        };
+        match pos {
+            Ok(pos) => pos + 1,
+            Err(pos) => pos,
+        }
+    }
+
+    /// Inserts separator by given index, keeping the order
+    ///
+    /// Prefix is shortened, if the separator does not start with it, and is not extended
+    /// until the node is split
+    pub(crate) fn insert(&mut self, index: usize, key: K) {
+        match &self.prefix {
+            None => self.keys.insert(index, key),
+            Some(prefix) if bytes(&key).starts_with(bytes(prefix)) => {
+                let suffix = slice(&key, bytes(prefix).len(), usize::MAX);
+                self.keys.insert(index, suffix);
+            }
+            Some(_) => {
+                let mut keys = self.to_vec();
+                keys.reserve_exact(self.keys.capacity() - keys.len());
+                keys.insert(index, key);
+                *self = Self::new(keys);
+            }
+        }
+    }
+
+    /// Splits separators in two at given index, compressing both halves again
+    ///
+    /// Returns separators from the index on
+    pub(crate) fn split_off(&mut self, at: usize) -> Vec<K> {
+        let mut keys = self.to_vec();
+        let right = keys.split_off(at);
+        keys.reserve_exact(self.keys.capacity() - keys.len());
+        *self = Self::new(keys);
+        right
+    }
+}
+
+impl<K: Clone + 'static> Separators<K> {
+    /// Returns number of separators
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns number of separators, that fit without reallocation
+    pub(crate) fn capacity(&self) -> usize {
+        self.keys.capacity()
+    }
+
+    /// Returns full separator by given index
+    pub(crate) fn get(&self, index: usize) -> Option<K> {
+        let key = self.keys.get(index)?;
+        Some(match &self.prefix {
+            Some(prefix) => concat(prefix, key),
+            None => key.clone(),
+        })
+    }
+
+    /// Returns full separators in order
+    pub(crate) fn to_vec(&self) -> Vec<K> {
+        (0..self.len()).filter_map(|i| self.get(i)).collect()
+    }
+
+    /// Returns bytes taken by the stored prefix, excluding heap memory owned by it
+    pub(crate) fn prefix_size(&self) -> usize {
+        self.prefix.as_ref().map_or(0, |_| size_of::<K>())
+    }
+}
+
+/// Returns the shortest separator, that is greater than left key and not greater than right one
+///
+/// Separator is a prefix of right key for byte strings, and right key itself for other keys
+pub(crate) fn shortest_separator<K: Clone + 'static>(left: &K, right: &K) -> K {
+    let Some(common) = common_prefix(left, right) else {
+        return right.clone();
+    };
+    // Right key is greater, so it is longer than the common prefix
+    let mut len = common + 1;
+    if let Some(string) = (right as &dyn Any).downcast_ref::<String>() {
+        len = (len..=string.len())
+            .find(|&i| string.is_char_boundary(i))
+            .unwrap_or(string.len());
+    }
+    slice(right, 0, len)
+}
+
+/// Returns bytes of key of a byte string type
+///
+/// Called only for keys of a node with a prefix, which are byte strings
+fn bytes<K: 'static>(key: &K) -> &[u8] {
+    as_bytes(key).expect("Only byte strings are compressed")
+}
+
+/// Returns bytes of key, if it is a byte string
+fn as_bytes<K: 'static>(key: &K) -> Option<&[u8]> {
+    let key = key as &dyn Any;
+    key.downcast_ref::<Vec<u8>>()
+        .map(Vec::as_slice)
+        .or_else(|| key.downcast_ref::<String>().map(String::as_bytes))
+}
+
+/// Returns length of common prefix of two keys, if they are byte strings
+///
+/// Prefix of strings ends on a character boundary
+fn common_prefix<K: 'static>(first: &K, second: &K) -> Option<usize> {
+    let (first_bytes, second_bytes) = (as_bytes(first)?, as_bytes(second)?);
+    let len = first_bytes
+        .iter()
+        .zip(second_bytes)
+        .take_while(|(a, b)| a == b)
+        .count();
+    Some(match (first as &dyn Any).downcast_ref::<String>() {
+        Some(string) => (0..=len)
+            .rev()
+            .find(|&i| string.is_char_boundary(i))
+            .unwrap_or(0),
+        None => len,
+    })
+}
+
+/// Returns key made of bytes of given key in start..end
+fn slice<K: 'static>(key: &K, start: usize, end: usize) -> K {
+    let bytes = bytes(key);
+    from_bytes(key, &bytes[start..end.min(bytes.len())])
+}
+
+/// Returns key made of bytes of prefix followed by bytes of suffix
+fn concat<K: 'static>(prefix: &K, suffix: &K) -> K {
+    from_bytes(prefix, &[bytes(prefix), bytes(suffix)].concat())
+}
+
+/// Returns key of the same type as given one, made of given bytes
+fn from_bytes<K: 'static>(like: &K, bytes: &[u8]) -> K {
+    let key: Box<dyn Any> = if (like as &dyn Any).is::<String>() {
+        let string = std::str::from_utf8(bytes).expect("Strings are split on character boundaries");
+        Box::new(string.to_string())
+    } else {
+        Box::new(bytes.to_vec())
+    };
+    *key.downcast().expect("Only byte strings are compressed")
+}
@@ -0,0 +1,291 @@
+//! Format of data files: their headers and framing of values in them
+//!
+//! Every data file starts with a header, that identifies the file, its format and the store,
+//! that it belongs to, so files of other versions or of other stores are rejected on open.
+//! Layout of the header, all integers are little-endian on every architecture:
+//!
+//! | Field    | Size | Content                                                  |
+//! |----------|------|----------------------------------------------------------|
+//! | magic    | 8    | `BPLSDATA`                                               |
+//! | version  | 4    | version of the format of data files, [`FILE_VERSION`]    |
+//! | flags    | 4    | features, that readers must support, e.g. compression    |
+//! | created  | 8    | time of creation of the file, in seconds since Unix epoch |
+//! | store    | 16   | random id of the store, that the file belongs to         |
+//! | checksum | 4    | CRC-32 (IEEE) of the fields before it                    |
+//!
+//! No flags are defined yet, so files with any of them set are rejected.
+//!
+//! Every value is written as a record: header of magic bytes, CRC-32 of the value and its
+//! length, followed by the value. So a value, that was torn by a crash during its append,
+//! is detected on read and skipped on recovery of the data file.
+//! Blob files of large values hold the header of a data file and one record
+//!
+//! Layout of a record, all integers are little-endian on every architecture:
+//!
+//! | Field    | Size   | Content                                  |
+//! |----------|--------|------------------------------------------|
+//! | magic    | 3      | `BPR`                                    |
+//! | version  | 1      | version of the layout, [`VERSION`]       |
+//! | checksum | 4      | CRC-32 (IEEE) of the value               |
+//! | length   | 8      | length of the value                      |
+//! | value    | length | bytes of the value                       |
+
+use std::{
+    fmt,
+    fs::File,
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{BPlusError, Result},
+    page::crc32,
+};
+
+/// Size of the header of a data file
+pub(crate) const FILE_HEADER_SIZE: u64 = 44;
+/// Bytes, that start every data file
+const FILE_MAGIC: &[u8; 8] = b"BPLSDATA";
+/// Version of the format, that data files are written in
+pub(crate) const FILE_VERSION: u32 = 1;
+/// Flags of features, that this version reads
+const KNOWN_FLAGS: u32 = 0;
+
+/// Size of the header of a record: magic (3), version (1), checksum (4), length (8)
+pub(crate) const HEADER_SIZE: u64 = 16;
+
+/// Bytes, that start every record
+const MAGIC: &[u8; 3] = b"BPR";
+/// Version of the layout, that records are written in
+const VERSION: u8 = 1;
+
+/// Appends record of given value to given buffer
+pub(crate) fn encode(value: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&header(value));
+    buf.extend_from_slice(value);
+}
+
+/// Returns header of the record of given value, that precedes the value
+pub(crate) fn header(value: &[u8]) -> [u8; HEADER_SIZE as usize] {
+    let mut header = [0; HEADER_SIZE as usize];
+    header[0..3].copy_from_slice(MAGIC);
+    header[3] = VERSION;
+    header[4..8].copy_from_slice(&crc32(value).to_le_bytes());
+    header[8..16].copy_from_slice(&(value.len() as u64).to_le_bytes());
+    header
+}
+
+/// Returns value of given record, that has to hold a value of given size
+///
+/// Returns Err(_) of kind InvalidData if record is torn or corrupted,
+/// or was written in a newer version of the layout
+pub(crate) fn decode(record: &[u8], size: usize) -> io::Result<&[u8]> {
+    let (header, value) = record.split_at(HEADER_SIZE as usize);
+    check_version(header)?;
+    let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    if &header[0..3] != MAGIC || len != size as u64 || value.len() != size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid header of a value record",
+        ));
+    }
+    if checksum != crc32(value) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "checksum mismatch in a value record",
+        ));
+    }
+    Ok(value)
+}
+
+/// Returns end of the last intact record in given file, that has given size, checking records
+/// from given offset, which is the start of a record
+///
+/// Returns Err(_) of kind InvalidData if a record was written in a newer version of the
+/// layout, so it is not mistaken for a torn one
+pub(crate) fn intact_end(file: &File, size: u64, mut offset: u64) -> io::Result<u64> {
+    let mut header = [0; HEADER_SIZE as usize];
+    while offset + HEADER_SIZE <= size {
+        file.read_exact_at(&mut header, offset)?;
+        let len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let end = (offset + HEADER_SIZE).saturating_add(len);
+        if &header[0..3] != MAGIC || end > size {
+            break;
+        }
+        check_version(&header)?;
+        let mut record = vec![0; (end - offset) as usize];
+        file.read_exact_at(&mut record, offset)?;
+        if decode(&record, len as usize).is_err() {
+            break;
+        }
+        offset = end;
+    }
+    Ok(offset)
+}
+
+/// Returns Err(_) of kind InvalidData if record with given header has a newer version
+fn check_version(header: &[u8]) -> io::Result<()> {
+    if &header[0..3] == MAGIC && header[3] > VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported version {} of a value record", header[3]),
+        ));
+    }
+    Ok(())
+}
+
+/// Header at the start of every data file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DataFileHeader {
+    /// Version of the format of the file
+    pub version: u32,
+    /// Features of the file, that readers must support
+    pub flags: u32,
+    /// Time of creation of the file, rounded down to seconds
+    pub created: SystemTime,
+    /// Id of the store, that the file belongs to, see [`StoreId`]
+    pub store: StoreId,
+}
+
+impl DataFileHeader {
+    /// Returns header of a new data file of given store
+    pub(crate) fn new(store: StoreId) -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            version: FILE_VERSION,
+            flags: 0,
+            created: UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()),
+            store,
+        }
+    }
+
+    /// Reads header of data file by given path
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if file does not start with a header of a data file,
+    /// Err(BPlusError::UnsupportedVersion { .. }) if it was written in another version,
+    /// which header may have another layout
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        Self::read_from(&file, path)
+    }
+
+    /// Reads header from given data file by given path
+    pub(crate) fn read_from(file: &File, path: &Path) -> Result<Self> {
+        let mut bytes = [0; FILE_HEADER_SIZE as usize];
+        match file.read_exact_at(&mut bytes, 0) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(BPlusError::Corruption(format!(
+                    "{} is too short for a data file",
+                    path.display()
+                )));
+            }
+            result => result?,
+        }
+        let corrupted = || {
+            BPlusError::Corruption(format!(
+                "{} has no valid header of a data file",
+                path.display()
+            ))
+        };
+        if &bytes[0..8] != FILE_MAGIC {
+            return Err(corrupted());
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != FILE_VERSION {
+            return Err(BPlusError::UnsupportedVersion {
+                version,
+                supported: FILE_VERSION,
+            });
+        }
+        let checksum = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        if checksum != crc32(&bytes[..40]) {
+            return Err(corrupted());
+        }
+        let created = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+        Ok(Self {
+            version,
+            flags: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            created: UNIX_EPOCH + Duration::from_secs(created),
+            store: StoreId(u128::from_le_bytes(bytes[24..40].try_into().unwrap())),
+        })
+    }
+
+    /// Returns encoded header, see the layout above
+    pub(crate) fn encode(&self) -> [u8; FILE_HEADER_SIZE as usize] {
+        let created = self
+            .created
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        let mut bytes = [0; FILE_HEADER_SIZE as usize];
+        bytes[0..8].copy_from_slice(FILE_MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[16..24].copy_from_slice(&created.to_le_bytes());
+        bytes[24..40].copy_from_slice(&self.store.0.to_le_bytes());
+        let checksum = crc32(&bytes[..40]);
+        bytes[40..44].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Checks, that data file by given path with this header is readable and belongs to given store
+    ///
+    /// Returns Err(BPlusError::Corruption(_)) if it belongs to another store
+    pub(crate) fn check(&self, path: &Path, store: StoreId) -> Result<()> {
+        if self.flags & !KNOWN_FLAGS != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} uses unsupported features {:#x}",
+                    path.display(),
+                    self.flags & !KNOWN_FLAGS
+                ),
+            )
+            .into());
+        }
+        if self.store != store {
+            return Err(BPlusError::Corruption(format!(
+                "{} belongs to store {}, not to {store}",
+                path.display(),
+                self.store
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Random id of a store, that is shared by all of its data files, formatted as a UUID
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StoreId(u128);
+
+impl StoreId {
+    /// Returns new random id, which is a version 4 UUID
+    pub(crate) fn random() -> Self {
+        let bits = rand::random::<u128>();
+        // Version 4 and variant 1 of RFC 9562
+        let bits = bits & !(0xf << 76) | (0x4 << 76);
+        let bits = bits & !(0x3 << 62) | (0x2 << 62);
+        Self(bits)
+    }
+}
+
+impl fmt::Display for StoreId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+}
@@ -0,0 +1,303 @@
+//! Recording of operations of a tree, that are replayed against a fresh tree
+//!
+//! Recorder appends every insert, removal, read, scan, rebuild and compaction of a tree
+//! to a file, with keys, sizes of values and results of reads, see
+//! [`crate::bplus_tree::BPlus::with_recorder`]. Values are recorded in full, or only
+//! by their sizes and hashes, see [`Recorder::with_hashed_values`].
+//!
+//! [`Replayer`] executes recorded operations in their order against another tree and
+//! reports operations, which results differ from the recorded ones, e.g. a read of a
+//! corrupted value. Values, that are recorded by hashes, are replayed as filler values
+//! of the same sizes, that are derived from their hashes
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, Write},
+    marker::PhantomData,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    audit::read_frame,
+    bplus_tree::{BPlus, BPlusKeySerializable},
+    error::{BPlusError, Result},
+    merkle::hash_bytes,
+};
+
+/// Value of a recorded operation
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordedValue {
+    /// Value itself
+    Full(Vec<u8>),
+    /// Size and hash of the value
+    Hashed {
+        /// Size of the value in bytes
+        size: u64,
+        /// Hash of the value, that is the same on every platform
+        hash: u64,
+    },
+}
+
+impl RecordedValue {
+    /// Returns size of the value in bytes
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::Full(value) => value.len() as u64,
+            Self::Hashed { size, .. } => *size,
+        }
+    }
+
+    /// Returns value, that is written, when the recorded one is replayed
+    ///
+    /// Filler of a hashed value repeats bytes of its hash, so replayed reads of values,
+    /// that were written with equal hashes, return equal values
+    pub fn replayed(&self) -> Vec<u8> {
+        match self {
+            Self::Full(value) => value.clone(),
+            Self::Hashed { size, hash } => hash
+                .to_le_bytes()
+                .into_iter()
+                .cycle()
+                .take(*size as usize)
+                .collect(),
+        }
+    }
+}
+
+/// Recorded operation of a tree
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation<K> {
+    /// Value was inserted by the key
+    Insert {
+        /// Inserted key
+        key: K,
+        /// Inserted value
+        value: RecordedValue,
+    },
+    /// Key was removed
+    Remove {
+        /// Removed key
+        key: K,
+        /// Whether the key was in the tree
+        found: bool,
+    },
+    /// Value was read by the key
+    Get {
+        /// Read key
+        key: K,
+        /// Read value; None if it was not found or could not be read
+        value: Option<RecordedValue>,
+    },
+    /// Entries with keys in the range were scanned
+    Scan {
+        /// Start of the range
+        start: Bound<K>,
+        /// End of the range
+        end: Bound<K>,
+        /// Number of scanned entries; None if values could not be read
+        entries: Option<u64>,
+    },
+    /// Tree was rebuilt, see [`BPlus::rebuild`]
+    Rebuild,
+    /// Data files were compacted, see [`BPlus::compact_files`]
+    Compact {
+        /// Max number of compacted files
+        limit: usize,
+    },
+}
+
+/// Log, that records operations of a tree to a file
+pub struct Recorder<K> {
+    path: PathBuf,
+    /// Whether values are recorded only by their sizes and hashes
+    hashed_values: bool,
+    file: Mutex<File>,
+    /// Error of the last failed record, that is not reported yet
+    error: Mutex<Option<BPlusError>>,
+    _keys: PhantomData<fn(K)>,
+}
+
+impl<K: Serialize + DeserializeOwned> Recorder<K> {
+    /// Opens log by given path, appending operations after the existing ones
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            hashed_values: false,
+            file: Mutex::new(file),
+            error: Mutex::default(),
+            _keys: PhantomData,
+        })
+    }
+
+    /// Makes log record values only by their sizes and hashes, e.g. to keep
+    /// contents of values private and the log small
+    ///
+    /// Values are recorded in full by default
+    pub fn with_hashed_values(mut self) -> Self {
+        self.hashed_values = true;
+        self
+    }
+
+    /// Returns path of the log
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends given operation to the log
+    pub fn append(&self, operation: &Operation<&K>) -> Result<()> {
+        let data = bincode::serialize(operation)?;
+        let mut frame = Vec::with_capacity(data.len() + 8);
+        frame.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        frame.extend_from_slice(&data);
+        // Whole operation is written at once, so a failed write leaves at most one torn record
+        self.file.lock().unwrap().write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Returns error of the last failed record of an operation of the tree, if there is one
+    ///
+    /// Returned error is considered reported and will not be returned again
+    pub fn last_error(&self) -> Option<BPlusError> {
+        self.error.lock().unwrap().take()
+    }
+}
+
+/// Receiver of operations of a tree, which keys may not be serializable themselves
+pub(crate) trait OperationSink<K>: Send + Sync {
+    /// Returns given value, as it is recorded
+    fn value(&self, value: &[u8]) -> RecordedValue;
+
+    /// Records given operation, keeping the error to report it later
+    fn record(&self, operation: Operation<&K>);
+}
+
+impl<K: Serialize + DeserializeOwned> OperationSink<K> for Recorder<K> {
+    fn value(&self, value: &[u8]) -> RecordedValue {
+        match self.hashed_values {
+            true => RecordedValue::Hashed {
+                size: value.len() as u64,
+                hash: hash_bytes(value),
+            },
+            false => RecordedValue::Full(value.to_vec()),
+        }
+    }
+
+    fn record(&self, operation: Operation<&K>) {
+        if let Err(e) = self.append(&operation) {
+            *self.error.lock().unwrap() = Some(e);
+        }
+    }
+}
+
+/// Operation, which result differs between the log and its replay
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence<K> {
+    /// Index of the operation in the log
+    pub index: usize,
+    /// Operation as it was recorded
+    pub recorded: Operation<K>,
+    /// Operation as it was replayed, with values read by the replay in full
+    pub replayed: Operation<K>,
+}
+
+/// Result of a replay, see [`Replayer::replay`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport<K> {
+    /// Number of replayed operations
+    pub operations: usize,
+    /// Operations, which results differ from the recorded ones, in order of the log
+    pub divergences: Vec<Divergence<K>>,
+}
+
+/// Operations, that are read from a log, to be executed against another tree
+pub struct Replayer<K> {
+    operations: Vec<Operation<K>>,
+}
+
+impl<K: Serialize + DeserializeOwned> Replayer<K> {
+    /// Reads operations from the log by given path
+    ///
+    /// Torn operation at the end of the log, which record was interrupted, is skipped
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut operations = Vec::new();
+        while let Some(data) = read_frame(&mut reader)? {
+            operations.push(bincode::deserialize(&data)?);
+        }
+        Ok(Self { operations })
+    }
+
+    /// Returns recorded operations in their order
+    pub fn operations(&self) -> &[Operation<K>] {
+        &self.operations
+    }
+}
+
+impl<K: BPlusKeySerializable> Replayer<K> {
+    /// Executes recorded operations one by one against given tree, which is usually
+    /// a fresh one, comparing results of removals, reads and scans with the recorded ones
+    ///
+    /// Returns Err(_) if an insert, rebuild or compaction fails, then following operations
+    /// are not replayed
+    pub async fn replay(&self, tree: &BPlus<K>) -> Result<ReplayReport<K>> {
+        let mut report = ReplayReport::default();
+        for (index, recorded) in self.operations.iter().enumerate() {
+            let replayed = match recorded {
+                Operation::Insert { key, value } => {
+                    tree.insert(key.clone(), value.replayed()).await?;
+                    None
+                }
+                Operation::Remove { key, found } => {
+                    let removed = tree.remove(key).await.is_some();
+                    (removed != *found).then(|| Operation::Remove {
+                        key: key.clone(),
+                        found: removed,
+                    })
+                }
+                Operation::Get { key, value } => {
+                    let read = tree.get(key).await.ok();
+                    let expected = value.as_ref().map(RecordedValue::replayed);
+                    (read != expected).then(|| Operation::Get {
+                        key: key.clone(),
+                        value: read.map(RecordedValue::Full),
+                    })
+                }
+                Operation::Scan {
+                    start,
+                    end,
+                    entries,
+                } => {
+                    let range = (start.clone(), end.clone());
+                    let scanned = tree.scan(range).await.ok().map(|e| e.len() as u64);
+                    (scanned != *entries).then(|| Operation::Scan {
+                        start: start.clone(),
+                        end: end.clone(),
+                        entries: scanned,
+                    })
+                }
+                Operation::Rebuild => {
+                    tree.rebuild().await?;
+                    None
+                }
+                Operation::Compact { limit } => {
+                    tree.compact_files(*limit).await?;
+                    None
+                }
+            };
+            if let Some(replayed) = replayed {
+                report.divergences.push(Divergence {
+                    index,
+                    recorded: recorded.clone(),
+                    replayed,
+                });
+            }
+            report.operations += 1;
+        }
+        Ok(report)
+    }
+}
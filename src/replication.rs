@@ -0,0 +1,97 @@
+//! Protocol, by which a tree streams its snapshot to a replica
+//!
+//! Stream starts with magic bytes and the shape of the tree, followed by entries of the
+//! snapshot in ascending order of keys, by changes, that were made while they were sent,
+//! and by the end record. Every record is framed by its length, see
+//! [`crate::bplus_tree::BPlus::send_snapshot`]
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bplus_tree::ChunkKind,
+    error::{BPlusError, Result},
+    key_codec::{self, KeyCodec},
+};
+
+/// Bytes, that start a replication stream
+const MAGIC: &[u8; 8] = b"BPLUSREP";
+
+/// Record of a replication stream
+///
+/// Keys are serialized by their [`KeyCodec`]
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "K: KeyCodec")]
+pub(crate) enum Record<K> {
+    /// Shape of the tree, that is the first record of the stream
+    Header {
+        t: usize,
+        #[serde(with = "key_codec::keys")]
+        bounds: Vec<K>,
+    },
+    /// Entry of the snapshot, or value inserted while the snapshot was sent
+    Insert {
+        #[serde(with = "key_codec::key")]
+        key: K,
+        kind: ChunkKind,
+        value: Vec<u8>,
+    },
+    /// Key, that was removed while the snapshot was sent
+    Remove {
+        #[serde(with = "key_codec::key")]
+        key: K,
+    },
+    /// End of the stream with sequence number of the last sent change of the tree
+    End { sequence: u64 },
+}
+
+/// Writes magic bytes, that start a replication stream
+pub(crate) async fn write_magic<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<()> {
+    writer.write_all(MAGIC).await?;
+    Ok(())
+}
+
+/// Reads magic bytes, that start a replication stream
+///
+/// Returns Err(BPlusError::Corruption(_)) if stream does not start with them
+pub(crate) async fn read_magic<R: AsyncRead + Unpin>(reader: &mut R) -> Result<()> {
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic).await?;
+    if magic != *MAGIC {
+        return Err(BPlusError::Corruption(
+            "stream is not a snapshot of a tree".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes given record, framed by its length
+pub(crate) async fn write_record<K: KeyCodec, W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    record: &Record<K>,
+) -> Result<()> {
+    let data = bincode::serialize(record)?;
+    writer.write_all(&(data.len() as u64).to_le_bytes()).await?;
+    writer.write_all(&data).await?;
+    Ok(())
+}
+
+/// Reads next record, framed by its length
+///
+/// Returns Err(_) if stream ends before the record does
+pub(crate) async fn read_record<K: KeyCodec, R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Record<K>> {
+    let mut len = [0; 8];
+    reader.read_exact(&mut len).await?;
+    let mut data = Vec::new();
+    let len = u64::from_le_bytes(len);
+    // Length is not trusted to preallocate, so a corrupted one fails with the end of the stream
+    (&mut *reader).take(len).read_to_end(&mut data).await?;
+    if data.len() as u64 != len {
+        return Err(BPlusError::Corruption(
+            "stream ends within a record".to_string(),
+        ));
+    }
+    Ok(bincode::deserialize(&data)?)
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+/// Future that can be handed to a Spawner
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Blocking job that can be handed to a Spawner
+pub type BlockingJob = Box<dyn FnOnce() + Send + 'static>;
+
+/// Executor, through which the crate spawns tasks and offloads blocking I/O.
+///
+/// The tree itself only relies on executor-agnostic `tokio::sync` primitives,
+/// so implementing this trait is enough to drive it from any runtime.
+pub trait Spawner: Send + Sync {
+    /// Spawns given future without waiting for it
+    fn spawn(&self, future: BoxFuture);
+
+    /// Runs given closure on a thread, where blocking is allowed
+    fn spawn_blocking(&self, job: BlockingJob);
+
+    /// Returns future, that completes after given duration
+    ///
+    /// By default uses a timer shared by the whole process, executors override it with their own timers
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        timer_sleep(duration)
+    }
+}
+
+/// Returns future, that completes after given duration, using a shared timer
+///
+/// Inside a tokio runtime uses its timer, so the runtime must have time enabled,
+/// otherwise registers on a single timer thread, that is started on first use
+pub(crate) fn timer_sleep(duration: Duration) -> BoxFuture {
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        let _guard = handle.enter();
+        return Box::pin(tokio::time::sleep(duration));
+    }
+    let receiver = TimerThread::get().register(Instant::now() + duration);
+    Box::pin(async move {
+        let _ = receiver.await;
+    })
+}
+
+/// Timers, that are waited by the timer thread
+#[derive(Default)]
+struct Timers {
+    /// Pending timers by deadline and registration number
+    pending: BTreeMap<(Instant, u64), oneshot::Sender<()>>,
+    /// Number of timers registered so far
+    registered: u64,
+}
+
+/// Timers, that are fired by a single background thread
+struct TimerThread {
+    timers: Mutex<Timers>,
+    /// Wakes the thread, when a timer is registered
+    wakeup: Condvar,
+}
+
+impl TimerThread {
+    /// Returns shared timer thread, starting it on first call
+    fn get() -> &'static TimerThread {
+        static TIMER: OnceLock<&'static TimerThread> = OnceLock::new();
+        TIMER.get_or_init(|| {
+            let timer: &'static TimerThread = Box::leak(Box::new(TimerThread {
+                timers: Mutex::new(Timers::default()),
+                wakeup: Condvar::new(),
+            }));
+            std::thread::Builder::new()
+                .name("bplus-timer".into())
+                .spawn(move || timer.run())
+                .expect("failed to start timer thread");
+            timer
+        })
+    }
+
+    /// Registers timer, receiver of which completes at given deadline
+    fn register(&self, deadline: Instant) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        let mut timers = self.timers.lock().unwrap_or_else(PoisonError::into_inner);
+        let number = timers.registered;
+        timers.pending.insert((deadline, number), sender);
+        timers.registered += 1;
+        self.wakeup.notify_one();
+        receiver
+    }
+
+    /// Fires due timers, sleeping until the earliest deadline in between
+    fn run(&self) {
+        let mut timers = self.timers.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let now = Instant::now();
+            let pending = &mut timers.pending;
+            while let Some(entry) = pending.first_entry() {
+                if entry.key().0 > now {
+                    break;
+                }
+                let _ = entry.remove().send(());
+            }
+            timers = match pending.first_key_value() {
+                Some(((deadline, _), _)) => {
+                    let wait = deadline.saturating_duration_since(now);
+                    self.wakeup
+                        .wait_timeout(timers, wait)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .wakeup
+                    .wait(timers)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+}
+
+/// Ability to block current thread until a future completes.
+///
+/// Used by the sync wrappers around the tree.
+pub trait Blocking {
+    /// Runs given future to completion, blocking current thread
+    fn block_on<F: Future>(&self, future: F) -> F::Output;
+}
+
+impl<B: Blocking + ?Sized> Blocking for &B {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        (**self).block_on(future)
+    }
+}
+
+/// Runtime, that can both spawn tasks and block on futures
+pub trait AsyncRuntime: Spawner + Blocking {}
+impl<T: Spawner + Blocking> AsyncRuntime for T {}
+
+impl<S: Spawner + ?Sized> Spawner for Arc<S> {
+    fn spawn(&self, future: BoxFuture) {
+        (**self).spawn(future)
+    }
+
+    fn spawn_blocking(&self, job: BlockingJob) {
+        (**self).spawn_blocking(job)
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        (**self).sleep(duration)
+    }
+}
+
+impl Spawner for tokio::runtime::Runtime {
+    fn spawn(&self, future: BoxFuture) {
+        tokio::runtime::Runtime::spawn(self, future);
+    }
+
+    fn spawn_blocking(&self, job: BlockingJob) {
+        tokio::runtime::Runtime::spawn_blocking(self, job);
+    }
+
+    /// Uses timer of the runtime, so the runtime must have time enabled
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        let _guard = self.enter();
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+impl Blocking for tokio::runtime::Runtime {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        tokio::runtime::Runtime::block_on(self, future)
+    }
+}
+
+impl Spawner for tokio::runtime::Handle {
+    fn spawn(&self, future: BoxFuture) {
+        tokio::runtime::Handle::spawn(self, future);
+    }
+
+    fn spawn_blocking(&self, job: BlockingJob) {
+        tokio::runtime::Handle::spawn_blocking(self, job);
+    }
+
+    /// Uses timer of the runtime, so the runtime must have time enabled
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        let _guard = self.enter();
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+impl Blocking for tokio::runtime::Handle {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        tokio::runtime::Handle::block_on(self, future)
+    }
+}
+
+/// Runtime that runs operations on the global async-std executor
+#[cfg(feature = "async-std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "async-std")]
+impl Spawner for AsyncStdRuntime {
+    fn spawn(&self, future: BoxFuture) {
+        async_std::task::spawn(future);
+    }
+
+    fn spawn_blocking(&self, job: BlockingJob) {
+        async_std::task::spawn_blocking(job);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
+
+#[cfg(feature = "async-std")]
+impl Blocking for AsyncStdRuntime {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        async_std::task::block_on(future)
+    }
+}
+
+/// Runtime that runs operations on the global smol executor
+#[cfg(feature = "smol")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmolRuntime;
+
+#[cfg(feature = "smol")]
+impl Spawner for SmolRuntime {
+    fn spawn(&self, future: BoxFuture) {
+        smol::spawn(future).detach();
+    }
+
+    fn spawn_blocking(&self, job: BlockingJob) {
+        smol::spawn(smol::unblock(job)).detach();
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        let timer = smol::Timer::after(duration);
+        Box::pin(async move {
+            timer.await;
+        })
+    }
+}
+
+#[cfg(feature = "smol")]
+impl Blocking for SmolRuntime {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        smol::block_on(future)
+    }
+}
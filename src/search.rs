@@ -0,0 +1,343 @@
+//! Search of a key among sorted keys of a node
+//!
+//! Small nodes are searched linearly. Keys of fixed-width integer types are searched
+//! by interpolation in large nodes, when they are spread evenly, e.g. hashes, and compared
+//! several at a time with AVX2, when the CPU supports it. Otherwise integer keys, also in
+//! entries of leaves, are searched by branch-free binary search, whose steps do not depend
+//! on the searched key, so they are not slowed by mispredicted branches. Other keys fall back
+//! to binary search
+
+use std::{any::TypeId, cmp::Ordering};
+
+/// Nodes with at most this many keys are searched linearly, which is faster than binary search
+/// for them both with integer and byte string keys
+const LINEAR_THRESHOLD: usize = 12;
+/// Nodes with fewer keys are searched by binary search alone
+const SIMD_THRESHOLD: usize = 16;
+/// Nodes with fewer keys are not searched by interpolation
+const INTERPOLATION_THRESHOLD: usize = 64;
+/// Maximal number of interpolation steps, before the rest of the range is searched by halving
+const INTERPOLATION_STEPS: usize = 4;
+/// Number of keys, below which the remaining range is not narrowed any further
+const BLOCK: usize = 32;
+
+/// Searches sorted unique keys for given key, like [`slice::binary_search`]
+pub(crate) fn search<K: Ord + 'static>(keys: &[K], key: &K) -> Result<usize, usize> {
+    if keys.len() <= LINEAR_THRESHOLD {
+        return search_by(keys, |k| k.cmp(key));
+    }
+    let pos = if keys.len() < SIMD_THRESHOLD {
+        None
+    } else {
+        integers(keys, key).and_then(|integers| integers.lower_bound())
+    };
+    match pos {
+        Some(pos) => match keys.get(pos) {
+            Some(found) if found == key => Ok(pos),
+            _ => Err(pos),
+        },
+        None if is_integer::<K>() => lower_bound_search(keys, key, |k| k),
+        None => keys.binary_search(key),
+    }
+}
+
+/// Searches items sorted by unique keys for given key, like [`slice::binary_search_by_key`]
+pub(crate) fn search_by_key<T, K: Ord + 'static>(
+    items: &[T],
+    key: &K,
+    key_of: impl Fn(&T) -> &K,
+) -> Result<usize, usize> {
+    if items.len() > LINEAR_THRESHOLD && is_integer::<K>() {
+        return lower_bound_search(items, key, key_of);
+    }
+    search_by(items, |item| key_of(item).cmp(key))
+}
+
+/// Searches items sorted by unique keys for given key by branch-free binary search
+///
+/// Every step halves the range and picks its half with a conditional move, so the number
+/// of steps depends only on the number of items
+fn lower_bound_search<T, K: Ord>(
+    items: &[T],
+    key: &K,
+    key_of: impl Fn(&T) -> &K,
+) -> Result<usize, usize> {
+    // Lower bound lies in base..=base + size
+    let (mut base, mut size) = (0, items.len());
+    while size > 1 {
+        let half = size / 2;
+        base = if key_of(&items[base + half - 1]) < key {
+            base + half
+        } else {
+            base
+        };
+        size -= half;
+    }
+    let pos = base + usize::from(size == 1 && key_of(&items[base]) < key);
+    match items.get(pos) {
+        Some(item) if key_of(item) == key => Ok(pos),
+        _ => Err(pos),
+    }
+}
+
+/// Returns whether K is a primitive integer type, whose keys are compared in one instruction
+fn is_integer<K: 'static>() -> bool {
+    let id = TypeId::of::<K>();
+    [
+        TypeId::of::<u8>(),
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<u128>(),
+        TypeId::of::<usize>(),
+        TypeId::of::<i8>(),
+        TypeId::of::<i16>(),
+        TypeId::of::<i32>(),
+        TypeId::of::<i64>(),
+        TypeId::of::<i128>(),
+        TypeId::of::<isize>(),
+    ]
+    .contains(&id)
+}
+
+/// Searches sorted items with given comparator, like [`slice::binary_search_by`]
+pub(crate) fn search_by<T>(items: &[T], compare: impl Fn(&T) -> Ordering) -> Result<usize, usize> {
+    if items.len() > LINEAR_THRESHOLD {
+        return items.binary_search_by(compare);
+    }
+    for (i, item) in items.iter().enumerate() {
+        match compare(item) {
+            Ordering::Less => {}
+            Ordering::Equal => return Ok(i),
+            Ordering::Greater => return Err(i),
+        }
+    }
+    Err(items.len())
+}
+
+/// Keys of fixed-width integer type and searched key, reinterpreted as unsigned integers
+/// of the same width
+///
+/// Bits are compared as signed integers after xor with flip, so unsigned keys are flipped
+/// by their sign bit
+enum Integers<'a> {
+    /// Keys of 64-bit types
+    Wide {
+        keys: &'a [u64],
+        key: u64,
+        flip: u64,
+    },
+    /// Keys of 32-bit types
+    Narrow {
+        keys: &'a [u32],
+        key: u32,
+        flip: u32,
+    },
+}
+
+/// Returns keys as integers, if K is a fixed-width integer type
+fn integers<'a, K: 'static>(keys: &'a [K], key: &K) -> Option<Integers<'a>> {
+    let id = TypeId::of::<K>();
+    let is = |other: TypeId| id == other;
+    let wide = size_of::<usize>() == 8;
+    let flip = if is(TypeId::of::<u64>()) || (wide && is(TypeId::of::<usize>())) {
+        1 << 63
+    } else if is(TypeId::of::<i64>()) || (wide && is(TypeId::of::<isize>())) {
+        0
+    } else if is(TypeId::of::<u32>()) {
+        return Some(Integers::Narrow {
+            keys: cast(keys),
+            key: *cast_key(key),
+            flip: 1 << 31,
+        });
+    } else if is(TypeId::of::<i32>()) {
+        return Some(Integers::Narrow {
+            keys: cast(keys),
+            key: *cast_key(key),
+            flip: 0,
+        });
+    } else {
+        return None;
+    };
+    Some(Integers::Wide {
+        keys: cast(keys),
+        key: *cast_key(key),
+        flip,
+    })
+}
+
+/// Reinterprets keys as integers of the same width
+///
+/// Called only after checking, that K is an integer type of that width
+fn cast<K, T>(keys: &[K]) -> &[T] {
+    debug_assert_eq!(size_of::<K>(), size_of::<T>());
+    // SAFETY: K and T are integer types of the same size and alignment
+    unsafe { std::slice::from_raw_parts(keys.as_ptr().cast(), keys.len()) }
+}
+
+/// Reinterprets key as integer of the same width, see [`cast`]
+fn cast_key<K, T>(key: &K) -> &T {
+    debug_assert_eq!(size_of::<K>(), size_of::<T>());
+    // SAFETY: K and T are integer types of the same size and alignment
+    unsafe { &*(key as *const K).cast() }
+}
+
+impl Integers<'_> {
+    /// Returns number of keys less than searched key, or None if there is no faster way
+    /// to find it than binary search
+    fn lower_bound(&self) -> Option<usize> {
+        let (mut lo, mut hi) = match *self {
+            Integers::Wide { keys, key, flip } => {
+                let ordered = |x: u64| i128::from((x ^ flip) as i64);
+                let value = |i: usize| ordered(keys[i]);
+                interpolate(keys.len(), value, ordered(key))?
+            }
+            Integers::Narrow { keys, key, flip } => {
+                let ordered = |x: u32| i128::from((x ^ flip) as i32);
+                let value = |i: usize| ordered(keys[i]);
+                interpolate(keys.len(), value, ordered(key))?
+            }
+        };
+        if lo == hi || !simd::available() {
+            // Lower bound lies in lo..=hi
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if self.is_less(mid) {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            return Some(lo);
+        }
+        Some(lo + simd::count_less(self, lo, hi))
+    }
+
+    /// Returns whether key by given index is less than searched key
+    fn is_less(&self, i: usize) -> bool {
+        match *self {
+            Integers::Wide { keys, key, flip } => ((keys[i] ^ flip) as i64) < ((key ^ flip) as i64),
+            Integers::Narrow { keys, key, flip } => {
+                ((keys[i] ^ flip) as i32) < ((key ^ flip) as i32)
+            }
+        }
+    }
+}
+
+/// Narrows range of indices of sorted values, that holds the lower bound of target
+///
+/// Values are searched by interpolation, if there are enough of them and they are spread
+/// evenly, by halving otherwise. Returns None if range could not be narrowed below the whole
+/// slice, so binary search over it is not slower
+fn interpolate(len: usize, value: impl Fn(usize) -> i128, target: i128) -> Option<(usize, usize)> {
+    let (first, last) = (value(0), value(len - 1));
+    if target <= first {
+        return Some((0, 0));
+    }
+    if target > last {
+        return Some((len, len));
+    }
+
+    // Lower bound lies in lo + 1..=hi, as value(lo) < target <= value(hi)
+    let (mut lo, mut hi) = (0, len - 1);
+    // Middle value must lie near the straight line between the first and the last one
+    let mid = len / 2;
+    let expected = first + (last - first) * mid as i128 / (len - 1) as i128;
+    let even =
+        len >= INTERPOLATION_THRESHOLD && (value(mid) - expected).abs() <= (last - first) / 8;
+    if even {
+        for _ in 0..INTERPOLATION_STEPS {
+            if hi - lo <= BLOCK {
+                break;
+            }
+            let (low, high) = (value(lo), value(hi));
+            let pos = lo + ((target - low) * (hi - lo) as i128 / (high - low)) as usize;
+            let pos = pos.clamp(lo + 1, hi - 1);
+            if value(pos) < target {
+                lo = pos;
+            } else {
+                hi = pos;
+            }
+        }
+    } else if !simd::available() {
+        return None;
+    }
+    while hi - lo > BLOCK {
+        let mid = lo + (hi - lo) / 2;
+        if value(mid) < target {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Some((lo + 1, hi))
+}
+
+#[cfg(target_arch = "x86_64")]
+mod simd {
+    use std::arch::x86_64::*;
+
+    use super::Integers;
+
+    /// Returns whether keys can be compared with AVX2
+    pub(super) fn available() -> bool {
+        is_x86_feature_detected!("avx2")
+    }
+
+    /// Returns number of keys in lo..hi less than searched key
+    pub(super) fn count_less(integers: &Integers, lo: usize, hi: usize) -> usize {
+        // SAFETY: AVX2 is available, checked by caller
+        unsafe {
+            match *integers {
+                Integers::Wide { keys, key, flip } => count_less_64(&keys[lo..hi], key, flip),
+                Integers::Narrow { keys, key, flip } => count_less_32(&keys[lo..hi], key, flip),
+            }
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn count_less_64(keys: &[u64], key: u64, flip: u64) -> usize {
+        let flip_lanes = _mm256_set1_epi64x(flip as i64);
+        let key_lanes = _mm256_set1_epi64x((key ^ flip) as i64);
+        let chunks = keys.chunks_exact(4);
+        let rest = chunks.remainder();
+        let mut count = 0;
+        for chunk in chunks {
+            let lanes = _mm256_loadu_si256(chunk.as_ptr().cast());
+            let less = _mm256_cmpgt_epi64(key_lanes, _mm256_xor_si256(lanes, flip_lanes));
+            count += _mm256_movemask_pd(_mm256_castsi256_pd(less)).count_ones() as usize;
+        }
+        let ordered = |x: u64| (x ^ flip) as i64;
+        count + rest.iter().filter(|&&x| ordered(x) < ordered(key)).count()
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn count_less_32(keys: &[u32], key: u32, flip: u32) -> usize {
+        let flip_lanes = _mm256_set1_epi32(flip as i32);
+        let key_lanes = _mm256_set1_epi32((key ^ flip) as i32);
+        let chunks = keys.chunks_exact(8);
+        let rest = chunks.remainder();
+        let mut count = 0;
+        for chunk in chunks {
+            let lanes = _mm256_loadu_si256(chunk.as_ptr().cast());
+            let less = _mm256_cmpgt_epi32(key_lanes, _mm256_xor_si256(lanes, flip_lanes));
+            count += _mm256_movemask_ps(_mm256_castsi256_ps(less)).count_ones() as usize;
+        }
+        let ordered = |x: u32| (x ^ flip) as i32;
+        count + rest.iter().filter(|&&x| ordered(x) < ordered(key)).count()
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod simd {
+    use super::Integers;
+
+    /// Returns whether keys can be compared with SIMD, never on this architecture
+    pub(super) fn available() -> bool {
+        false
+    }
+
+    pub(super) fn count_less(_: &Integers, _: usize, _: usize) -> usize {
+        unreachable!("SIMD is not available")
+    }
+}
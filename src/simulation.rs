@@ -0,0 +1,327 @@
+//! Deterministic simulation of concurrent use of the tree, behind the `simulation` feature
+//!
+//! [`Simulation`] is a runtime, that runs all tasks on the thread, which blocks on it, and
+//! picks the next task to poll with a random generator seeded by the test. Blocking jobs
+//! of the tree, i.e. its file I/O, are run as tasks of their own, so they complete in an
+//! order chosen by the seed too. Latches of nodes may additionally yield before they are
+//! taken, so tasks are interleaved inside of inserts, splits and saves, and not only at I/O.
+//! Timers run on a simulated clock, that jumps to the next timer, once no task is runnable
+//!
+//! So a run is determined by its seed: a failing interleaving is replayed by running
+//! the same test with the same seed. Data files are written to the real file system
+//!
+//! Run, in which no task is runnable and no timer is pending, deadlocked and panics.
+//! Panic in a simulation reports its seed and the number of polled tasks
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::sync::oneshot;
+
+use crate::runtime::{Blocking, BlockingJob, BoxFuture, Spawner};
+
+/// Default probability, that a latch yields before it is taken
+const DEFAULT_YIELD_PROBABILITY: f64 = 0.3;
+
+thread_local! {
+    /// Simulation, that runs on this thread, if any
+    static CURRENT: RefCell<Option<Arc<Inner>>> = const { RefCell::new(None) };
+}
+
+/// Deterministic single-threaded runtime, whose interleavings of tasks are chosen by a seed,
+/// see [the module](self)
+///
+/// Clones run the same simulation
+#[derive(Clone)]
+pub struct Simulation {
+    inner: Arc<Inner>,
+}
+
+/// State of a simulation, shared by its clones, wakers and timers
+struct Inner {
+    seed: u64,
+    yield_probability: f64,
+    rng: Mutex<StdRng>,
+    /// Pending tasks by their ids; a task is taken out, while it is polled
+    tasks: Mutex<BTreeMap<usize, BoxFuture>>,
+    /// Ids of tasks, that were woken since they were last polled
+    ready: Mutex<BTreeSet<usize>>,
+    clock: Mutex<Clock>,
+    /// Ids of polled tasks in order of polls
+    schedule: Mutex<Vec<usize>>,
+    next_id: AtomicUsize,
+}
+
+/// Simulated time with pending timers
+#[derive(Default)]
+struct Clock {
+    now: Duration,
+    /// Wakers of sleeping tasks by their deadlines and ids of their timers
+    timers: BTreeMap<(Duration, usize), Waker>,
+}
+
+/// Waker of a task of a simulation
+struct TaskWaker {
+    id: usize,
+    inner: Arc<Inner>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.inner.ready.lock().unwrap().insert(self.id);
+    }
+}
+
+impl Simulation {
+    /// Creates simulation, whose interleavings are chosen by given seed
+    pub fn new(seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                seed,
+                yield_probability: DEFAULT_YIELD_PROBABILITY,
+                rng: Mutex::new(StdRng::seed_from_u64(seed)),
+                tasks: Mutex::default(),
+                ready: Mutex::default(),
+                clock: Mutex::default(),
+                schedule: Mutex::default(),
+                next_id: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Sets probability, that a latch of a node yields to other tasks before it is taken
+    ///
+    /// Panics if probability does not lie in [0, 1] or the simulation already has clones
+    pub fn with_yield_probability(mut self, probability: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "Probability must lie in [0, 1]"
+        );
+        Arc::get_mut(&mut self.inner)
+            .expect("simulation is not shared")
+            .yield_probability = probability;
+        self
+    }
+
+    /// Returns seed of the simulation
+    pub fn seed(&self) -> u64 {
+        self.inner.seed
+    }
+
+    /// Returns simulated time since the start of the simulation
+    pub fn now(&self) -> Duration {
+        self.inner.clock.lock().unwrap().now
+    }
+
+    /// Returns ids of polled tasks in order of polls, which are equal for runs
+    /// with equal seeds
+    pub fn schedule(&self) -> Vec<usize> {
+        self.inner.schedule.lock().unwrap().clone()
+    }
+
+    /// Spawns given future and returns receiver of its output
+    ///
+    /// Receiver fails, if the future did not complete before the simulation was dropped
+    pub fn spawn_task<F>(&self, future: F) -> oneshot::Receiver<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.spawn(Box::pin(async move {
+            let _ = sender.send(future.await);
+        }));
+        receiver
+    }
+}
+
+impl Inner {
+    /// Returns id for a new task
+    fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns waker of task with given id
+    fn waker(self: &Arc<Self>, id: usize) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            id,
+            inner: self.clone(),
+        }))
+    }
+
+    /// Takes random task of woken ones, or returns None if no task is woken
+    fn next_task(&self) -> Option<usize> {
+        let mut ready = self.ready.lock().unwrap();
+        if ready.is_empty() {
+            return None;
+        }
+        let index = self.rng.lock().unwrap().gen_range(0..ready.len());
+        let id = *ready.iter().nth(index).expect("index is below length");
+        ready.remove(&id);
+        self.schedule.lock().unwrap().push(id);
+        Some(id)
+    }
+
+    /// Moves clock to the deadline of the earliest timer and wakes its task
+    ///
+    /// Returns false if no timer is pending
+    fn fire_timer(&self) -> bool {
+        let mut clock = self.clock.lock().unwrap();
+        let Some(((deadline, _), waker)) = clock.timers.pop_first() else {
+            return false;
+        };
+        clock.now = clock.now.max(deadline);
+        drop(clock);
+        waker.wake();
+        true
+    }
+
+    /// Returns whether a latch should yield before it is taken
+    fn should_yield(&self) -> bool {
+        self.yield_probability > 0.0 && self.rng.lock().unwrap().gen_bool(self.yield_probability)
+    }
+
+    /// Returns future, that completes once the clock reaches given deadline
+    fn sleep_until(self: Arc<Self>, deadline: Duration) -> impl Future<Output = ()> {
+        let timer = self.next_id();
+        poll_fn(move |cx| {
+            let mut clock = self.clock.lock().unwrap();
+            if clock.now >= deadline {
+                clock.timers.remove(&(deadline, timer));
+                return Poll::Ready(());
+            }
+            clock.timers.insert((deadline, timer), cx.waker().clone());
+            Poll::Pending
+        })
+    }
+}
+
+impl Spawner for Simulation {
+    fn spawn(&self, future: BoxFuture) {
+        let id = self.inner.next_id();
+        self.inner.tasks.lock().unwrap().insert(id, future);
+        self.inner.ready.lock().unwrap().insert(id);
+    }
+
+    /// Runs job as a task, so it is interleaved with other tasks like them
+    fn spawn_blocking(&self, job: BlockingJob) {
+        self.spawn(Box::pin(async move { job() }));
+    }
+
+    /// Sleeps on the simulated clock
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        let deadline = self.now() + duration;
+        Box::pin(self.inner.clone().sleep_until(deadline))
+    }
+}
+
+impl Blocking for Simulation {
+    /// Runs tasks of the simulation in order chosen by its seed, until given future completes
+    ///
+    /// Panics if no task is runnable and no timer is pending before the future completes
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let _current = Current::enter(self.inner.clone());
+        let _report = Report(self);
+        let mut future = pin!(future);
+        let main = self.inner.next_id();
+        let main_waker = self.inner.waker(main);
+        self.inner.ready.lock().unwrap().insert(main);
+        loop {
+            let Some(id) = self.inner.next_task() else {
+                if self.inner.fire_timer() {
+                    continue;
+                }
+                panic!("simulation deadlocked, no task is runnable");
+            };
+            if id == main {
+                let mut cx = Context::from_waker(&main_waker);
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+                continue;
+            }
+            // Task may be polled by a nested simulation or be finished already
+            let Some(mut task) = self.inner.tasks.lock().unwrap().remove(&id) else {
+                continue;
+            };
+            let waker = self.inner.waker(id);
+            if Pin::new(&mut task)
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending()
+            {
+                self.inner.tasks.lock().unwrap().insert(id, task);
+            }
+        }
+    }
+}
+
+/// Makes given simulation the current one of the thread, until it is dropped
+struct Current(Option<Arc<Inner>>);
+
+impl Current {
+    fn enter(inner: Arc<Inner>) -> Self {
+        Self(CURRENT.with(|current| current.borrow_mut().replace(inner)))
+    }
+}
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+/// Reports seed of given simulation, if it panics
+struct Report<'a>(&'a Simulation);
+
+impl Drop for Report<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            eprintln!(
+                "simulation with seed {} panicked after {} polls",
+                self.0.seed(),
+                self.0.inner.schedule.lock().unwrap().len()
+            );
+        }
+    }
+}
+
+/// Yields to other tasks with the probability of the simulation, that runs on this thread,
+/// so they may run before the caller continues
+///
+/// Completes right away outside of a simulation
+pub(crate) fn yield_point() -> impl Future<Output = ()> {
+    let mut yielded = false;
+    poll_fn(move |cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        let yields = CURRENT.with(|current| {
+            current
+                .borrow()
+                .as_ref()
+                .is_some_and(|inner| inner.should_yield())
+        });
+        if !yields {
+            return Poll::Ready(());
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
@@ -0,0 +1,549 @@
+//! Format of files, that [`BPlus::save`](crate::bplus_tree::BPlus::save) writes
+//!
+//! Serialized tree is split into sections, each preceded by its length and CRC-32. Sections
+//! end with an empty one, followed by CRC-32 of all bytes of the file before it. So a
+//! truncated or corrupted snapshot is detected on load, before a tree is built from it
+//!
+//! Layout of a snapshot, all integers are little-endian on every architecture:
+//!
+//! | Field           | Size | Content                                                 |
+//! |-----------------|------|---------------------------------------------------------|
+//! | magic           | 8    | `BPLSSNAP`                                              |
+//! | version         | 4    | version of the format, [`FORMAT_VERSION`]               |
+//! | sections        | ...  | length (4) and CRC-32 (4) of data, followed by the data |
+//! | end of sections | 8    | section of zero length                                  |
+//! | checksum        | 4    | CRC-32 (IEEE) of all bytes before it                    |
+//!
+//! Data of sections, joined together, is the tree encoded by [`options`]: integers have fixed
+//! width, `usize` is 8 bytes, lengths of sequences and strings are 8 bytes before their
+//! items, variants of enums are 4-byte indices and `Option` is a 1-byte tag. Fields of
+//! structs follow each other in their order of declaration, without names or padding.
+//! Keys are byte strings, that hold their encodings by [`crate::key_codec::KeyCodec`].
+//! Keys of fixed width are packed into one byte string per node, that is followed by values
+//! of entries in leaves.
+//! Readers reject snapshots of versions other than theirs
+//!
+//! Archives, that [`BPlus::export_archive`] writes, hold the encoded tree with its data files,
+//! so it is moved to another directory as one file
+//!
+//! Snapshots, that [`BPlus::send_snapshot`] streams to a replica, are not written in this
+//! format, but as records of replication, see [`crate::replication`]
+
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use bincode::Options;
+use futures::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    bplus_tree::{
+        blob_path, ensure_empty, unblock, BPlus, BPlusKeySerializable, SerializableBPlus, BLOBS_DIR,
+    },
+    error::{BPlusError, Result},
+    key_codec::KeyCodec,
+    page::{crc32, crc32_extend},
+    replication::{self, Record},
+};
+
+/// Magic bytes at the start of a snapshot
+const MAGIC: &[u8; 8] = b"BPLSSNAP";
+/// Max size of data in one section
+const SECTION_SIZE: usize = 1 << 20;
+/// Size of the header of a section: length (4) and checksum (4)
+const SECTION_HEADER_SIZE: u64 = 8;
+/// Size of the checksum of the whole file at its end
+const TRAILER_SIZE: u64 = 4;
+/// Bytes, that start an archive of a tree
+const ARCHIVE_MAGIC: &[u8; 8] = b"BPLUSARC";
+
+/// Version of the format, that snapshots are written in
+///
+/// Incremented on every change of the layout or of the encoded structure of the tree
+pub(crate) const FORMAT_VERSION: u32 = 7;
+
+/// Returns options of bincode, that encode the tree in snapshots, see the layout above
+///
+/// Options are set explicitly, so the encoding does not depend on defaults of bincode
+pub(crate) fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .allow_trailing_bytes()
+}
+
+/// Writes given value as a snapshot to file by given path
+///
+/// Returns size of the written file
+pub(crate) fn write<T: Serialize>(path: &Path, value: &T) -> Result<u64> {
+    let mut writer = Writer {
+        inner: BufWriter::new(File::create(path)?),
+        section: Vec::new(),
+        checksum: 0,
+        written: 0,
+    };
+    writer.put(MAGIC)?;
+    writer.put(&FORMAT_VERSION.to_le_bytes())?;
+    options().serialize_into(&mut writer, value)?;
+    Ok(writer.finish()?)
+}
+
+/// Reads value from snapshot by given path
+///
+/// Returns Err(BPlusError::Corruption(_)) if snapshot is truncated or its checksums mismatch,
+/// Err(BPlusError::UnsupportedVersion { .. }) if it was written in another format
+pub(crate) fn read<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let mut reader = Reader {
+        inner: BufReader::new(File::open(path)?),
+        section: Vec::new(),
+        position: 0,
+        sections: 0,
+        ended: false,
+        checksum: 0,
+        error: None,
+    };
+    let mut magic = [0; MAGIC.len()];
+    reader.read_raw(&mut magic)?;
+    if magic != *MAGIC {
+        return Err(BPlusError::Corruption(format!(
+            "{} is not a snapshot of a tree",
+            path.display()
+        )));
+    }
+    let mut version = [0; 4];
+    reader.read_raw(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(BPlusError::UnsupportedVersion {
+            version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    let value = options().deserialize_from(&mut reader);
+    // Corruption is reported as is, rather than as a failure to deserialize
+    if let Some(err) = reader.error.take() {
+        return Err(err);
+    }
+    let value = value?;
+    reader.finish()?;
+    Ok(value)
+}
+
+/// Returns size of snapshot, that holds serialized value of given size
+pub(crate) fn framed_size(size: u64) -> u64 {
+    let sections = size.div_ceil(SECTION_SIZE as u64) + 1;
+    (MAGIC.len() + 4) as u64 + sections * SECTION_HEADER_SIZE + size + TRAILER_SIZE
+}
+
+/// Writer, that splits written bytes into checksummed sections
+struct Writer<W> {
+    inner: W,
+    /// Bytes of the section, that is not written yet
+    section: Vec<u8>,
+    /// Checksum of all written bytes
+    checksum: u32,
+    written: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes given bytes to the file as is
+    fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)?;
+        self.checksum = crc32_extend(self.checksum, bytes);
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the pending section with its header
+    fn write_section(&mut self) -> io::Result<()> {
+        let mut section = std::mem::take(&mut self.section);
+        self.put(&(section.len() as u32).to_le_bytes())?;
+        self.put(&crc32(&section).to_le_bytes())?;
+        self.put(&section)?;
+        section.clear();
+        self.section = section;
+        Ok(())
+    }
+
+    /// Writes the last section, the empty one after it and the checksum of the file
+    ///
+    /// Returns size of the file
+    fn finish(mut self) -> io::Result<u64> {
+        if !self.section.is_empty() {
+            self.write_section()?;
+        }
+        self.write_section()?;
+        let checksum = self.checksum;
+        self.put(&checksum.to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.written)
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(SECTION_SIZE - self.section.len());
+        self.section.extend_from_slice(&buf[..len]);
+        if self.section.len() == SECTION_SIZE {
+            self.write_section()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader, that checks sections before their bytes are read
+struct Reader<R> {
+    inner: R,
+    /// Data of the current section
+    section: Vec<u8>,
+    /// Position of the next byte to read in the current section
+    position: usize,
+    /// Number of sections read
+    sections: u64,
+    /// Whether the empty section at the end was read
+    ended: bool,
+    /// Checksum of all bytes read from the file
+    checksum: u32,
+    /// Error, that broke the last read
+    error: Option<BPlusError>,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads exactly given number of bytes from the file
+    fn read_raw(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.inner.read_exact(buf).map_err(truncated)?;
+        self.checksum = crc32_extend(self.checksum, buf);
+        Ok(())
+    }
+
+    /// Reads next section, returns false if it is the empty one at the end
+    fn next_section(&mut self) -> Result<bool> {
+        let mut header = [0; SECTION_HEADER_SIZE as usize];
+        self.read_raw(&mut header)?;
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if len > SECTION_SIZE {
+            return Err(BPlusError::Corruption(format!(
+                "section {} of snapshot has invalid length {len}",
+                self.sections
+            )));
+        }
+        let mut section = std::mem::take(&mut self.section);
+        section.resize(len, 0);
+        self.read_raw(&mut section)?;
+        if crc32(&section) != checksum {
+            return Err(BPlusError::Corruption(format!(
+                "checksum mismatch in section {} of snapshot",
+                self.sections
+            )));
+        }
+        self.section = section;
+        self.position = 0;
+        self.sections += 1;
+        Ok(len != 0)
+    }
+
+    /// Checks, that the read value is followed by the end of sections and
+    /// the checksum of the whole file
+    fn finish(mut self) -> Result<()> {
+        if self.position != self.section.len() || (!self.ended && self.next_section()?) {
+            return Err(BPlusError::Corruption(
+                "snapshot has data after the tree".to_string(),
+            ));
+        }
+        let mut trailer = [0; TRAILER_SIZE as usize];
+        self.inner.read_exact(&mut trailer).map_err(truncated)?;
+        if u32::from_le_bytes(trailer) != self.checksum {
+            return Err(BPlusError::Corruption(
+                "checksum mismatch in snapshot".to_string(),
+            ));
+        }
+        if self.inner.read(&mut [0])? != 0 {
+            return Err(BPlusError::Corruption(
+                "snapshot has data after its end".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.section.len() && !self.ended {
+            match self.next_section() {
+                Ok(more) => self.ended = !more,
+                Err(err) => {
+                    let message = err.to_string();
+                    self.error = Some(err);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+            }
+        }
+        let len = buf.len().min(self.section.len() - self.position);
+        buf[..len].copy_from_slice(&self.section[self.position..][..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Reports end of file in the middle of a snapshot as its corruption
+fn truncated(err: io::Error) -> BPlusError {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        BPlusError::Corruption("snapshot is truncated".to_string())
+    } else {
+        err.into()
+    }
+}
+
+impl<K: BPlusKeySerializable> BPlus<K> {
+    /// Writes the tree with its data files to one file by provided path, so it may be
+    /// imported into another directory, see [`BPlus::import_archive`]
+    ///
+    /// Archive holds version of the format and the saved tree, encoded like in snapshots,
+    /// followed by data files framed by their numbers and sizes, and then by blob files,
+    /// that the tree refers to, framed by their ids and sizes, all little-endian.
+    /// Changes made during the export are not included
+    pub async fn export_archive(&self, path: &Path) -> Result<()>
+    where
+        K: 'static,
+    {
+        in_span!("export_archive", path = %path.display(); async {
+            self.flush_buffer().await?;
+            // Rebuild may not remove data files, until they are copied
+            let _writes = self.writes.read().await;
+            let serializable = {
+                let _splits = self.splits.write().await;
+                self.serialize().await
+            };
+            let path = path.to_path_buf();
+            self.unblock(move || write_archive(&path, &serializable))
+                .await?
+        })
+    }
+
+    /// Imports tree from archive by provided path into directory by dest_dir,
+    /// see [`BPlus::export_archive`]
+    ///
+    /// Returns Err(_) if dest_dir is a non-empty directory, as data files there would be
+    /// overwritten, or if the archive is corrupted
+    pub async fn import_archive(path: &Path, dest_dir: PathBuf) -> Result<Self>
+    where
+        K: 'static,
+    {
+        in_span!("import_archive", path = %path.display(); async {
+            ensure_empty(&dest_dir)?;
+            let path = path.to_path_buf();
+            let serializable = unblock(None, move || read_archive(&path, &dest_dir)).await??;
+            serializable.deserialize().await
+        })
+    }
+
+    /// Streams snapshot of the tree to given writer, e.g. a socket to another node, so a replica
+    /// is bootstrapped from it by [`BPlus::receive_snapshot`], while the tree goes on serving
+    ///
+    /// Entries are pinned at once, like by [`BPlus::snapshot_stream`], and sent in ascending
+    /// order of keys, each framed by its length. Changes, that are made meanwhile, are found
+    /// by versions of entries, when the snapshot is sent, and follow it, so the replica ends up
+    /// with entries of the tree as of the end of the transfer. Keys are pinned twice for that,
+    /// so the tree waits for two scans of its leaves
+    ///
+    /// Returns sequence number of the last change, that the replica receives
+    ///
+    /// Returns Err(_) if some of the values could not be read or written to the writer
+    pub async fn send_snapshot<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<u64> {
+        in_span!("send_snapshot"; async {
+            replication::write_magic(writer).await?;
+            let header = Record::Header {
+                t: self.t,
+                bounds: self.bounds.clone(),
+            };
+            replication::write_record(writer, &header).await?;
+
+            let (pinned, _) = self.pin_entries().await;
+            let mut sent = BTreeMap::new();
+            for (key, (value, version)) in pinned {
+                let kind = value.kind();
+                let value = self.read_pinned(value).await?;
+                sent.insert(key.clone(), version);
+                replication::write_record(writer, &Record::Insert { key, kind, value }).await?;
+            }
+
+            // Changes made during the transfer changed versions of entries
+            let (current, sequence) = self.pin_entries().await;
+            for (key, (value, version)) in current {
+                if sent.remove(&key) == Some(version) {
+                    continue;
+                }
+                let kind = value.kind();
+                let value = self.read_pinned(value).await?;
+                replication::write_record(writer, &Record::Insert { key, kind, value }).await?;
+            }
+            for key in sent.into_keys() {
+                replication::write_record(writer, &Record::Remove { key }).await?;
+            }
+            replication::write_record::<K, _>(writer, &Record::End { sequence }).await?;
+            writer.flush().await?;
+            trace_event!(sequence = sequence, "snapshot sent");
+            Ok(sequence)
+        })
+    }
+
+    /// Creates tree with given path to data files from snapshot, that given reader streams,
+    /// see [`BPlus::send_snapshot`]
+    ///
+    /// Returns the tree with sequence number of the last change of the source, that it holds
+    ///
+    /// Returns Err(_) if path is a non-empty directory, as data files there would be overwritten,
+    /// or if the stream is corrupted or ends early
+    pub async fn receive_snapshot<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        path: PathBuf,
+    ) -> Result<(Self, u64)> {
+        in_span!("receive_snapshot", path = %path.display(); async {
+            ensure_empty(&path)?;
+            replication::read_magic(reader).await?;
+            let Record::Header { t, bounds } = replication::read_record(reader).await? else {
+                return Err(BPlusError::Corruption(
+                    "snapshot does not start with a header".to_string(),
+                ));
+            };
+            if t < 2 {
+                return Err(BPlusError::Corruption(format!("invalid t = {t}")));
+            }
+            let tree = Self::new_partitioned(t, path, bounds)?;
+            loop {
+                match replication::read_record(reader).await? {
+                    Record::Insert { key, kind, value } => {
+                        tree.insert_as(key, value, kind).await?;
+                    }
+                    Record::Remove { key } => {
+                        tree.remove(&key).await;
+                    }
+                    Record::End { sequence } => return Ok((tree, sequence)),
+                    Record::Header { .. } => {
+                        return Err(BPlusError::Corruption(
+                            "snapshot has a header in the middle".to_string(),
+                        ))
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Writes saved tree and its data files to an archive by given path
+fn write_archive<K: KeyCodec>(path: &Path, tree: &SerializableBPlus<K>) -> Result<()> {
+    let mut files = Vec::new();
+    for number in 0..=tree.file_number {
+        let file = match File::open(tree.path.join(number.to_string())) {
+            Ok(file) => file,
+            // Files, that rebuild removed, are not referenced by the tree
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        // Current file is copied up to the offset, at which the tree was saved
+        let size = if number == tree.file_number {
+            tree.offset
+        } else {
+            file.metadata()?.len()
+        };
+        files.push((number, file, size));
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(ARCHIVE_MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    let saved = options().serialize(tree)?;
+    writer.write_all(&(saved.len() as u64).to_le_bytes())?;
+    writer.write_all(&saved)?;
+    writer.write_all(&(files.len() as u64).to_le_bytes())?;
+    for (number, file, size) in files {
+        writer.write_all(&(number as u64).to_le_bytes())?;
+        writer.write_all(&size.to_le_bytes())?;
+        if io::copy(&mut file.take(size), &mut writer)? != size {
+            return Err(BPlusError::Corruption(format!(
+                "data file {number} is shorter than the saved tree expects"
+            )));
+        }
+    }
+    let blobs = tree.blobs();
+    writer.write_all(&(blobs.len() as u64).to_le_bytes())?;
+    for id in blobs {
+        let mut file = File::open(blob_path(&tree.path, id))?;
+        writer.write_all(&id.to_le_bytes())?;
+        writer.write_all(&file.metadata()?.len().to_le_bytes())?;
+        io::copy(&mut file, &mut writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads archive by given path, writing its data files to given directory
+///
+/// Returns saved tree, that refers to the written data files
+fn read_archive<K: KeyCodec>(path: &Path, dir: &Path) -> Result<SerializableBPlus<K>> {
+    let read_u64 = |reader: &mut BufReader<File>| -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        reader.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    };
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; ARCHIVE_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != *ARCHIVE_MAGIC {
+        return Err(BPlusError::Corruption(format!(
+            "{} is not an archive of a tree",
+            path.display()
+        )));
+    }
+    let mut version = [0; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(BPlusError::UnsupportedVersion {
+            version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    let snapshot_size = read_u64(&mut reader)?;
+    let mut saved = (&mut reader).take(snapshot_size);
+    let mut tree: SerializableBPlus<K> = options().deserialize_from(&mut saved)?;
+    // Files follow the whole snapshot, even if it was not read to the end
+    io::copy(&mut saved, &mut io::sink())?;
+
+    create_dir_all(dir)?;
+    for _ in 0..read_u64(&mut reader)? {
+        let number = read_u64(&mut reader)?;
+        let size = read_u64(&mut reader)?;
+        let mut file = File::create(dir.join(number.to_string()))?;
+        if io::copy(&mut (&mut reader).take(size), &mut file)? != size {
+            return Err(BPlusError::Corruption(format!(
+                "data file {number} is truncated in the archive"
+            )));
+        }
+    }
+    let blobs = read_u64(&mut reader)?;
+    if blobs > 0 {
+        create_dir_all(dir.join(BLOBS_DIR))?;
+    }
+    for _ in 0..blobs {
+        let id = read_u64(&mut reader)?;
+        let size = read_u64(&mut reader)?;
+        let mut file = File::create(blob_path(dir, id))?;
+        if io::copy(&mut (&mut reader).take(size), &mut file)? != size {
+            return Err(BPlusError::Corruption(format!(
+                "blob file {id} is truncated in the archive"
+            )));
+        }
+    }
+    tree.relocate(dir);
+    Ok(tree)
+}
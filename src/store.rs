@@ -0,0 +1,633 @@
+//! Named trees, that share one directory and one writer of data files
+//!
+//! Every tree of a store has its own keys, while values of all trees are appended
+//! to the same data files, so a store needs one directory for a data index and
+//! several metadata indexes
+//!
+//! [`BPlusStorage`] serves a tree to ChunkFS through blocking calls on an async runtime
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use chunkfs::{Data, DataContainer, Database};
+use tokio::{
+    runtime::{Handle, Runtime},
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+};
+
+use crate::{
+    bplus_tree::{BPlus, BPlusKey, BPlusKeySerializable, ChunkKind, DataFile},
+    error::{BPlusError, Result},
+    record::StoreId,
+    runtime::{AsyncRuntime, Blocking, BlockingJob, BoxFuture, Spawner},
+};
+
+/// Directory of the store, that holds saved trees by their names
+const FAMILIES_DIR: &str = "families";
+/// Default limit of background inserts in flight of [`BPlusStorage`]
+const DEFAULT_MAX_IN_FLIGHT: usize = 1024;
+
+/// Named trees with the same t, that share directory and data files
+pub struct Store<K> {
+    /// Directory with data files of all trees
+    path: PathBuf,
+    /// Parameter of new trees, see [`BPlus::new`]
+    t: usize,
+    /// Data file, that all trees append values to
+    data_file: Arc<Mutex<DataFile>>,
+    /// Trees by their names
+    families: Mutex<BTreeMap<String, Arc<BPlus<K>>>>,
+}
+
+impl<K: BPlusKey> Store<K> {
+    /// Creates empty store, which trees have given t, in directory by given path
+    pub fn new(t: usize, path: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        let data_file = DataFile::create(&path, 0, StoreId::random())?;
+        Ok(Self {
+            path,
+            t,
+            data_file: Arc::new(Mutex::new(data_file)),
+            families: Mutex::default(),
+        })
+    }
+
+    /// Returns tree with given name, creating an empty one, if there is none
+    ///
+    /// Returns Err(_) if name is empty or has characters other than ASCII letters,
+    /// digits, '-' and '_', as it names the file of the saved tree
+    pub fn family(&self, name: &str) -> Result<Arc<BPlus<K>>> {
+        check_name(name)?;
+        let mut families = self.families.lock().unwrap();
+        let tree = families.entry(name.to_string()).or_insert_with(|| {
+            let tree = BPlus::with_data_file(
+                self.t,
+                self.path.clone(),
+                Vec::new(),
+                self.data_file.clone(),
+            );
+            // Directory is shared, so one tree may not mark it clean for others
+            tree.set_flush_on_drop(false);
+            Arc::new(tree)
+        });
+        Ok(tree.clone())
+    }
+
+    /// Returns names of all trees in ascending order
+    pub fn names(&self) -> Vec<String> {
+        self.families.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns trees of the store by their names
+    fn trees(&self) -> Vec<(String, Arc<BPlus<K>>)> {
+        let families = self.families.lock().unwrap();
+        families
+            .iter()
+            .map(|(name, tree)| (name.clone(), tree.clone()))
+            .collect()
+    }
+
+    /// Syncs data files of all trees to disk
+    pub async fn sync(&self) -> Result<()> {
+        for (_, tree) in self.trees() {
+            tree.sync().await?;
+        }
+        Ok(())
+    }
+}
+
+impl<K: BPlusKeySerializable + 'static> Store<K> {
+    /// Saves all trees to the directory of the store by their names
+    pub async fn save(&self) -> Result<()> {
+        let dir = self.path.join(FAMILIES_DIR);
+        std::fs::create_dir_all(&dir)?;
+        for (name, tree) in self.trees() {
+            tree.save(&dir.join(name)).await?;
+        }
+        Ok(())
+    }
+
+    /// Opens store in directory by given path, loading its saved trees,
+    /// and creates new trees with given t
+    ///
+    /// Values are appended after the last one, that any saved tree refers to,
+    /// or after the end of the last data file, if it is further
+    pub async fn open(t: usize, path: PathBuf) -> Result<Self> {
+        let dir = path.join(FAMILIES_DIR);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut trees = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let tree: BPlus<K> = BPlus::load(&entry.path()).await?;
+            trees.push((name, tree));
+        }
+
+        let last = trees
+            .iter()
+            .map(|(_, tree)| {
+                let data_file = tree.data_file();
+                let data_file = data_file.lock().unwrap();
+                (data_file.position(), data_file.store())
+            })
+            .max_by_key(|&(position, _)| position);
+        let data_file = match last {
+            // Trees were saved by the same store, so they share its id
+            Some(((number, offset), store)) => DataFile::recover(&path, number, offset, store)?,
+            // Nothing refers to existing data files
+            None => {
+                std::fs::create_dir_all(&path)?;
+                DataFile::create(&path, 0, StoreId::random())?
+            }
+        };
+        let data_file = Arc::new(Mutex::new(data_file));
+        let families = trees
+            .into_iter()
+            .map(|(name, mut tree)| {
+                tree.share_data_file(data_file.clone());
+                tree.set_flush_on_drop(false);
+                (name, Arc::new(tree))
+            })
+            .collect();
+        Ok(Self {
+            path,
+            t,
+            data_file,
+            families: Mutex::new(families),
+        })
+    }
+}
+
+/// Returns Err(_) if given name of a tree may not be a name of a file
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid name of a tree: {name:?}"),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Wrapper for BPlusTree with sync functions with async runtime
+pub struct BPlusStorage<K, R: Blocking = Runtime> {
+    /// BPlusTree
+    tree: Arc<BPlus<K>>,
+    /// Async runtime for operations
+    runtime: Arc<R>,
+    /// Currently inserting keys
+    inserting: Arc<InsertingKeys<K>>,
+    /// Background inserts, that are still in flight
+    pending: Arc<PendingInserts>,
+}
+
+/// Keys of BPlusStorage, that are being inserted in background
+struct InsertingKeys<K> {
+    /// Number of inserts in flight and notifier for every key
+    keys: Mutex<HashMap<K, (usize, Arc<Notify>)>>,
+}
+
+impl<K: std::hash::Hash + Eq> InsertingKeys<K> {
+    /// Marks key as being inserted
+    fn start(&self, key: K) {
+        self.keys.lock().unwrap().entry(key).or_default().0 += 1;
+    }
+
+    /// Marks one insert of key as finished, waking readers if it was the last one
+    fn finish(&self, key: &K) {
+        let mut keys = self.keys.lock().unwrap();
+        let Some((count, notify)) = keys.get_mut(key) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            notify.notify_waiters();
+            keys.remove(key);
+        }
+    }
+
+    /// Waits until there are no inserts of key in flight
+    async fn wait(&self, key: &K) {
+        while let Some(notify) = self.notifier(key) {
+            let notified = notify.notified();
+            // Inserts could have finished before notified was created, then nobody will wake it
+            if self
+                .notifier(key)
+                .is_some_and(|current| Arc::ptr_eq(&current, &notify))
+            {
+                notified.await;
+            }
+        }
+    }
+
+    /// Returns notifier of key, if it is being inserted
+    fn notifier(&self, key: &K) -> Option<Arc<Notify>> {
+        self.keys
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|(_, notify)| notify.clone())
+    }
+}
+
+impl<K> Default for InsertingKeys<K> {
+    fn default() -> Self {
+        Self {
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Tracks background inserts of BPlusStorage and errors they ended with
+struct PendingInserts {
+    /// Permits of inserts in flight, every insert holds one until it is finished
+    permits: Arc<Semaphore>,
+    /// Maximal number of inserts in flight, that is the total number of permits
+    limit: AtomicUsize,
+    /// Whether new inserts are rejected
+    closed: AtomicBool,
+    /// First error, that was not yet reported
+    error: Mutex<Option<BPlusError>>,
+}
+
+impl PendingInserts {
+    /// Creates tracker, that allows at most limit inserts in flight
+    fn new(limit: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(limit)),
+            limit: limit.into(),
+            closed: false.into(),
+            error: Mutex::new(None),
+        }
+    }
+
+    /// Registers new insert in flight, waiting while there are too many of them
+    ///
+    /// Returned permit must be held until the insert is finished
+    ///
+    /// Returns Err(_) if storage is closed
+    async fn start(&self) -> Result<OwnedSemaphorePermit> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Permits of inserts are never closed");
+        self.check_open()?;
+        Ok(permit)
+    }
+
+    /// Rejects all inserts, that are not registered yet
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+    }
+
+    /// Returns Err(_) if storage is closed
+    fn check_open(&self) -> Result<()> {
+        match self.closed.load(Ordering::Acquire) {
+            true => Err(BPlusError::Closed),
+            false => Ok(()),
+        }
+    }
+
+    /// Marks insert as finished, remembering its error if there was one
+    ///
+    /// Permit of the insert is released after that, so waiters see its error
+    fn finish(&self, result: Result<()>, permit: OwnedSemaphorePermit) {
+        if let Err(e) = result {
+            self.error.lock().unwrap().get_or_insert(e);
+        }
+        drop(permit);
+    }
+
+    /// Waits until there are no inserts in flight
+    ///
+    /// Inserts are driven by the runtime, so it is awaited on the runtime and not by blocking
+    async fn wait(&self) {
+        let limit = self.limit.load(Ordering::Acquire) as u32;
+        // All permits are free only when no insert is in flight
+        let _permits = self
+            .permits
+            .acquire_many(limit)
+            .await
+            .expect("Permits of inserts are never closed");
+    }
+
+    /// Takes unreported error, if any
+    fn take_error(&self) -> Result<()> {
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Spawner of the tree of BPlusStorage, that runs jobs on the runtime of the storage
+///
+/// Runtime is referenced weakly, so it is dropped with the last handle to the storage,
+/// and not by a background insert, that holds the tree. Jobs are dropped after that
+struct StorageSpawner<R>(Weak<R>);
+
+impl<R: Spawner> Spawner for StorageSpawner<R> {
+    fn spawn(&self, future: BoxFuture) {
+        if let Some(runtime) = self.0.upgrade() {
+            runtime.spawn(future);
+        }
+    }
+
+    fn spawn_blocking(&self, job: BlockingJob) {
+        if let Some(runtime) = self.0.upgrade() {
+            runtime.spawn_blocking(job);
+        }
+    }
+}
+
+impl<K: BPlusKey, R: AsyncRuntime + 'static> BPlusStorage<K, R> {
+    /// Creates new instance of B+ tree with given runtime, t and path
+    ///
+    /// runtime is any AsyncRuntime, e.g. owned tokio runtime or handle to a running one.
+    /// Blocking file I/O of the tree is offloaded to it
+    ///
+    /// t represents minimal and maximum quantity of keys in the node
+    ///
+    /// All data will be written in directory by given path
+    pub fn new(runtime: R, t: usize, path: PathBuf) -> Result<Self> {
+        Self::new_partitioned(runtime, t, path, Vec::new())
+    }
+
+    /// Creates new instance of B+ tree with given runtime, t and path, which key space
+    /// is split into partitions by given bounds, see [`BPlus::new_partitioned`]
+    ///
+    /// Returns Err(_) if bounds are not strictly ascending
+    pub fn new_partitioned(runtime: R, t: usize, path: PathBuf, bounds: Vec<K>) -> Result<Self> {
+        let runtime = Arc::new(runtime);
+        let spawner = StorageSpawner(Arc::downgrade(&runtime));
+        let tree = BPlus::new_partitioned(t, path, bounds)?.with_spawner(Arc::new(spawner));
+        Ok(Self {
+            tree: Arc::new(tree),
+            runtime,
+            inserting: Arc::new(InsertingKeys::default()),
+            pending: Arc::new(PendingInserts::new(DEFAULT_MAX_IN_FLIGHT)),
+        })
+    }
+
+    /// Sets maximal number of background inserts in flight
+    ///
+    /// Once it is reached, insert blocks until some of them are finished
+    ///
+    /// Panics if limit is zero or exceeds u32::MAX
+    pub fn with_max_in_flight(self, limit: usize) -> Self {
+        assert!(limit > 0, "Limit of inserts in flight must be positive");
+        assert!(
+            limit <= u32::MAX as usize,
+            "Limit of inserts in flight is too big"
+        );
+        let current = self.pending.limit.swap(limit, Ordering::AcqRel);
+        if limit > current {
+            self.pending.permits.add_permits(limit - current);
+        } else {
+            let permits = self.pending.permits.acquire_many((current - limit) as u32);
+            self.runtime
+                .block_on(permits)
+                .expect("Permits of inserts are never closed")
+                .forget();
+        }
+        self
+    }
+
+    /// Waits until all background inserts are finished
+    ///
+    /// Returns Err(_) if some of them failed since the last reported error
+    pub fn flush(&self) -> Result<()> {
+        self.runtime.block_on(self.pending.wait());
+        self.pending.take_error()
+    }
+
+    /// Returns error of a failed background insert, if there is one, without waiting
+    ///
+    /// Returned error is considered reported and will not be returned again
+    pub fn last_error(&self) -> Option<BPlusError> {
+        self.pending.take_error().err()
+    }
+
+    /// Sets whether in-flight inserts are awaited and data files are synced, when storage is dropped
+    ///
+    /// Enabled by default. Inserts are not awaited, if storage is dropped inside a tokio runtime
+    pub fn with_flush_on_drop(self, flush: bool) -> Self {
+        self.tree.set_flush_on_drop(flush);
+        self
+    }
+
+    /// Closes storage: stops accepting new operations, waits for in-flight inserts
+    /// and syncs data files to disk
+    ///
+    /// Closing affects all clones of the storage
+    ///
+    /// Returns Err(_) if some of the inserts failed or data files could not be synced
+    pub fn close(&self) -> Result<()> {
+        self.pending.close();
+        self.runtime.block_on(self.pending.wait());
+        let tree = self.tree.clone();
+        self.runtime.block_on(async move { tree.sync().await })?;
+        self.pending.take_error()
+    }
+}
+
+impl<K: BPlusKeySerializable + 'static, R: AsyncRuntime + 'static> BPlusStorage<K, R> {
+    /// Closes storage and saves final checkpoint of the tree by given path
+    ///
+    /// Checkpoint is saved even if some of the inserts failed
+    pub fn close_with_checkpoint(&self, path: &Path) -> Result<()> {
+        let result = self.close();
+        let tree = self.tree.clone();
+        self.runtime
+            .block_on(async move { tree.save(path).await })?;
+        result
+    }
+}
+
+impl<K: BPlusKey> BPlusStorage<K, Handle> {
+    /// Creates new instance of B+ tree with given t and path on the ambient tokio runtime
+    ///
+    /// Returns Err(_) if called outside of the tokio runtime context
+    pub fn current(t: usize, path: PathBuf) -> Result<Self> {
+        let handle = Handle::try_current().map_err(io::Error::other)?;
+        Self::new(handle, t, path)
+    }
+}
+
+impl<K, R: Blocking> Drop for BPlusStorage<K, R> {
+    /// Waits for in-flight inserts, if this is the last handle and tree flushes on drop
+    ///
+    /// Waiting is best-effort: it is skipped inside a tokio runtime, where blocking panics,
+    /// and inserts, that are still in flight, may be cancelled then. Use
+    /// [`BPlusStorage::close`] to make sure they are finished
+    fn drop(&mut self) {
+        // Only handles hold the runtime, while background inserts hold everything else
+        if Arc::strong_count(&self.runtime) == 1
+            && self.tree.flushes_on_drop()
+            && Handle::try_current().is_err()
+        {
+            self.runtime.block_on(self.pending.wait());
+        }
+    }
+}
+
+impl<K, R: Blocking> Clone for BPlusStorage<K, R> {
+    /// Creates new handle to the same storage
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            runtime: self.runtime.clone(),
+            inserting: self.inserting.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<K: std::hash::Hash + 'static + BPlusKey, R: AsyncRuntime + 'static> BPlusStorage<K, R> {
+    /// Inserts given value by given key in the B+ tree in background
+    ///
+    /// Unlike Database::insert, does not require exclusive access, so storage can be shared
+    ///
+    /// Returns Err(_) if some of the previous inserts failed
+    pub fn insert(&self, key: K, value: DataContainer<()>) -> Result<()> {
+        self.pending.check_open()?;
+        self.pending.take_error()?;
+        let tree = self.tree.clone();
+
+        let (value, kind) = match value.extract() {
+            Data::Chunk(chunk) => (chunk.clone(), ChunkKind::Chunk),
+            Data::TargetChunk(targets) => (bincode::serialize(targets)?, ChunkKind::Target),
+        };
+
+        let pending = self.pending.clone();
+        let permit = self.runtime.block_on(pending.start())?;
+
+        let inserting = self.inserting.clone();
+        inserting.start(key.clone());
+        self.runtime.spawn(Box::pin(async move {
+            let result = tree.insert_as(key.clone(), value, kind).await.map(|_| ());
+            inserting.finish(&key);
+            pending.finish(result, permit);
+        }));
+        Ok(())
+    }
+
+    /// Removes value by given key from the B+ tree
+    ///
+    /// Waits for in-flight inserts of the key, so removal always wins over inserts issued before it
+    ///
+    /// Returns Err(_) if some of the previous inserts failed
+    pub fn remove(&self, key: &K) -> Result<bool> {
+        self.pending.check_open()?;
+        self.pending.take_error()?;
+        let tree = self.tree.clone();
+        let inserting = self.inserting.clone();
+
+        Ok(self.runtime.block_on(async move {
+            inserting.wait(key).await;
+            tree.remove(key).await.is_some()
+        }))
+    }
+
+    /// Removes values by all given keys from the B+ tree
+    ///
+    /// Returns number of keys, that were present
+    pub fn remove_batch(&self, keys: &[K]) -> Result<usize> {
+        let mut removed = 0;
+        for key in keys {
+            if self.remove(key)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl<K: std::hash::Hash + 'static + BPlusKey, R: AsyncRuntime + 'static>
+    Database<K, DataContainer<()>> for BPlusStorage<K, R>
+{
+    /// Inserts given value by given key in the B+ tree
+    ///
+    /// Returns Err(_) if some of the previous inserts failed
+    fn insert(&mut self, key: K, value: DataContainer<()>) -> io::Result<()> {
+        Ok(BPlusStorage::insert(self, key, value)?)
+    }
+
+    /// Gets value by given key from B+ tree
+    ///
+    /// Returns Err(_) if some of the previous inserts failed
+    fn get(&self, key: &K) -> io::Result<DataContainer<()>> {
+        self.pending.check_open()?;
+        self.pending.take_error()?;
+        let tree = self.tree.clone();
+        let inserting = self.inserting.clone();
+
+        let (data, kind) = self.runtime.block_on(async move {
+            inserting.wait(key).await;
+            tree.get_with_kind(key).await
+        })?;
+        to_container(data, kind)
+    }
+
+    /// Gets values by all given keys from B+ tree, reading adjacent values at once
+    ///
+    /// Returns Err(_) if some of the keys is not present or some of the previous inserts failed
+    fn get_multi(&self, keys: &[K]) -> io::Result<Vec<DataContainer<()>>> {
+        self.pending.check_open()?;
+        self.pending.take_error()?;
+        let tree = self.tree.clone();
+        let inserting = self.inserting.clone();
+
+        let values = self.runtime.block_on(async move {
+            for key in keys {
+                inserting.wait(key).await;
+            }
+            tree.get_many_with_kind(keys).await
+        })?;
+        values
+            .into_iter()
+            .map(|(data, kind)| to_container(data, kind))
+            .collect()
+    }
+
+    /// Returns whether key is contained in the B+ tree or not
+    fn contains(&self, key: &K) -> bool {
+        let tree = self.tree.clone();
+        let inserting = self.inserting.clone();
+
+        self.runtime.block_on(async move {
+            inserting.wait(key).await;
+            tree.contains_key(key).await
+        })
+    }
+}
+
+/// Converts value of given kind, stored in the tree, into a container of ChunkFS
+fn to_container(data: Vec<u8>, kind: ChunkKind) -> io::Result<DataContainer<()>> {
+    match kind {
+        ChunkKind::Chunk => Ok(data.into()),
+        ChunkKind::Target => {
+            let targets = bincode::deserialize(&data).map_err(BPlusError::from)?;
+            let mut container = DataContainer::from(Vec::new());
+            container.make_target(targets);
+            Ok(container)
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Tracing instrumentation, that compiles to nothing without the `tracing` feature
+
+/// Emits debug event with given fields and message, if tracing is enabled
+macro_rules! trace_event {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)+);
+    };
+}
+
+/// Emits warning event with given fields and message, if tracing is enabled
+macro_rules! trace_warn {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)+);
+    };
+}
+
+/// Awaits future inside of a debug span with given name and fields, if tracing is enabled
+///
+/// Fields are recorded with their `Display` implementation, and only evaluated if tracing is enabled
+macro_rules! in_span {
+    ($name:literal $(, $field:ident = $(%)? $value:expr)*; $future:expr) => {{
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!($name $(, $field = %$value)*);
+        let future = $future;
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(future, span);
+        future.await
+    }};
+}
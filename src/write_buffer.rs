@@ -0,0 +1,215 @@
+//! Write-behind buffer of inserts and removals
+//!
+//! Inserts and removals are absorbed by a sorted in-memory table, which is readable at once.
+//! When the table outgrows its limit, it is frozen and applied to data files and the tree
+//! in one sorted batch, while new changes go to a fresh table. Frozen table stays readable
+//! until the tree holds all of its entries
+
+use std::{
+    collections::BTreeMap,
+    mem,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard};
+
+use crate::bplus_tree::ChunkKind;
+
+/// Buffered value with its kind; None if the key was removed
+pub(crate) type Buffered = Option<(Vec<u8>, ChunkKind)>;
+
+/// Buffered change with its sequence number
+pub(crate) type Change = (Buffered, u64);
+
+/// Sorted tables of buffered changes
+pub(crate) struct WriteBuffer<K> {
+    tables: Mutex<Tables<K>>,
+    /// Number of bytes, above which active table is flushed
+    limit: usize,
+    /// Held by the flush, that applies the frozen table
+    flush: AsyncMutex<()>,
+}
+
+struct Tables<K> {
+    /// Table, that absorbs new changes
+    active: BTreeMap<K, Change>,
+    /// Table, that is being applied to the tree, or was not applied due to an error
+    frozen: Arc<BTreeMap<K, Change>>,
+    /// Approximate bytes taken by active table
+    active_bytes: usize,
+    /// Approximate bytes taken by frozen table
+    frozen_bytes: usize,
+}
+
+impl<K: Ord + Clone> WriteBuffer<K> {
+    /// Creates empty buffer, that is flushed after given number of bytes
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            tables: Mutex::new(Tables {
+                active: BTreeMap::new(),
+                frozen: Arc::default(),
+                active_bytes: 0,
+                frozen_bytes: 0,
+            }),
+            limit,
+            flush: AsyncMutex::new(()),
+        }
+    }
+
+    /// Buffers value by given key, replacing the buffered one
+    ///
+    /// Sequence number of the change is assigned by given function while tables are locked,
+    /// so changes of a key are numbered in their order. Returns it with size of the value,
+    /// that was buffered by the key before, and whether active table outgrew the limit.
+    /// Size is None if the key was not buffered, and Some(None) if its removal was
+    pub(crate) fn put(
+        &self,
+        key: K,
+        value: Buffered,
+        sequence: impl FnOnce() -> u64,
+    ) -> (u64, Option<Option<usize>>, bool) {
+        let mut tables = self.tables.lock().unwrap();
+        let sequence = sequence();
+        let change = (value, sequence);
+        tables.active_bytes += entry_size::<K>(&change);
+        let size = |(value, _): &Change| value.as_ref().map(|(value, _)| value.len());
+        let previous = tables
+            .active
+            .get(&key)
+            .or_else(|| tables.frozen.get(&key))
+            .map(size);
+        if let Some(old) = tables.active.insert(key, change) {
+            tables.active_bytes -= entry_size::<K>(&old);
+        }
+        (sequence, previous, tables.active_bytes >= self.limit)
+    }
+
+    /// Returns buffered change by given key, or None if the key is not buffered
+    pub(crate) fn get(&self, key: &K) -> Option<Change> {
+        let tables = self.tables.lock().unwrap();
+        tables
+            .active
+            .get(key)
+            .or_else(|| tables.frozen.get(key))
+            .cloned()
+    }
+
+    /// Returns buffered entries with keys in given range, newer ones replacing older
+    pub(crate) fn range<R: RangeBounds<K>>(&self, range: &R) -> BTreeMap<K, Buffered> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty(&bounds) {
+            return BTreeMap::new();
+        }
+        let tables = self.tables.lock().unwrap();
+        let mut entries: BTreeMap<_, _> = tables
+            .frozen
+            .range(bounds.clone())
+            .map(|(key, (value, _))| (key.clone(), value.clone()))
+            .collect();
+        entries.extend(
+            tables
+                .active
+                .range(bounds)
+                .map(|(key, (value, _))| (key.clone(), value.clone())),
+        );
+        entries
+    }
+
+    /// Returns all buffered changes with their sequence numbers, newer ones replacing older
+    pub(crate) fn changes(&self) -> BTreeMap<K, Change> {
+        let tables = self.tables.lock().unwrap();
+        let mut changes = (*tables.frozen).clone();
+        changes.extend(
+            tables
+                .active
+                .iter()
+                .map(|(key, change)| (key.clone(), change.clone())),
+        );
+        changes
+    }
+
+    /// Waits for the running flush and returns guard, that lets the caller flush
+    pub(crate) async fn lock_flush(&self) -> MutexGuard<'_, ()> {
+        self.flush.lock().await
+    }
+
+    /// Returns guard, that lets the caller flush, or None if a flush is running
+    pub(crate) fn try_lock_flush(&self) -> Option<MutexGuard<'_, ()>> {
+        self.flush.try_lock().ok()
+    }
+
+    /// Moves active table to the frozen one, merging it with entries of a failed flush
+    ///
+    /// Returns frozen table, that is to be applied to the tree
+    pub(crate) fn freeze(&self) -> Arc<BTreeMap<K, Change>> {
+        let mut tables = self.tables.lock().unwrap();
+        let active = mem::take(&mut tables.active);
+        let active_bytes = mem::take(&mut tables.active_bytes);
+        if tables.frozen.is_empty() {
+            tables.frozen = Arc::new(active);
+            tables.frozen_bytes = active_bytes;
+        } else {
+            let frozen = Arc::make_mut(&mut tables.frozen);
+            for (key, value) in active {
+                frozen.insert(key, value);
+            }
+            tables.frozen_bytes = frozen.values().map(entry_size::<K>).sum();
+        }
+        tables.frozen.clone()
+    }
+
+    /// Drops frozen table, once the tree holds all of its entries
+    pub(crate) fn release(&self) {
+        let mut tables = self.tables.lock().unwrap();
+        tables.frozen = Arc::default();
+        tables.frozen_bytes = 0;
+    }
+
+    /// Returns approximate bytes taken by buffered entries
+    pub(crate) fn bytes(&self) -> usize {
+        let tables = self.tables.lock().unwrap();
+        tables.active_bytes + tables.frozen_bytes
+    }
+}
+
+/// Returns approximate bytes taken by buffered entry
+fn entry_size<K>((value, _): &Change) -> usize {
+    mem::size_of::<(K, Change)>() + value.as_ref().map_or(0, |(data, _)| data.capacity())
+}
+
+/// Returns whether range holds no keys, so it can not be passed to [`BTreeMap::range`]
+fn is_empty<K: Ord>((start, end): &(Bound<K>, Bound<K>)) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Returns entries of the tree in ascending order of keys, replaced by buffered ones
+///
+/// Both entries and buffered ones are sorted by keys
+pub(crate) fn overlay<K: Ord>(
+    entries: Vec<(K, Vec<u8>)>,
+    buffered: BTreeMap<K, Buffered>,
+) -> Vec<(K, Vec<u8>)> {
+    let mut result = Vec::with_capacity(entries.len() + buffered.len());
+    let mut buffered = buffered.into_iter().peekable();
+    for (key, value) in entries {
+        let mut replaced = false;
+        while let Some((buffered_key, buffered_value)) = buffered.next_if(|(k, _)| *k <= key) {
+            // Buffered key equal to the key of the entry comes last
+            replaced = buffered_key == key;
+            if let Some((data, _)) = buffered_value {
+                result.push((buffered_key, data));
+            }
+        }
+        if !replaced {
+            result.push((key, value));
+        }
+    }
+    result.extend(buffered.filter_map(|(key, value)| value.map(|(data, _)| (key, data))));
+    result
+}
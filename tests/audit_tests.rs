@@ -0,0 +1,66 @@
+use bplus_tree::audit::{AuditLog, AuditOp};
+use bplus_tree::bplus_tree::{BPlus, WriteOptions};
+use std::sync::Arc;
+use tempdir::TempDir;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_audit_log() {
+    let tempdir = TempDir::new("audit").unwrap();
+    let log = Arc::new(AuditLog::open(tempdir.path().join("audit")).unwrap());
+    let tree = BPlus::<u64>::new(2, tempdir.path().join("data"))
+        .unwrap()
+        .with_audit_log(log.clone());
+
+    tree.insert(1, vec![1; 10]).await.unwrap();
+    let tagged = WriteOptions::default().with_origin("import");
+    tree.insert_opt(2, vec![2; 20], &tagged).await.unwrap();
+    assert!(tree.remove(&1).await.is_some());
+    assert!(tree.remove(&3).await.is_none());
+
+    let records = log.records().unwrap();
+    let changes: Vec<_> = records
+        .iter()
+        .map(|record| (record.op, record.key, record.size, record.origin.as_deref()))
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            (AuditOp::Insert, 1, 10, None),
+            (AuditOp::Insert, 2, 20, Some("import")),
+            (AuditOp::Remove, 1, 0, None),
+        ]
+    );
+    assert!(records
+        .windows(2)
+        .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+    let removals = log.query(|record| record.op == AuditOp::Remove).unwrap();
+    assert_eq!(removals.len(), 1);
+    assert!(log.last_error().is_none());
+
+    // Reopened log appends after the existing records
+    drop(tree);
+    drop(log);
+    let log = AuditLog::<u64>::open(tempdir.path().join("audit")).unwrap();
+    log.append(AuditOp::Insert, &4, 4, None).unwrap();
+    assert_eq!(log.records().unwrap().len(), 4);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_audit_log_rotation() {
+    let tempdir = TempDir::new("audit_rotation").unwrap();
+    let path = tempdir.path().join("audit");
+    let log = AuditLog::<u64>::open(path.clone())
+        .unwrap()
+        .with_max_file_size(100)
+        .with_max_files(2);
+    for key in 0..100 {
+        log.append(AuditOp::Insert, &key, key, None).unwrap();
+    }
+
+    assert_eq!(std::fs::read_dir(&path).unwrap().count(), 2);
+    let records = log.records().unwrap();
+    assert!(!records.is_empty() && records.len() < 100);
+    let keys: Vec<_> = records.iter().map(|record| record.key).collect();
+    let last: Vec<_> = (100 - keys.len() as u64..100).collect();
+    assert_eq!(keys, last);
+}
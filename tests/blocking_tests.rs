@@ -0,0 +1,102 @@
+use bplus_tree::blocking::BlockingBPlus;
+use std::collections::BTreeMap;
+use tempdir::TempDir;
+use tokio::runtime::Builder;
+
+#[test]
+fn test_blocking_insert_and_get() {
+    let tempdir = TempDir::new("blocking_insert").unwrap();
+    let tree: BlockingBPlus<usize> = BlockingBPlus::new(2, tempdir.path().into()).unwrap();
+
+    for i in 0..100 {
+        tree.insert(i, vec![i as u8]).unwrap();
+    }
+
+    for i in 0..100 {
+        assert_eq!(tree.get(&i).unwrap(), vec![i as u8]);
+    }
+    assert!(tree.get(&100).is_err());
+}
+
+#[test]
+fn test_blocking_scan() {
+    let tempdir = TempDir::new("blocking_scan").unwrap();
+    let tree: BlockingBPlus<usize> = BlockingBPlus::new(2, tempdir.path().into()).unwrap();
+
+    for i in (0..50).rev() {
+        tree.insert(i, vec![i as u8]).unwrap();
+    }
+
+    let entries = tree.scan(10..20).unwrap();
+    let expected: Vec<_> = (10..20).map(|i| (i, vec![i as u8])).collect();
+    assert_eq!(entries, expected);
+    assert_eq!(tree.scan(..).unwrap().len(), 50);
+}
+
+#[test]
+fn test_blocking_borrowed_runtime() {
+    let tempdir = TempDir::new("blocking_borrowed").unwrap();
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let tree = BlockingBPlus::with_runtime(&runtime, 2, tempdir.path().into()).unwrap();
+
+    tree.insert(1u64, vec![1, 2, 3]).unwrap();
+    assert_eq!(tree.get(&1).unwrap(), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_blocking_save_load() {
+    let tempdir = TempDir::new("blocking_save_load").unwrap();
+    let tree_path = tempdir.path().join("tree.bin");
+    let tree: BlockingBPlus<u64> = BlockingBPlus::new(2, tempdir.path().into()).unwrap();
+
+    for i in 0..20 {
+        tree.insert(i, vec![i as u8; 3]).unwrap();
+    }
+    tree.save(&tree_path).unwrap();
+
+    let loaded: BlockingBPlus<u64> = BlockingBPlus::load(&tree_path).unwrap();
+    for i in 0..20 {
+        assert_eq!(loaded.get(&i).unwrap(), vec![i as u8; 3]);
+    }
+}
+
+#[test]
+fn test_blocking_snapshot() {
+    let tempdir = TempDir::new("blocking_snapshot").unwrap();
+    let tree: BlockingBPlus<usize> = BlockingBPlus::new(2, tempdir.path().into()).unwrap();
+    assert!(tree.snapshot().unwrap().is_empty());
+    for i in (0..30).rev() {
+        tree.insert(i, vec![i as u8]).unwrap();
+    }
+
+    let snapshot = tree.snapshot().unwrap();
+    assert_eq!(snapshot.len(), 30);
+    let keys: Vec<usize> = (&snapshot).into_iter().map(|(key, _)| *key).collect();
+    assert_eq!(keys, (0..30).collect::<Vec<_>>());
+    let map: BTreeMap<usize, Vec<u8>> = snapshot.into_iter().collect();
+    assert_eq!(map[&7], vec![7]);
+
+    tree.insert(30, vec![30]).unwrap();
+    let mut total = 0;
+    for (key, value) in tree.snapshot().unwrap() {
+        assert_eq!(value, vec![key as u8]);
+        total += 1;
+    }
+    assert_eq!(total, 31);
+}
+
+#[test]
+fn test_blocking_from_entries_and_extend() {
+    let tempdir = TempDir::new("blocking_from_entries").unwrap();
+    let entries = (0..100).map(|i| (i, vec![i as u8]));
+    let mut tree: BlockingBPlus<usize> =
+        BlockingBPlus::from_entries(3, tempdir.path().into(), entries).unwrap();
+    assert_eq!(tree.scan(..).unwrap().len(), 100);
+    assert_eq!(tree.get(&42).unwrap(), vec![42]);
+
+    tree.extend((100..150).rev().map(|i| (i, vec![i as u8])));
+    tree.extend([(0, vec![1])]);
+    assert_eq!(tree.scan(..).unwrap().len(), 150);
+    assert_eq!(tree.get(&120).unwrap(), vec![120]);
+    assert_eq!(tree.get(&0).unwrap(), vec![1]);
+}
@@ -0,0 +1,308 @@
+use bplus_tree::bplus_tree::ChunkHandler;
+use bplus_tree::buffer_pool::{BufferPool, BufferPoolBuilder, EvictionPolicy, PagedNode};
+use bplus_tree::error::BPlusError;
+use bplus_tree::page::{Page, PageFile, PageKind, DEFAULT_PAGE_SIZE};
+use tempdir::TempDir;
+
+fn leaf(keys: std::ops::Range<u64>) -> PagedNode<u64> {
+    PagedNode::Leaf {
+        entries: keys.map(|k| (k, ChunkHandler::default())).collect(),
+        next: None,
+    }
+}
+
+fn leaf_keys(node: &PagedNode<u64>) -> Vec<u64> {
+    match node {
+        PagedNode::Leaf { entries, .. } => entries.iter().map(|(k, _)| *k).collect(),
+        PagedNode::Internal { keys, .. } => keys.clone(),
+    }
+}
+
+#[test]
+fn test_buffer_pool_round_trip() {
+    let tempdir = TempDir::new("buffer_pool").unwrap();
+    let pool = BufferPool::open(&tempdir.path().join("nodes"), 1 << 20).unwrap();
+
+    let first = pool.allocate(leaf(0..3)).unwrap();
+    let second = pool
+        .allocate(PagedNode::Internal {
+            keys: vec![10],
+            children: vec![first, first],
+        })
+        .unwrap();
+    assert_ne!(first, second);
+    assert_eq!(leaf_keys(&pool.get(first).unwrap()), vec![0, 1, 2]);
+
+    pool.update(first, leaf(5..7)).unwrap();
+    assert_eq!(leaf_keys(&pool.get(first).unwrap()), vec![5, 6]);
+    assert!(matches!(
+        pool.update(100, leaf(0..1)),
+        Err(BPlusError::NotFound)
+    ));
+    drop(pool);
+
+    let pool = BufferPool::<u64>::open(&tempdir.path().join("nodes"), 1 << 20).unwrap();
+    assert_eq!(pool.cached(), 0);
+    assert_eq!(leaf_keys(&pool.get(first).unwrap()), vec![5, 6]);
+    assert_eq!(leaf_keys(&pool.get(second).unwrap()), vec![10]);
+    assert!(pool.allocate(leaf(0..1)).unwrap() > second);
+
+    pool.free(first).unwrap();
+    assert!(matches!(pool.get(first), Err(BPlusError::NotFound)));
+    // Released page is reused
+    assert_eq!(pool.allocate(leaf(0..1)).unwrap(), first);
+}
+
+#[test]
+fn test_buffer_pool_eviction() {
+    let tempdir = TempDir::new("buffer_pool_eviction").unwrap();
+    let capacity = 4 * DEFAULT_PAGE_SIZE;
+    let pool = BufferPool::open(&tempdir.path().join("nodes"), capacity).unwrap();
+
+    let ids: Vec<_> = (0..20)
+        .map(|i| pool.allocate(leaf(i * 10..i * 10 + 10)).unwrap())
+        .collect();
+    assert!(pool.resident_bytes() <= capacity);
+    assert!(pool.cached() < ids.len());
+    let stats = pool.stats();
+    assert!(stats.evictions > 0);
+    assert_eq!(stats.evictions, stats.write_backs);
+
+    // Evicted nodes are written back and loaded again on access
+    for (i, id) in ids.iter().enumerate() {
+        let expected: Vec<u64> = (i as u64 * 10..i as u64 * 10 + 10).collect();
+        assert_eq!(leaf_keys(&pool.get(*id).unwrap()), expected);
+    }
+    assert!(pool.stats().misses > 0);
+    assert!(pool.resident_bytes() <= capacity);
+
+    // Recently used node stays cached
+    let hits = pool.stats().hits;
+    pool.get(ids[19]).unwrap();
+    assert_eq!(pool.stats().hits, hits + 1);
+}
+
+#[test]
+fn test_node_overflows_page() {
+    let tempdir = TempDir::new("page_overflow").unwrap();
+    let pool =
+        BufferPool::open_with_page_size(&tempdir.path().join("nodes"), 512, 1 << 20).unwrap();
+
+    assert!(pool.allocate(leaf(0..5)).is_ok());
+    assert!(matches!(
+        pool.allocate(leaf(0..100)),
+        Err(BPlusError::PageOverflow { page_size: 512, .. })
+    ));
+    drop(pool);
+
+    assert!(matches!(
+        BufferPool::<u64>::open(&tempdir.path().join("nodes"), 1 << 20),
+        Err(BPlusError::Corruption(_))
+    ));
+}
+
+#[test]
+fn test_long_keys_overflow() {
+    let tempdir = TempDir::new("overflow_keys").unwrap();
+    let path = tempdir.path().join("nodes");
+    let long_leaf = |fill: char| PagedNode::<String>::Leaf {
+        entries: (0..3)
+            .map(|i| {
+                (
+                    format!("{i}{}", fill.to_string().repeat(2000)),
+                    ChunkHandler::default(),
+                )
+            })
+            .collect(),
+        next: None,
+    };
+    let pool = BufferPool::open_with_page_size(&path, 512, 1 << 20).unwrap();
+    let id = pool.allocate(long_leaf('a')).unwrap();
+    let other = pool.allocate(long_leaf('b')).unwrap();
+    drop(pool);
+
+    // Keys are read back whole from their overflow pages
+    let pool = BufferPool::<String>::open_with_page_size(&path, 512, 1 << 20).unwrap();
+    let PagedNode::Leaf { entries, .. } = &*pool.get(id).unwrap() else {
+        panic!("node is a leaf");
+    };
+    assert_eq!(entries[2].0.len(), 2001);
+    assert!(entries[2].0.starts_with("2aaa"));
+
+    // Overflow pages of replaced and freed nodes are released
+    pool.update(
+        id,
+        PagedNode::Leaf {
+            entries: vec![("short".to_string(), ChunkHandler::default())],
+            next: None,
+        },
+    )
+    .unwrap();
+    pool.flush().unwrap();
+    let released = PageFile::open(&path, 512)
+        .unwrap()
+        .free_pages()
+        .unwrap()
+        .len();
+    assert!(released >= 3 * 4);
+    pool.free(other).unwrap();
+    let pages = PageFile::open(&path, 512).unwrap();
+    assert_eq!(
+        pages.free_pages().unwrap().len(),
+        pages.page_count() as usize - 2
+    );
+}
+
+#[test]
+fn test_slotted_page() {
+    let mut page = Page::new(PageKind::Leaf, 512);
+    assert!(page.is_empty());
+    assert!(page.push(b"first"));
+    assert!(page.push(b"a longer second cell"));
+    page.set_link(Some(7));
+    assert_eq!(page.len(), 2);
+    assert_eq!(page.cell(0).unwrap(), b"first");
+    assert_eq!(page.cell(1).unwrap(), b"a longer second cell");
+    assert_eq!(page.link(), Some(7));
+
+    let free_space = page.free_space();
+    assert!(!page.push(&vec![0; free_space]));
+    assert!(page.push(&vec![0; free_space - 4]));
+    assert_eq!(page.free_space(), 0);
+}
+
+#[test]
+fn test_page_checksums() {
+    let tempdir = TempDir::new("page_checksums").unwrap();
+    let path = tempdir.path().join("pages");
+    let mut pages = PageFile::open(&path, 1024).unwrap();
+    let id = pages.allocate().unwrap();
+    let mut page = Page::new(PageKind::Leaf, 1024);
+    page.push(b"cell");
+    pages.write(id, &page).unwrap();
+    assert_eq!(pages.read(id).unwrap().cell(0).unwrap(), b"cell");
+    drop(pages);
+
+    let mut data = std::fs::read(&path).unwrap();
+    data[id as usize * 1024 + 1020] ^= 1;
+    std::fs::write(&path, data).unwrap();
+    let pages = PageFile::open(&path, 1024).unwrap();
+    assert!(matches!(pages.read(id), Err(BPlusError::Corruption(_))));
+    assert!(matches!(pages.read(100), Err(BPlusError::NotFound)));
+}
+
+#[test]
+fn test_eviction_policies() {
+    for policy in [
+        EvictionPolicy::Lru,
+        EvictionPolicy::Clock,
+        EvictionPolicy::TwoQueue,
+    ] {
+        let tempdir = TempDir::new("eviction_policies").unwrap();
+        let capacity = 8 * DEFAULT_PAGE_SIZE;
+        let pool = BufferPoolBuilder::new(capacity)
+            .eviction_policy(policy)
+            .open(&tempdir.path().join("nodes"))
+            .unwrap();
+
+        let ids: Vec<_> = (0..32)
+            .map(|i| pool.allocate(leaf(i..i + 1)).unwrap())
+            .collect();
+        for _ in 0..3 {
+            for (i, id) in ids.iter().enumerate() {
+                assert_eq!(leaf_keys(&pool.get(*id).unwrap()), vec![i as u64]);
+                assert!(pool.resident_bytes() <= capacity, "{:?}", policy);
+            }
+        }
+        assert!(pool.stats().evictions > 0, "{:?}", policy);
+    }
+}
+
+#[test]
+fn test_pinned_nodes_are_not_evicted() {
+    let tempdir = TempDir::new("pinned_nodes").unwrap();
+    let pool = BufferPoolBuilder::new(2 * DEFAULT_PAGE_SIZE)
+        .pin_internal_nodes(true)
+        .open(&tempdir.path().join("nodes"))
+        .unwrap();
+
+    let root = pool
+        .allocate(PagedNode::Internal {
+            keys: vec![],
+            children: vec![],
+        })
+        .unwrap();
+    let pinned = pool.allocate(leaf(0..1)).unwrap();
+    pool.pin(pinned).unwrap();
+    for i in 0..10 {
+        pool.allocate(leaf(i..i + 1)).unwrap();
+    }
+
+    let misses = pool.stats().misses;
+    pool.get(root).unwrap();
+    pool.get(pinned).unwrap();
+    assert_eq!(pool.stats().misses, misses);
+
+    pool.unpin(pinned).unwrap();
+    pool.allocate(leaf(0..1)).unwrap();
+    pool.get(pinned).unwrap();
+    assert_eq!(pool.stats().misses, misses + 1);
+}
+
+#[test]
+fn test_two_queue_resists_scans() {
+    let tempdir = TempDir::new("two_queue").unwrap();
+    let pool = BufferPoolBuilder::new(8 * DEFAULT_PAGE_SIZE)
+        .eviction_policy(EvictionPolicy::TwoQueue)
+        .open(&tempdir.path().join("nodes"))
+        .unwrap();
+
+    let hot: Vec<_> = (0..4)
+        .map(|i| pool.allocate(leaf(i..i + 1)).unwrap())
+        .collect();
+    // Hot nodes are evicted right after being cached and are remembered as recently evicted,
+    // so they get into the queue of frequently used nodes, when loaded again
+    for i in 0..8 {
+        pool.allocate(leaf(i..i + 1)).unwrap();
+    }
+    for id in &hot {
+        pool.get(*id).unwrap();
+    }
+
+    for i in 0..32 {
+        let id = pool.allocate(leaf(i..i + 1)).unwrap();
+        pool.get(id).unwrap();
+    }
+    let misses = pool.stats().misses;
+    for id in &hot {
+        pool.get(*id).unwrap();
+    }
+    assert_eq!(pool.stats().misses, misses);
+}
+
+#[test]
+fn test_buffer_pool_shrink() {
+    let tempdir = TempDir::new("buffer_pool_shrink").unwrap();
+    let pool = BufferPool::open(&tempdir.path().join("nodes"), 1 << 20).unwrap();
+    let ids: Vec<_> = (0..10)
+        .map(|i| pool.allocate(leaf(i * 10..i * 10 + 5)).unwrap())
+        .collect();
+    pool.pin(ids[0]).unwrap();
+    assert_eq!(pool.cached(), 10);
+
+    assert_eq!(
+        pool.shrink(2 * DEFAULT_PAGE_SIZE).unwrap(),
+        8 * DEFAULT_PAGE_SIZE
+    );
+    assert_eq!(pool.cached(), 2);
+    // Pinned nodes are kept, evicted ones are written back and loaded again
+    assert_eq!(pool.shrink(0).unwrap(), DEFAULT_PAGE_SIZE);
+    assert_eq!(pool.cached(), 1);
+    for (i, id) in ids.into_iter().enumerate() {
+        let start = i as u64 * 10;
+        assert_eq!(
+            leaf_keys(&pool.get(id).unwrap()),
+            (start..start + 5).collect::<Vec<_>>()
+        );
+    }
+}
@@ -4,19 +4,76 @@ use std::collections::HashMap;
 use std::io;
 use std::io::{Seek, Write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use approx::assert_relative_eq;
 
+use bplus_tree::blocking::BlockingBPlus;
 use bplus_tree::bplus_tree::BPlusStorage;
+use bplus_tree::error::BPlusError;
+use bplus_tree::runtime::{Blocking, BlockingJob, BoxFuture, Spawner};
 use chunkfs::chunkers::{FSChunker, LeapChunker};
 use chunkfs::hashers::SimpleHasher;
-use chunkfs::{create_cdc_filesystem, DataContainer, Database, WriteMeasurements};
+use chunkfs::{create_cdc_filesystem, Data, DataContainer, Database, WriteMeasurements};
 use tempdir::TempDir;
 
-use tokio::runtime::Builder;
+use tokio::runtime::{Builder, Runtime};
 
 const MB: usize = 1024 * 1024;
 
+/// Tokio runtime, that counts blocking jobs and delays start of tasks spawned on it
+struct CountingRuntime {
+    runtime: Runtime,
+    blocking_jobs: Arc<AtomicUsize>,
+    /// Tasks, that are waiting for their delay to pass
+    delayed_tasks: Arc<AtomicUsize>,
+    max_delayed_tasks: Arc<AtomicUsize>,
+    task_delay: Duration,
+}
+
+impl CountingRuntime {
+    fn new(task_delay: Duration) -> Self {
+        Self {
+            runtime: Builder::new_multi_thread().enable_all().build().unwrap(),
+            blocking_jobs: Arc::default(),
+            delayed_tasks: Arc::default(),
+            max_delayed_tasks: Arc::default(),
+            task_delay,
+        }
+    }
+}
+
+impl Spawner for CountingRuntime {
+    fn spawn(&self, future: BoxFuture) {
+        let delayed = self.delayed_tasks.clone();
+        let max_delayed = self.max_delayed_tasks.clone();
+        let delay = self.task_delay;
+        Spawner::spawn(
+            &self.runtime,
+            Box::pin(async move {
+                let now = delayed.fetch_add(1, Ordering::SeqCst) + 1;
+                max_delayed.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(delay).await;
+                delayed.fetch_sub(1, Ordering::SeqCst);
+                future.await;
+            }),
+        );
+    }
+
+    fn spawn_blocking(&self, job: BlockingJob) {
+        self.blocking_jobs.fetch_add(1, Ordering::SeqCst);
+        Spawner::spawn_blocking(&self.runtime, job);
+    }
+}
+
+impl Blocking for CountingRuntime {
+    fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+}
+
 #[test]
 fn write_read_complete_test() {
     let tempdir = &TempDir::new("storage1").unwrap();
@@ -235,3 +292,272 @@ fn write_from_stream_buf_reader() {
     assert_eq!(read.len(), MB);
     assert_eq!(read, [1; MB]);
 }
+
+#[test]
+fn target_chunk_round_trip() {
+    let tempdir = &TempDir::new("storage11").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let storage = BPlusStorage::new(runtime, 100, path).unwrap();
+
+    let mut target = DataContainer::from(Vec::new());
+    target.make_target(vec![(); 3]);
+    storage.insert(vec![1u8], target).unwrap();
+    storage.insert(vec![2u8], vec![7; 10].into()).unwrap();
+
+    match storage.get(&vec![1u8]).unwrap().extract() {
+        Data::TargetChunk(targets) => assert_eq!(targets.len(), 3),
+        Data::Chunk(_) => panic!("Target chunk was read as a plain chunk"),
+    }
+    match storage.get(&vec![2u8]).unwrap().extract() {
+        Data::Chunk(chunk) => assert_eq!(chunk, &vec![7; 10]),
+        Data::TargetChunk(_) => panic!("Plain chunk was read as a target chunk"),
+    }
+    assert!(!storage.contains(&vec![3u8]));
+}
+
+#[test]
+fn flush_waits_for_background_inserts() {
+    let tempdir = &TempDir::new("storage12").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let storage = BPlusStorage::new(runtime, 3, path).unwrap();
+
+    for i in 0..200u8 {
+        storage.insert(vec![i], vec![i; 16].into()).unwrap();
+    }
+    storage.flush().unwrap();
+    assert!(storage.last_error().is_none());
+
+    for i in 0..200u8 {
+        match storage.get(&vec![i]).unwrap().extract() {
+            Data::Chunk(chunk) => assert_eq!(chunk, &vec![i; 16]),
+            Data::TargetChunk(_) => panic!("Plain chunk was read as a target chunk"),
+        }
+    }
+}
+
+#[test]
+fn get_waits_for_key_being_inserted() {
+    let tempdir = &TempDir::new("storage13").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let storage = BPlusStorage::new(runtime, 3, path).unwrap();
+
+    for i in 0..100u8 {
+        storage.insert(vec![i], vec![i; 8].into()).unwrap();
+        match storage.get(&vec![i]).unwrap().extract() {
+            Data::Chunk(chunk) => assert_eq!(chunk, &vec![i; 8]),
+            Data::TargetChunk(_) => panic!("Plain chunk was read as a target chunk"),
+        }
+    }
+}
+
+#[test]
+fn bounded_in_flight_inserts() {
+    let tempdir = &TempDir::new("storage14").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    // Every insert task is delayed, so without a bound all of them would be in flight together
+    let runtime = CountingRuntime::new(Duration::from_millis(5));
+    let max_in_flight = runtime.max_delayed_tasks.clone();
+    let storage = BPlusStorage::new(runtime, 3, path)
+        .unwrap()
+        .with_max_in_flight(2);
+
+    for i in 0..100u8 {
+        storage.insert(vec![i], vec![i; 32].into()).unwrap();
+    }
+    storage.flush().unwrap();
+    assert!(max_in_flight.load(Ordering::SeqCst) <= 2);
+
+    for i in 0..100u8 {
+        match storage.get(&vec![i]).unwrap().extract() {
+            Data::Chunk(chunk) => assert_eq!(chunk, &vec![i; 32]),
+            Data::TargetChunk(_) => panic!("Plain chunk was read as a target chunk"),
+        }
+    }
+}
+
+#[test]
+fn storage_on_runtime_handle() {
+    let tempdir = &TempDir::new("storage15").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+
+    assert!(BPlusStorage::<Vec<u8>, _>::current(3, path.clone()).is_err());
+    let storage = {
+        let _guard = runtime.enter();
+        BPlusStorage::current(3, path).unwrap()
+    };
+
+    for i in 0..50u8 {
+        storage.insert(vec![i], vec![i; 4].into()).unwrap();
+    }
+    storage.flush().unwrap();
+    for i in 0..50u8 {
+        assert!(storage.contains(&vec![i]));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn storage_dropped_inside_runtime() {
+    let tempdir = &TempDir::new("storage_drop").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let storage = BPlusStorage::current(3, path).unwrap();
+
+    let writer = storage.clone();
+    tokio::task::spawn_blocking(move || {
+        for i in 0..50u8 {
+            writer.insert(vec![i], vec![i; 4].into()).unwrap();
+        }
+        writer.flush().unwrap();
+    })
+    .await
+    .unwrap();
+    // The last handle does not block inside the runtime
+    drop(storage);
+}
+
+#[test]
+fn shared_storage_inserts() {
+    let tempdir = &TempDir::new("storage16").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let storage = BPlusStorage::new(runtime, 3, path).unwrap();
+
+    let writers: Vec<_> = (0..4u8)
+        .map(|w| {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                for i in 0..50u8 {
+                    storage.insert(vec![w, i], vec![w ^ i; 8].into()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    storage.flush().unwrap();
+
+    for w in 0..4u8 {
+        for i in 0..50u8 {
+            match storage.get(&vec![w, i]).unwrap().extract() {
+                Data::Chunk(chunk) => assert_eq!(chunk, &vec![w ^ i; 8]),
+                Data::TargetChunk(_) => panic!("Plain chunk was read as a target chunk"),
+            }
+        }
+    }
+}
+
+#[test]
+fn remove_after_in_flight_insert() {
+    let tempdir = &TempDir::new("storage17").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let storage = BPlusStorage::new(runtime, 3, path).unwrap();
+
+    for i in 0..50u8 {
+        storage.insert(vec![i], vec![i; 8].into()).unwrap();
+        assert!(storage.remove(&vec![i]).unwrap());
+    }
+    storage.flush().unwrap();
+    for i in 0..50u8 {
+        assert!(!storage.contains(&vec![i]));
+    }
+
+    for i in 0..50u8 {
+        storage.insert(vec![i], vec![i; 8].into()).unwrap();
+    }
+    let keys: Vec<_> = (0..50u8).map(|i| vec![i]).collect();
+    assert_eq!(storage.remove_batch(&keys).unwrap(), 50);
+    assert_eq!(storage.remove_batch(&keys).unwrap(), 0);
+}
+
+#[test]
+fn close_rejects_operations() {
+    let tempdir = &TempDir::new("storage18").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let checkpoint = tempdir.path().join("tree.bin");
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let storage = BPlusStorage::new(runtime, 3, path).unwrap();
+
+    for i in 0..50u8 {
+        storage.insert(vec![i], vec![i; 8].into()).unwrap();
+    }
+    storage.clone().close_with_checkpoint(&checkpoint).unwrap();
+
+    assert!(matches!(
+        storage.insert(vec![100], vec![0; 8].into()),
+        Err(BPlusError::Closed)
+    ));
+    assert!(matches!(storage.remove(&vec![1]), Err(BPlusError::Closed)));
+    assert!(storage.get(&vec![1]).is_err());
+
+    let loaded: BlockingBPlus<Vec<u8>> = BlockingBPlus::load(&checkpoint).unwrap();
+    for i in 0..50u8 {
+        assert_eq!(loaded.get(&vec![i]).unwrap(), vec![i; 8]);
+    }
+}
+
+#[test]
+fn drop_marks_store_clean() {
+    let tempdir = &TempDir::new("storage19").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let checkpoint = tempdir.path().join("tree.bin");
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let storage = BPlusStorage::new(runtime, 3, path).unwrap();
+
+    for i in 0..50u8 {
+        storage.insert(vec![i], vec![i; 8].into()).unwrap();
+    }
+    storage.close_with_checkpoint(&checkpoint).unwrap();
+    drop(storage);
+
+    let loaded: BlockingBPlus<Vec<u8>> = BlockingBPlus::load(&checkpoint).unwrap();
+    assert!(loaded.tree().closed_cleanly());
+}
+
+#[test]
+fn storage_offloads_io_to_runtime() {
+    let tempdir = &TempDir::new("storage20").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let runtime = CountingRuntime::new(Duration::ZERO);
+    let blocking_jobs = runtime.blocking_jobs.clone();
+    let storage = BPlusStorage::new(runtime, 3, path).unwrap();
+
+    for i in 0..20u8 {
+        storage.insert(vec![i], vec![i; 8].into()).unwrap();
+    }
+    storage.flush().unwrap();
+    let written = blocking_jobs.load(Ordering::SeqCst);
+    assert!(written >= 20);
+
+    for i in 0..20u8 {
+        storage.get(&vec![i]).unwrap();
+    }
+    assert!(blocking_jobs.load(Ordering::SeqCst) >= written + 20);
+}
+
+#[test]
+fn storage_on_current_thread_runtime() {
+    let tempdir = &TempDir::new("storage21").unwrap();
+    let path = PathBuf::new().join(tempdir.path());
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    let storage = BPlusStorage::new(runtime, 3, path).unwrap();
+
+    // Background inserts only run, while the storage drives the runtime
+    for i in 0..50u8 {
+        storage.insert(vec![i], vec![i; 8].into()).unwrap();
+    }
+    storage.flush().unwrap();
+    for i in 0..50u8 {
+        assert!(storage.contains(&vec![i]));
+    }
+
+    for i in 50..100u8 {
+        storage.insert(vec![i], vec![i; 8].into()).unwrap();
+    }
+    storage.clone().close().unwrap();
+    drop(storage);
+}
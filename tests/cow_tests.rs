@@ -0,0 +1,316 @@
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+use bplus_tree::buffer_pool::BufferPoolBuilder;
+use bplus_tree::cow::CowTree;
+use bplus_tree::error::BPlusError;
+use bplus_tree::page::DEFAULT_PAGE_SIZE;
+use tempdir::TempDir;
+
+fn create_tree(tempdir: &TempDir) -> CowTree<u64, Vec<u8>> {
+    CowTree::create(
+        &tempdir.path().join("pages"),
+        2,
+        BufferPoolBuilder::new(1 << 20),
+    )
+    .unwrap()
+}
+
+fn open_tree(tempdir: &TempDir) -> CowTree<u64, Vec<u8>> {
+    CowTree::open(
+        &tempdir.path().join("pages"),
+        BufferPoolBuilder::new(1 << 20),
+    )
+    .unwrap()
+}
+
+fn commit_range(tree: &CowTree<u64, Vec<u8>>, keys: std::ops::Range<u64>) -> u64 {
+    let mut txn = tree.begin_write();
+    for i in keys {
+        txn.insert(i, vec![i as u8]).unwrap();
+    }
+    txn.commit().unwrap()
+}
+
+#[test]
+fn test_cow_insert_and_get() {
+    let tempdir = TempDir::new("cow_insert").unwrap();
+    let tree = create_tree(&tempdir);
+
+    let mut txn = tree.begin_write();
+    for i in (0..200).rev() {
+        txn.insert(i, vec![i as u8; 3]).unwrap();
+    }
+    txn.insert(7, vec![0]).unwrap();
+    assert_eq!(txn.get(&7).unwrap(), Some(vec![0]));
+    assert_eq!(txn.commit().unwrap(), 1);
+
+    let snapshot = tree.snapshot();
+    assert_eq!(snapshot.version(), 1);
+    assert_eq!(snapshot.get(&7).unwrap(), Some(vec![0]));
+    for i in (0..200).filter(|i| *i != 7) {
+        assert_eq!(snapshot.get(&i).unwrap(), Some(vec![i as u8; 3]));
+    }
+    assert_eq!(snapshot.get(&200).unwrap(), None);
+
+    let entries = snapshot.scan(10..20).unwrap();
+    let keys: Vec<_> = entries.iter().map(|(k, _)| *k).collect();
+    assert_eq!(keys, (10..20).collect::<Vec<_>>());
+    assert_eq!(snapshot.scan(..).unwrap().len(), 200);
+}
+
+#[test]
+fn test_cow_snapshot_isolation() {
+    let tempdir = TempDir::new("cow_isolation").unwrap();
+    let tree = create_tree(&tempdir);
+
+    let mut txn = tree.begin_write();
+    for i in 0..50 {
+        txn.insert(i, vec![1]).unwrap();
+    }
+    txn.commit().unwrap();
+    let old = tree.snapshot();
+
+    let mut txn = tree.begin_write();
+    for i in 0..50 {
+        txn.insert(i, vec![2]).unwrap();
+    }
+    assert!(txn.remove(&0).unwrap());
+    assert!(!txn.remove(&100).unwrap());
+    txn.commit().unwrap();
+
+    // Old snapshot still reads its version, while pages of it are retained
+    assert!(tree.retained_pages() > 0);
+    assert_eq!(old.get(&0).unwrap(), Some(vec![1]));
+    assert!(old.scan(..).unwrap().iter().all(|(_, v)| *v == vec![1]));
+    let new = tree.snapshot();
+    assert_eq!(new.get(&0).unwrap(), None);
+    assert_eq!(new.get(&1).unwrap(), Some(vec![2]));
+
+    // Pages of the previous version are kept until the next commit is durable
+    drop(old);
+    assert!(tree.retained_pages() > 0);
+    tree.begin_write().commit().unwrap();
+    assert_eq!(tree.retained_pages(), 0);
+    assert_eq!(new.scan(..).unwrap().len(), 49);
+}
+
+#[test]
+fn test_cow_abort() {
+    let tempdir = TempDir::new("cow_abort").unwrap();
+    let tree = create_tree(&tempdir);
+
+    let mut txn = tree.begin_write();
+    txn.insert(1, vec![1]).unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = tree.begin_write();
+    txn.insert(2, vec![2]).unwrap();
+    drop(txn);
+
+    assert_eq!(tree.version(), 1);
+    let snapshot = tree.snapshot();
+    assert_eq!(snapshot.get(&1).unwrap(), Some(vec![1]));
+    assert_eq!(snapshot.get(&2).unwrap(), None);
+}
+
+#[test]
+fn test_cow_readers_during_commits() {
+    let tempdir = TempDir::new("cow_readers").unwrap();
+    let tree = create_tree(&tempdir);
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            for i in 0..100 {
+                let mut txn = tree.begin_write();
+                txn.insert(i, vec![i as u8]).unwrap();
+                txn.commit().unwrap();
+            }
+        });
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..50 {
+                    let snapshot = tree.snapshot();
+                    let entries = snapshot.scan(..).unwrap();
+                    assert_eq!(entries.len() as u64, snapshot.version());
+                }
+            });
+        }
+    });
+    assert_eq!(tree.snapshot().scan(..).unwrap().len(), 100);
+    tree.begin_write().commit().unwrap();
+    assert_eq!(tree.retained_pages(), 0);
+}
+
+#[test]
+fn test_cow_reopen() {
+    let tempdir = TempDir::new("cow_reopen").unwrap();
+    let tree = create_tree(&tempdir);
+    for i in 0..10 {
+        commit_range(&tree, i * 20..(i + 1) * 20);
+    }
+    drop(tree);
+    assert!(CowTree::<u64, Vec<u8>>::create(
+        &tempdir.path().join("pages"),
+        2,
+        BufferPoolBuilder::new(1 << 20)
+    )
+    .is_err());
+
+    let tree = open_tree(&tempdir);
+    assert_eq!(tree.version(), 10);
+    let snapshot = tree.snapshot();
+    let entries = snapshot.scan(..).unwrap();
+    assert_eq!(entries.len(), 200);
+    assert!(entries.iter().all(|(k, v)| *v == vec![*k as u8]));
+    drop(snapshot);
+
+    assert_eq!(commit_range(&tree, 200..210), 11);
+    assert_eq!(tree.snapshot().get(&205).unwrap(), Some(vec![205]));
+}
+
+#[test]
+fn test_cow_reopen_reclaims_old_pages() {
+    let tempdir = TempDir::new("cow_reopen_gc").unwrap();
+    let path = tempdir.path().join("pages");
+    let tree = create_tree(&tempdir);
+    commit_range(&tree, 0..100);
+    drop(tree);
+
+    let mut sizes = Vec::new();
+    for _ in 0..5 {
+        let tree = open_tree(&tempdir);
+        commit_range(&tree, 0..100);
+        drop(tree);
+
+        // Pages of the previous version are known after reopen and freed by the next commit
+        let tree = open_tree(&tempdir);
+        assert!(tree.retained_pages() > 0);
+        tree.begin_write().commit().unwrap();
+        assert_eq!(tree.retained_pages(), 0);
+        drop(tree);
+        sizes.push(std::fs::metadata(&path).unwrap().len());
+    }
+    assert!(sizes.windows(2).all(|pair| pair[1] <= pair[0]));
+}
+
+#[test]
+fn test_cow_reopen_reclaims_interrupted_txn() {
+    let tempdir = TempDir::new("cow_reopen_crash").unwrap();
+    let path = tempdir.path().join("pages");
+    let tree = create_tree(&tempdir);
+    commit_range(&tree, 0..50);
+
+    let mut txn = tree.begin_write();
+    for i in 50..100 {
+        txn.insert(i, vec![i as u8]).unwrap();
+    }
+    std::mem::forget(txn);
+    drop(tree);
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    // Pages of the interrupted transaction are freed on open and reused by the same changes
+    let tree = open_tree(&tempdir);
+    assert_eq!(commit_range(&tree, 50..100), 2);
+    drop(tree);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+
+    let tree = open_tree(&tempdir);
+    assert_eq!(tree.snapshot().scan(..).unwrap().len(), 100);
+}
+
+#[test]
+fn test_cow_uncommitted_not_durable() {
+    let tempdir = TempDir::new("cow_crash").unwrap();
+    let tree = create_tree(&tempdir);
+    commit_range(&tree, 0..50);
+
+    // Transaction is interrupted before commit, while its pages may reach the disk
+    let mut txn = tree.begin_write();
+    for i in 50..100 {
+        txn.insert(i, vec![0]).unwrap();
+    }
+    std::mem::forget(txn);
+    drop(tree);
+
+    let tree = open_tree(&tempdir);
+    assert_eq!(tree.version(), 1);
+    let snapshot = tree.snapshot();
+    assert_eq!(snapshot.scan(..).unwrap().len(), 50);
+    assert_eq!(snapshot.get(&60).unwrap(), None);
+}
+
+#[test]
+fn test_cow_torn_meta_page() {
+    let tempdir = TempDir::new("cow_torn").unwrap();
+    let tree = create_tree(&tempdir);
+    commit_range(&tree, 0..50);
+    commit_range(&tree, 50..100);
+    drop(tree);
+
+    // Meta page of version 2 is page 1
+    let file = OpenOptions::new()
+        .write(true)
+        .open(tempdir.path().join("pages"))
+        .unwrap();
+    file.write_all_at(&[0xAB; 16], DEFAULT_PAGE_SIZE as u64 + 100)
+        .unwrap();
+    drop(file);
+
+    let tree = open_tree(&tempdir);
+    assert_eq!(tree.version(), 1);
+    let snapshot = tree.snapshot();
+    assert_eq!(snapshot.scan(..).unwrap().len(), 50);
+    assert_eq!(snapshot.get(&70).unwrap(), None);
+    drop(snapshot);
+    drop(tree);
+
+    // Both meta pages are torn
+    let file = OpenOptions::new()
+        .write(true)
+        .open(tempdir.path().join("pages"))
+        .unwrap();
+    file.write_all_at(&[0xAB; 16], 2 * DEFAULT_PAGE_SIZE as u64 + 100)
+        .unwrap();
+    drop(file);
+    assert!(matches!(
+        CowTree::<u64, Vec<u8>>::open(
+            &tempdir.path().join("pages"),
+            BufferPoolBuilder::new(1 << 20)
+        ),
+        Err(BPlusError::Corruption(_))
+    ));
+}
+
+#[test]
+fn test_cow_long_keys() {
+    let tempdir = TempDir::new("cow_long_keys").unwrap();
+    let path = tempdir.path().join("pages");
+    let pool = || BufferPoolBuilder::new(1 << 20).page_size(512);
+    let key = |i: u64| format!("{}/file{i:03}", "directory/".repeat(100));
+    let commit = |tree: &CowTree<String, Vec<u8>>, value: u8| {
+        let mut txn = tree.begin_write();
+        for i in 0..50 {
+            txn.insert(key(i), vec![value]).unwrap();
+        }
+        txn.commit().unwrap();
+    };
+
+    let tree = CowTree::create(&path, 2, pool()).unwrap();
+    commit(&tree, 0);
+    drop(tree);
+
+    let mut sizes = Vec::new();
+    for value in 1..5 {
+        let tree = CowTree::open(&path, pool()).unwrap();
+        let entries = tree.snapshot().scan(..).unwrap();
+        assert_eq!(entries.len(), 50);
+        assert_eq!(entries[7], (key(7), vec![value - 1]));
+        commit(&tree, value);
+        // Overflow pages of the previous version are freed with its nodes
+        tree.begin_write().commit().unwrap();
+        drop(tree);
+        sizes.push(std::fs::metadata(&path).unwrap().len());
+    }
+    assert!(sizes.windows(2).all(|pair| pair[1] <= pair[0]));
+}
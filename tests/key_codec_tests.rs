@@ -0,0 +1,149 @@
+use bplus_tree::bplus_tree::BPlus;
+use bplus_tree::error::{BPlusError, Result};
+use bplus_tree::key_codec::KeyCodec;
+use std::fmt::Debug;
+use tempdir::TempDir;
+
+/// Checks, that encodings of given keys, sorted by the keys, are sorted and decode to them
+fn assert_ordered<K: KeyCodec + Ord + Debug>(mut keys: Vec<K>) {
+    keys.sort();
+    let encoded: Vec<_> = keys.iter().map(KeyCodec::to_bytes).collect();
+    for (pair, keys) in encoded.windows(2).zip(keys.windows(2)) {
+        assert!(
+            pair[0] < pair[1],
+            "{:?} is not encoded before {:?}",
+            keys[0],
+            keys[1]
+        );
+    }
+    for (bytes, key) in encoded.iter().zip(&keys) {
+        assert_eq!(&K::from_bytes(bytes).unwrap(), key);
+    }
+}
+
+#[test]
+fn test_order_preserving() {
+    assert_ordered(vec![0u64, 1, 255, 256, u64::MAX - 1, u64::MAX]);
+    assert_ordered(vec![i32::MIN, -256, -1, 0, 1, 255, i32::MAX]);
+    assert_ordered(vec![i128::MIN, -1, 0, i128::MAX]);
+    assert_ordered(vec![false, true]);
+    assert_ordered(vec!['a', 'z', 'я', '😀']);
+    assert_ordered(vec![
+        Vec::new(),
+        vec![0],
+        vec![0, 0],
+        vec![0, 1],
+        vec![0, 255],
+        vec![1],
+        vec![255, 0],
+        vec![255, 255],
+    ]);
+    assert_ordered(
+        ["", "a", "a\0", "a\0b", "ab", "b", "ба"]
+            .map(String::from)
+            .to_vec(),
+    );
+    assert_ordered(vec![None, Some(0u8), Some(1)]);
+    // Shorter strings in the first item are not mixed with the second item
+    assert_ordered(vec![
+        (String::from("a"), 2u64),
+        (String::from("a"), 10),
+        (String::from("a\0"), 0),
+        (String::from("ab"), 0),
+        (String::from("b"), 1),
+    ]);
+    assert_ordered(vec![
+        (-1i64, [1u8; 4], true),
+        (-1, [1; 4], false),
+        (0, [0; 4], false),
+    ]);
+}
+
+#[test]
+fn test_malformed_encodings() {
+    assert!(matches!(
+        u64::from_bytes(&[1, 2, 3]),
+        Err(BPlusError::Corruption(_))
+    ));
+    assert!(matches!(
+        u32::from_bytes(&[0, 0, 0, 1, 0]),
+        Err(BPlusError::Corruption(_))
+    ));
+    // Unterminated and wrongly escaped bytes
+    assert!(Vec::<u8>::from_bytes(&[1, 2]).is_err());
+    assert!(Vec::<u8>::from_bytes(&[1, 0, 7]).is_err());
+    assert!(String::from_bytes(&[0xC0, 0, 0]).is_err());
+    assert!(bool::from_bytes(&[2]).is_err());
+
+    let mut bytes: &[u8] = &[0, 0, 0, 7, 1, 0, 0];
+    assert_eq!(u32::decode(&mut bytes).unwrap(), 7);
+    assert_eq!(bytes, &[1, 0, 0]);
+}
+
+/// Key, that is persisted by its codec alone, without serde
+#[derive(Clone, Default, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Account {
+    tenant: u32,
+    name: String,
+}
+
+impl KeyCodec for Account {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.tenant.encode(buf);
+        self.name.encode(buf);
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Self> {
+        Ok(Self {
+            tenant: u32::decode(bytes)?,
+            name: String::decode(bytes)?,
+        })
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_custom_key() {
+    let temp_dir = TempDir::new("custom_key").unwrap();
+    let tree_path = temp_dir.path().join("tree");
+    let tree = BPlus::<Account>::new(2, temp_dir.path().join("data")).unwrap();
+    let accounts: Vec<_> = (0..50)
+        .map(|i| Account {
+            tenant: i % 3,
+            name: format!("user{i}"),
+        })
+        .collect();
+    for (i, account) in accounts.iter().enumerate() {
+        tree.insert(account.clone(), vec![i as u8; 10])
+            .await
+            .unwrap();
+    }
+    tree.save(&tree_path).await.unwrap();
+
+    let loaded = BPlus::<Account>::load(&tree_path).await.unwrap();
+    assert_eq!(loaded.scan(..).await.unwrap(), tree.scan(..).await.unwrap());
+    assert_eq!(loaded.get(&accounts[7]).await.unwrap(), vec![7; 10]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_fixed_width_keys() {
+    assert_eq!(u64::WIDTH, Some(8));
+    assert_eq!(<(u32, [u8; 20], bool)>::WIDTH, Some(25));
+    assert_eq!(<(u32, String)>::WIDTH, None);
+
+    // Hashes of chunks are packed into snapshots without lengths
+    let temp_dir = TempDir::new("fixed_width").unwrap();
+    let tree_path = temp_dir.path().join("tree");
+    let tree = BPlus::<[u8; 32]>::new(3, temp_dir.path().join("data")).unwrap();
+    for i in 0..200u8 {
+        tree.insert([i.wrapping_mul(37); 32], vec![i; 4])
+            .await
+            .unwrap();
+    }
+    tree.save(&tree_path).await.unwrap();
+    let size = std::fs::metadata(&tree_path).unwrap().len();
+    assert_eq!(tree.estimated_snapshot_size().await, size);
+
+    let loaded = BPlus::<[u8; 32]>::load(&tree_path).await.unwrap();
+    assert_eq!(loaded.scan(..).await.unwrap(), tree.scan(..).await.unwrap());
+    assert_eq!(loaded.get(&[37; 32]).await.unwrap(), vec![1; 4]);
+}
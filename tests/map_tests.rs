@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+
+use bplus_tree::map::BPlusMap;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[test]
+fn test_map() {
+    let mut map = BPlusMap::with_t(2);
+    assert!(map.is_empty());
+    for key in 0..100u64 {
+        assert_eq!(map.insert(key, key * 10), None);
+    }
+    assert_eq!(map.len(), 100);
+    assert!(map.height() > 2);
+    assert_eq!(map.get(&42), Some(&420));
+    assert_eq!(map.get(&100), None);
+    assert_eq!(map.insert(42, 0), Some(420));
+    *map.get_mut(&43).unwrap() += 1;
+    assert_eq!(map.get(&43), Some(&431));
+    assert_eq!(map.len(), 100);
+
+    assert_eq!(map.first_key_value(), Some((&0, &0)));
+    assert_eq!(map.last_key_value(), Some((&99, &990)));
+    assert!(map.keys().copied().eq(0..100));
+
+    for key in (0..100).step_by(2) {
+        assert_eq!(map.remove(&key), Some(if key == 42 { 0 } else { key * 10 }));
+    }
+    assert_eq!(map.remove(&0), None);
+    assert_eq!(map.len(), 50);
+    assert!(map.keys().copied().eq((1..100).step_by(2)));
+    assert!(map.range(10..=20).map(|(k, _)| *k).eq([11, 13, 15, 17, 19]));
+    assert!(map.range(..5).map(|(k, _)| *k).eq([1, 3]));
+    assert_eq!(map.range(200..).count(), 0);
+
+    for key in (1..100).step_by(2) {
+        assert!(map.remove(&key).is_some());
+    }
+    assert!(map.is_empty());
+    assert_eq!(map.height(), 1);
+    assert_eq!(map.first_key_value(), None);
+}
+
+#[test]
+fn test_map_string_keys() {
+    let map: BPlusMap<String, usize> = (0..500)
+        .map(|i| (format!("/home/user/files/{i:04}"), i))
+        .collect();
+    assert_eq!(map.len(), 500);
+    assert_eq!(map.get(&"/home/user/files/0123".to_string()), Some(&123));
+    let from = "/home/user/files/0490".to_string();
+    assert!(map.range(from..).map(|(_, v)| *v).eq(490..500));
+    assert!(map.into_iter().map(|(_, v)| v).eq(0..500));
+}
+
+#[test]
+fn test_map_matches_btree_map() {
+    let mut rng = StdRng::seed_from_u64(7);
+    for t in [2, 3, 8] {
+        let mut map = BPlusMap::with_t(t);
+        let mut expected = BTreeMap::new();
+        for _ in 0..20000 {
+            let key = rng.gen_range(0..2000u32);
+            if rng.gen_bool(0.6) {
+                assert_eq!(map.insert(key, key + 1), expected.insert(key, key + 1));
+            } else {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            }
+            assert_eq!(map.len(), expected.len());
+        }
+        assert!(map.iter().eq(expected.iter()));
+        let (start, end) = (500, 1500);
+        assert!(map.range(start..end).eq(expected.range(start..end)));
+        assert!(map.clone().into_iter().eq(expected.into_iter()));
+        assert_eq!(format!("{map:?}").matches(':').count(), map.len());
+    }
+}
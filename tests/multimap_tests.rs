@@ -0,0 +1,52 @@
+use bplus_tree::error::BPlusError;
+use bplus_tree::multimap::MultiBPlus;
+use tempdir::TempDir;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_multimap() {
+    let tempdir = TempDir::new("multimap").unwrap();
+    let tree = MultiBPlus::new(2, tempdir.path().into()).unwrap();
+    for value in 0..10u8 {
+        tree.insert(value % 2, vec![value]).await.unwrap();
+    }
+    tree.insert(2, vec![100]).await.unwrap();
+
+    assert_eq!(
+        tree.get_all(&0).await.unwrap(),
+        vec![vec![0], vec![2], vec![4], vec![6], vec![8]]
+    );
+    assert_eq!(tree.get_all(&2).await.unwrap(), vec![vec![100]]);
+    assert!(tree.get_all(&3).await.unwrap().is_empty());
+
+    assert_eq!(tree.remove_at(&0, 1).await.unwrap(), vec![2]);
+    assert_eq!(tree.remove_at(&0, 1).await.unwrap(), vec![4]);
+    assert!(matches!(
+        tree.remove_at(&0, 3).await,
+        Err(BPlusError::NotFound)
+    ));
+    assert_eq!(
+        tree.get_all(&0).await.unwrap(),
+        vec![vec![0], vec![6], vec![8]]
+    );
+
+    assert_eq!(tree.remove_all(&1).await.unwrap(), 5);
+    assert!(tree.get_all(&1).await.unwrap().is_empty());
+    assert_eq!(tree.get_all(&2).await.unwrap(), vec![vec![100]]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_multimap_save_load() {
+    let tempdir = TempDir::new("multimap_save").unwrap();
+    let tree = MultiBPlus::new(2, tempdir.path().into()).unwrap();
+    for value in 0..5u8 {
+        tree.insert("key".to_string(), vec![value]).await.unwrap();
+    }
+    let path = tempdir.path().join("tree");
+    tree.save(&path).await.unwrap();
+    drop(tree);
+
+    let tree = MultiBPlus::<String>::load(&path).await.unwrap();
+    tree.insert("key".to_string(), vec![5]).await.unwrap();
+    let values: Vec<_> = (0..6).map(|value| vec![value]).collect();
+    assert_eq!(tree.get_all(&"key".to_string()).await.unwrap(), values);
+}
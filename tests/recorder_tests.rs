@@ -0,0 +1,128 @@
+use bplus_tree::bplus_tree::BPlus;
+use bplus_tree::recorder::{Divergence, Operation, RecordedValue, Recorder, Replayer};
+use std::io::Write;
+use std::ops::Bound;
+use std::sync::Arc;
+use tempdir::TempDir;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_record_and_replay() {
+    let tempdir = TempDir::new("recorder").unwrap();
+    let path = tempdir.path().join("operations");
+    let recorder = Arc::new(Recorder::open(path.clone()).unwrap());
+    let tree = BPlus::<u64>::new(2, tempdir.path().join("data"))
+        .unwrap()
+        .with_recorder(recorder.clone());
+
+    for key in 0..50 {
+        tree.insert(key, vec![key as u8; 10]).await.unwrap();
+    }
+    tree.insert(7, vec![70; 20]).await.unwrap();
+    assert!(tree.remove(&3).await.is_some());
+    assert!(tree.remove(&100).await.is_none());
+    assert_eq!(tree.get(&7).await.unwrap(), vec![70; 20]);
+    assert!(tree.get(&3).await.is_err());
+    assert_eq!(tree.scan(10..20).await.unwrap().len(), 10);
+    tree.compact().await.unwrap();
+    tree.rebuild().await.unwrap();
+    assert!(recorder.last_error().is_none());
+
+    let replayer = Replayer::<u64>::open(&path).unwrap();
+    let operations = replayer.operations();
+    assert_eq!(operations.len(), 58);
+    assert_eq!(
+        operations[50],
+        Operation::Insert {
+            key: 7,
+            value: RecordedValue::Full(vec![70; 20]),
+        }
+    );
+    assert_eq!(
+        operations[52..],
+        [
+            Operation::Remove {
+                key: 100,
+                found: false,
+            },
+            Operation::Get {
+                key: 7,
+                value: Some(RecordedValue::Full(vec![70; 20])),
+            },
+            Operation::Get {
+                key: 3,
+                value: None
+            },
+            Operation::Scan {
+                start: Bound::Included(10),
+                end: Bound::Excluded(20),
+                entries: Some(10),
+            },
+            Operation::Compact { limit: usize::MAX },
+            Operation::Rebuild,
+        ]
+    );
+
+    // Fresh tree ends with the same entries
+    let replayed = BPlus::<u64>::new(2, tempdir.path().join("replayed")).unwrap();
+    let report = replayer.replay(&replayed).await.unwrap();
+    assert_eq!(report.operations, 58);
+    assert!(report.divergences.is_empty());
+    assert_eq!(
+        replayed.scan(..).await.unwrap(),
+        tree.scan(..).await.unwrap()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replay_hashed_values() {
+    let tempdir = TempDir::new("recorder_hashed").unwrap();
+    let path = tempdir.path().join("operations");
+    let recorder = Arc::new(Recorder::open(path.clone()).unwrap().with_hashed_values());
+    let tree = BPlus::<u64>::new(2, tempdir.path().join("data"))
+        .unwrap()
+        .with_recorder(recorder.clone());
+
+    tree.insert(1, vec![1; 100]).await.unwrap();
+    tree.insert(2, vec![2; 3]).await.unwrap();
+    tree.get(&1).await.unwrap();
+    // Read of a corrupted value, as it was reported
+    recorder
+        .append(&Operation::Get {
+            key: &2,
+            value: Some(RecordedValue::Hashed { size: 3, hash: 42 }),
+        })
+        .unwrap();
+    // Torn record of an interrupted append
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    log.write_all(&[100, 0, 0]).unwrap();
+
+    let replayer = Replayer::<u64>::open(&path).unwrap();
+    let Operation::Insert { value, .. } = &replayer.operations()[0] else {
+        panic!("first operation is an insert");
+    };
+    assert_eq!(value.size(), 100);
+    assert!(matches!(value, RecordedValue::Hashed { .. }));
+
+    let replayed = BPlus::<u64>::new(2, tempdir.path().join("replayed")).unwrap();
+    let report = replayer.replay(&replayed).await.unwrap();
+    assert_eq!(report.operations, 4);
+    let filler = replayed.get(&2).await.unwrap();
+    assert_eq!(filler.len(), 3);
+    assert_eq!(
+        report.divergences,
+        vec![Divergence {
+            index: 3,
+            recorded: Operation::Get {
+                key: 2,
+                value: Some(RecordedValue::Hashed { size: 3, hash: 42 }),
+            },
+            replayed: Operation::Get {
+                key: 2,
+                value: Some(RecordedValue::Full(filler)),
+            },
+        }]
+    );
+}
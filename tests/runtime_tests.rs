@@ -0,0 +1,40 @@
+use bplus_tree::{bplus_tree::BPlus, error::BPlusError};
+use futures::future::join_all;
+use std::time::Duration;
+use tempdir::TempDir;
+
+/// Returns number of threads of the current process
+fn thread_count() -> usize {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn test_timeouts_share_timer_thread() {
+    let tempdir = TempDir::new("shared_timer").unwrap();
+    let tree: BPlus<usize> = BPlus::new(2, tempdir.path().into()).unwrap();
+
+    futures::executor::block_on(async {
+        tree.insert(1, vec![1]).await.unwrap();
+        let before = thread_count();
+        let keys: Vec<usize> = (0..100).map(|i| i % 2).collect();
+        let gets = keys
+            .iter()
+            .map(|key| tree.get_timeout(key, Duration::from_secs(10)));
+        let results = join_all(gets).await;
+        // Timers are still pending, but they share a single thread
+        assert!(thread_count() <= before + 1);
+        for (i, result) in results.into_iter().enumerate() {
+            match i % 2 {
+                0 => assert!(matches!(result, Err(BPlusError::NotFound))),
+                _ => assert_eq!(result.unwrap(), vec![1]),
+            }
+        }
+    });
+}
@@ -0,0 +1,78 @@
+use bplus_tree::store::Store;
+use tempdir::TempDir;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_families_share_data_files() {
+    let tempdir = TempDir::new("store").unwrap();
+    let store: Store<u64> = Store::new(2, tempdir.path().into()).unwrap();
+    let data = store.family("data").unwrap();
+    let meta = store.family("meta").unwrap();
+    assert!(store.family("").is_err());
+    assert!(store.family("../data").is_err());
+
+    for key in 0..100 {
+        data.insert(key, vec![1; 10]).await.unwrap();
+        meta.insert(key, vec![2; 10]).await.unwrap();
+    }
+    assert!(meta.remove(&5).await.is_some());
+    // Keys of trees are independent
+    assert_eq!(data.get(&5).await.unwrap(), vec![1; 10]);
+    assert!(meta.get(&5).await.is_err());
+    assert!(std::sync::Arc::ptr_eq(
+        &data,
+        &store.family("data").unwrap()
+    ));
+    assert_eq!(store.names(), vec!["data", "meta"]);
+
+    // Values of both trees are appended to one data file
+    let data_files: Vec<_> = std::fs::read_dir(tempdir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(data_files, vec!["0"]);
+    assert_eq!(
+        std::fs::metadata(tempdir.path().join("0")).unwrap().len(),
+        // File starts with a 44-byte header, and every value is preceded by a 16-byte one
+        44 + 200 * (16 + 10)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_store_save_open() {
+    let tempdir = TempDir::new("store_open").unwrap();
+    {
+        let store: Store<u64> = Store::new(2, tempdir.path().into()).unwrap();
+        let data = store.family("data").unwrap();
+        let meta = store.family("meta").unwrap();
+        for key in 0..50 {
+            data.insert(key, vec![key as u8]).await.unwrap();
+        }
+        meta.insert(1, vec![42]).await.unwrap();
+        store.save().await.unwrap();
+        store.sync().await.unwrap();
+    }
+
+    let store: Store<u64> = Store::open(2, tempdir.path().into()).await.unwrap();
+    assert_eq!(store.names(), vec!["data", "meta"]);
+    let data = store.family("data").unwrap();
+    let meta = store.family("meta").unwrap();
+    assert_eq!(data.scan(..).await.unwrap().len(), 50);
+    assert_eq!(meta.get(&1).await.unwrap(), vec![42]);
+
+    // New values do not overwrite values of any tree
+    meta.insert(2, vec![7; 100]).await.unwrap();
+    data.insert(100, vec![8; 100]).await.unwrap();
+    for key in 0..50 {
+        assert_eq!(data.get(&key).await.unwrap(), vec![key as u8]);
+    }
+    assert_eq!(meta.get(&1).await.unwrap(), vec![42]);
+    assert_eq!(meta.get(&2).await.unwrap(), vec![7; 100]);
+
+    let other = store.family("other").unwrap();
+    other.insert(1, vec![3]).await.unwrap();
+    assert_eq!(other.get(&1).await.unwrap(), vec![3]);
+
+    let empty = TempDir::new("store_empty").unwrap();
+    let store: Store<u64> = Store::open(2, empty.path().into()).await.unwrap();
+    assert!(store.names().is_empty());
+}